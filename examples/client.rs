@@ -72,6 +72,16 @@ fn main() {
       }
       Ok (Some (event @ Event::Receive    {..})) =>
         println!("client received packet event:\n{event:#?}"),
+      Ok (Some (event @ Event::Authenticated {..})) =>
+        println!("client received authenticated event:\n{event:#?}"),
+      Ok (Some (event @ Event::Drained {..})) =>
+        println!("client received drained event:\n{event:#?}"),
+      Ok (Some (event @ (Event::Delivered {..} | Event::Dropped {..}))) =>
+        println!("client received delivery event:\n{event:#?}"),
+      Ok (Some (event @ Event::QualityChanged {..})) =>
+        println!("client received quality event:\n{event:#?}"),
+      Ok (Some (event)) =>
+        println!("client received event:\n{event:#?}"),
       Ok (None) => {}
       Err (err) => println!("client received error: {err:?}")
     }
@@ -65,6 +65,16 @@ fn main() {
       }
       Ok (Some (event @ Event::Receive    {..})) =>
         println!("server received packet event:\n{event:#?}"),
+      Ok (Some (event @ Event::Authenticated {..})) =>
+        println!("server received authenticated event:\n{event:#?}"),
+      Ok (Some (event @ Event::Drained {..})) =>
+        println!("server received drained event:\n{event:#?}"),
+      Ok (Some (event @ (Event::Delivered {..} | Event::Dropped {..}))) =>
+        println!("server received delivery event:\n{event:#?}"),
+      Ok (Some (event @ Event::QualityChanged {..})) =>
+        println!("server received quality event:\n{event:#?}"),
+      Ok (Some (event)) =>
+        println!("server received event:\n{event:#?}"),
       Ok  (None) => {}
       Err (err)  => println!("service error: {err:?}")
     }
@@ -111,6 +111,37 @@ impl serde::Serialize for Address {
   }
 }
 
+impl From <Address> for std::net::SocketAddrV4 {
+  fn from (address : Address) -> Self {
+    let ll::ENetAddress { host, port } = address.address;
+    std::net::SocketAddrV4::new (host.to_le_bytes().into(), port)
+  }
+}
+impl From <std::net::SocketAddrV4> for Address {
+  fn from (address : std::net::SocketAddrV4) -> Self {
+    let host = u32::from_le_bytes (address.ip().octets());
+    Address::from_ll (ll::ENetAddress { host, port: address.port() })
+  }
+}
+
+impl std::fmt::Display for AddressError {
+  fn fmt (&self, f : &mut std::fmt::Formatter) -> std::fmt::Result {
+    match self {
+      AddressError::HostNameResolveFailure (hostname) =>
+        write!(f, "failed to resolve host name {hostname:?}"),
+      AddressError::CStringNulError (_) => write!(f, "host name contains a nul byte")
+    }
+  }
+}
+impl std::error::Error for AddressError {
+  fn source (&self) -> Option <&(dyn std::error::Error + 'static)> {
+    match self {
+      AddressError::HostNameResolveFailure (_) => None,
+      AddressError::CStringNulError (error) => Some (error)
+    }
+  }
+}
+
 impl From <std::ffi::NulError> for AddressError {
   fn from (err : std::ffi::NulError) -> AddressError {
     AddressError::CStringNulError (err)
@@ -0,0 +1,400 @@
+//! Authenticated connection handshake.
+//!
+//! When a `Handshake` is set on a `Host`, newly connected peers are not
+//! reported with `Event::Connect`. Instead each end of the connection sends a
+//! challenge to the other end on a reserved channel, and checks the answer
+//! with an `Authenticator`. Once the remote peer has answered correctly,
+//! `Event::Authenticated` is returned by `service()` or `check_events()`.
+//!
+//! Peers that answer incorrectly or do not answer within the handshake timeout
+//! are disconnected, and an `Event::Disconnect` is generated with the `data`
+//! field set to `DISCONNECT_REJECTED` or `DISCONNECT_TIMEOUT`. The remote peer
+//! receives the same reason code in its own `Disconnect` event.
+//!
+//! Until a peer is authenticated, `peer.send()` will fail with
+//! `SendErrorKind::PeerNotAuthenticated` and `host.broadcast()` will skip the
+//! peer. Packets received from the peer in the meantime are held (up to a
+//! limit) and returned after the `Authenticated` event, or dropped if the peer
+//! is rejected.
+//!
+//! Answers are bound to the connection with a `Binding`: the role of the end
+//! that answers, the ENet connect ID and, if encryption is set (see the
+//! `crypto` module), the public keys of the key exchange. The handshake of an
+//! encrypted connection runs once the key exchange is complete, so that it only
+//! succeeds if both ends exchanged keys with each other and not with a man in
+//! the middle. A host that receives a challenge equal
+//! to one it sent and that has not been answered yet rejects the peer, and each
+//! peer is only answered once, so that a peer cannot get the host to answer its
+//! own challenge.
+//!
+//! Both hosts of a connection must use a handshake on the same channel.
+
+use std;
+use crate::{host, Peer};
+
+/// Disconnect `data` for a peer that failed to answer a challenge correctly
+pub const DISCONNECT_REJECTED : u32 = 0xAE70_0001;
+/// Disconnect `data` for a peer that did not answer a challenge in time
+pub const DISCONNECT_TIMEOUT  : u32 = 0xAE70_0002;
+
+/// Handshake message tags; the first byte of each packet on the handshake
+/// channel
+const TAG_CHALLENGE : u8 = 0x01;
+const TAG_RESPONSE  : u8 = 0x02;
+
+////////////////////////////////////////////////////////////////////////////////
+//  traits                                                                    //
+////////////////////////////////////////////////////////////////////////////////
+
+/// Challenge/response scheme used by a `Handshake`.
+///
+/// For example a server nonce as the challenge and an HMAC with a shared key
+/// of the nonce and `binding.to_bytes()` as the response. The response must
+/// depend on the binding: `respond()` is given the binding of the local end,
+/// and `verify()` the binding of the remote end, so that an answer given by
+/// one end is never accepted from the other.
+///
+/// Methods are called from within `service()` and `check_events()`.
+pub trait Authenticator {
+  /// Create a challenge for a newly connected peer
+  fn challenge (&mut self, peer : &Peer) -> Vec <u8>;
+  /// Answer a challenge received from a peer
+  fn respond (&mut self, peer : &Peer, binding : &Binding, challenge : &[u8])
+    -> Vec <u8>;
+  /// Check the answer of a peer to the challenge that was sent to it
+  fn verify (&mut self,
+    peer : &Peer, binding : &Binding, challenge : &[u8], response : &[u8]
+  ) -> bool;
+}
+
+////////////////////////////////////////////////////////////////////////////////
+//  structs                                                                   //
+////////////////////////////////////////////////////////////////////////////////
+
+/// Handshake configuration for a `Host`
+pub struct Handshake {
+  authenticator : Box <dyn Authenticator>,
+  channel_id    : u8,
+  timeout       : u32
+}
+
+/// The end of a connection giving an answer, which the answer is bound to
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Binding {
+  pub role       : Role,
+  /// Connect ID of the connection, the same at both ends
+  pub connect_id : u32,
+  /// Public keys of the key exchange, the initiator's first; empty if the
+  /// connection is not encrypted
+  pub keys       : Vec <u8>
+}
+
+/// Handshake progress of a single peer
+#[derive(Debug)]
+pub(crate) struct PeerAuth {
+  /// Role of the local end
+  role          : Role,
+  /// Public keys of the key exchange, once complete
+  keys          : Vec <u8>,
+  challenge     : Vec <u8>,
+  /// The challenge of the peer has been answered
+  answered      : bool,
+  authenticated : bool
+}
+
+/// Handshake message received on the handshake channel
+pub(crate) enum Message <'a> {
+  Challenge (&'a [u8]),
+  Response  (&'a [u8])
+}
+
+////////////////////////////////////////////////////////////////////////////////
+//  enums                                                                     //
+////////////////////////////////////////////////////////////////////////////////
+
+/// Role of an end of a connection
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Role {
+  /// The end that called `host.connect()`
+  Initiator,
+  /// The end that accepted the connection
+  Responder
+}
+
+////////////////////////////////////////////////////////////////////////////////
+//  impls                                                                     //
+////////////////////////////////////////////////////////////////////////////////
+
+impl Handshake {
+  /// Run the handshake on `channel_id`; peers that have not answered after
+  /// `timeout` milliseconds are disconnected.
+  ///
+  /// The handshake channel is reserved: packets received on it are not
+  /// reported to the application.
+  pub fn new (
+    authenticator : Box <dyn Authenticator>,
+    channel_id    : u8,
+    timeout       : u32
+  ) -> Self {
+    Handshake { authenticator, channel_id, timeout }
+  }
+
+  #[inline]
+  pub const fn channel_id (&self) -> u8 {
+    self.channel_id
+  }
+
+  /// Milliseconds
+  #[inline]
+  pub const fn timeout (&self) -> u32 {
+    self.timeout
+  }
+
+  /// Create a challenge for a peer and send it.
+  ///
+  /// Returns `None` if the challenge could not be sent.
+  pub(crate) fn begin (&mut self, peer : &Peer, role : Role)
+    -> Option <PeerAuth>
+  {
+    let challenge = self.authenticator.challenge (peer);
+    if !send (peer, self.channel_id, TAG_CHALLENGE, &challenge) {
+      return None
+    }
+    Some (PeerAuth {
+      role, keys: Vec::new(), challenge, answered: false, authenticated: false
+    })
+  }
+
+  /// Answer the challenge of a peer; only the first challenge is answered
+  pub(crate) fn respond (&mut self,
+    peer : &Peer, auth : &mut PeerAuth, challenge : &[u8]
+  ) {
+    if auth.answered {
+      return
+    }
+    auth.answered = true;
+    let binding  = Binding {
+      role: auth.role, connect_id: peer.connect_id(), keys: auth.keys.clone()
+    };
+    let response = self.authenticator.respond (peer, &binding, challenge);
+    let _ = send (peer, self.channel_id, TAG_RESPONSE, &response);
+  }
+
+  /// Check the answer of a peer, marking it as authenticated on success
+  pub(crate) fn verify (&mut self,
+    peer : &Peer, auth : &mut PeerAuth, response : &[u8]
+  ) -> bool {
+    let binding = Binding {
+      role:       auth.role.remote(),
+      connect_id: peer.connect_id(),
+      keys:       auth.keys.clone()
+    };
+    auth.authenticated =
+      self.authenticator.verify (peer, &binding, &auth.challenge, response);
+    auth.authenticated
+  }
+}
+
+impl std::fmt::Debug for Handshake {
+  fn fmt (&self, f : &mut std::fmt::Formatter) -> std::fmt::Result {
+    f.debug_struct ("Handshake")
+      .field ("channel_id", &self.channel_id)
+      .field ("timeout", &self.timeout)
+      .finish_non_exhaustive()
+  }
+}
+
+impl Binding {
+  /// Bytes identifying the binding, to include in a response: the role (0 for
+  /// the initiator, 1 for the responder), the little endian connect ID and the
+  /// public keys
+  pub fn to_bytes (&self) -> Vec <u8> {
+    let mut bytes = vec![match self.role {
+      Role::Initiator => 0,
+      Role::Responder => 1
+    }];
+    bytes.extend_from_slice (&self.connect_id.to_le_bytes());
+    bytes.extend_from_slice (&self.keys);
+    bytes
+  }
+}
+
+impl Role {
+  /// The role of the other end of the connection
+  #[inline]
+  pub const fn remote (self) -> Self {
+    match self {
+      Role::Initiator => Role::Responder,
+      Role::Responder => Role::Initiator
+    }
+  }
+}
+
+impl PeerAuth {
+  #[cfg(feature = "encryption")]
+  #[inline]
+  pub(crate) const fn role (&self) -> Role {
+    self.role
+  }
+
+  #[inline]
+  pub(crate) const fn authenticated (&self) -> bool {
+    self.authenticated
+  }
+
+  /// Bind answers to the public keys of the completed key exchange
+  #[cfg(feature = "encryption")]
+  #[inline]
+  pub(crate) fn bind_keys (&mut self, keys : Vec <u8>) {
+    self.keys = keys;
+  }
+
+  /// The challenge sent to the peer, if it has not been answered correctly
+  pub(crate) fn outstanding (&self) -> Option <&[u8]> {
+    (!self.authenticated).then_some (self.challenge.as_slice())
+  }
+}
+
+impl <'a> Message <'a> {
+  pub(crate) const fn parse (bytes : &'a [u8]) -> Option <Self> {
+    match bytes.split_first() {
+      Some ((&TAG_CHALLENGE, challenge)) => Some (Message::Challenge (challenge)),
+      Some ((&TAG_RESPONSE,  response))  => Some (Message::Response  (response)),
+      _ => None
+    }
+  }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+//  functions                                                                 //
+////////////////////////////////////////////////////////////////////////////////
+
+/// Queue a reliable handshake message
+fn send (peer : &Peer, channel_id : u8, tag : u8, payload : &[u8]) -> bool {
+  let mut message = Vec::with_capacity (1 + payload.len());
+  message.push (tag);
+  message.extend_from_slice (payload);
+  host::send_control (peer, channel_id, &message)
+}
+
+////////////////////////////////////////////////////////////////////////////////
+//  tests                                                                     //
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg (test)]
+mod tests {
+  use std::hash::{BuildHasher, Hash, Hasher};
+  use crate::{packet, testing, Address, Event, Host, Packet, Peer};
+  use super::*;
+
+  const CHANNEL : u8 = 1;
+
+  /// Answers with a keyed hash of the challenge and binding
+  struct TestAuthenticator {
+    key : u64
+  }
+
+  impl TestAuthenticator {
+    fn handshake() -> Handshake {
+      Handshake::new (Box::new (TestAuthenticator { key: 0x5eed }), CHANNEL,
+        2000)
+    }
+
+    fn answer (&self, binding : &Binding, challenge : &[u8]) -> Vec <u8> {
+      let mut hasher = std::hash::DefaultHasher::new();
+      (self.key, binding.to_bytes(), challenge).hash (&mut hasher);
+      hasher.finish().to_le_bytes().to_vec()
+    }
+  }
+
+  impl Authenticator for TestAuthenticator {
+    fn challenge (&mut self, _peer : &Peer) -> Vec <u8> {
+      let nonce = std::collections::hash_map::RandomState::new()
+        .build_hasher().finish();
+      nonce.to_le_bytes().to_vec()
+    }
+    fn respond (&mut self, _peer : &Peer, binding : &Binding, challenge : &[u8])
+      -> Vec <u8>
+    {
+      self.answer (binding, challenge)
+    }
+    fn verify (&mut self,
+      _peer : &Peer, binding : &Binding, challenge : &[u8], response : &[u8]
+    ) -> bool {
+      self.answer (binding, challenge) == response
+    }
+  }
+
+  fn server() -> Host {
+    testing::enet().server_host_create (Address::localhost (0), 2, Some (2),
+      None, None).unwrap()
+  }
+
+  /// Connect a client to a server, both set up with `setup`, and check that
+  /// both ends report the peer as authenticated
+  fn authenticate (setup : fn (&mut Host)) {
+    let mut server = server();
+    let mut client = testing::enet().client_host_create (1, None, None).unwrap();
+    setup (&mut server);
+    setup (&mut client);
+    let port = server.local_address().port();
+    client.connect (&Address::localhost (port), 2, 7).unwrap();
+    let events = testing::pump_until (&mut [&mut server, &mut client],
+      testing::TIMEOUT, |events| events.len() == 2).unwrap();
+    for host in [0, 1] {
+      assert!(events.iter().any (|recorded| recorded.host == host &&
+        matches!(recorded.event, Event::Authenticated {..})), "{events:?}");
+    }
+  }
+
+  #[test]
+  fn handshake_authenticates_both_ends() {
+    authenticate (|host|
+      host.set_handshake (Some (TestAuthenticator::handshake())));
+  }
+
+  #[cfg(feature = "encryption")]
+  #[test]
+  fn handshake_authenticates_encrypted_connection() {
+    authenticate (|host| {
+      host.set_encryption (Some (crate::crypto::Encryption::new (0, 2000)));
+      host.set_handshake (Some (TestAuthenticator::handshake()));
+    });
+  }
+
+  #[test]
+  fn reflected_challenge_is_rejected() {
+    let mut server   = server();
+    let mut attacker =
+      testing::enet().client_host_create (1, None, None).unwrap();
+    server.set_handshake (Some (TestAuthenticator::handshake()));
+    let port = server.local_address().port();
+    let mut peer = attacker.connect (&Address::localhost (port), 2, 0).unwrap();
+    let is_challenge = |event : &Event| matches!(event, Event::Receive {
+      channel_id: CHANNEL, packet, ..
+    } if packet.first() == Some (&TAG_CHALLENGE));
+    let events = testing::pump_until (&mut [&mut server, &mut attacker],
+      testing::TIMEOUT,
+      |events| events.iter().any (|recorded| is_challenge (&recorded.event))
+    ).unwrap();
+    let challenge = events.iter().find_map (|recorded| match &recorded.event {
+      Event::Receive { packet, .. } if is_challenge (&recorded.event) =>
+        Some (packet.to_vec()),
+      _ => None
+    }).unwrap();
+    // send the challenge of the server back to it as our own
+    peer.send (CHANNEL, Packet::Allocate {
+      bytes: &challenge, flags: packet::Flags::RELIABLE
+    }).unwrap();
+    let events = testing::pump_until (&mut [&mut server, &mut attacker],
+      testing::TIMEOUT, |events| events.iter().any (|recorded|
+        recorded.host == 1 && matches!(recorded.event, Event::Disconnect {..}))
+    ).unwrap();
+    assert!(events.iter().all (|recorded| match &recorded.event {
+      Event::Authenticated {..} => false,
+      Event::Receive { packet, .. } => packet.first() != Some (&TAG_RESPONSE),
+      Event::Disconnect { data, .. } => *data == DISCONNECT_REJECTED,
+      _ => true
+    }), "{events:?}");
+  }
+}
@@ -0,0 +1,905 @@
+//! Capture of raw datagrams to pcap files, and offline replay of captures.
+//!
+//! `host.start_capture()` writes every UDP datagram received or sent by the
+//! host to a pcap file (link type raw IPv4) that can be opened in Wireshark.
+//! Incoming datagrams are captured through the host intercept, before any
+//! `Intercept` sees them.
+//!
+//! Outgoing datagrams are captured by wrapping the send path of the host: ENet
+//! hands each datagram to the host compressor just before sending it, so the
+//! capture wraps the compressor set on the host (if any) with a pass-through
+//! that copies the datagram. A compressor set with `enet_host_compress` during
+//! a capture is wrapped in turn the next time the host sends.
+//!
+//! ENet writes the peer ID into the header only after compressing, so the
+//! capture finds the peer once the datagram has been sent, from the send time
+//! ENet sets on it, without changing any peer state. A datagram sent to a peer
+//! that was already sent one in the same millisecond can only be told apart if
+//! no other peer was sent one in that millisecond either; otherwise it is
+//! recorded with the unspecified destination `0.0.0.0:0`.
+//!
+//! `Replay` reads the datagrams received by a host from a capture and feeds
+//! them to a fresh host over loopback with the original timing, so that the
+//! same sequence of events can be reproduced.
+
+use std;
+use std::collections::{HashMap, VecDeque};
+use std::io::{Read, Write};
+use std::net::{Ipv4Addr, SocketAddrV4, UdpSocket};
+use std::rc::Rc;
+use std::time::{Duration, Instant, SystemTime};
+
+use ll;
+use crate::{host, intercept, Address, Event, Host, MAX_PEERS};
+
+const PCAP_MAGIC         : u32 = 0xA1B2_C3D4;
+const PCAP_MAGIC_NANOS   : u32 = 0xA1B2_3C4D;
+const LINKTYPE_RAW       : u32 = 101;
+const LINKTYPE_IPV4      : u32 = 228;
+const SNAPLEN            : u32 = 65535;
+const IPV4_HEADER_LENGTH : usize = 20;
+const UDP_HEADER_LENGTH  : usize = 8;
+const PROTOCOL_UDP       : u8 = 17;
+/// Time the replayed host is serviced after the last datagram was sent
+const REPLAY_DRAIN       : Duration = Duration::from_millis (100);
+
+////////////////////////////////////////////////////////////////////////////////
+//  structs                                                                   //
+////////////////////////////////////////////////////////////////////////////////
+
+/// Capture in progress on a host
+#[derive(Debug)]
+pub(crate) struct Capture {
+  writer     : std::io::BufWriter <std::fs::File>,
+  local      : SocketAddrV4,
+  /// Pass-through compressor installed on the host
+  tap        : ll::ENetCompressor,
+  /// Compressor wrapped by `tap`
+  inner      : ll::ENetCompressor,
+  /// Datagram passed to the compressor, recorded once ENet has sent it
+  sending    : Option <Sending>,
+  /// Peers already sent a datagram at the service time of `sent`, by index
+  sent       : (u32, Vec <usize>),
+  /// Peer that the current ENet call resets after sending to it
+  resetting  : Option <Resetting>,
+  /// First write error, returned when the capture is stopped
+  error      : Option <std::io::Error>
+}
+
+/// Outgoing datagram whose peer is not known yet
+#[derive(Debug)]
+struct Sending {
+  time         : SystemTime,
+  /// Host service time, which ENet sets as the send time of the peer
+  service_time : u32,
+  /// Header flags, without the peer ID
+  flags        : u16,
+  /// Header following the peer ID
+  header       : Vec <u8>,
+  /// Datagram without its protocol header, uncompressed
+  data         : Vec <u8>,
+  compressed   : Option <Vec <u8>>
+}
+
+/// State of a peer that ENet writes into the header of datagrams sent to it
+#[derive(Clone, Copy, Debug)]
+struct PeerState {
+  peer_id    : u16,
+  session_id : u8,
+  connect_id : u32,
+  address    : SocketAddrV4
+}
+
+/// Peer reset by an ENet call after sending to it
+#[derive(Clone, Copy, Debug)]
+struct Resetting {
+  index : usize,
+  /// State before the call
+  peer  : PeerState,
+  /// Whether a datagram sent during the call was found to be for the peer
+  sent  : bool
+}
+
+/// Datagrams received by a host, read from a capture
+#[derive(Debug)]
+pub struct Replay {
+  datagrams : Vec <Datagram>
+}
+
+#[derive(Debug)]
+struct Datagram {
+  /// Since the first datagram of the capture
+  time   : Duration,
+  source : SocketAddrV4,
+  data   : Vec <u8>
+}
+
+/// UDP datagram read from a capture
+#[derive(Debug)]
+struct Record {
+  time        : Duration,
+  source      : SocketAddrV4,
+  destination : SocketAddrV4,
+  data        : Vec <u8>
+}
+
+/// Rewrites the address of datagrams sent by a `Replay` to the address each
+/// datagram is replayed from
+#[derive(Debug)]
+struct ReplayIntercept {
+  sender  : SocketAddrV4,
+  sources : Rc <std::cell::RefCell <VecDeque <SocketAddrV4>>>
+}
+
+////////////////////////////////////////////////////////////////////////////////
+//  impls                                                                     //
+////////////////////////////////////////////////////////////////////////////////
+
+impl Capture {
+  /// Create the capture file and wrap the send path of the host.
+  ///
+  /// # Safety
+  ///
+  /// `hostdrop` must be the host `raw` belongs to, and must not move while the
+  /// capture is installed.
+  pub(crate) unsafe fn start (
+    path     : &std::path::Path,
+    raw      : *mut ll::ENetHost,
+    hostdrop : *const host::HostDrop,
+    local    : Address
+  ) -> std::io::Result <Self> {
+    let mut writer = std::io::BufWriter::new (std::fs::File::create (path)?);
+    write_header (&mut writer)?;
+    writer.flush()?;
+    let tap = ll::ENetCompressor {
+      context:    hostdrop as *mut std::os::raw::c_void,
+      compress:   Some (compress),
+      decompress: Some (decompress),
+      destroy:    Some (destroy)
+    };
+    unsafe {
+      let inner = (*raw).compressor;
+      // set directly: `enet_host_compress` would destroy the current compressor
+      (*raw).compressor = tap;
+      let mut capture = Capture {
+        writer, local: local.into(), tap, inner, sending: None,
+        sent: (0, Vec::new()), resetting: None, error: None
+      };
+      capture.unseen (raw);
+      Ok (capture)
+    }
+  }
+
+  /// Record the last datagram sent, restore the compressor of the host if the
+  /// capture is still installed, and flush the capture file
+  pub(crate) unsafe fn stop (mut self, raw : *mut ll::ENetHost)
+    -> std::io::Result <()>
+  {
+    unsafe {
+      self.sent (raw);
+      if (*raw).compressor.context == self.tap.context {
+        (*raw).compressor = self.inner;
+      }
+    }
+    if let Some (err) = self.error.take() {
+      return Err (err)
+    }
+    self.writer.flush()
+  }
+
+  /// Wrap the compressor of the host again if it was replaced since the last
+  /// send
+  pub(crate) unsafe fn wrap (&mut self, raw : *mut ll::ENetHost) {
+    unsafe {
+      if (*raw).compressor.context != self.tap.context {
+        self.inner = (*raw).compressor;
+        (*raw).compressor = self.tap;
+        self.unseen (raw);
+      }
+    }
+  }
+
+  /// Note that the next ENet call resets `peer` after sending to it, as
+  /// `enet_peer_disconnect_now` does
+  pub(crate) unsafe fn resetting (&mut self,
+    raw : *mut ll::ENetHost, peer : *mut ll::ENetPeer
+  ) {
+    unsafe {
+      let index = peer.offset_from ((*raw).peers) as usize;
+      self.resetting = Some (
+        Resetting { index, peer: PeerState::from_ll (&*peer), sent: false });
+    }
+  }
+
+  pub(crate) unsafe fn incoming (&mut self,
+    raw : *mut ll::ENetHost, datagram : &intercept::Datagram
+  ) {
+    unsafe { self.sent (raw) }
+    let source = datagram.address().into();
+    self.record (SystemTime::now(), source, self.local, &[datagram.data()]);
+  }
+
+  /// Hold an outgoing datagram until it is sent; `buffers` is the datagram
+  /// without its protocol header, as passed to the compressor
+  unsafe fn outgoing (&mut self,
+    raw : *mut ll::ENetHost, buffers : &[ll::ENetBuffer], compressed : Option <&[u8]>
+  ) {
+    unsafe {
+      let host = &*raw;
+      let mut flags = host.headerFlags;
+      if compressed.is_some() {
+        flags |= ll::_ENetProtocolFlag_ENET_PROTOCOL_HEADER_FLAG_COMPRESSED as u16;
+      }
+      self.sending = Some (Sending {
+        time: SystemTime::now(),
+        service_time: host.serviceTime,
+        flags,
+        header: buffer_bytes (&host.buffers[0])[2..].to_vec(),
+        data: buffers.iter().flat_map (|buffer| buffer_bytes (buffer))
+          .copied().collect(),
+        compressed: compressed.map (<[u8]>::to_vec)
+      });
+    }
+  }
+
+  /// Record the datagram held by `outgoing()`, now that ENet has sent it
+  pub(crate) unsafe fn sent (&mut self, raw : *mut ll::ENetHost) {
+    let Some (sending) = self.sending.take() else {
+      return
+    };
+    unsafe {
+      let host = &*raw;
+      let peer = self.sent_to (host, sending.service_time);
+      // the header as written by ENet after compressing
+      let mut peer_id = sending.flags;
+      if let Some (peer) = peer {
+        if u32::from (peer.peer_id) < MAX_PEERS {
+          peer_id |= u16::from (peer.session_id) <<
+            ll::_ENetProtocolFlag_ENET_PROTOCOL_HEADER_SESSION_SHIFT;
+        }
+        peer_id |= peer.peer_id;
+      }
+      let mut header = peer_id.to_be_bytes().to_vec();
+      header.extend_from_slice (&sending.header);
+      if let Some (checksum) = host.checksum {
+        // computed as ENet does: over the uncompressed datagram, with the
+        // checksum field holding the connect ID
+        let connect_id = peer
+          .filter (|peer| u32::from (peer.peer_id) < MAX_PEERS)
+          .map_or (0, |peer| peer.connect_id);
+        let offset = header.len();
+        header.extend_from_slice (&connect_id.to_ne_bytes());
+        let all = [
+          ll::ENetBuffer {
+            data:       header.as_mut_ptr() as *mut std::os::raw::c_void,
+            dataLength: header.len()
+          },
+          ll::ENetBuffer {
+            data:       sending.data.as_ptr() as *mut std::os::raw::c_void,
+            dataLength: sending.data.len()
+          }
+        ];
+        let value = checksum (all.as_ptr(), all.len());
+        header[offset..].copy_from_slice (&value.to_ne_bytes());
+      }
+      let destination = peer.map_or (SocketAddrV4::new (Ipv4Addr::UNSPECIFIED, 0),
+        |peer| peer.address);
+      let payload = sending.compressed.as_deref().unwrap_or (&sending.data);
+      self.record (sending.time, self.local, destination, &[&header, payload]);
+    }
+  }
+
+  /// Record the last datagram sent by an ENet call once it has returned
+  pub(crate) unsafe fn returned (&mut self, raw : *mut ll::ENetHost) {
+    unsafe { self.sent (raw) }
+    self.resetting = None;
+  }
+
+  /// Find the peer a datagram compressed at `service_time` was sent to: the
+  /// peer ENet has since set that send time on, or else the only peer that
+  /// could have been sent it again
+  unsafe fn sent_to (&mut self, host : &ll::ENetHost, service_time : u32)
+    -> Option <PeerState>
+  {
+    let (time, sent) = &mut self.sent;
+    if *time != service_time {
+      *time = service_time;
+      sent.clear();
+    }
+    let peers = unsafe { std::slice::from_raw_parts (host.peers, host.peerCount) };
+    let newly = peers.iter().enumerate().position (|(index, peer)|
+      peer.lastSendTime == service_time && !sent.contains (&index));
+    let found = if let Some (index) = newly {
+      sent.push (index);
+      Some ((index, PeerState::from_ll (&peers[index])))
+    } else {
+      // sent again, or to a peer reset since: ENet leaves no trace, unless a
+      // single peer can be meant
+      let reset = self.resetting.filter (|resetting| !resetting.sent &&
+        peers[resetting.index].state ==
+          ll::_ENetPeerState_ENET_PEER_STATE_DISCONNECTED);
+      let mut again = sent.iter()
+        .filter (|index| peers[**index].lastSendTime == service_time);
+      match (reset, again.next(), again.next()) {
+        (Some (reset), None, None) => Some ((reset.index, reset.peer)),
+        (None, Some (index), None) =>
+          Some ((*index, PeerState::from_ll (&peers[*index]))),
+        _ => None
+      }
+    };
+    let (index, peer) = found?;
+    if let Some (resetting) = self.resetting.as_mut() &&
+      resetting.index == index
+    {
+      resetting.sent = true;
+    }
+    Some (peer)
+  }
+
+  /// Note the peers that were sent a datagram at the current service time
+  /// without the capture seeing it
+  unsafe fn unseen (&mut self, raw : *mut ll::ENetHost) {
+    unsafe {
+      let host  = &*raw;
+      let peers = std::slice::from_raw_parts (host.peers, host.peerCount);
+      self.sent = (host.serviceTime, peers.iter().enumerate()
+        .filter (|(_, peer)| peer.lastSendTime == host.serviceTime)
+        .map (|(index, _)| index).collect());
+    }
+  }
+
+  fn record (&mut self,
+    time : SystemTime, source : SocketAddrV4, destination : SocketAddrV4,
+    parts : &[&[u8]]
+  ) {
+    if self.error.is_some() {
+      return
+    }
+    if let Err (err) =
+      write_record (&mut self.writer, time, source, destination, parts)
+    {
+      self.error = Some (err);
+    }
+  }
+}
+
+impl PeerState {
+  fn from_ll (peer : &ll::ENetPeer) -> Self {
+    PeerState {
+      peer_id:    peer.outgoingPeerID,
+      session_id: peer.outgoingSessionID,
+      connect_id: peer.connectID,
+      address:    Address::from_ll (peer.address).into()
+    }
+  }
+}
+
+impl Replay {
+  /// Read the datagrams received on local port `port` from a capture
+  pub fn open <P : AsRef <std::path::Path>> (path : P, port : u16)
+    -> std::io::Result <Self>
+  {
+    let mut bytes = Vec::new();
+    std::fs::File::open (path)?.read_to_end (&mut bytes)?;
+    let records = read_capture (&bytes)?.into_iter()
+      .filter (|record| record.destination.port() == port)
+      .collect::<Vec <_>>();
+    let first = records.first().map_or (Duration::ZERO, |record| record.time);
+    let datagrams = records.into_iter()
+      .map (|Record { time, source, data, .. }|
+        Datagram { time: time.saturating_sub (first), source, data })
+      .collect();
+    Ok (Replay { datagrams })
+  }
+
+  /// Number of datagrams to replay
+  #[inline]
+  pub const fn len (&self) -> usize {
+    self.datagrams.len()
+  }
+
+  #[inline]
+  pub const fn is_empty (&self) -> bool {
+    self.datagrams.is_empty()
+  }
+
+  /// Send the datagrams to `host` over loopback with their original timing,
+  /// servicing the host and passing each event to `handler`.
+  ///
+  /// Each original source address is replayed as a distinct loopback address
+  /// (`127.0.0.2` and up, keeping the port), so replies from the host are not
+  /// sent to the original peers. The host must be reachable on localhost.
+  pub fn run <F> (&self, host : &mut Host, mut handler : F) -> std::io::Result <()>
+    where F : FnMut (Event)
+  {
+    let socket  = UdpSocket::bind ("127.0.0.1:0")?;
+    let target  = SocketAddrV4::new (Ipv4Addr::LOCALHOST, host.local_address().port());
+    let sources = Rc::new (std::cell::RefCell::new (VecDeque::new()));
+    let sender  = match socket.local_addr()? {
+      std::net::SocketAddr::V4 (address) => address,
+      std::net::SocketAddr::V6 (_) => unreachable!("bound to an IPv4 address")
+    };
+    let id = host.add_intercept (Box::new (
+      ReplayIntercept { sender, sources: sources.clone() }));
+    let mut replayed = HashMap::new();
+    let start  = Instant::now();
+    let result = (|| {
+      for datagram in &self.datagrams {
+        service_until (host, start + datagram.time, &mut handler)?;
+        let count  = replayed.len() as u32;
+        let source = *replayed.entry (datagram.source).or_insert_with (||
+          SocketAddrV4::new (
+            Ipv4Addr::from_bits (Ipv4Addr::new (127, 0, 0, 2).to_bits() + count),
+            datagram.source.port()));
+        sources.borrow_mut().push_back (source);
+        socket.send_to (&datagram.data, target)?;
+      }
+      service_until (host, Instant::now() + REPLAY_DRAIN, &mut handler)
+    })();
+    host.remove_intercept (id);
+    result
+  }
+}
+
+impl intercept::Intercept for ReplayIntercept {
+  fn receive (&mut self, datagram : &mut intercept::Datagram)
+    -> intercept::Action
+  {
+    if SocketAddrV4::from (datagram.address()) == self.sender &&
+      let Some (source) = self.sources.borrow_mut().pop_front()
+    {
+      datagram.set_address (&source.into());
+    }
+    intercept::Action::Continue
+  }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+//  functions                                                                 //
+////////////////////////////////////////////////////////////////////////////////
+
+unsafe extern "C" fn compress (
+  context      : *mut std::os::raw::c_void,
+  in_buffers   : *const ll::ENetBuffer,
+  in_count     : usize,
+  in_limit     : usize,
+  out_data     : *mut ll::enet_uint8,
+  out_limit    : usize
+) -> usize {
+  unsafe {
+    let hostdrop    = &*(context as *const host::HostDrop);
+    let mut capture = hostdrop.capture.borrow_mut();
+    let Some (capture) = capture.as_mut() else {
+      return 0
+    };
+    // the previous datagram has been sent
+    capture.sent (hostdrop.raw());
+    let inner = capture.inner;
+    let size  = match inner.compress {
+      Some (inner_compress) if !inner.context.is_null() => inner_compress (
+        inner.context, in_buffers, in_count, in_limit, out_data, out_limit),
+      _ => 0
+    };
+    // as ENet: compressed output is only used if it is smaller
+    let compressed = (size > 0 && size < in_limit)
+      .then (|| std::slice::from_raw_parts (out_data, size));
+    let buffers = std::slice::from_raw_parts (in_buffers, in_count);
+    capture.outgoing (hostdrop.raw(), buffers, compressed);
+    size
+  }
+}
+
+unsafe extern "C" fn decompress (
+  context   : *mut std::os::raw::c_void,
+  in_data   : *const ll::enet_uint8,
+  in_limit  : usize,
+  out_data  : *mut ll::enet_uint8,
+  out_limit : usize
+) -> usize {
+  unsafe {
+    let hostdrop = &*(context as *const host::HostDrop);
+    let capture  = hostdrop.capture.borrow();
+    match capture.as_ref().map (|capture| capture.inner) {
+      Some (ll::ENetCompressor { context, decompress: Some (decompress), .. })
+        if !context.is_null() =>
+        decompress (context, in_data, in_limit, out_data, out_limit),
+      _ => 0
+    }
+  }
+}
+
+/// Called by `enet_host_compress` when the compressor is replaced during a
+/// capture: destroys the wrapped compressor
+unsafe extern "C" fn destroy (context : *mut std::os::raw::c_void) {
+  unsafe {
+    let hostdrop = &*(context as *const host::HostDrop);
+    let inner = hostdrop.capture.borrow_mut().as_mut().map (|capture| {
+      let inner = capture.inner;
+      capture.inner.context = std::ptr::null_mut();
+      inner
+    });
+    if let Some (ll::ENetCompressor { context, destroy: Some (destroy), .. }) = inner
+      && !context.is_null()
+    {
+      destroy (context);
+    }
+  }
+}
+
+const unsafe fn buffer_bytes (buffer : &ll::ENetBuffer) -> &[u8] {
+  unsafe {
+    std::slice::from_raw_parts (buffer.data as *const u8, buffer.dataLength)
+  }
+}
+
+fn service_until <F> (host : &mut Host, until : Instant, handler : &mut F)
+  -> std::io::Result <()>
+  where F : FnMut (Event)
+{
+  loop {
+    let remaining = until.saturating_duration_since (Instant::now());
+    let timeout   = u32::try_from (remaining.as_millis()).unwrap_or (u32::MAX);
+    match host.service (timeout) {
+      Ok (Some (event)) => handler (event),
+      Ok (None) => {}
+      Err (err) => return Err (std::io::Error::other (
+        format!("host service failed: {err:?}")))
+    }
+    if until <= Instant::now() {
+      return Ok (())
+    }
+  }
+}
+
+/// Write the pcap file header
+fn write_header <W : Write> (writer : &mut W) -> std::io::Result <()> {
+  writer.write_all (&PCAP_MAGIC.to_le_bytes())?;
+  writer.write_all (&2u16.to_le_bytes())?;
+  writer.write_all (&4u16.to_le_bytes())?;
+  writer.write_all (&0i32.to_le_bytes())?;
+  writer.write_all (&0u32.to_le_bytes())?;
+  writer.write_all (&SNAPLEN.to_le_bytes())?;
+  writer.write_all (&LINKTYPE_RAW.to_le_bytes())
+}
+
+/// Write a pcap record holding an IPv4 UDP datagram
+fn write_record <W : Write> (
+  writer      : &mut W,
+  time        : SystemTime,
+  source      : SocketAddrV4,
+  destination : SocketAddrV4,
+  parts       : &[&[u8]]
+) -> std::io::Result <()> {
+  let payload_length = parts.iter().map (|part| part.len()).sum::<usize>();
+  let udp_length     = UDP_HEADER_LENGTH + payload_length;
+  let total_length   = IPV4_HEADER_LENGTH + udp_length;
+  let too_long = || std::io::Error::new (std::io::ErrorKind::InvalidInput,
+    "datagram too long to capture");
+  let udp_length   = u16::try_from (udp_length).map_err (|_| too_long())?;
+  let total_length = u16::try_from (total_length).map_err (|_| too_long())?;
+  let time = time.duration_since (SystemTime::UNIX_EPOCH).unwrap_or_default();
+  let mut ip = [0u8; IPV4_HEADER_LENGTH];
+  ip[0] = 0x45;
+  ip[2..4].copy_from_slice (&total_length.to_be_bytes());
+  ip[6] = 0x40;                         // don't fragment
+  ip[8] = 64;                           // time to live
+  ip[9] = PROTOCOL_UDP;
+  ip[12..16].copy_from_slice (&source.ip().octets());
+  ip[16..20].copy_from_slice (&destination.ip().octets());
+  let checksum = ipv4_checksum (&ip);
+  ip[10..12].copy_from_slice (&checksum.to_be_bytes());
+  let mut udp = [0u8; UDP_HEADER_LENGTH];
+  udp[0..2].copy_from_slice (&source.port().to_be_bytes());
+  udp[2..4].copy_from_slice (&destination.port().to_be_bytes());
+  udp[4..6].copy_from_slice (&udp_length.to_be_bytes());
+  writer.write_all (&(time.as_secs() as u32).to_le_bytes())?;
+  writer.write_all (&time.subsec_micros().to_le_bytes())?;
+  writer.write_all (&u32::from (total_length).to_le_bytes())?;
+  writer.write_all (&u32::from (total_length).to_le_bytes())?;
+  writer.write_all (&ip)?;
+  writer.write_all (&udp)?;
+  for part in parts {
+    writer.write_all (part)?;
+  }
+  Ok (())
+}
+
+fn ipv4_checksum (header : &[u8; IPV4_HEADER_LENGTH]) -> u16 {
+  let mut sum = header.as_chunks::<2>().0.iter()
+    .map (|word| u32::from (u16::from_be_bytes (*word)))
+    .sum::<u32>();
+  while sum > 0xFFFF {
+    sum = (sum & 0xFFFF) + (sum >> 16);
+  }
+  !(sum as u16)
+}
+
+/// Read the UDP datagrams of a pcap capture
+fn read_capture (bytes : &[u8]) -> std::io::Result <Vec <Record>> {
+  let invalid = |message| std::io::Error::new (
+    std::io::ErrorKind::InvalidData, message);
+  if bytes.len() < 24 {
+    return Err (invalid ("truncated pcap header"))
+  }
+  let magic : [u8; 4] = bytes[..4].try_into().unwrap();
+  let (big_endian, nanos) = match magic {
+    _ if u32::from_le_bytes (magic) == PCAP_MAGIC       => (false, false),
+    _ if u32::from_be_bytes (magic) == PCAP_MAGIC       => (true,  false),
+    _ if u32::from_le_bytes (magic) == PCAP_MAGIC_NANOS => (false, true),
+    _ if u32::from_be_bytes (magic) == PCAP_MAGIC_NANOS => (true,  true),
+    _ => return Err (invalid ("not a pcap file"))
+  };
+  let read_u32 = |at : usize| {
+    let word : [u8; 4] = bytes[at..at + 4].try_into().unwrap();
+    if big_endian { u32::from_be_bytes (word) } else { u32::from_le_bytes (word) }
+  };
+  let linktype = read_u32 (20);
+  if linktype != LINKTYPE_RAW && linktype != LINKTYPE_IPV4 {
+    return Err (invalid ("unsupported pcap link type"))
+  }
+  let mut datagrams = Vec::new();
+  let mut offset    = 24;
+  while offset + 16 <= bytes.len() {
+    let seconds  = read_u32 (offset);
+    let fraction = read_u32 (offset + 4);
+    let length   = read_u32 (offset + 8) as usize;
+    offset += 16;
+    let Some (packet) = bytes.get (offset..offset + length) else {
+      return Err (invalid ("truncated pcap record"))
+    };
+    offset += length;
+    let time = Duration::new (u64::from (seconds),
+      if nanos { fraction } else { fraction.saturating_mul (1000) });
+    if let Some ((source, destination, payload)) = parse_udp (packet) {
+      datagrams.push (
+        Record { time, source, destination, data: payload.to_vec() });
+    }
+  }
+  Ok (datagrams)
+}
+
+/// Split an IPv4 UDP packet into source, destination and payload
+fn parse_udp (packet : &[u8]) -> Option <(SocketAddrV4, SocketAddrV4, &[u8])> {
+  let version_length = *packet.first()?;
+  let header_length  = usize::from (version_length & 0x0F) * 4;
+  if version_length >> 4 != 4 || *packet.get (9)? != PROTOCOL_UDP ||
+    header_length < IPV4_HEADER_LENGTH
+  {
+    return None
+  }
+  let source_ip      = <[u8; 4]>::try_from (packet.get (12..16)?).ok()?;
+  let destination_ip = <[u8; 4]>::try_from (packet.get (16..20)?).ok()?;
+  let udp = packet.get (header_length..)?;
+  let udp_header : &[u8; UDP_HEADER_LENGTH] =
+    udp.get (..UDP_HEADER_LENGTH)?.try_into().ok()?;
+  let source_port      = u16::from_be_bytes ([udp_header[0], udp_header[1]]);
+  let destination_port = u16::from_be_bytes ([udp_header[2], udp_header[3]]);
+  let udp_length = usize::from (u16::from_be_bytes ([udp_header[4], udp_header[5]]));
+  let payload = udp.get (UDP_HEADER_LENGTH..udp_length.max (UDP_HEADER_LENGTH))?;
+  Some ((
+    SocketAddrV4::new (source_ip.into(), source_port),
+    SocketAddrV4::new (destination_ip.into(), destination_port),
+    payload
+  ))
+}
+
+////////////////////////////////////////////////////////////////////////////////
+//  tests                                                                     //
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg (test)]
+mod tests {
+  use crate::{packet, testing, Packet, Peer};
+  use super::*;
+
+  fn capture_path (name : &str) -> std::path::PathBuf {
+    std::env::temp_dir().join (
+      format!("enet-capture-{}-{name}.pcap", std::process::id()))
+  }
+
+  fn send (peer : &mut Peer, data : &[u8]) {
+    peer.send (1, Packet::Allocate { bytes: data, flags: packet::Flags::RELIABLE })
+      .unwrap();
+  }
+
+  /// Service the hosts for a while so that acknowledgements are exchanged
+  fn settle (hosts : &mut [&mut Host]) {
+    let _ = testing::pump_until (hosts, Duration::from_millis (100), |_| false);
+  }
+
+  fn read (path : &std::path::Path) -> Vec <Record> {
+    let records = read_capture (&std::fs::read (path).unwrap()).unwrap();
+    std::fs::remove_file (path).unwrap();
+    records
+  }
+
+  /// Peer ID, without session and flags, from the header of a datagram
+  fn header_peer_id (data : &[u8]) -> u16 {
+    u16::from_be_bytes (data[..2].try_into().unwrap()) & 0x0FFF
+  }
+
+  fn address (a : u8, port : u16) -> SocketAddrV4 {
+    SocketAddrV4::new (Ipv4Addr::new (10, 0, 0, a), port)
+  }
+
+  #[test]
+  fn records_read_back_as_written() {
+    let start = SystemTime::UNIX_EPOCH + Duration::new (1_700_000_000, 123_456_000);
+    let mut bytes = Vec::new();
+    write_header (&mut bytes).unwrap();
+    write_record (&mut bytes, start, address (1, 1000), address (2, 2000),
+      &[b"head", b"er and payload"]).unwrap();
+    write_record (&mut bytes, start + Duration::from_millis (5), address (2, 2000),
+      address (1, 1000), &[]).unwrap();
+    let records = read_capture (&bytes).unwrap();
+    assert_eq!(records.len(), 2);
+    assert_eq!(records[0].source, address (1, 1000));
+    assert_eq!(records[0].destination, address (2, 2000));
+    assert_eq!(records[0].data, b"header and payload");
+    assert_eq!(records[0].time, Duration::new (1_700_000_000, 123_456_000));
+    assert_eq!(records[1].source, address (2, 2000));
+    assert_eq!(records[1].destination, address (1, 1000));
+    assert!(records[1].data.is_empty());
+    assert_eq!(records[1].time - records[0].time, Duration::from_millis (5));
+  }
+
+  #[test]
+  fn written_headers_are_valid() {
+    let mut bytes = Vec::new();
+    write_record (&mut bytes, SystemTime::now(), address (1, 1), address (2, 2),
+      &[&[0; 100]]).unwrap();
+    let ip : &[u8; IPV4_HEADER_LENGTH] =
+      bytes[16..16 + IPV4_HEADER_LENGTH].try_into().unwrap();
+    // the checksum of a header including its checksum is zero
+    assert_eq!(ipv4_checksum (ip), 0);
+    assert_eq!(u16::from_be_bytes ([ip[2], ip[3]]),
+      (IPV4_HEADER_LENGTH + UDP_HEADER_LENGTH + 100) as u16);
+  }
+
+  #[test]
+  fn oversized_datagrams_are_refused() {
+    let mut bytes = Vec::new();
+    let data = vec![0; 65536];
+    let err = write_record (&mut bytes, SystemTime::now(), address (1, 1),
+      address (2, 2), &[&data]).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+  }
+
+  #[test]
+  fn invalid_captures_are_rejected() {
+    let mut bytes = Vec::new();
+    write_header (&mut bytes).unwrap();
+    write_record (&mut bytes, SystemTime::now(), address (1, 1), address (2, 2),
+      &[b"data"]).unwrap();
+    let kind = |bytes : &[u8]| read_capture (bytes).unwrap_err().kind();
+    assert_eq!(kind (&bytes[..bytes.len() - 1]), std::io::ErrorKind::InvalidData);
+    assert_eq!(kind (&bytes[..10]), std::io::ErrorKind::InvalidData);
+    let mut wrong_magic = bytes.clone();
+    wrong_magic[0] ^= 0xFF;
+    assert_eq!(kind (&wrong_magic), std::io::ErrorKind::InvalidData);
+  }
+
+  #[test]
+  fn host_pair_capture_attributes_datagrams() {
+    let paths = [capture_path ("pair-server"), capture_path ("pair-client")];
+    let mut next = paths.iter();
+    let mut pair = testing::connected_pair_with (2,
+      |host| host.start_capture (next.next().unwrap()).unwrap());
+    send (&mut pair.client_peer, b"hello");
+    send (&mut pair.server_peer, b"world");
+    let mut events = testing::pump_until (
+      &mut [&mut pair.server, &mut pair.client], testing::TIMEOUT,
+      |events| events.len() == 2
+    ).unwrap();
+    events.sort_by_key (|recorded| recorded.host);
+    testing::assert_events (&events, &[
+      (0, testing::Expect::Receive { channel_id: 1, data: b"hello" }),
+      (1, testing::Expect::Receive { channel_id: 1, data: b"world" })
+    ]);
+    settle (&mut [&mut pair.server, &mut pair.client]);
+    pair.server.stop_capture().unwrap();
+    pair.client.stop_capture().unwrap();
+    let server_address = SocketAddrV4::from (pair.server.local_address());
+    let client_address = SocketAddrV4::from (pair.server_peer.address());
+    let client_local   = SocketAddrV4::from (pair.client.local_address());
+    let server_records = read (&paths[0]);
+    let client_records = read (&paths[1]);
+    let to_client = server_records.iter()
+      .filter (|record| record.source == server_address).collect::<Vec <_>>();
+    let to_server = client_records.iter()
+      .filter (|record| record.source == client_local).collect::<Vec <_>>();
+    assert!(!to_client.is_empty() && !to_server.is_empty());
+    for record in &to_client {
+      assert_eq!(record.destination, client_address);
+      assert_eq!(header_peer_id (&record.data),
+        pair.client_peer.incoming_peer_id());
+    }
+    for record in &to_server {
+      assert_eq!(record.destination, server_address);
+    }
+    // each side recorded exactly the datagrams the other received
+    let received = |records : &[Record], local : SocketAddrV4| records.iter()
+      .filter (|record| record.destination == local)
+      .map (|record| record.data.clone()).collect::<Vec <_>>();
+    assert_eq!(received (&client_records, client_local),
+      to_client.iter().map (|record| record.data.clone()).collect::<Vec <_>>());
+    assert_eq!(received (&server_records, server_address),
+      to_server.iter().map (|record| record.data.clone()).collect::<Vec <_>>());
+  }
+
+  #[test]
+  fn server_capture_attributes_datagrams_to_each_peer() {
+    let server_path = capture_path ("peers-server");
+    let mut pair = testing::connected_pair();
+    pair.server.start_capture (&server_path).unwrap();
+    let mut other = testing::enet().client_host_create (1, None, None).unwrap();
+    let port = pair.server.local_address().port();
+    other.connect (&Address::localhost (port), 2, 0).unwrap();
+    let events = testing::pump_until (&mut [&mut pair.server, &mut other],
+      testing::TIMEOUT, |events| events.len() == 2).unwrap();
+    let mut other_peer = events.into_iter().find_map (|recorded|
+      match recorded.event {
+        Event::Connect { peer, .. } if recorded.host == 1 => Some (peer),
+        _ => None
+      }).unwrap();
+    let mut server_peers = pair.server.peers()
+      .filter (|peer| peer.state() == crate::peer::State::Connected)
+      .collect::<Vec <_>>();
+    assert_eq!(server_peers.len(), 2);
+    for round in 0..10u8 {
+      send (&mut pair.client_peer, &[round]);
+      send (&mut other_peer, &[round]);
+      for peer in &mut server_peers {
+        send (peer, &[round]);
+      }
+      settle (&mut [&mut pair.server, &mut pair.client, &mut other]);
+    }
+    pair.server.stop_capture().unwrap();
+    let server_address = SocketAddrV4::from (pair.server.local_address());
+    let mut attributed = HashMap::new();
+    for record in read (&server_path) {
+      if record.source != server_address ||
+        record.destination.ip().is_unspecified()
+      {
+        continue
+      }
+      let peer = server_peers.iter().find (|peer|
+        SocketAddrV4::from (peer.address()) == record.destination).unwrap();
+      // the header names the peer ID the client assigned to the server
+      let client_peer = if peer.address().port() ==
+        pair.client.local_address().port()
+      { &pair.client_peer } else { &other_peer };
+      assert_eq!(header_peer_id (&record.data), client_peer.incoming_peer_id());
+      *attributed.entry (record.destination).or_insert (0) += 1;
+    }
+    assert_eq!(attributed.len(), 2, "{attributed:?}");
+  }
+
+  #[test]
+  fn replay_reproduces_received_events() {
+    let path = capture_path ("replay");
+    let mut next = Some (&path);
+    let mut pair = testing::connected_pair_with (2, |host|
+      if let Some (path) = next.take() {
+        host.start_capture (path).unwrap();
+      });
+    send (&mut pair.client_peer, b"replayed");
+    testing::pump_until (&mut [&mut pair.server, &mut pair.client],
+      testing::TIMEOUT, |events| !events.is_empty()).unwrap();
+    settle (&mut [&mut pair.server, &mut pair.client]);
+    pair.server.stop_capture().unwrap();
+    let replay = Replay::open (&path, pair.server.local_address().port()).unwrap();
+    std::fs::remove_file (&path).unwrap();
+    assert!(!replay.is_empty());
+    let mut host = testing::enet().server_host_create (Address::localhost (0),
+      1, Some (2), None, None).unwrap();
+    let mut events = Vec::new();
+    replay.run (&mut host, |event| events.push (event)).unwrap();
+    let events = events.into_iter()
+      .map (|event| testing::Recorded { host: 0, event }).collect::<Vec <_>>();
+    testing::assert_events (&events, &[
+      (0, testing::Expect::Connect),
+      (0, testing::Expect::Receive { channel_id: 1, data: b"replayed" })
+    ]);
+  }
+}
@@ -0,0 +1,453 @@
+//! Clock synchronization between peers.
+//!
+//! Each host with a `ClockSync` has a clock: the time since its `ClockSync`
+//! was created, from `clock.now()`. A host that probes its peers estimates the
+//! offset and drift of the clock of each peer, so that a client can follow the
+//! time of an authoritative server with `peer.remote_time_now()`:
+//!
+//! ```no_run
+//! # use std::time::Duration;
+//! # use enet::clock::ClockSync;
+//! # let enet = enet::Enet::shared().unwrap();
+//! # let mut server = enet.server_host_create (enet::Address::any (12345), 8,
+//! #   None, None, None).unwrap();
+//! # let mut client = enet.client_host_create (1, None, None).unwrap();
+//! // the server only answers probes
+//! server.set_clock_sync (Some (ClockSync::new (1, None))).unwrap();
+//! let probe_interval = Duration::from_secs (1);
+//! client.set_clock_sync (Some (ClockSync::new (1, Some (probe_interval))))
+//!   .unwrap();
+//! let peer = client.connect (&enet::Address::localhost (12345), 2, 0)
+//!   .unwrap();
+//! // ... service both hosts for a few seconds
+//! let server_time = peer.remote_time_now();
+//! ```
+//!
+//! Probes and answers are sent unsequenced on a reserved channel, which must be
+//! the same on both hosts; packets received on it are not returned as events.
+//! They go through the session layers like application packets: with a
+//! handshake or encryption set, clocks are only exchanged with peers that have
+//! completed them, and the messages are encrypted. The channel cannot be the
+//! handshake or key exchange channel; probes stop while one of them uses it.
+//! The offset is measured from the time a probe was sent, the time the remote
+//! host answered it, and the time the answer arrived, assuming the path delay
+//! is the same in both directions. Answers whose round trip took longer than
+//! the ENet round trip time plus twice its variance (`peer.round_trip_time()`
+//! and `peer.round_trip_time_variance()`) are discarded, and the offset and
+//! drift are fitted over the remaining samples.
+//!
+//! The ENet millisecond time used by hosts for timeouts is available from
+//! `enet.time()` and can be changed with the unsafe `enet.set_time()`.
+
+use std;
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use crate::{packet, peer, Host, Peer};
+
+/// Samples kept for each peer
+const MAX_SAMPLES : usize = 16;
+/// Round trip time above the ENet estimate that is always accepted, in
+/// microseconds
+const ROUND_TRIP_SLACK : u64 = 1000;
+
+/// Clock message tags; the first byte of each packet on the clock channel
+const TAG_PROBE  : u8 = 0x01;
+const TAG_ANSWER : u8 = 0x02;
+
+////////////////////////////////////////////////////////////////////////////////
+//  structs                                                                   //
+////////////////////////////////////////////////////////////////////////////////
+
+/// Shared handle to the clock of a host and the clock estimates of its peers
+#[derive(Clone, Debug)]
+pub struct ClockSync {
+  inner : Rc <RefCell <Inner>>
+}
+
+/// Estimate of the clock of a peer
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Estimate {
+  /// Remote clock minus local clock, in microseconds
+  pub offset          : i64,
+  /// Rate at which the offset changes, in parts per million
+  pub drift           : f64,
+  /// Round trip time of the fastest probe kept
+  pub round_trip_time : Duration,
+  /// Samples the estimate is fitted over
+  pub samples         : usize
+}
+
+#[derive(Debug)]
+struct Inner {
+  channel_id     : u8,
+  probe_interval : Option <Duration>,
+  epoch          : Instant,
+  /// Indexed by `incomingPeerID`
+  peers          : HashMap <u16, PeerClock>
+}
+
+#[derive(Debug)]
+struct PeerClock {
+  connect_id : u32,
+  next_probe : Instant,
+  samples    : VecDeque <Sample>
+}
+
+/// A probe answer, times in microseconds of the local clock
+#[derive(Clone, Copy, Debug)]
+struct Sample {
+  /// Midpoint of the round trip
+  time       : u64,
+  offset     : i64,
+  round_trip : u64
+}
+
+////////////////////////////////////////////////////////////////////////////////
+//  impls                                                                     //
+////////////////////////////////////////////////////////////////////////////////
+
+impl ClockSync {
+  /// Synchronize on `channel_id`, probing each connected peer every
+  /// `probe_interval`; with `None` probes from peers are only answered
+  pub fn new (channel_id : u8, probe_interval : Option <Duration>) -> Self {
+    ClockSync {
+      inner: Rc::new (RefCell::new (Inner {
+        channel_id,
+        probe_interval,
+        epoch: Instant::now(),
+        peers: HashMap::new()
+      }))
+    }
+  }
+
+  pub fn channel_id (&self) -> u8 {
+    self.inner.borrow().channel_id
+  }
+
+  /// The local clock: time since this `ClockSync` was created
+  pub fn now (&self) -> Duration {
+    self.inner.borrow().epoch.elapsed()
+  }
+
+  /// Estimate of the clock of a peer, or `None` if no probe has been answered
+  pub fn estimate (&self, peer : &Peer) -> Option <Estimate> {
+    let inner = self.inner.borrow();
+    let clock = inner.peer (peer)?;
+    let (offset, drift) = clock.fit (inner.micros())?;
+    let round_trip = clock.samples.iter().map (|sample| sample.round_trip)
+      .min().unwrap_or_default();
+    Some (Estimate {
+      offset,
+      drift: drift * 1_000_000.0,
+      round_trip_time: Duration::from_micros (round_trip),
+      samples: clock.samples.len()
+    })
+  }
+
+  /// Estimate of the current time of the clock of a peer
+  pub fn remote_time (&self, peer : &Peer) -> Option <Duration> {
+    let offset = self.estimate (peer)?.offset;
+    let now    = self.inner.borrow().micros();
+    Some (Duration::from_micros (now.saturating_add_signed (offset)))
+  }
+
+  /// Send the probes that are due to the connected peers of the host
+  pub(crate) fn probe (&self, host : &Host) {
+    let now = Instant::now();
+    let mut inner = self.inner.borrow_mut();
+    let Some (interval) = inner.probe_interval else {
+      return
+    };
+    let channel_id = inner.channel_id;
+    let time = inner.micros();
+    for peer in host.peers() {
+      if peer.state() != peer::State::Connected {
+        continue
+      }
+      let connect_id = peer.connect_id();
+      let clock = inner.peers.entry (peer.incoming_peer_id())
+        .or_insert_with (|| PeerClock::new (connect_id, now));
+      if clock.connect_id != connect_id {
+        // the peer slot is used by a new connection
+        *clock = PeerClock::new (connect_id, now);
+      }
+      if now < clock.next_probe {
+        continue
+      }
+      let mut message = vec![TAG_PROBE];
+      message.extend_from_slice (&time.to_le_bytes());
+      // a peer that has not completed the session layers is probed once it has
+      if send (&peer, channel_id, &message) {
+        clock.next_probe = now + interval;
+      }
+    }
+  }
+
+  /// Handle a packet received on the clock channel, answering probes
+  pub(crate) fn receive (&self, peer : &Peer, bytes : &[u8]) {
+    let mut inner = self.inner.borrow_mut();
+    let now = inner.micros();
+    match bytes.split_first() {
+      Some ((&TAG_PROBE, sent)) if sent.len() == 8 => {
+        let mut message = vec![TAG_ANSWER];
+        message.extend_from_slice (sent);
+        message.extend_from_slice (&now.to_le_bytes());
+        send (peer, inner.channel_id, &message);
+      }
+      Some ((&TAG_ANSWER, times)) if times.len() == 16 => {
+        let sent   = u64::from_le_bytes (times[..8].try_into().unwrap());
+        let remote = u64::from_le_bytes (times[8..].try_into().unwrap());
+        if now < sent {
+          return
+        }
+        // ENet round trip time and variance are in milliseconds
+        let limit = (u64::from (peer.round_trip_time()) +
+          2 * u64::from (peer.round_trip_time_variance())) * 1000 +
+          ROUND_TRIP_SLACK;
+        let round_trip = now - sent;
+        let Some (clock) = inner.peer_mut (peer) else {
+          return
+        };
+        if limit < round_trip {
+          return
+        }
+        let time = sent + round_trip / 2;
+        if clock.samples.len() == MAX_SAMPLES {
+          clock.samples.pop_front();
+        }
+        clock.samples.push_back (Sample {
+          time, offset: remote as i64 - time as i64, round_trip
+        });
+      }
+      _ => {}
+    }
+  }
+}
+
+impl Inner {
+  fn micros (&self) -> u64 {
+    self.epoch.elapsed().as_micros() as u64
+  }
+
+  fn peer (&self, peer : &Peer) -> Option <&PeerClock> {
+    self.peers.get (&peer.incoming_peer_id())
+      .filter (|clock| clock.connect_id == peer.connect_id())
+  }
+
+  fn peer_mut (&mut self, peer : &Peer) -> Option <&mut PeerClock> {
+    self.peers.get_mut (&peer.incoming_peer_id())
+      .filter (|clock| clock.connect_id == peer.connect_id())
+  }
+}
+
+impl PeerClock {
+  /// The first probe is sent right away
+  const fn new (connect_id : u32, now : Instant) -> Self {
+    PeerClock { connect_id, next_probe: now, samples: VecDeque::new() }
+  }
+
+  /// Offset at the local time `now` and drift, from a least squares fit of the
+  /// offsets of the samples
+  fn fit (&self, now : u64) -> Option <(i64, f64)> {
+    let last = self.samples.back()?;
+    let count = self.samples.len() as f64;
+    // times relative to the last sample keep the sums small
+    let points = || self.samples.iter().map (|sample| (
+      sample.time as f64 - last.time as f64,
+      (sample.offset - last.offset) as f64));
+    let (mean_time, mean_offset) = points()
+      .fold ((0.0, 0.0),
+        |(time, offset), point| (time + point.0, offset + point.1));
+    let (mean_time, mean_offset) = (mean_time / count, mean_offset / count);
+    let (covariance, variance) = points().fold ((0.0, 0.0),
+      |(covariance, variance), (time, offset)| {
+        let time = time - mean_time;
+        ( time.mul_add (offset - mean_offset, covariance),
+          time.mul_add (time, variance) )
+      });
+    let drift = if variance > 0.0 { covariance / variance } else { 0.0 };
+    let elapsed = now as f64 - last.time as f64;
+    let offset  = drift.mul_add (elapsed - mean_time, mean_offset);
+    Some ((last.offset + offset.round() as i64, drift))
+  }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+//  functions                                                                 //
+////////////////////////////////////////////////////////////////////////////////
+
+/// Send a clock message unsequenced through the session layers
+fn send (peer : &Peer, channel_id : u8, message : &[u8]) -> bool {
+  peer.clone().send_session (channel_id, message, packet::Flags::UNSEQUENCED)
+    .is_ok()
+}
+
+////////////////////////////////////////////////////////////////////////////////
+//  tests                                                                     //
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg (test)]
+mod tests {
+  use super::*;
+  use crate::{auth, testing, Address};
+
+  /// Accepts any response
+  struct Accept;
+
+  impl auth::Authenticator for Accept {
+    fn challenge (&mut self, _peer : &Peer) -> Vec <u8> {
+      vec![0; 8]
+    }
+    fn respond (&mut self,
+      _peer : &Peer, _binding : &auth::Binding, _challenge : &[u8]
+    ) -> Vec <u8> {
+      Vec::new()
+    }
+    fn verify (&mut self,
+      _peer : &Peer, _binding : &auth::Binding, _challenge : &[u8],
+      _response : &[u8]
+    ) -> bool {
+      true
+    }
+  }
+
+  /// A connected pair where the server answers probes and the client probes
+  /// every `probe_interval`
+  fn synchronized_pair (probe_interval : Duration)
+    -> (testing::Pair, ClockSync, ClockSync)
+  {
+    let server_sync = ClockSync::new (1, None);
+    let client_sync = ClockSync::new (1, Some (probe_interval));
+    let mut syncs   = [server_sync.clone(), client_sync.clone()].into_iter();
+    let pair = testing::connected_pair_with (2,
+      |host| host.set_clock_sync (syncs.next()).unwrap());
+    (pair, server_sync, client_sync)
+  }
+
+  /// Service both hosts for `duration`, returning the events received
+  fn pump (pair : &mut testing::Pair, duration : Duration)
+    -> Vec <testing::Recorded>
+  {
+    match testing::pump_until (&mut [&mut pair.server, &mut pair.client],
+      duration, |_| false)
+    {
+      Err (testing::PumpError::Timeout (events)) => events,
+      result => panic!("{result:?}")
+    }
+  }
+
+  /// An answer to a probe sent at `sent`, answered at `remote`
+  fn answer (sent : u64, remote : u64) -> Vec <u8> {
+    let mut message = vec![TAG_ANSWER];
+    message.extend_from_slice (&sent.to_le_bytes());
+    message.extend_from_slice (&remote.to_le_bytes());
+    message
+  }
+
+  /// A clock with a sample every second, offsets given by `offset (time)`
+  fn clock (count : u64, offset : impl Fn (u64) -> i64) -> PeerClock {
+    let mut clock = PeerClock::new (0, Instant::now());
+    clock.samples = (1..=count).map (|second| {
+      let time = second * 1_000_000;
+      Sample { time, offset: offset (time), round_trip: 1000 }
+    }).collect();
+    clock
+  }
+
+  #[test]
+  fn fit_without_samples() {
+    assert_eq!(clock (0, |_| 0).fit (0), None);
+  }
+
+  #[test]
+  fn fit_single_sample() {
+    assert_eq!(clock (1, |_| -250).fit (5_000_000), Some ((-250, 0.0)));
+  }
+
+  #[test]
+  fn fit_constant_offset() {
+    let (offset, drift) = clock (8, |_| 12_345).fit (20_000_000).unwrap();
+    assert_eq!(offset, 12_345);
+    assert!(drift.abs() < 1e-12);
+  }
+
+  #[test]
+  fn fit_extrapolates_drift() {
+    // 100 parts per million
+    let clock = clock (10, |time| 5_000 + time as i64 / 10_000);
+    let (offset, drift) = clock.fit (20_000_000).unwrap();
+    assert!((drift - 1e-4).abs() < 1e-9, "{drift}");
+    assert_eq!(offset, 7_000);
+  }
+
+  #[test]
+  fn fit_averages_noise() {
+    let clock = clock (8,
+      |time| if time / 1_000_000 % 2 == 0 { 110 } else { 90 });
+    let (offset, drift) = clock.fit (8_000_000).unwrap();
+    assert!((offset - 100).abs() <= 5, "{offset}");
+    assert!(drift.abs() < 1e-5, "{drift}");
+  }
+
+  #[test]
+  fn clock_sync_cannot_use_the_handshake_channel() {
+    let mut server = testing::enet().server_host_create (Address::localhost (0),
+      2, Some (2), None, None).unwrap();
+    server.set_handshake (Some (auth::Handshake::new (Box::new (Accept), 1,
+      2000)));
+    let err = server.set_clock_sync (Some (ClockSync::new (1, None)))
+      .unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+    assert!(server.clock_sync().is_none());
+    server.set_clock_sync (Some (ClockSync::new (0, None))).unwrap();
+  }
+
+  #[test]
+  fn probes_are_answered_over_a_connected_pair() {
+    let (mut pair, server_sync, client_sync) =
+      synchronized_pair (Duration::from_millis (20));
+    let events = pump (&mut pair, Duration::from_millis (300));
+    // clock messages are not returned as events
+    assert!(events.is_empty(), "{events:?}");
+    let estimate = client_sync.estimate (&pair.client_peer).unwrap();
+    assert!(1 < estimate.samples, "{estimate:?}");
+    assert!(estimate.round_trip_time < Duration::from_millis (5),
+      "{estimate:?}");
+    // the server only answers
+    assert_eq!(server_sync.estimate (&pair.server_peer), None);
+    assert_eq!(pair.server_peer.remote_time_now(), None);
+    // the server clock was created first
+    let offset = Duration::from_micros (estimate.offset.unsigned_abs());
+    assert!(offset < Duration::from_millis (5), "{estimate:?}");
+    let remote = pair.client_peer.remote_time_now().unwrap();
+    let server = server_sync.now();
+    let error  = remote.abs_diff (server);
+    assert!(error < Duration::from_millis (3), "{remote:?} {server:?}");
+  }
+
+  #[test]
+  fn answers_slower_than_the_round_trip_time_are_discarded() {
+    // a single probe is sent
+    let (mut pair, _, client_sync) =
+      synchronized_pair (Duration::from_secs (3600));
+    pump (&mut pair, Duration::from_millis (50));
+    let samples = || client_sync.estimate (&pair.client_peer).unwrap().samples;
+    assert_eq!(samples(), 1);
+    let limit = (u64::from (pair.client_peer.round_trip_time()) +
+      2 * u64::from (pair.client_peer.round_trip_time_variance())) * 1000 +
+      ROUND_TRIP_SLACK;
+    assert!(limit < 2_000_000, "{limit}");
+    // a probe sent when the clock was created
+    let limit = Duration::from_micros (limit);
+    std::thread::sleep (limit.saturating_sub (client_sync.now()) * 2);
+    let now = client_sync.now().as_micros() as u64;
+    client_sync.receive (&pair.client_peer, &answer (0, now));
+    assert_eq!(samples(), 1);
+    let now = client_sync.now().as_micros() as u64;
+    client_sync.receive (&pair.client_peer, &answer (now, now));
+    assert_eq!(samples(), 2);
+  }
+}
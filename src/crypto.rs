@@ -0,0 +1,426 @@
+//! End-to-end encryption of peer traffic (requires the `encryption` feature).
+//!
+//! When `Encryption` is set on a `Host`, each new connection runs an X25519
+//! key exchange on a reserved channel. The shared secret is expanded with
+//! HKDF-SHA256 into one ChaCha20-Poly1305 key for each direction.
+//!
+//! After the key exchange every packet sent with `peer.send()` or
+//! `host.broadcast()` is encrypted and authenticated, and `Event::Receive` only
+//! returns packets that were decrypted and verified. Each packet carries a
+//! 64-bit counter used as the nonce; counters that were already received, or
+//! that are too old to be checked, are rejected to prevent replays. Packets that
+//! fail to decrypt or are replayed are dropped and counted by
+//! `host.tampered_packets()`.
+//!
+//! The connection is reported with `Event::Connect` once the key exchange is
+//! complete (or `Event::Authenticated` if a handshake is also set). Until then
+//! `peer.send()` fails with `SendErrorKind::KeyExchangePending`.
+//!
+//! Peers that connected before encryption was set on the host are not
+//! encrypted: packets are sent to and received from them in plaintext.
+//!
+//! Both hosts of a connection must use encryption on the same channel. The key
+//! exchange is not authenticated by itself. With a `Handshake` also set, the
+//! handshake runs after the key exchange and its answers are bound to the
+//! public keys of both ends (see `auth::Binding`), which authenticates the
+//! keys.
+
+use std;
+use chacha20poly1305::{self, aead::{AeadInPlace, KeyInit}};
+use hkdf;
+use rand_core;
+use sha2;
+use x25519_dalek;
+
+use crate::{auth, packet};
+
+/// Disconnect `data` for a peer that sent an invalid key or did not complete
+/// the key exchange in time
+pub const DISCONNECT_KEY_EXCHANGE : u32 = 0xAE70_0003;
+
+/// Bytes added to each encrypted packet: an 8 byte counter and a 16 byte
+/// authentication tag
+pub const OVERHEAD : usize = COUNTER_SIZE + TAG_SIZE;
+
+/// Key exchange message tag; tags 0x01 and 0x02 are used by `auth`
+const TAG_KEY_EXCHANGE : u8 = 0x03;
+const COUNTER_SIZE     : usize = 8;
+const TAG_SIZE         : usize = 16;
+const KEY_INFO         : &[u8] = b"enet-rs session keys";
+/// Number of counters below the highest received counter that are still
+/// accepted
+const REPLAY_WINDOW    : u64 = 4096;
+
+////////////////////////////////////////////////////////////////////////////////
+//  structs                                                                   //
+////////////////////////////////////////////////////////////////////////////////
+
+/// Encryption configuration for a `Host`
+#[derive(Clone, Debug)]
+pub struct Encryption {
+  channel_id : u8,
+  timeout    : u32
+}
+
+/// Key exchange and cipher state of a single peer
+pub(crate) struct PeerCrypto {
+  secret : Option <x25519_dalek::EphemeralSecret>,
+  public : x25519_dalek::PublicKey,
+  remote : Option <x25519_dalek::PublicKey>,
+  keys   : Option <Keys>
+}
+
+struct Keys {
+  send         : chacha20poly1305::ChaCha20Poly1305,
+  receive      : chacha20poly1305::ChaCha20Poly1305,
+  send_counter : u64,
+  replay       : ReplayWindow
+}
+
+/// Sliding window of received counters
+struct ReplayWindow {
+  highest : Option <u64>,
+  bitmap  : [u64; (REPLAY_WINDOW / 64) as usize]
+}
+
+////////////////////////////////////////////////////////////////////////////////
+//  impls                                                                     //
+////////////////////////////////////////////////////////////////////////////////
+
+impl Encryption {
+  /// Run the key exchange on `channel_id`; peers that have not completed it
+  /// after `timeout` milliseconds are disconnected.
+  ///
+  /// The key exchange channel is reserved: packets received on it are not
+  /// reported to the application.
+  pub const fn new (channel_id : u8, timeout : u32) -> Self {
+    Encryption { channel_id, timeout }
+  }
+
+  #[inline]
+  pub const fn channel_id (&self) -> u8 {
+    self.channel_id
+  }
+
+  /// Milliseconds
+  #[inline]
+  pub const fn timeout (&self) -> u32 {
+    self.timeout
+  }
+}
+
+impl PeerCrypto {
+  /// Create a key pair for a new connection, returning it together with the
+  /// key exchange message to send to the peer
+  pub(crate) fn new() -> (Self, Vec <u8>) {
+    let secret = x25519_dalek::EphemeralSecret::random_from_rng (rand_core::OsRng);
+    let public = x25519_dalek::PublicKey::from (&secret);
+    let mut message = Vec::with_capacity (1 + 32);
+    message.push (TAG_KEY_EXCHANGE);
+    message.extend_from_slice (public.as_bytes());
+    let crypto = PeerCrypto {
+      secret: Some (secret), public, remote: None, keys: None
+    };
+    (crypto, message)
+  }
+
+  /// True once the key exchange is complete
+  #[inline]
+  pub(crate) const fn established (&self) -> bool {
+    self.keys.is_some()
+  }
+
+  /// Complete the key exchange with the public key received from the peer.
+  ///
+  /// Returns false if the key is invalid or a key was already received.
+  pub(crate) fn complete (&mut self, remote : [u8; 32]) -> bool {
+    let Some (secret) = self.secret.take() else {
+      return false
+    };
+    let remote = x25519_dalek::PublicKey::from (remote);
+    let shared = secret.diffie_hellman (&remote);
+    if !shared.was_contributory() {
+      return false
+    }
+    // both ends order the public keys the same way: the end with the lower key
+    // sends with the first key
+    let local_first = self.public.as_bytes() < remote.as_bytes();
+    let (first, second) = if local_first {
+      (self.public.as_bytes(), remote.as_bytes())
+    } else {
+      (remote.as_bytes(), self.public.as_bytes())
+    };
+    let mut salt = [0u8; 64];
+    salt[..32].copy_from_slice (first);
+    salt[32..].copy_from_slice (second);
+    let mut okm = [0u8; 64];
+    if hkdf::Hkdf::<sha2::Sha256>::new (Some (&salt), shared.as_bytes())
+      .expand (KEY_INFO, &mut okm).is_err()
+    {
+      return false
+    }
+    let (key_first, key_second) = okm.split_at (32);
+    let (send, receive) = if local_first {
+      (key_first, key_second)
+    } else {
+      (key_second, key_first)
+    };
+    self.keys = Some (Keys {
+      send:         chacha20poly1305::ChaCha20Poly1305::new (send.into()),
+      receive:      chacha20poly1305::ChaCha20Poly1305::new (receive.into()),
+      send_counter: 0,
+      replay:       ReplayWindow::new()
+    });
+    self.remote = Some (remote);
+    true
+  }
+
+  /// Public keys of both ends, the initiator's first, once the key exchange is
+  /// complete
+  pub(crate) fn public_keys (&self, role : auth::Role) -> Option <Vec <u8>> {
+    let remote = self.remote.as_ref()?;
+    let (first, second) = match role {
+      auth::Role::Initiator => (&self.public, remote),
+      auth::Role::Responder => (remote, &self.public)
+    };
+    Some ([first.as_bytes().as_slice(), second.as_bytes()].concat())
+  }
+
+  /// Encrypt a payload for `channel_id`.
+  ///
+  /// Returns `None` if the key exchange is not complete.
+  pub(crate) fn seal (&mut self, channel_id : u8, payload : &[u8])
+    -> Option <Vec <u8>>
+  {
+    let keys    = self.keys.as_mut()?;
+    let counter = keys.send_counter;
+    keys.send_counter += 1;
+    let mut bytes = Vec::with_capacity (payload.len() + OVERHEAD);
+    bytes.extend_from_slice (&counter.to_le_bytes());
+    bytes.extend_from_slice (payload);
+    let tag = keys.send.encrypt_in_place_detached (
+      &nonce (counter), &[channel_id], &mut bytes[COUNTER_SIZE..]
+    ).ok()?;
+    bytes.extend_from_slice (&tag);
+    Some (bytes)
+  }
+
+  /// Decrypt and verify a received packet in place.
+  ///
+  /// Returns false if the packet was tampered with or replayed.
+  pub(crate) fn open (&mut self, channel_id : u8,
+    packet : &mut packet::PacketRecv
+  ) -> bool {
+    let Some (keys) = self.keys.as_mut() else {
+      return false
+    };
+    let length = packet.data_length();
+    if length < OVERHEAD {
+      return false
+    }
+    let data    = packet.data_mut();
+    let counter = u64::from_le_bytes (
+      data[..COUNTER_SIZE].try_into().unwrap());
+    if !keys.replay.check (counter) {
+      return false
+    }
+    let (body, tag) = data[COUNTER_SIZE..].split_at_mut (length - OVERHEAD);
+    let tag = chacha20poly1305::Tag::from_slice (tag);
+    if keys.receive.decrypt_in_place_detached (
+      &nonce (counter), &[channel_id], body, tag
+    ).is_err() {
+      return false
+    }
+    keys.replay.insert (counter);
+    packet.retain (COUNTER_SIZE..length - TAG_SIZE);
+    true
+  }
+}
+
+impl std::fmt::Debug for PeerCrypto {
+  fn fmt (&self, f : &mut std::fmt::Formatter) -> std::fmt::Result {
+    f.debug_struct ("PeerCrypto")
+      .field ("established", &self.established())
+      .finish_non_exhaustive()
+  }
+}
+
+impl ReplayWindow {
+  const fn new() -> Self {
+    ReplayWindow { highest: None, bitmap: [0; (REPLAY_WINDOW / 64) as usize] }
+  }
+
+  /// True if the counter has not been received and is inside the window
+  const fn check (&self, counter : u64) -> bool {
+    match self.highest {
+      None => true,
+      Some (highest) if highest < counter => true,
+      Some (highest) if highest - counter < REPLAY_WINDOW =>
+        !self.bit (counter),
+      Some (_) => false
+    }
+  }
+
+  /// Mark a verified counter as received
+  fn insert (&mut self, counter : u64) {
+    match self.highest {
+      Some (highest) if counter <= highest => {}
+      Some (highest) => {
+        // clear the bits of counters that are skipped over
+        let skipped = (counter - highest).min (REPLAY_WINDOW);
+        for next in counter + 1 - skipped..=counter {
+          self.set_bit (next, false);
+        }
+        self.highest = Some (counter);
+      }
+      None => {
+        self.highest = Some (counter);
+      }
+    }
+    self.set_bit (counter, true);
+  }
+
+  const fn bit (&self, counter : u64) -> bool {
+    let index = counter % REPLAY_WINDOW;
+    self.bitmap[(index / 64) as usize] & (1 << (index % 64)) != 0
+  }
+
+  const fn set_bit (&mut self, counter : u64, value : bool) {
+    let index = counter % REPLAY_WINDOW;
+    let word  = &mut self.bitmap[(index / 64) as usize];
+    if value {
+      *word |= 1 << (index % 64);
+    } else {
+      *word &= !(1 << (index % 64));
+    }
+  }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+//  functions                                                                 //
+////////////////////////////////////////////////////////////////////////////////
+
+/// Parse a key exchange message received on the encryption channel
+pub(crate) fn parse_key_exchange (bytes : &[u8]) -> Option <[u8; 32]> {
+  match bytes.split_first() {
+    Some ((&TAG_KEY_EXCHANGE, key)) => key.try_into().ok(),
+    _ => None
+  }
+}
+
+fn nonce (counter : u64) -> chacha20poly1305::Nonce {
+  let mut nonce = chacha20poly1305::Nonce::default();
+  nonce[4..].copy_from_slice (&counter.to_le_bytes());
+  nonce
+}
+
+////////////////////////////////////////////////////////////////////////////////
+//  tests                                                                     //
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg (test)]
+mod tests {
+  use ll;
+  use super::*;
+
+  /// Both ends of a completed key exchange
+  fn pair() -> (PeerCrypto, PeerCrypto) {
+    let (mut a, message_a) = PeerCrypto::new();
+    let (mut b, message_b) = PeerCrypto::new();
+    assert!(a.complete (parse_key_exchange (&message_b).unwrap()));
+    assert!(b.complete (parse_key_exchange (&message_a).unwrap()));
+    (a, b)
+  }
+
+  /// A received packet holding a copy of `bytes`
+  fn received (bytes : &[u8]) -> packet::PacketRecv {
+    unsafe {
+      let raw = ll::enet_packet_create (bytes.as_ptr().cast(), bytes.len(), 0);
+      assert!(!raw.is_null());
+      packet::PacketRecv::from_raw (raw)
+    }
+  }
+
+  #[test]
+  fn replay_window_accepts_in_order() {
+    let mut window = ReplayWindow::new();
+    for counter in 0..2 * REPLAY_WINDOW {
+      assert!(window.check (counter), "{counter}");
+      window.insert (counter);
+    }
+  }
+
+  #[test]
+  fn replay_window_rejects_duplicates() {
+    let mut window = ReplayWindow::new();
+    window.insert (5);
+    assert!(!window.check (5));
+    // out of order within the window
+    window.insert (10);
+    assert!(window.check (7));
+    window.insert (7);
+    assert!(!window.check (7));
+    assert!(!window.check (10));
+  }
+
+  #[test]
+  fn replay_window_rejects_too_old() {
+    let mut window = ReplayWindow::new();
+    window.insert (REPLAY_WINDOW + 10);
+    assert!(!window.check (10));
+    assert!(window.check (11));
+  }
+
+  #[test]
+  fn replay_window_clears_skipped_counters() {
+    let mut window = ReplayWindow::new();
+    window.insert (10);
+    window.insert (4200);
+    // shares the bit of counter 10
+    assert!(window.check (REPLAY_WINDOW + 10));
+  }
+
+  #[test]
+  fn sealed_packets_open_once() {
+    let (mut a, mut b) = pair();
+    let sealed = a.seal (1, b"payload").unwrap();
+    assert_eq!(sealed.len(), b"payload".len() + OVERHEAD);
+    let mut packet = received (&sealed);
+    assert!(b.open (1, &mut packet));
+    assert_eq!(packet.data(), b"payload");
+    assert!(!b.open (1, &mut received (&sealed)));
+    // the other direction uses the other key
+    let sealed = b.seal (0, b"reply").unwrap();
+    assert!(!b.open (0, &mut received (&sealed)));
+    let mut packet = received (&sealed);
+    assert!(a.open (0, &mut packet));
+    assert_eq!(packet.data(), b"reply");
+  }
+
+  #[test]
+  fn sealed_packets_too_old_are_rejected() {
+    let (mut a, mut b) = pair();
+    let first = a.seal (0, b"first").unwrap();
+    for _ in 0..REPLAY_WINDOW {
+      let sealed = a.seal (0, b"next").unwrap();
+      assert!(b.open (0, &mut received (&sealed)));
+    }
+    assert!(!b.open (0, &mut received (&first)));
+  }
+
+  #[test]
+  fn forged_packets_are_rejected() {
+    let (mut a, mut b) = pair();
+    let sealed = a.seal (1, b"payload").unwrap();
+    // counter, body and tag
+    for index in [0, COUNTER_SIZE + 2, sealed.len() - 1] {
+      let mut forged = sealed.clone();
+      forged[index] ^= 0x01;
+      assert!(!b.open (1, &mut received (&forged)), "{index}");
+    }
+    assert!(!b.open (2, &mut received (&sealed)));
+    assert!(!b.open (1, &mut received (&sealed[..OVERHEAD - 1])));
+    // failed packets do not advance the replay window
+    assert!(b.open (1, &mut received (&sealed)));
+  }
+}
@@ -0,0 +1,290 @@
+//! LAN server discovery through broadcast beacons.
+//!
+//! A server creates a `LanBeacon` and calls `poll()` regularly, e.g. each time
+//! it services its host. Every interval the beacon sends a datagram with the
+//! server's ENet port and application metadata to the discovery port of the
+//! broadcast address.
+//!
+//! A client creates a `LanBrowser` listening on the discovery port and calls
+//! `poll()` to collect beacons. `servers()` lists the servers heard from, with
+//! the address to connect to; servers that have not sent a beacon within the
+//! expiry time are removed.
+//!
+//! For testing, beacons can be sent to a loopback address instead of the
+//! broadcast address.
+
+use std;
+use std::collections::HashMap;
+use std::net::SocketAddrV4;
+use std::time::{Duration, Instant};
+
+use crate::{socket, Address, Enet, Socket};
+
+/// Discovery port used by default
+pub const DEFAULT_PORT : u16 = 45_654;
+/// Largest metadata sent in a beacon
+pub const MAX_METADATA_LENGTH : usize = 1024;
+
+const BEACON_MAGIC   : [u8; 4] = *b"ENLB";
+const BEACON_VERSION : u8 = 1;
+/// Magic, version, port and metadata length
+const BEACON_HEADER_LENGTH : usize = 9;
+
+////////////////////////////////////////////////////////////////////////////////
+//  structs                                                                   //
+////////////////////////////////////////////////////////////////////////////////
+
+/// Periodic sender of discovery beacons for a server
+#[derive(Debug)]
+pub struct LanBeacon {
+  socket    : Socket,
+  target    : Address,
+  port      : u16,
+  message   : Vec <u8>,
+  interval  : Duration,
+  last_sent : Option <Instant>
+}
+
+/// Collects discovery beacons into a list of servers
+#[derive(Debug)]
+pub struct LanBrowser {
+  socket  : Socket,
+  expiry  : Duration,
+  servers : HashMap <SocketAddrV4, LanServer>,
+  buffer  : Vec <u8>
+}
+
+/// A server heard from by a `LanBrowser`
+#[derive(Clone, Debug)]
+pub struct LanServer {
+  /// Address of the server host: the beacon source IP and the advertised port
+  pub address   : Address,
+  pub metadata  : Vec <u8>,
+  pub last_seen : Instant
+}
+
+////////////////////////////////////////////////////////////////////////////////
+//  impls                                                                     //
+////////////////////////////////////////////////////////////////////////////////
+
+impl LanBeacon {
+  /// Advertise the server host listening on `port`, sending a beacon to
+  /// `target` every `interval`.
+  ///
+  /// `target` is usually `255.255.255.255` (or a subnet broadcast address) with
+  /// the discovery port, e.g. `DEFAULT_PORT`.
+  ///
+  /// Returns an error if the socket cannot be created or `metadata` is longer
+  /// than `MAX_METADATA_LENGTH`.
+  pub fn new (
+    enet     : &Enet,
+    port     : u16,
+    target   : Address,
+    metadata : &[u8],
+    interval : Duration
+  ) -> std::io::Result <Self> {
+    let socket = enet.socket_create (socket::Type::Datagram)?;
+    socket.set_option (socket::SocketOption::Broadcast (true))?;
+    socket.set_option (socket::SocketOption::NonBlock (true))?;
+    let message = encode (port, metadata)?;
+    Ok (LanBeacon { socket, target, port, message, interval, last_sent: None })
+  }
+
+  /// Change the metadata sent in beacons
+  pub fn set_metadata (&mut self, metadata : &[u8]) -> std::io::Result <()> {
+    self.message = encode (self.port, metadata)?;
+    Ok (())
+  }
+
+  /// Send a beacon if the interval has elapsed since the last one
+  pub fn poll (&mut self) -> std::io::Result <()> {
+    if self.last_sent
+      .is_none_or (|last_sent| self.interval <= last_sent.elapsed())
+    {
+      self.send()?;
+    }
+    Ok (())
+  }
+
+  /// Send a beacon now
+  pub fn send (&mut self) -> std::io::Result <()> {
+    self.socket.send_to (&self.target, &[&self.message])?;
+    self.last_sent = Some (Instant::now());
+    Ok (())
+  }
+}
+
+impl LanBrowser {
+  /// Listen for beacons on `address`, usually `Address::any (DEFAULT_PORT)`.
+  ///
+  /// The address is bound with `SO_REUSEADDR` so that several browsers on one
+  /// machine can listen on the same port.
+  pub fn new (enet : &Enet, address : &Address, expiry : Duration)
+    -> std::io::Result <Self>
+  {
+    let socket = enet.socket_create (socket::Type::Datagram)?;
+    socket.set_option (socket::SocketOption::ReuseAddress (true))?;
+    socket.set_option (socket::SocketOption::NonBlock (true))?;
+    socket.bind (address)?;
+    Ok (LanBrowser {
+      socket,
+      expiry,
+      servers: HashMap::new(),
+      buffer:  vec![0; BEACON_HEADER_LENGTH + MAX_METADATA_LENGTH]
+    })
+  }
+
+  /// Receive the beacons that have arrived and remove expired servers.
+  ///
+  /// Returns the number of beacons received.
+  pub fn poll (&mut self) -> std::io::Result <usize> {
+    let mut received = 0;
+    loop {
+      let (source, length) = match self.socket.recv_from (&mut [&mut self.buffer]) {
+        Ok (Some (received)) => received,
+        Ok (None) => break,
+        // too large to be a beacon
+        Err (err) if err.kind() == std::io::ErrorKind::InvalidData => continue,
+        Err (err) => return Err (err)
+      };
+      let Some ((port, metadata)) = decode (&self.buffer[..length]) else {
+        continue
+      };
+      let address = SocketAddrV4::new (*SocketAddrV4::from (source).ip(), port);
+      self.servers.insert (address, LanServer {
+        address:   address.into(),
+        metadata:  metadata.to_vec(),
+        last_seen: Instant::now()
+      });
+      received += 1;
+    }
+    let expiry = self.expiry;
+    self.servers.retain (|_, server| server.last_seen.elapsed() < expiry);
+    Ok (received)
+  }
+
+  /// Servers heard from within the expiry time, most recently seen first
+  pub fn servers (&self) -> Vec <LanServer> {
+    let mut servers = self.servers.values()
+      .filter (|server| server.last_seen.elapsed() < self.expiry)
+      .cloned().collect::<Vec <_>>();
+    servers.sort_by_key (|server| std::cmp::Reverse (server.last_seen));
+    servers
+  }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+//  functions                                                                 //
+////////////////////////////////////////////////////////////////////////////////
+
+fn encode (port : u16, metadata : &[u8]) -> std::io::Result <Vec <u8>> {
+  if MAX_METADATA_LENGTH < metadata.len() {
+    return Err (std::io::Error::new (std::io::ErrorKind::InvalidInput,
+      "beacon metadata too long"))
+  }
+  let mut message = Vec::with_capacity (BEACON_HEADER_LENGTH + metadata.len());
+  message.extend_from_slice (&BEACON_MAGIC);
+  message.push (BEACON_VERSION);
+  message.extend_from_slice (&port.to_be_bytes());
+  message.extend_from_slice (&(metadata.len() as u16).to_be_bytes());
+  message.extend_from_slice (metadata);
+  Ok (message)
+}
+
+/// Decode a beacon into the advertised port and the metadata
+fn decode (message : &[u8]) -> Option <(u16, &[u8])> {
+  let rest = message.strip_prefix (&BEACON_MAGIC)?
+    .strip_prefix (&[BEACON_VERSION])?;
+  let (port,   rest) = rest.split_first_chunk::<2>()?;
+  let (length, rest) = rest.split_first_chunk::<2>()?;
+  let metadata = rest.get (..usize::from (u16::from_be_bytes (*length)))?;
+  Some ((u16::from_be_bytes (*port), metadata))
+}
+
+////////////////////////////////////////////////////////////////////////////////
+//  tests                                                                     //
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg (test)]
+mod tests {
+  use super::*;
+  use crate::testing;
+
+  #[test]
+  fn beacon_decodes_as_encoded() {
+    let message = encode (7777, b"name=lan party").unwrap();
+    assert_eq!(message.len(), BEACON_HEADER_LENGTH + 14);
+    assert_eq!(decode (&message), Some ((7777, &b"name=lan party"[..])));
+    let empty = encode (1, &[]).unwrap();
+    assert_eq!(decode (&empty), Some ((1, &[][..])));
+    let largest = vec![0xAB; MAX_METADATA_LENGTH];
+    let message = encode (2, &largest).unwrap();
+    assert_eq!(decode (&message), Some ((2, largest.as_slice())));
+  }
+
+  #[test]
+  fn beacon_metadata_length_is_limited() {
+    let err = encode (1, &[0; MAX_METADATA_LENGTH + 1]).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+  }
+
+  #[test]
+  fn beacon_decode_rejects_invalid_messages() {
+    let message = encode (7777, b"metadata").unwrap();
+    for length in 0..message.len() {
+      assert_eq!(decode (&message[..length]), None);
+    }
+    let mut wrong_magic = message.clone();
+    wrong_magic[0] = b'X';
+    assert_eq!(decode (&wrong_magic), None);
+    let mut wrong_version = message.clone();
+    wrong_version[4] = BEACON_VERSION + 1;
+    assert_eq!(decode (&wrong_version), None);
+    // trailing bytes after the metadata are ignored
+    let mut trailing = message;
+    trailing.extend_from_slice (b"trailing");
+    assert_eq!(decode (&trailing), Some ((7777, &b"metadata"[..])));
+  }
+
+  #[test]
+  fn browser_lists_a_server_until_it_expires() {
+    let enet   = testing::enet();
+    let expiry = Duration::from_millis (200);
+    let mut server = enet.server_host_create (Address::localhost (0), 1, None,
+      None, None).unwrap();
+    let mut browser =
+      LanBrowser::new (&enet, &Address::localhost (0), expiry).unwrap();
+    let browser_port = browser.socket.local_address().unwrap().port();
+    let mut beacon = LanBeacon::new (&enet, server.local_address().port(),
+      Address::localhost (browser_port), b"name=test",
+      Duration::from_millis (20)
+    ).unwrap();
+    let start = Instant::now();
+    while browser.servers().is_empty() {
+      assert!(start.elapsed() < testing::TIMEOUT);
+      beacon.poll().unwrap();
+      browser.poll().unwrap();
+      std::thread::sleep (Duration::from_millis (1));
+    }
+    let [found] = &browser.servers()[..] else {
+      panic!("{:?}", browser.servers())
+    };
+    assert_eq!(SocketAddrV4::from (found.address.clone()),
+      SocketAddrV4::from (server.local_address()));
+    assert_eq!(found.metadata, b"name=test");
+    // the advertised address is the server host
+    let mut client = enet.client_host_create (1, None, None).unwrap();
+    client.connect (&found.address, 1, 0).unwrap();
+    testing::pump_until (&mut [&mut server, &mut client], testing::TIMEOUT,
+      |events| events.len() == 2).unwrap();
+    // more beacons keep the server listed
+    std::thread::sleep (expiry / 2);
+    beacon.send().unwrap();
+    std::thread::sleep (expiry / 2);
+    assert_eq!(browser.poll().unwrap(), 1);
+    assert_eq!(browser.servers().len(), 1);
+    std::thread::sleep (expiry);
+    assert_eq!(browser.poll().unwrap(), 0);
+    assert!(browser.servers().is_empty());
+  }
+}
@@ -1,10 +1,13 @@
 use ll;
-use crate::{host, packet, Peer};
+use crate::{host, packet, quality, Peer};
 
+use std::cell::Cell;
+use std::collections::VecDeque;
 use std::rc::Rc;
 
 /// Event structure returned by `host.service()` or `host.check_events()`
 #[derive(Debug)]
+#[non_exhaustive]
 pub enum Event {
   Connect {
     peer : Peer,
@@ -18,9 +21,95 @@ pub enum Event {
     peer       : Peer,
     channel_id : u8,
     packet     : packet::PacketRecv
+  },
+  /// A connected peer has answered the connection handshake (see `auth`); sent
+  /// instead of `Connect` when the host has a handshake set
+  Authenticated {
+    peer : Peer,
+    data : u32
+  },
+  /// All reliable data for a peer has been sent and acknowledged after
+  /// `peer.try_send()` returned `WouldBlock`
+  Drained {
+    peer : Peer
+  },
+  /// A packet sent with `peer.send_tracked()` has been acknowledged by the peer.
+  ///
+  /// `connect_id` is the connection the packet was sent on: the peer may have
+  /// been reset or reused by another connection since (see
+  /// `peer.connect_id()`).
+  Delivered {
+    peer       : Peer,
+    connect_id : u32,
+    ticket     : packet::SendTicket
+  },
+  /// A packet sent with `peer.send_tracked()` was discarded before being
+  /// acknowledged, because the peer disconnected or was reset.
+  ///
+  /// `connect_id` is the connection the packet was sent on, as for `Delivered`.
+  Dropped {
+    peer       : Peer,
+    connect_id : u32,
+    ticket     : packet::SendTicket
+  },
+  /// The connection quality of a peer changed; see the `quality` module.
+  ///
+  /// `connect_id` is the connection whose quality changed, as for `Delivered`.
+  QualityChanged {
+    peer       : Peer,
+    connect_id : u32,
+    level      : quality::Level
+  }
+}
+
+/// An event generated by the host session layers, queued until it is returned
+/// by `host.service()` or `host.check_events()`
+#[derive(Debug)]
+pub(crate) enum Pending {
+  Connect {
+    peer : *mut ll::ENetPeer,
+    data : u32
+  },
+  Authenticated {
+    peer : *mut ll::ENetPeer,
+    data : u32
+  },
+  Disconnect {
+    peer : *mut ll::ENetPeer,
+    data : u32
+  },
+  Receive {
+    peer       : *mut ll::ENetPeer,
+    channel_id : u8,
+    packet     : packet::PacketRecv
+  },
+  Drained {
+    peer : *mut ll::ENetPeer
+  },
+  Delivered {
+    peer       : *mut ll::ENetPeer,
+    connect_id : u32,
+    ticket     : packet::SendTicket
+  },
+  Dropped {
+    peer       : *mut ll::ENetPeer,
+    connect_id : u32,
+    ticket     : packet::SendTicket
+  },
+  QualityChanged {
+    peer       : *mut ll::ENetPeer,
+    connect_id : u32,
+    level      : quality::Level
   }
 }
 
+/// Queue of pending events.
+///
+/// Pushing never fails, so that the free callback of a tracked packet can
+/// report it even while the host is adding other events.
+#[derive(Default)]
+pub(crate) struct Queue (Cell <VecDeque <Pending>>);
+
 impl Event {
   pub (crate) fn from_ll (event : ll::ENetEvent, hostdrop : Rc <host::HostDrop>)
     -> Option <Self>
@@ -53,3 +142,77 @@ impl Event {
     }
   }
 }
+
+impl Pending {
+  pub(crate) fn into_event (self, hostdrop : Rc <host::HostDrop>) -> Event {
+    unsafe {
+      match self {
+        Pending::Connect { peer, data } => Event::Connect {
+          peer: Peer::from_raw (peer, hostdrop),
+          data
+        },
+        Pending::Authenticated { peer, data } => Event::Authenticated {
+          peer: Peer::from_raw (peer, hostdrop),
+          data
+        },
+        Pending::Disconnect { peer, data } => Event::Disconnect {
+          peer: Peer::from_raw (peer, hostdrop),
+          data
+        },
+        Pending::Receive { peer, channel_id, packet } => Event::Receive {
+          peer: Peer::from_raw (peer, hostdrop),
+          channel_id,
+          packet
+        },
+        Pending::Drained { peer } => Event::Drained {
+          peer: Peer::from_raw (peer, hostdrop)
+        },
+        Pending::Delivered { peer, connect_id, ticket } => Event::Delivered {
+          peer: Peer::from_raw (peer, hostdrop),
+          connect_id,
+          ticket
+        },
+        Pending::Dropped { peer, connect_id, ticket } => Event::Dropped {
+          peer: Peer::from_raw (peer, hostdrop),
+          connect_id,
+          ticket
+        },
+        Pending::QualityChanged { peer, connect_id, level } =>
+          Event::QualityChanged {
+            peer: Peer::from_raw (peer, hostdrop),
+            connect_id,
+            level
+          }
+      }
+    }
+  }
+}
+
+impl Queue {
+  pub(crate) fn push (&self, pending : Pending) {
+    let mut queue = self.0.take();
+    queue.push_back (pending);
+    self.0.set (queue);
+  }
+
+  pub(crate) fn pop (&self) -> Option <Pending> {
+    let mut queue = self.0.take();
+    let pending = queue.pop_front();
+    self.0.set (queue);
+    pending
+  }
+
+  #[cfg(test)]
+  pub(crate) fn take (&self) -> VecDeque <Pending> {
+    self.0.take()
+  }
+}
+
+impl std::fmt::Debug for Queue {
+  fn fmt (&self, f : &mut std::fmt::Formatter) -> std::fmt::Result {
+    let queue = self.0.take();
+    let result = f.debug_tuple ("Queue").field (&queue).finish();
+    self.0.set (queue);
+    result
+  }
+}
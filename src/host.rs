@@ -1,8 +1,25 @@
 use std;
+use std::collections::HashMap;
 use ll;
 use crate::{
-  peer, Address, EnetDrop, Event, Packet, Peer, MAX_PEERS, MAX_CHANNEL_COUNT
+  auth, capture, clock, event, intercept, netgraph, packet, peer, quality,
+  query, socket, Address, EnetDrop, Event, Packet, Peer, MAX_PEERS,
+  MAX_CHANNEL_COUNT
 };
+#[cfg(feature = "encryption")]
+use crate::crypto;
+
+pub mod access_control;
+
+pub use self::access_control::AccessControl;
+
+/// Maximum number of packets held for a peer that has not finished the
+/// handshake or key exchange
+const MAX_HELD_PACKETS : usize = 64;
+/// Maximum number of handshake messages held for a peer until the key exchange
+/// is complete: its challenge and its response
+#[cfg(feature = "encryption")]
+const MAX_DEFERRED_MESSAGES : usize = 2;
 
 ////////////////////////////////////////////////////////////////////////////////
 //  structs                                                                   //
@@ -16,22 +33,123 @@ pub struct Host {
   hostdrop : std::rc::Rc <HostDrop>
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug)]
 pub(crate) struct HostDrop {
   raw      : *mut ll::ENetHost,
+  pub(crate) state   : std::cell::RefCell <State>,
+  /// Events generated by the session layers and tracked packets, returned
+  /// before servicing the host again
+  pub(crate) pending : std::rc::Rc <event::Queue>,
+  pub(crate) intercepts : std::cell::RefCell <intercept::Intercepts>,
+  pub(crate) capture : std::cell::RefCell <Option <capture::Capture>>,
+  /// Dropped last: held packets must be destroyed before ENet is deinitialized
   enetdrop : std::sync::Arc <EnetDrop>
 }
 
+/// Session layer state of a host
+#[derive(Debug, Default)]
+pub(crate) struct State {
+  pub(crate) handshake  : Option <auth::Handshake>,
+  #[cfg(feature = "encryption")]
+  pub(crate) encryption : Option <crypto::Encryption>,
+  #[cfg(feature = "encryption")]
+  tampered_packets : u64,
+  /// Intercept enforcing the access control set on the host
+  access_control   : Option <intercept::InterceptId>,
+  /// Intercept answering server info queries
+  query_responder  : Option <intercept::InterceptId>,
+  /// Indexed by `incomingPeerID`
+  sessions : HashMap <u16, Session>,
+  /// Connect IDs of connections started by `connect()`, indexed by
+  /// `incomingPeerID`
+  initiated : HashMap <u16, u32>,
+  /// Bytes a peer may have queued for `try_send()`
+  send_budget : Option <usize>,
+  /// Connect IDs of peers refused by `try_send()` and waiting for a `Drained`
+  /// event, indexed by `incomingPeerID`
+  backlogged  : HashMap <u16, u32>,
+  /// Pool providing outgoing packets
+  packet_pool : Option <packet::PacketPool>,
+  /// Id of the next `SendTicket`
+  next_ticket : u64,
+  /// Payload bytes sent and received, indexed by `incomingPeerID` and channel
+  channel_bytes : HashMap <(u16, u8), ChannelBytes>,
+  /// Time series of the peers, sampled when the host is serviced
+  net_graph     : Option <netgraph::NetGraph>,
+  /// Connection quality levels of the peers, checked when the host is serviced
+  quality_monitor : Option <quality::QualityMonitor>,
+  /// Clock probes exchanged on a reserved channel
+  pub(crate) clock_sync : Option <clock::ClockSync>
+}
+
+/// Payload bytes of a peer channel
+#[derive(Debug, Default)]
+struct ChannelBytes {
+  /// Connection the bytes were counted for
+  connect_id : u32,
+  sent       : u64,
+  received   : u64
+}
+
+/// State of a host and its peers, from `host.debug_snapshot()`
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[derive(Clone, Debug)]
+pub struct DebugSnapshot {
+  pub local_address          : Address,
+  pub connected_peers        : usize,
+  pub total_sent_data        : u32,
+  pub total_sent_packets     : u32,
+  pub total_received_data    : u32,
+  pub total_received_packets : u32,
+  /// Peers that are not disconnected
+  pub peers                  : Vec <PeerSnapshot>
+}
+
+/// State of a peer and its channels in a `DebugSnapshot`
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[derive(Clone, Debug)]
+pub struct PeerSnapshot {
+  pub incoming_peer_id : u16,
+  pub connect_id       : u32,
+  pub address          : Address,
+  pub state            : peer::State,
+  pub round_trip_time  : u32,
+  pub packet_loss      : u32,
+  pub queued_bytes     : usize,
+  pub channels         : Vec <peer::ChannelStats>
+}
+
+/// Session layer state of a connected peer
+#[derive(Debug)]
+struct Session {
+  /// Distinguishes re-use of the same peer slot by a new connection
+  connect_id  : u32,
+  /// Connect event data, reported once the session is established
+  data        : u32,
+  started     : std::time::Instant,
+  established : bool,
+  auth        : Option <auth::PeerAuth>,
+  #[cfg(feature = "encryption")]
+  crypto      : Option <crypto::PeerCrypto>,
+  /// Handshake messages received before the key exchange was complete
+  #[cfg(feature = "encryption")]
+  deferred    : Vec <Vec <u8>>,
+  /// Packets received before the session was established
+  held        : Vec <(u8, packet::PacketRecv)>
+}
+
 ////////////////////////////////////////////////////////////////////////////////
 //  enums                                                                     //
 ////////////////////////////////////////////////////////////////////////////////
 
-#[derive(Debug)]
+/// Host errors, with the OS error at the time of the failure
+#[expect(clippy::error_impl_error)] // public name kept for compatibility
+#[derive(Clone, Debug)]
 pub enum Error {
   /// Error from `service()`
-  ServiceError,
+  ServiceError  (std::sync::Arc <std::io::Error>),
   /// Error from `check_events()`
-  DispatchError
+  DispatchError (std::sync::Arc <std::io::Error>)
 }
 
 #[derive(Clone, Debug)]
@@ -40,7 +158,10 @@ pub enum CreateError {
   TooManyPeers    (u32),
   /// Maximum channel count is `enet::MAX_CHANNEL_COUNT` (255)
   TooManyChannels (u32),
-  ReturnedNull
+  /// `enet_host_create` failed, e.g. because the address is in use
+  ReturnedNull    (std::sync::Arc <std::io::Error>),
+  /// A socket option given at creation could not be set
+  SocketOption    (socket::SocketOption, std::sync::Arc <std::io::Error>)
 }
 
 ////////////////////////////////////////////////////////////////////////////////
@@ -54,6 +175,7 @@ impl Host {
     channel_limit      : Option <u32>,
     incoming_bandwidth : Option <u32>,
     outgoing_bandwidth : Option <u32>,
+    socket_options     : &[socket::SocketOption],
     enetdrop           : std::sync::Arc <EnetDrop>
   ) -> Result <Self, CreateError> {
     if MAX_PEERS < peer_count {
@@ -74,7 +196,8 @@ impl Host {
           outgoing_bandwidth.unwrap_or (0)
         );
         if host.is_null() {
-          return Err (CreateError::ReturnedNull)
+          return Err (
+            CreateError::ReturnedNull (std::io::Error::last_os_error().into()))
         }
       },
       None => unsafe {
@@ -86,15 +209,26 @@ impl Host {
           outgoing_bandwidth.unwrap_or (0)
         );
         if host.is_null() {
-          return Err (CreateError::ReturnedNull)
+          return Err (
+            CreateError::ReturnedNull (std::io::Error::last_os_error().into()))
         }
       }
     } // end match address
-    Ok (Host {
+    let host = Host {
       hostdrop: std::rc::Rc::new (HostDrop {
-        raw: host, enetdrop
+        raw:     host,
+        state:   std::cell::RefCell::default(),
+        pending: std::rc::Rc::default(),
+        intercepts: std::cell::RefCell::default(),
+        capture: std::cell::RefCell::default(),
+        enetdrop
       })
-    })
+    };
+    for option in socket_options {
+      host.set_socket_option (*option)
+        .map_err (|err| CreateError::SocketOption (*option, err.into()))?;
+    }
+    Ok (host)
   } // end new
 
   /// # Safety
@@ -105,6 +239,42 @@ impl Host {
     unsafe { self.hostdrop.raw() }
   }
 
+  /// Address the host socket is bound to.
+  ///
+  /// For a host bound to port 0 (including client hosts) this is the port
+  /// chosen by the operating system. If the socket address cannot be queried,
+  /// the address the host was created with is returned.
+  pub fn local_address (&self) -> Address {
+    unsafe {
+      let raw = self.raw();
+      let mut address = (*raw).address;
+      if ll::enet_socket_get_address ((*raw).socket, &mut address) < 0 {
+        address = (*raw).address;
+      }
+      Address::from_ll (address)
+    }
+  }
+
+  /// Set an option on the host socket, e.g. a larger `ReceiveBuffer` for a
+  /// busy server.
+  ///
+  /// ENet sets the receive and send buffers to 256 KiB when creating a host.
+  pub fn set_socket_option (&self, option : socket::SocketOption)
+    -> std::io::Result <()>
+  {
+    socket::set_option (unsafe { (*self.raw()).socket }, option)
+  }
+
+  /// Read back the value of an option of the host socket as applied by the
+  /// operating system, e.g. Linux doubles the buffer sizes that are set.
+  ///
+  /// See `Socket::option()` for the options that cannot be read.
+  pub fn socket_option (&self, name : socket::OptionName)
+    -> std::io::Result <socket::SocketOption>
+  {
+    socket::get_option (unsafe { (*self.raw()).socket }, name)
+  }
+
   /// Number of peers allocated for this host
   #[inline]
   pub fn peer_count (&self) -> usize {
@@ -220,6 +390,8 @@ impl Host {
       if peer.is_null() {
         return Err (peer::ConnectError::Failure)
       }
+      self.hostdrop.state.borrow_mut().initiated
+        .insert ((*peer).incomingPeerID, (*peer).connectID);
       Ok (Peer::from_raw(peer, self.hostdrop.clone()))
     }
   }
@@ -231,60 +403,773 @@ impl Host {
   ///
   /// `timeout` is the number of milliseconds that ENet should wait for events.
   pub fn service (&mut self, timeout : u32) -> Result <Option <Event>, Error> {
-    let event = unsafe {
-      let mut mem = std::mem::MaybeUninit::<ll::ENetEvent>::uninit();
-      let event   = mem.as_mut_ptr();
-      if ll::enet_host_service (self.hostdrop.raw, event, timeout) < 0 {
-        return Err (Error::ServiceError)
+    let start = std::time::Instant::now();
+    self.sample_net_graph();
+    self.check_quality();
+    self.clock_probes();
+    loop {
+      if let Some (event) = self.pending_event() {
+        return Ok (Some (event))
       }
-      *event
-    };
-    Ok (Event::from_ll (event, self.hostdrop.clone()))
+      self.handshake_timeouts();
+      let remaining = u128::from (timeout)
+        .saturating_sub (start.elapsed().as_millis()) as u32;
+      let event = unsafe {
+        let mut mem = std::mem::MaybeUninit::<ll::ENetEvent>::uninit();
+        let event   = mem.as_mut_ptr();
+        let result = self.hostdrop.send (||
+          ll::enet_host_service (self.hostdrop.raw, event, remaining));
+        if result < 0 {
+          let error = std::io::Error::last_os_error();
+          // a signal interrupted the wait
+          if error.kind() == std::io::ErrorKind::Interrupted {
+            continue
+          }
+          return Err (Error::ServiceError (error.into()))
+        }
+        *event
+      };
+      match Event::from_ll (event, self.hostdrop.clone()) {
+        Some (event) => if let Some (event) = self.session_event (event) {
+          return Ok (Some (event))
+        }
+        None => return Ok (self.pending_event())
+      }
+    }
   }
 
   /// Checks for any queued events on the host and dispatches one if available
-  #[inline]
   pub fn check_events (&mut self) -> Result <Option <Event>, Error> {
-    let event = unsafe {
-      let mut mem = std::mem::MaybeUninit::<ll::ENetEvent>::uninit();
-      let event   = mem.as_mut_ptr();
-      if ll::enet_host_check_events (self.hostdrop.raw, event) < 0 {
-        return Err (Error::DispatchError)
+    self.sample_net_graph();
+    self.check_quality();
+    self.clock_probes();
+    loop {
+      if let Some (event) = self.pending_event() {
+        return Ok (Some (event))
       }
-      *event
-    };
-    Ok (Event::from_ll (event, self.hostdrop.clone()))
+      self.handshake_timeouts();
+      let event = unsafe {
+        let mut mem = std::mem::MaybeUninit::<ll::ENetEvent>::uninit();
+        let event   = mem.as_mut_ptr();
+        if ll::enet_host_check_events (self.hostdrop.raw, event) < 0 {
+          return Err (
+            Error::DispatchError (std::io::Error::last_os_error().into()))
+        }
+        *event
+      };
+      match Event::from_ll (event, self.hostdrop.clone()) {
+        Some (event) => if let Some (event) = self.session_event (event) {
+          return Ok (Some (event))
+        }
+        None => return Ok (self.pending_event())
+      }
+    }
   }
 
   /// Send any queued messages without dispatching events. Alternatively,
   /// `service()` will send queued messages and also dispatch events.
   #[inline]
   pub fn flush (&mut self) {
-    unsafe { ll::enet_host_flush (self.hostdrop.raw) }
+    self.hostdrop.send (|| unsafe { ll::enet_host_flush (self.hostdrop.raw) })
   }
 
-  /// Queue a packet to be sent to all peers associated with the host
+  /// Queue a packet to be sent to all peers associated with the host.
+  ///
+  /// If a handshake or encryption is set, only peers that have completed them
+  /// are sent the packet.
   pub fn broadcast (&mut self, channel_id : u8, packet : Packet) {
     unsafe {
+      let mut state = self.hostdrop.state.borrow_mut();
       let raw = match packet {
-        Packet::Allocate { bytes, flags } => {
-          ll::enet_packet_create (
-            bytes.as_ptr() as *const std::os::raw::c_void,
-            bytes.len(),
-            flags.bits())
+        Packet::Allocate { bytes, flags } => state.create_packet (bytes, flags),
+        Packet::NoAllocate { bytes, flags } =>
+          state.create_packet (bytes, flags | packet::Flags::NO_ALLOCATE)
+      };
+      if raw.is_null() {
+        return
+      }
+      if !state.active() {
+        let bytes = (*raw).dataLength;
+        for index in 0..self.peer_count() {
+          // ENet broadcasts to connected peers
+          let peer = (*self.raw()).peers.add (index);
+          if (*peer).state == ll::_ENetPeerState_ENET_PEER_STATE_CONNECTED {
+            state.count_sent (peer, channel_id, bytes);
+          }
+        }
+        return ll::enet_host_broadcast (self.raw(), channel_id, raw)
+      }
+      for index in 0..self.peer_count() {
+        let peer = (*self.raw()).peers.add (index);
+        if (*peer).state != ll::_ENetPeerState_ENET_PEER_STATE_CONNECTED ||
+          state.check_send (peer).is_err()
+        {
+          continue
+        }
+        #[cfg(feature = "encryption")]
+        {
+          let bytes = std::slice::from_raw_parts ((*raw).data, (*raw).dataLength);
+          if let Some (sealed) = state.seal (peer, channel_id, bytes) {
+            let sealed = state.create_packet (&sealed,
+              packet::Flags::from_bits_retain ((*raw).flags) -
+                packet::Flags::NO_ALLOCATE);
+            if sealed.is_null() {
+              continue
+            }
+            if ll::enet_peer_send (peer, channel_id, sealed) < 0 {
+              if (*sealed).referenceCount == 0 {
+                ll::enet_packet_destroy (sealed);
+              }
+            } else {
+              state.count_sent (peer, channel_id, bytes.len());
+            }
+            continue
+          }
+        }
+        if ll::enet_peer_send (peer, channel_id, raw) == 0 {
+          state.count_sent (peer, channel_id, (*raw).dataLength);
+        }
+      }
+      if (*raw).referenceCount == 0 {
+        ll::enet_packet_destroy (raw)
+      }
+    }
+  }
+
+  /// Add an intercept called with each datagram received by the host, before
+  /// ENet processes it; see the `intercept` module
+  pub fn add_intercept (&mut self, intercept : Box <dyn intercept::Intercept>)
+    -> intercept::InterceptId
+  {
+    intercept::register (&self.hostdrop);
+    self.hostdrop.intercepts.borrow_mut().add (intercept)
+  }
+
+  /// Remove an intercept, returning it if it was found
+  pub fn remove_intercept (&mut self, id : intercept::InterceptId)
+    -> Option <Box <dyn intercept::Intercept>>
+  {
+    self.hostdrop.intercepts.borrow_mut().remove (id)
+  }
+
+  /// Drop datagrams and connection requests according to address based rules;
+  /// see the `access_control` module.
+  ///
+  /// Replaces the access control set previously, if any.
+  pub fn set_access_control (&mut self, access_control : Option <AccessControl>) {
+    let previous = self.hostdrop.state.borrow_mut().access_control.take();
+    if let Some (id) = previous {
+      self.remove_intercept (id);
+    }
+    if let Some (access_control) = access_control {
+      let id = self.add_intercept (access_control.intercept (unsafe { self.raw() }));
+      self.hostdrop.state.borrow_mut().access_control = Some (id);
+    }
+  }
+
+  /// Answer server info queries received on the host port; see the `query`
+  /// module.
+  ///
+  /// Replaces the responder set previously, if any.
+  pub fn set_query_responder (&mut self,
+    query_responder : Option <query::QueryResponder>
+  ) {
+    let previous = self.hostdrop.state.borrow_mut().query_responder.take();
+    if let Some (id) = previous {
+      self.remove_intercept (id);
+    }
+    if let Some (query_responder) = query_responder {
+      let id = self.add_intercept (
+        query_responder.intercept (unsafe { self.raw() }));
+      self.hostdrop.state.borrow_mut().query_responder = Some (id);
+    }
+  }
+
+  /// Write every datagram received or sent by the host to a pcap file at
+  /// `path`; see the `capture` module.
+  ///
+  /// A capture already in progress is stopped first.
+  pub fn start_capture <P : AsRef <std::path::Path>> (&mut self, path : P)
+    -> std::io::Result <()>
+  {
+    self.stop_capture()?;
+    let local   = self.local_address();
+    let capture = unsafe {
+      capture::Capture::start (path.as_ref(), self.raw(),
+        std::rc::Rc::as_ptr (&self.hostdrop), local)?
+    };
+    *self.hostdrop.capture.borrow_mut() = Some (capture);
+    intercept::register (&self.hostdrop);
+    Ok (())
+  }
+
+  /// Stop the capture in progress, if any.
+  ///
+  /// Returns the first error that occurred while writing the capture.
+  pub fn stop_capture (&mut self) -> std::io::Result <()> {
+    let capture = self.hostdrop.capture.borrow_mut().take();
+    capture.map_or (Ok (()), |capture| unsafe { capture.stop (self.raw()) })
+  }
+
+  /// Require peers to pass a connection handshake before they are reported to
+  /// the application; see the `auth` module.
+  ///
+  /// Peers that connected before the handshake was set are not authenticated:
+  /// packets are sent to and received from them as before.
+  #[inline]
+  pub fn set_handshake (&mut self, handshake : Option <auth::Handshake>) {
+    self.hostdrop.state.borrow_mut().handshake = handshake;
+  }
+
+  /// Encrypt traffic with peers; see the `crypto` module.
+  ///
+  /// Peers that connected before encryption was set are not encrypted: packets
+  /// are sent to and received from them in plaintext.
+  #[cfg(feature = "encryption")]
+  #[inline]
+  pub fn set_encryption (&mut self, encryption : Option <crypto::Encryption>) {
+    self.hostdrop.state.borrow_mut().encryption = encryption;
+  }
+
+  /// Number of received packets that were dropped because they failed to
+  /// decrypt or were replayed
+  #[cfg(feature = "encryption")]
+  #[inline]
+  pub fn tampered_packets (&self) -> u64 {
+    self.hostdrop.state.borrow().tampered_packets
+  }
+
+  /// Provide the packets for `peer.send()` and `host.broadcast()` from a pool
+  pub fn set_packet_pool (&mut self, pool : Option <packet::PacketPool>) {
+    self.hostdrop.state.borrow_mut().packet_pool = pool;
+  }
+
+  pub fn packet_pool (&self) -> Option <packet::PacketPool> {
+    self.hostdrop.state.borrow().packet_pool.clone()
+  }
+
+  /// Sample the peers into a net graph when the host is serviced; see the
+  /// `netgraph` module
+  pub fn set_net_graph (&mut self, net_graph : Option <netgraph::NetGraph>) {
+    self.hostdrop.state.borrow_mut().net_graph = net_graph;
+  }
+
+  pub fn net_graph (&self) -> Option <netgraph::NetGraph> {
+    self.hostdrop.state.borrow().net_graph.clone()
+  }
+
+  /// Check the connection quality of the peers when the host is serviced,
+  /// generating `Event::QualityChanged`; see the `quality` module
+  pub fn set_quality_monitor (&mut self,
+    quality_monitor : Option <quality::QualityMonitor>
+  ) {
+    self.hostdrop.state.borrow_mut().quality_monitor = quality_monitor;
+  }
+
+  pub fn quality_monitor (&self) -> Option <quality::QualityMonitor> {
+    self.hostdrop.state.borrow().quality_monitor.clone()
+  }
+
+  /// Exchange clock probes with the peers on the channel of the clock sync;
+  /// see the `clock` module.
+  ///
+  /// Fails with `InvalidInput` if the channel is the handshake or key exchange
+  /// channel.
+  pub fn set_clock_sync (&mut self, clock_sync : Option <clock::ClockSync>)
+    -> std::io::Result <()>
+  {
+    let mut state = self.hostdrop.state.borrow_mut();
+    if clock_sync.as_ref().is_some_and (
+      |clock_sync| state.is_control_channel (clock_sync.channel_id()))
+    {
+      return Err (std::io::Error::new (std::io::ErrorKind::InvalidInput,
+        "the clock channel is the handshake or key exchange channel"))
+    }
+    state.clock_sync = clock_sync;
+    Ok (())
+  }
+
+  pub fn clock_sync (&self) -> Option <clock::ClockSync> {
+    self.hostdrop.state.borrow().clock_sync.clone()
+  }
+
+  /// Create a builder for an outgoing packet with room for `capacity` bytes.
+  ///
+  /// Writes that would exceed the maximum packet size of the host fail; with
+  /// encryption set, the limit leaves room for `crypto::OVERHEAD`.
+  pub fn packet_builder (&self, flags : packet::Flags, capacity : usize)
+    -> Result <packet::PacketBuilder, peer::SendErrorKind>
+  {
+    let maximum = unsafe { (*self.raw()).maximumPacketSize };
+    #[cfg(feature = "encryption")]
+    let limit = if self.hostdrop.state.borrow().encryption.is_some() {
+      maximum.saturating_sub (crypto::OVERHEAD)
+    } else {
+      maximum
+    };
+    #[cfg(not(feature = "encryption"))]
+    let limit = maximum;
+    packet::PacketBuilder::new (flags, capacity, limit)
+  }
+
+  /// Queue a shared packet to each of `peers`, returning the result for each
+  /// peer in order.
+  ///
+  /// Unlike `broadcast()` the packet is only sent to the given peers and
+  /// failures are reported.
+  pub fn send_shared (&mut self,
+    peers : &mut [Peer], channel_id : u8, packet : &packet::SharedPacket
+  ) -> Vec <Result <(), peer::SendErrorKind>> {
+    peers.iter_mut().map (|peer| peer.send_shared (channel_id, packet)).collect()
+  }
+
+  /// Bytes a peer may have queued before `peer.try_send()` returns
+  /// `WouldBlock`
+  pub fn send_budget (&self) -> usize {
+    self.hostdrop.state.borrow().send_budget()
+  }
+
+  #[inline]
+  pub fn set_send_budget (&mut self, bytes : usize) {
+    self.hostdrop.state.borrow_mut().send_budget = Some (bytes);
+  }
+
+  /// Collect the state of the host, its peers and their channels, e.g. to
+  /// find out why a channel stalls.
+  ///
+  /// With the `serde` feature the snapshot can be serialized.
+  pub fn debug_snapshot (&self) -> DebugSnapshot {
+    let peers = self.peers().filter_map (|peer| {
+      if peer.state() == peer::State::Disconnected {
+        return None
+      }
+      let channel_count = unsafe { (*peer.raw()).channelCount };
+      let channels = (0..=u8::MAX).take (channel_count)
+        .filter_map (|channel_id| peer.channel_stats (channel_id))
+        .collect();
+      Some (PeerSnapshot {
+        incoming_peer_id: peer.incoming_peer_id(),
+        connect_id:       peer.connect_id(),
+        address:          peer.address(),
+        state:            peer.state(),
+        round_trip_time:  peer.round_trip_time(),
+        packet_loss:      peer.packet_loss(),
+        queued_bytes:     peer.queued_bytes(),
+        channels
+      })
+    }).collect();
+    DebugSnapshot {
+      local_address:          self.local_address(),
+      connected_peers:        self.connected_peers(),
+      total_sent_data:        self.total_sent_data(),
+      total_sent_packets:     self.total_sent_packets(),
+      total_received_data:    self.total_received_data(),
+      total_received_packets: self.total_received_packets(),
+      peers
+    }
+  }
+
+  /// All peers allocated for the host
+  pub(crate) fn peers (&self) -> impl Iterator <Item = Peer> {
+    (0..self.peer_count()).map (|index| unsafe {
+      Peer::from_raw ((*self.raw()).peers.add (index), self.hostdrop.clone())
+    })
+  }
+
+  fn pending_event (&self) -> Option <Event> {
+    self.drained();
+    let pending = self.hostdrop.pending.pop()?;
+    Some (pending.into_event (self.hostdrop.clone()))
+  }
+
+  fn sample_net_graph (&self) {
+    let net_graph = self.hostdrop.state.borrow().net_graph.clone();
+    if let Some (net_graph) = net_graph {
+      net_graph.sample (self);
+    }
+  }
+
+  fn check_quality (&self) {
+    let quality_monitor = self.hostdrop.state.borrow().quality_monitor.clone();
+    let Some (quality_monitor) = quality_monitor else {
+      return
+    };
+    let changed = quality_monitor.check (self);
+    for (peer, level) in changed {
+      self.hostdrop.pending.push (event::Pending::QualityChanged {
+        peer: unsafe { peer.raw() }, connect_id: peer.connect_id(), level
+      });
+    }
+  }
+
+  fn clock_probes (&self) {
+    let clock_sync = self.hostdrop.state.borrow().clock_sync.clone()
+      .filter (|clock_sync| !self.hostdrop.state.borrow()
+        .is_control_channel (clock_sync.channel_id()));
+    if let Some (clock_sync) = clock_sync {
+      clock_sync.probe (self);
+    }
+  }
+
+  /// Pass an event through the session layers, returning `None` if the event
+  /// was consumed
+  fn session_event (&self, event : Event) -> Option <Event> {
+    match self.session_layers (event)? {
+      Event::Receive { peer, channel_id, packet } =>
+        self.application_receive (peer, channel_id, packet),
+      event => Some (event)
+    }
+  }
+
+  /// Handle a packet that passed the session layers: clock messages are
+  /// consumed, other packets are counted and returned to the application
+  fn application_receive (&self,
+    peer : Peer, channel_id : u8, packet : packet::PacketRecv
+  ) -> Option <Event> {
+    let clock_sync = self.hostdrop.state.borrow().clock_sync.clone();
+    if let Some (clock_sync) = clock_sync &&
+      clock_sync.channel_id() == channel_id
+    {
+      clock_sync.receive (&peer, &packet);
+      return None
+    }
+    self.hostdrop.state.borrow_mut()
+      .count_received (unsafe { peer.raw() }, channel_id, packet.len());
+    Some (Event::Receive { peer, channel_id, packet })
+  }
+
+  fn session_layers (&self, event : Event) -> Option <Event> {
+    if !self.hostdrop.state.borrow().active() {
+      return Some (event)
+    }
+    // the handshake is taken while calling into the authenticator so that the
+    // authenticator may use the peer and host
+    let mut handshake = self.hostdrop.state.borrow_mut().handshake.take();
+    let event = match event {
+      Event::Connect { peer, data } => {
+        self.begin_session (handshake.as_mut(), &peer, data);
+        None
+      }
+      Event::Receive { peer, channel_id, packet }
+        if self.is_control_channel (handshake.as_ref(), channel_id) =>
+      {
+        self.control_message (handshake.as_mut(), &peer, packet.data());
+        None
+      }
+      Event::Receive { peer, channel_id, packet } =>
+        self.session_receive (peer, channel_id, packet),
+      Event::Disconnect { peer, data } => {
+        let _ = self.hostdrop.state.borrow_mut().take_session (&peer);
+        Some (Event::Disconnect { peer, data })
+      }
+      Event::Authenticated { .. } | Event::Drained { .. } |
+      Event::Delivered { .. } | Event::Dropped { .. } |
+      Event::QualityChanged { .. } => Some (event)
+    };
+    if let Some (handshake) = handshake {
+      self.hostdrop.state.borrow_mut().handshake.get_or_insert (handshake);
+    }
+    event
+  }
+
+  #[cfg_attr(not(feature = "encryption"), expect(clippy::unused_self))]
+  fn is_control_channel (&self,
+    handshake : Option <&auth::Handshake>, channel_id : u8
+  ) -> bool {
+    #[cfg(feature = "encryption")]
+    if self.hostdrop.state.borrow().encryption.as_ref()
+      .is_some_and (|encryption| encryption.channel_id() == channel_id)
+    {
+      return true
+    }
+    handshake.is_some_and (|handshake| handshake.channel_id() == channel_id)
+  }
+
+  /// Start the handshake and key exchange with a newly connected peer
+  fn begin_session (&self,
+    handshake : Option <&mut auth::Handshake>, peer : &Peer, data : u32
+  ) {
+    let role = {
+      let mut state = self.hostdrop.state.borrow_mut();
+      match state.initiated.remove (&peer.incoming_peer_id()) {
+        Some (connect_id) if connect_id == peer.connect_id() =>
+          auth::Role::Initiator,
+        _ => auth::Role::Responder
+      }
+    };
+    let mut session = Session {
+      connect_id:  peer.connect_id(),
+      data,
+      started:     std::time::Instant::now(),
+      established: false,
+      auth:        None,
+      #[cfg(feature = "encryption")]
+      crypto:      None,
+      #[cfg(feature = "encryption")]
+      deferred:    Vec::new(),
+      held:        Vec::new()
+    };
+    #[cfg(feature = "encryption")]
+    {
+      let encryption = self.hostdrop.state.borrow().encryption.clone();
+      if let Some (encryption) = encryption {
+        let (crypto, message) = crypto::PeerCrypto::new();
+        if !send_control (peer, encryption.channel_id(), &message) {
+          return self.reject (peer, crypto::DISCONNECT_KEY_EXCHANGE)
+        }
+        session.crypto = Some (crypto);
+      }
+    }
+    if let Some (handshake) = handshake {
+      match handshake.begin (peer, role) {
+        Some (auth) => session.auth = Some (auth),
+        None => return self.reject (peer, auth::DISCONNECT_REJECTED)
+      }
+    }
+    self.hostdrop.state.borrow_mut().sessions
+      .insert (peer.incoming_peer_id(), session);
+  }
+
+  /// Handle a message received on a handshake or key exchange channel
+  fn control_message (&self,
+    handshake : Option <&mut auth::Handshake>, peer : &Peer, bytes : &[u8]
+  ) {
+    match auth::Message::parse (bytes) {
+      Some (message) => if let Some (handshake) = handshake {
+        #[cfg(feature = "encryption")]
+        if self.defer_auth_message (peer, bytes) {
+          return
+        }
+        self.auth_message (handshake, peer, message)
+      }
+      #[cfg(feature = "encryption")]
+      None => if let Some (key) = crypto::parse_key_exchange (bytes) {
+        self.key_exchange_message (handshake, peer, key)
+      }
+      #[cfg(not(feature = "encryption"))]
+      None => {}
+    }
+  }
+
+  fn auth_message (&self,
+    handshake : &mut auth::Handshake, peer : &Peer, message : auth::Message
+  ) {
+    match message {
+      auth::Message::Challenge (challenge) => {
+        // a challenge sent by this host is being reflected back to it
+        let reflected = self.hostdrop.state.borrow().sessions.values()
+          .filter_map (|session| session.auth.as_ref()?.outstanding())
+          .any (|outstanding| outstanding == challenge);
+        if reflected {
+          return self.reject (peer, auth::DISCONNECT_REJECTED)
+        }
+        let session = self.hostdrop.state.borrow_mut().take_session (peer);
+        let Some (mut session) = session else {
+          return
+        };
+        if let Some (auth) = session.auth.as_mut() {
+          handshake.respond (peer, auth, challenge);
         }
-        #[expect(clippy::unnecessary_cast)]  // NOTE: on windows ll flags are i32
-        Packet::NoAllocate { bytes, flags } => {
-          ll::enet_packet_create (
-            bytes.as_ptr() as *const std::os::raw::c_void,
-            bytes.len(),
-            flags.bits() | ll::_ENetPacketFlag_ENET_PACKET_FLAG_NO_ALLOCATE as u32)
+        self.hostdrop.state.borrow_mut().sessions
+          .insert (peer.incoming_peer_id(), session);
+      }
+      auth::Message::Response (response) => {
+        let session = self.hostdrop.state.borrow_mut().take_session (peer);
+        let Some (mut session) = session else {
+          return
+        };
+        let verified = match session.auth.as_mut() {
+          Some (auth) if !auth.authenticated() =>
+            Some (handshake.verify (peer, auth, response)),
+          _ => None
+        };
+        self.hostdrop.state.borrow_mut().sessions
+          .insert (peer.incoming_peer_id(), session);
+        match verified {
+          Some (true)  => self.establish (peer),
+          Some (false) => self.reject (peer, auth::DISCONNECT_REJECTED),
+          None => {}
         }
+      }
+    }
+  }
+
+  /// Hold a handshake message until the key exchange is complete, since the
+  /// answers are bound to the public keys.
+  ///
+  /// Returns false if the message can be handled now.
+  #[cfg(feature = "encryption")]
+  fn defer_auth_message (&self, peer : &Peer, bytes : &[u8]) -> bool {
+    let mut state = self.hostdrop.state.borrow_mut();
+    let Some (session) = state.session_mut (unsafe { peer.raw() }) else {
+      return false
+    };
+    if session.crypto.as_ref().is_none_or (crypto::PeerCrypto::established) {
+      return false
+    }
+    if session.deferred.len() < MAX_DEFERRED_MESSAGES {
+      session.deferred.push (bytes.to_vec());
+    }
+    true
+  }
+
+  #[cfg(feature = "encryption")]
+  fn key_exchange_message (&self,
+    handshake : Option <&mut auth::Handshake>, peer : &Peer, key : [u8; 32]
+  ) {
+    let completed = {
+      let mut state = self.hostdrop.state.borrow_mut();
+      state.session_mut (unsafe { peer.raw() }).and_then (|session| {
+        let crypto = session.crypto.as_mut()?;
+        if !crypto.complete (key) {
+          return Some (None)
+        }
+        if let Some (auth) = session.auth.as_mut() {
+          auth.bind_keys (crypto.public_keys (auth.role()).unwrap_or_default());
+        }
+        Some (Some (std::mem::take (&mut session.deferred)))
+      })
+    };
+    match completed {
+      Some (Some (deferred)) => {
+        if let Some (handshake) = handshake {
+          for bytes in deferred {
+            if let Some (message) = auth::Message::parse (&bytes) {
+              self.auth_message (handshake, peer, message);
+            }
+          }
+        }
+        self.establish (peer)
+      }
+      Some (None) => self.reject (peer, crypto::DISCONNECT_KEY_EXCHANGE),
+      None => {}
+    }
+  }
+
+  /// Report a peer to the application once its session layers are complete,
+  /// along with any packets held in the meantime
+  fn establish (&self, peer : &Peer) {
+    let raw  = unsafe { peer.raw() };
+    let held = {
+      let mut state = self.hostdrop.state.borrow_mut();
+      let Some (session) = state.session_mut (raw) else {
+        return
+      };
+      if session.established || !session.ready() {
+        return
+      }
+      session.established = true;
+      let data = session.data;
+      self.hostdrop.pending.push (if session.auth.is_some() {
+        event::Pending::Authenticated { peer: raw, data }
+      } else {
+        event::Pending::Connect { peer: raw, data }
+      });
+      std::mem::take (&mut session.held)
+    };
+    for (channel_id, packet) in held {
+      #[cfg(feature = "encryption")]
+      let Some (packet) =
+        self.hostdrop.state.borrow_mut().open (raw, channel_id, packet)
+      else {
+        continue
+      };
+      if let Some (Event::Receive { channel_id, packet, .. }) =
+        self.application_receive (peer.clone(), channel_id, packet)
+      {
+        self.hostdrop.pending.push (
+          event::Pending::Receive { peer: raw, channel_id, packet });
+      }
+    }
+  }
+
+  fn session_receive (&self,
+    peer : Peer, channel_id : u8, packet : packet::PacketRecv
+  ) -> Option <Event> {
+    let raw       = unsafe { peer.raw() };
+    let mut state = self.hostdrop.state.borrow_mut();
+    // a peer without a session connected before the session layers were set
+    if state.session (raw).is_none_or (|session| session.established) {
+      #[cfg(feature = "encryption")]
+      let packet = state.open (raw, channel_id, packet)?;
+      return Some (Event::Receive { peer, channel_id, packet })
+    }
+    // packets from a peer that has finished its end of the session may arrive
+    // before its handshake answer or key does, so they are held until then
+    if let Some (session) = state.session_mut (raw) &&
+      session.held.len() < MAX_HELD_PACKETS
+    {
+      session.held.push ((channel_id, packet));
+    }
+    None
+  }
+
+  /// Queue `Drained` events for backlogged peers whose reliable data has been
+  /// acknowledged
+  fn drained (&self) {
+    let mut state = self.hostdrop.state.borrow_mut();
+    if state.backlogged.is_empty() {
+      return
+    }
+    let raw = unsafe { self.raw() };
+    state.backlogged.retain (|index, connect_id| unsafe {
+      let peer = (*raw).peers.add (usize::from (*index));
+      if (*peer).connectID != *connect_id ||
+        (*peer).state != ll::_ENetPeerState_ENET_PEER_STATE_CONNECTED
+      {
+        return false
+      }
+      if !peer::reliable_drained (peer) {
+        return true
+      }
+      self.hostdrop.pending.push (event::Pending::Drained { peer });
+      false
+    });
+  }
+
+  /// Disconnect peers that have not finished the handshake or key exchange in
+  /// time
+  fn handshake_timeouts (&self) {
+    let timed_out = {
+      let state = self.hostdrop.state.borrow();
+      let Some (timeout) = state.timeout() else {
+        return
       };
-      ll::enet_host_broadcast (self.raw(), channel_id, raw)
+      state.sessions.iter().filter (|(_, session)|
+        !session.established && timeout < session.started.elapsed()
+      ).map (|(index, session)| {
+        let reason = if session.auth.as_ref()
+          .is_none_or (auth::PeerAuth::authenticated)
+        {
+          #[cfg(feature = "encryption")]
+          { crypto::DISCONNECT_KEY_EXCHANGE }
+          #[cfg(not(feature = "encryption"))]
+          { auth::DISCONNECT_TIMEOUT }
+        } else {
+          auth::DISCONNECT_TIMEOUT
+        };
+        (*index, reason)
+      }).collect::<Vec <_>>()
+    };
+    for (index, reason) in timed_out {
+      let peer = unsafe {
+        Peer::from_raw (
+          (*self.raw()).peers.add (index as usize), self.hostdrop.clone())
+      };
+      self.reject (&peer, reason);
     }
   }
 
+  /// Disconnect a peer that failed the handshake or key exchange and report the
+  /// disconnection
+  fn reject (&self, peer : &Peer, reason : u32) {
+    let _ = self.hostdrop.state.borrow_mut().take_session (peer);
+    self.hostdrop.send_resetting (unsafe { peer.raw() }, ||
+      unsafe { ll::enet_peer_disconnect_now (peer.raw(), reason) });
+    self.hostdrop.pending.push (
+      event::Pending::Disconnect { peer: unsafe { peer.raw() }, data: reason });
+  }
+
 } // end impl Host
 
 impl HostDrop {
@@ -292,10 +1177,594 @@ impl HostDrop {
   pub(crate) const unsafe fn raw (&self) -> *mut ll::ENetHost {
     self.raw
   }
+
+  /// Call ENet to send datagrams, with the capture in progress, if any, wrapped
+  /// around the send path
+  pub(crate) fn send <T, F : FnOnce() -> T> (&self, send : F) -> T {
+    if let Some (capture) = self.capture.borrow_mut().as_mut() {
+      unsafe { capture.wrap (self.raw) }
+    }
+    let result = send();
+    if let Some (capture) = self.capture.borrow_mut().as_mut() {
+      unsafe { capture.returned (self.raw) }
+    }
+    result
+  }
+
+  /// As `send()`, for a call that resets `peer` after sending to it
+  pub(crate) fn send_resetting <T, F : FnOnce() -> T> (&self,
+    peer : *mut ll::ENetPeer, send : F
+  ) -> T {
+    if let Some (capture) = self.capture.borrow_mut().as_mut() {
+      unsafe { capture.resetting (self.raw, peer) }
+    }
+    self.send (send)
+  }
+}
+impl PartialEq for HostDrop {
+  fn eq (&self, other : &Self) -> bool {
+    self.raw == other.raw && self.enetdrop == other.enetdrop
+  }
 }
+impl State {
+  /// True if a handshake or encryption is set
+  pub(crate) const fn active (&self) -> bool {
+    #[cfg(feature = "encryption")]
+    if self.encryption.is_some() {
+      return true
+    }
+    self.handshake.is_some()
+  }
+
+  /// Create an outgoing packet, from the packet pool if one is set and the
+  /// data is to be copied
+  pub(crate) fn create_packet (&self, bytes : &[u8], flags : packet::Flags)
+    -> *mut ll::ENetPacket
+  {
+    if !flags.contains (packet::Flags::NO_ALLOCATE) &&
+      let Some (raw) = self.packet_pool.as_ref()
+        .and_then (|pool| pool.acquire (bytes, flags))
+    {
+      return raw
+    }
+    unsafe {
+      ll::enet_packet_create (
+        bytes.as_ptr() as *const std::os::raw::c_void, bytes.len(), flags.bits())
+    }
+  }
+
+  pub(crate) const fn next_ticket (&mut self) -> packet::SendTicket {
+    let ticket = packet::SendTicket::new (self.next_ticket);
+    self.next_ticket += 1;
+    ticket
+  }
+
+  /// Application payload bytes sent and received on a channel over the current
+  /// connection of the peer, before encryption and after decryption
+  pub(crate) fn channel_bytes (&self, peer : *mut ll::ENetPeer, channel_id : u8)
+    -> (u64, u64)
+  {
+    let (index, connect_id) =
+      unsafe { ((*peer).incomingPeerID, (*peer).connectID) };
+    self.channel_bytes.get (&(index, channel_id))
+      .filter (|bytes| bytes.connect_id == connect_id)
+      .map_or ((0, 0), |bytes| (bytes.sent, bytes.received))
+  }
+
+  /// Application payload bytes sent and received on all channels over the
+  /// current connection of the peer
+  pub(crate) fn peer_bytes (&self, peer : *mut ll::ENetPeer) -> (u64, u64) {
+    let channel_count = unsafe { (*peer).channelCount };
+    (0..=u8::MAX).take (channel_count)
+      .map (|channel_id| self.channel_bytes (peer, channel_id))
+      .fold ((0, 0),
+        |(sent, received), bytes| (sent + bytes.0, received + bytes.1))
+  }
+
+  pub(crate) fn count_sent (&mut self,
+    peer : *mut ll::ENetPeer, channel_id : u8, bytes : usize
+  ) {
+    self.channel_bytes_mut (peer, channel_id).sent += bytes as u64;
+  }
+
+  fn count_received (&mut self,
+    peer : *mut ll::ENetPeer, channel_id : u8, bytes : usize
+  ) {
+    self.channel_bytes_mut (peer, channel_id).received += bytes as u64;
+  }
+
+  fn channel_bytes_mut (&mut self, peer : *mut ll::ENetPeer, channel_id : u8)
+    -> &mut ChannelBytes
+  {
+    let (index, connect_id) =
+      unsafe { ((*peer).incomingPeerID, (*peer).connectID) };
+    let bytes = self.channel_bytes.entry ((index, channel_id)).or_default();
+    if bytes.connect_id != connect_id {
+      // the peer slot is used by a new connection
+      *bytes = ChannelBytes { connect_id, .. ChannelBytes::default() };
+    }
+    bytes
+  }
+
+  pub(crate) fn send_budget (&self) -> usize {
+    self.send_budget.unwrap_or (peer::DEFAULT_SEND_BUDGET)
+  }
+
+  /// Generate a `Drained` event once the peer's reliable data is acknowledged
+  pub(crate) fn backlogged (&mut self, peer : *mut ll::ENetPeer) {
+    let (index, connect_id) =
+      unsafe { ((*peer).incomingPeerID, (*peer).connectID) };
+    self.backlogged.insert (index, connect_id);
+  }
+
+  /// True if a channel carries handshake or key exchange messages
+  fn is_control_channel (&self, channel_id : u8) -> bool {
+    #[cfg(feature = "encryption")]
+    if self.encryption.as_ref()
+      .is_some_and (|encryption| encryption.channel_id() == channel_id)
+    {
+      return true
+    }
+    self.handshake.as_ref()
+      .is_some_and (|handshake| handshake.channel_id() == channel_id)
+  }
+
+  /// Check that a peer may be sent application packets
+  pub(crate) fn check_send (&self, peer : *mut ll::ENetPeer)
+    -> Result <(), peer::SendErrorKind>
+  {
+    if !self.active() {
+      return Ok (())
+    }
+    match self.session (peer) {
+      // the peer connected before the session layers were set
+      None => Ok (()),
+      Some (session) if session.established => Ok (()),
+      Some (session) if session.auth.as_ref()
+        .is_none_or (auth::PeerAuth::authenticated) =>
+        Err (peer::SendErrorKind::KeyExchangePending),
+      _ => Err (peer::SendErrorKind::PeerNotAuthenticated)
+    }
+  }
+
+  /// True if the session with the peer is encrypted
+  #[cfg(feature = "encryption")]
+  pub(crate) fn encrypted (&self, peer : *mut ll::ENetPeer) -> bool {
+    self.session (peer).is_some_and (|session| session.crypto.is_some())
+  }
+
+  /// Encrypt a packet payload for a peer.
+  ///
+  /// Returns `None` if the peer session is not encrypted.
+  #[cfg(feature = "encryption")]
+  pub(crate) fn seal (&mut self,
+    peer : *mut ll::ENetPeer, channel_id : u8, payload : &[u8]
+  ) -> Option <Vec <u8>> {
+    self.session_mut (peer)?.crypto.as_mut()?.seal (channel_id, payload)
+  }
+
+  /// Decrypt a received packet in place if the peer session is encrypted.
+  ///
+  /// Returns `None` if the packet should be dropped.
+  #[cfg(feature = "encryption")]
+  fn open (&mut self,
+    peer : *mut ll::ENetPeer, channel_id : u8, mut packet : packet::PacketRecv
+  ) -> Option <packet::PacketRecv> {
+    if let Some (crypto) = self.session_mut (peer)
+      .and_then (|session| session.crypto.as_mut()) &&
+      !crypto.open (channel_id, &mut packet)
+    {
+      self.tampered_packets += 1;
+      return None
+    }
+    Some (packet)
+  }
+
+  /// Shortest time allowed for a peer to establish a session
+  fn timeout (&self) -> Option <std::time::Duration> {
+    let handshake = self.handshake.as_ref().map (auth::Handshake::timeout);
+    #[cfg(feature = "encryption")]
+    let timeout = match (handshake,
+      self.encryption.as_ref().map (crypto::Encryption::timeout)
+    ) {
+      (Some (a), Some (b)) => Some (a.min (b)),
+      (a, b) => a.or (b)
+    };
+    #[cfg(not(feature = "encryption"))]
+    let timeout = handshake;
+    timeout.map (|ms| std::time::Duration::from_millis (ms.into()))
+  }
+
+  fn session (&self, peer : *mut ll::ENetPeer) -> Option <&Session> {
+    let (index, connect_id) =
+      unsafe { ((*peer).incomingPeerID, (*peer).connectID) };
+    self.sessions.get (&index)
+      .filter (|session| session.connect_id == connect_id)
+  }
+
+  fn session_mut (&mut self, peer : *mut ll::ENetPeer)
+    -> Option <&mut Session>
+  {
+    let (index, connect_id) =
+      unsafe { ((*peer).incomingPeerID, (*peer).connectID) };
+    self.sessions.get_mut (&index)
+      .filter (|session| session.connect_id == connect_id)
+  }
+
+  fn take_session (&mut self, peer : &Peer) -> Option <Session> {
+    let index = peer.incoming_peer_id();
+    if self.sessions.get (&index)?.connect_id != peer.connect_id() {
+      return None
+    }
+    self.sessions.remove (&index)
+  }
+}
+
+impl Session {
+  /// True if the handshake and key exchange are complete
+  fn ready (&self) -> bool {
+    #[cfg(feature = "encryption")]
+    if self.crypto.as_ref().is_some_and (|crypto| !crypto.established()) {
+      return false
+    }
+    self.auth.as_ref().is_none_or (auth::PeerAuth::authenticated)
+  }
+}
+
+/// Queue a reliable message on a handshake or key exchange channel, bypassing
+/// the session checks of `peer.send()`
+pub(crate) fn send_control (peer : &Peer, channel_id : u8, message : &[u8])
+  -> bool
+{
+  unsafe {
+    if (*peer.raw()).channelCount <= channel_id as usize {
+      return false
+    }
+    let raw = ll::enet_packet_create (
+      message.as_ptr() as *const std::os::raw::c_void,
+      message.len(),
+      packet::Flags::RELIABLE.bits());
+    if raw.is_null() {
+      return false
+    }
+    if ll::enet_peer_send (peer.raw(), channel_id, raw) < 0 {
+      ll::enet_packet_destroy (raw);
+      return false
+    }
+  }
+  true
+}
+
+impl Error {
+  /// The OS error at the time of the failure
+  pub fn io_error (&self) -> &std::io::Error {
+    match self {
+      Error::ServiceError (error) | Error::DispatchError (error) => error
+    }
+  }
+
+  /// True if the failure is temporary and the host can be serviced again
+  pub fn is_transient (&self) -> bool {
+    matches!(self.io_error().kind(),
+      std::io::ErrorKind::Interrupted |
+      std::io::ErrorKind::WouldBlock |
+      std::io::ErrorKind::ConnectionReset |
+      std::io::ErrorKind::ConnectionRefused)
+  }
+}
+
+impl std::fmt::Display for Error {
+  fn fmt (&self, f : &mut std::fmt::Formatter) -> std::fmt::Result {
+    match self {
+      Error::ServiceError  (error) => write!(f, "host service failed: {error}"),
+      Error::DispatchError (error) => write!(f, "host dispatch failed: {error}")
+    }
+  }
+}
+impl std::error::Error for Error {
+  fn source (&self) -> Option <&(dyn std::error::Error + 'static)> {
+    Some (self.io_error())
+  }
+}
+
+impl std::fmt::Display for CreateError {
+  fn fmt (&self, f : &mut std::fmt::Formatter) -> std::fmt::Result {
+    match self {
+      CreateError::TooManyPeers (count) =>
+        write!(f, "peer count {count} exceeds the maximum of {MAX_PEERS}"),
+      CreateError::TooManyChannels (count) => write!(f,
+        "channel count {count} exceeds the maximum of {MAX_CHANNEL_COUNT}"),
+      CreateError::ReturnedNull (_) => write!(f, "host creation failed"),
+      CreateError::SocketOption (option, _) =>
+        write!(f, "failed to set socket option {option:?}")
+    }
+  }
+}
+impl std::error::Error for CreateError {
+  fn source (&self) -> Option <&(dyn std::error::Error + 'static)> {
+    match self {
+      CreateError::ReturnedNull (error) | CreateError::SocketOption (_, error) =>
+        Some (error.as_ref()),
+      CreateError::TooManyPeers (_) | CreateError::TooManyChannels (_) => None
+    }
+  }
+}
+
 impl Drop for HostDrop {
-  #[inline]
   fn drop (&mut self) {
+    intercept::unregister (self.raw);
+    if let Some (capture) = self.capture.get_mut().take() {
+      let _ = unsafe { capture.stop (self.raw) };
+    }
     unsafe { ll::enet_host_destroy (self.raw) }
   }
 }
+
+////////////////////////////////////////////////////////////////////////////////
+//  tests                                                                     //
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg (test)]
+mod tests {
+  use std::error::Error as _;
+  use super::*;
+  use crate::testing;
+
+  #[test]
+  fn local_address_reports_the_ephemeral_port() {
+    let enet = testing::enet();
+    let mut server = enet.server_host_create (Address::localhost (0), 1, None,
+      None, None).unwrap();
+    let address = std::net::SocketAddrV4::from (server.local_address());
+    assert_eq!(*address.ip(), std::net::Ipv4Addr::LOCALHOST);
+    assert_ne!(address.port(), 0);
+    // the port is bound by the host
+    let err = std::net::UdpSocket::bind (address).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::AddrInUse);
+    let mut client = enet.client_host_create (1, None, None).unwrap();
+    assert_ne!(client.local_address().port(), 0);
+    client.connect (&Address::localhost (address.port()), 1, 0).unwrap();
+    testing::pump_until (&mut [&mut server, &mut client], testing::TIMEOUT,
+      |events| events.len() == 2).unwrap();
+  }
+
+  #[test]
+  fn error_reports_the_os_error() {
+    let error = Error::ServiceError (
+      std::io::Error::from (std::io::ErrorKind::PermissionDenied).into());
+    assert!(error.to_string().starts_with ("host service failed: "), "{error}");
+    let source = error.source().unwrap().downcast_ref::<std::io::Error>()
+      .unwrap();
+    assert_eq!(source.kind(), std::io::ErrorKind::PermissionDenied);
+    assert_eq!(error.clone().io_error().kind(),
+      std::io::ErrorKind::PermissionDenied);
+    assert!(!error.is_transient());
+    for kind in [
+      std::io::ErrorKind::Interrupted,
+      std::io::ErrorKind::WouldBlock,
+      std::io::ErrorKind::ConnectionReset,
+      std::io::ErrorKind::ConnectionRefused
+    ] {
+      let error = Error::DispatchError (std::io::Error::from (kind).into());
+      assert!(error.to_string().starts_with ("host dispatch failed: "));
+      assert!(error.is_transient(), "{kind:?}");
+    }
+  }
+
+  #[test]
+  fn create_error_sources() {
+    let error = CreateError::SocketOption (
+      socket::SocketOption::Broadcast (true),
+      std::io::Error::from (std::io::ErrorKind::InvalidInput).into());
+    assert_eq!(error.to_string(),
+      "failed to set socket option Broadcast(true)");
+    assert_eq!(error.source().unwrap().downcast_ref::<std::io::Error>()
+      .unwrap().kind(), std::io::ErrorKind::InvalidInput);
+    let error = CreateError::TooManyChannels (256);
+    assert_eq!(error.to_string(),
+      "channel count 256 exceeds the maximum of 255");
+    assert!(error.source().is_none());
+  }
+
+  #[test]
+  fn socket_buffer_size_is_read_back() {
+    let enet = testing::enet();
+    let host = enet.client_host_create_with_options (1, None, None,
+      &[socket::SocketOption::SendBuffer (96 * 1024)]).unwrap();
+    let receive_buffer = || {
+      let Ok (socket::SocketOption::ReceiveBuffer (size)) =
+        host.socket_option (socket::OptionName::ReceiveBuffer)
+      else {
+        panic!("receive buffer not read")
+      };
+      size
+    };
+    let initial = receive_buffer();
+    host.set_socket_option (socket::SocketOption::ReceiveBuffer (160 * 1024))
+      .unwrap();
+    let receive = receive_buffer();
+    // linux doubles the sizes for bookkeeping
+    assert!(160 * 1024 <= receive, "{receive}");
+    assert_ne!(receive, initial);
+    let Ok (socket::SocketOption::SendBuffer (send)) =
+      host.socket_option (socket::OptionName::SendBuffer)
+    else {
+      panic!("send buffer not read")
+    };
+    assert!(96 * 1024 <= send, "{send}");
+    assert_eq!(host.socket_option (socket::OptionName::NonBlock).unwrap(),
+      socket::SocketOption::NonBlock (true));
+  }
+
+  #[cfg(feature = "encryption")]
+  #[test]
+  fn packet_builder_leaves_room_for_encryption() {
+    use std::io::Write;
+    const MAXIMUM : usize = 4096;
+    let mut pair = testing::connected_pair_with (2, |host| {
+      host.set_encryption (Some (crypto::Encryption::new (1, 5000)));
+      unsafe { (*host.raw()).maximumPacketSize = MAXIMUM }
+    });
+    let mut builder =
+      pair.client.packet_builder (packet::Flags::RELIABLE, 0).unwrap();
+    assert_eq!(builder.limit(), MAXIMUM - crypto::OVERHEAD);
+    let data = vec![3; builder.limit()];
+    builder.write_all (&data).unwrap();
+    builder.write_all (&[3]).unwrap_err();
+    pair.client_peer.send_shared (0, &builder.finish()).unwrap();
+    let events = testing::pump_until (&mut [&mut pair.server, &mut pair.client],
+      testing::TIMEOUT, |events| !events.is_empty()).unwrap();
+    testing::assert_events (&events,
+      &[(0, testing::Expect::Receive { channel_id: 0, data: &data })]);
+  }
+
+  #[test]
+  fn send_shared_reports_each_peer_and_frees_the_packet() {
+    let mut pair = testing::connected_pair();
+    let port = pair.server.local_address().port();
+    let mut other = testing::enet().client_host_create (1, None, None).unwrap();
+    other.connect (&Address::localhost (port), 2, 0).unwrap();
+    let events = testing::pump_until (&mut [&mut pair.server, &mut other],
+      testing::TIMEOUT, |events| events.len() == 2).unwrap();
+    let other_peer = events.into_iter().find_map (|recorded| match recorded {
+      testing::Recorded { host: 0, event: Event::Connect { peer, .. } } =>
+        Some (peer),
+      _ => None
+    }).unwrap();
+    let gone = other_peer;
+    gone.disconnect_now();
+    let pool = packet::PacketPool::new (&[64], 4);
+    let packet = pool.write (6, packet::Flags::RELIABLE,
+      |data| data.copy_from_slice (b"shared")).unwrap();
+    let results = pair.server.send_shared (
+      &mut [pair.server_peer.clone(), gone, pair.server_peer.clone()], 1,
+      &packet);
+    assert_eq!(results, [
+      Ok (()),
+      Err (peer::SendErrorKind::PeerNotConnected (peer::State::Disconnected)),
+      Ok (())
+    ]);
+    assert_eq!(packet.queued(), 2);
+    drop (packet);
+    assert_eq!(pool.stats().in_use, 1);
+    let events = testing::pump_until (&mut [&mut pair.server, &mut pair.client],
+      testing::TIMEOUT, |events| events.len() == 2).unwrap();
+    testing::assert_events (&events, &[
+      (1, testing::Expect::Receive { channel_id: 1, data: b"shared" }),
+      (1, testing::Expect::Receive { channel_id: 1, data: b"shared" })
+    ]);
+    // the last reference is released once both sends are acknowledged
+    let start = std::time::Instant::now();
+    while pool.stats().in_use != 0 && start.elapsed() < testing::TIMEOUT {
+      pair.server.service (1).unwrap();
+      pair.client.service (1).unwrap();
+    }
+    assert_eq!(pool.stats().in_use, 0);
+    assert_eq!(pool.stats().available, 1);
+  }
+
+  #[test]
+  fn debug_snapshot_lists_connected_peers_and_channels() {
+    let mut pair = testing::connected_pair();
+    pair.client_peer.send (1, Packet::Allocate {
+      bytes: b"queued", flags: packet::Flags::RELIABLE
+    }).unwrap();
+    let snapshot = pair.client.debug_snapshot();
+    assert_eq!(snapshot.local_address.clone().port(),
+      pair.client.local_address().port());
+    assert_eq!(snapshot.connected_peers, 1);
+    assert!(0 < snapshot.total_sent_packets);
+    assert!(0 < snapshot.total_received_packets);
+    let [peer] = &snapshot.peers[..] else {
+      panic!("{snapshot:?}")
+    };
+    assert_eq!(peer.incoming_peer_id, pair.client_peer.incoming_peer_id());
+    assert_eq!(peer.connect_id, pair.client_peer.connect_id());
+    assert_eq!(peer.address.clone().port(),
+      pair.server.local_address().port());
+    assert_eq!(peer.state, peer::State::Connected);
+    assert_eq!(peer.queued_bytes, 6);
+    let channels = peer.channels.iter()
+      .map (|channel| (channel.channel_id, channel.queued_outgoing))
+      .collect::<Vec <_>>();
+    assert_eq!(channels, [(0, 0), (1, 1)]);
+    let snapshot = pair.server.debug_snapshot();
+    let [peer] = &snapshot.peers[..] else {
+      panic!("{snapshot:?}")
+    };
+    assert_eq!(peer.connect_id, pair.server_peer.connect_id());
+    // disconnected peers are left out
+    pair.server_peer.disconnect_now();
+    let snapshot = pair.server.debug_snapshot();
+    assert_eq!(snapshot.connected_peers, 0);
+    assert!(snapshot.peers.is_empty(), "{snapshot:?}");
+  }
+
+  /// Replaces the next datagram from `client` at least `length` bytes long
+  /// with a copy that has its last byte flipped, relayed through `relay`
+  #[cfg(feature = "encryption")]
+  struct Tamper {
+    client : std::net::SocketAddrV4,
+    server : std::net::SocketAddrV4,
+    relay  : std::net::UdpSocket,
+    length : usize,
+    armed  : std::rc::Rc <std::cell::Cell <bool>>
+  }
+
+  #[cfg(feature = "encryption")]
+  impl intercept::Intercept for Tamper {
+    fn receive (&mut self, datagram : &mut intercept::Datagram)
+      -> intercept::Action
+    {
+      let from = std::net::SocketAddrV4::from (datagram.address());
+      if std::net::SocketAddr::V4 (from) == self.relay.local_addr().unwrap() {
+        // the tampered copy, received as if from the client
+        datagram.set_address (&self.client.into());
+        return intercept::Action::Continue
+      }
+      if from != self.client || datagram.data().len() < self.length ||
+        !self.armed.replace (false)
+      {
+        return intercept::Action::Continue
+      }
+      let mut copy = datagram.data().to_vec();
+      *copy.last_mut().unwrap() ^= 0x01;
+      self.relay.send_to (&copy, self.server).unwrap();
+      intercept::Action::Consume
+    }
+  }
+
+  #[cfg(feature = "encryption")]
+  #[test]
+  fn tampered_datagram_is_dropped_and_counted() {
+    let mut pair = testing::connected_pair_with (2, |host|
+      host.set_encryption (Some (crypto::Encryption::new (1, 5000))));
+    let armed = std::rc::Rc::new (std::cell::Cell::new (false));
+    pair.server.add_intercept (Box::new (Tamper {
+      client: Address::localhost (pair.client.local_address().port()).into(),
+      server: pair.server.local_address().into(),
+      relay:  std::net::UdpSocket::bind ("127.0.0.1:0").unwrap(),
+      length: 100,
+      armed:  armed.clone()
+    }));
+    assert_eq!(pair.server.tampered_packets(), 0);
+    armed.set (true);
+    let send = |peer : &mut Peer, bytes : &[u8]| peer.send (0,
+      Packet::Allocate { bytes, flags: packet::Flags::RELIABLE }).unwrap();
+    send (&mut pair.client_peer, &[1; 100]);
+    let start = std::time::Instant::now();
+    while pair.server.tampered_packets() == 0 {
+      assert!(start.elapsed() < testing::TIMEOUT);
+      assert!(pair.server.service (1).unwrap().is_none());
+      assert!(pair.client.service (0).unwrap().is_none());
+    }
+    assert!(!armed.get());
+    // the connection is unaffected
+    send (&mut pair.client_peer, &[2; 100]);
+    let events = testing::pump_until (&mut [&mut pair.server, &mut pair.client],
+      testing::TIMEOUT, |events| !events.is_empty()).unwrap();
+    testing::assert_events (&events,
+      &[(0, testing::Expect::Receive { channel_id: 0, data: &[2; 100] })]);
+    assert_eq!(pair.server.tampered_packets(), 1);
+  }
+}
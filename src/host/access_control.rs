@@ -0,0 +1,614 @@
+//! Address based access control, enforced before ENet allocates a peer.
+//!
+//! An `AccessControl` set on a host with `host.set_access_control()` checks
+//! every received datagram through the host intercept:
+//!
+//! - datagrams from addresses matching the deny list, or not matching a
+//!   non-empty allow list, are dropped; connected peers at a newly denied
+//!   address time out
+//! - connection requests from an address that already has the maximum number of
+//!   connections, or that exceeded the connection attempt rate, are dropped
+//!
+//! Dropped connection requests never take a peer slot. Connection requests are
+//! datagrams sent by a peer that has not been assigned a peer ID yet, including
+//! retransmissions, which count towards the attempt rate.
+//!
+//! `AccessControl` is a handle: clones share the same rules, which can be
+//! changed at any time. Each rejection is counted in `stats()` and recorded in a
+//! bounded log returned by `take_rejections()`.
+//!
+//! Rules can be saved to and loaded from a text file with one rule per line:
+//!
+//! ```text
+//! # comment
+//! allow 10.0.0.0/8
+//! deny 10.1.2.3
+//! max-connections-per-ip 4
+//! connect-rate 10 60000
+//! ```
+//!
+//! where `connect-rate` is the number of attempts allowed per window of
+//! milliseconds.
+
+use std;
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::rc::Rc;
+use std::time::{Duration, Instant, SystemTime};
+
+use ll;
+use crate::{intercept, Address, MAX_PEERS};
+
+/// Maximum number of rejections kept in the log
+pub const MAX_LOGGED_REJECTIONS : usize = 256;
+/// Number of tracked addresses above which expired connection attempts are
+/// pruned from all addresses
+const PRUNE_THRESHOLD : usize = 1024;
+
+////////////////////////////////////////////////////////////////////////////////
+//  structs                                                                   //
+////////////////////////////////////////////////////////////////////////////////
+
+/// Shared handle to access control rules and statistics
+#[derive(Clone, Debug, Default)]
+pub struct AccessControl {
+  inner : Rc <RefCell <Inner>>
+}
+
+/// An IPv4 address range, e.g. `192.168.0.0/16`
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct Cidr {
+  address : Ipv4Addr,
+  prefix  : u8
+}
+
+/// Connection attempt limit for a single IP address
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct ConnectRate {
+  pub attempts : u32,
+  pub window   : Duration
+}
+
+/// Number of datagrams rejected for each reason
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct Stats {
+  pub denied          : u64,
+  pub not_allowed     : u64,
+  pub too_many_peers  : u64,
+  pub rate_limited    : u64
+}
+
+#[derive(Clone, Debug)]
+pub struct Rejection {
+  pub time    : SystemTime,
+  pub address : Address,
+  pub reason  : Reason
+}
+
+#[derive(Debug)]
+pub struct CidrParseError (pub String);
+
+#[derive(Debug, Default)]
+struct Inner {
+  allow                  : Vec <Cidr>,
+  deny                   : Vec <Cidr>,
+  max_connections_per_ip : Option <u32>,
+  connect_rate           : Option <ConnectRate>,
+  /// Recent connection attempts by IP address
+  attempts               : HashMap <Ipv4Addr, VecDeque <Instant>>,
+  stats                  : Stats,
+  rejections             : VecDeque <Rejection>
+}
+
+/// Intercept enforcing access control on a host
+struct AccessIntercept {
+  control : AccessControl,
+  host    : *mut ll::ENetHost
+}
+
+////////////////////////////////////////////////////////////////////////////////
+//  enums                                                                     //
+////////////////////////////////////////////////////////////////////////////////
+
+/// Reason a datagram was rejected
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Reason {
+  /// The address matches the deny list
+  Denied,
+  /// The allow list is not empty and the address does not match it
+  NotAllowed,
+  /// The address has the maximum number of connections
+  TooManyPeers,
+  /// The address exceeded the connection attempt rate
+  RateLimited
+}
+
+////////////////////////////////////////////////////////////////////////////////
+//  impls                                                                     //
+////////////////////////////////////////////////////////////////////////////////
+
+impl AccessControl {
+  /// Access control without any rules
+  #[inline]
+  pub fn new() -> Self {
+    AccessControl::default()
+  }
+
+  /// Accept only addresses matching the allow list, unless it is empty
+  pub fn allow (&self, cidr : Cidr) {
+    let mut inner = self.inner.borrow_mut();
+    if !inner.allow.contains (&cidr) {
+      inner.allow.push (cidr);
+    }
+  }
+
+  /// Reject addresses matching the deny list; takes precedence over the allow
+  /// list
+  pub fn deny (&self, cidr : Cidr) {
+    let mut inner = self.inner.borrow_mut();
+    if !inner.deny.contains (&cidr) {
+      inner.deny.push (cidr);
+    }
+  }
+
+  /// Remove a range from the allow list, returning false if it was not present
+  pub fn remove_allow (&self, cidr : Cidr) -> bool {
+    remove (&mut self.inner.borrow_mut().allow, cidr)
+  }
+
+  /// Remove a range from the deny list, returning false if it was not present
+  pub fn remove_deny (&self, cidr : Cidr) -> bool {
+    remove (&mut self.inner.borrow_mut().deny, cidr)
+  }
+
+  pub fn allow_list (&self) -> Vec <Cidr> {
+    self.inner.borrow().allow.clone()
+  }
+
+  pub fn deny_list (&self) -> Vec <Cidr> {
+    self.inner.borrow().deny.clone()
+  }
+
+  /// Maximum number of peers (connected or connecting) per IP address, or
+  /// `None` for no limit
+  pub fn set_max_connections_per_ip (&self, max : Option <u32>) {
+    self.inner.borrow_mut().max_connections_per_ip = max;
+  }
+
+  pub fn max_connections_per_ip (&self) -> Option <u32> {
+    self.inner.borrow().max_connections_per_ip
+  }
+
+  /// Limit connection attempts per IP address, or `None` for no limit
+  pub fn set_connect_rate (&self, rate : Option <ConnectRate>) {
+    let mut inner = self.inner.borrow_mut();
+    inner.connect_rate = rate;
+    inner.attempts.clear();
+  }
+
+  pub fn connect_rate (&self) -> Option <ConnectRate> {
+    self.inner.borrow().connect_rate
+  }
+
+  pub fn stats (&self) -> Stats {
+    self.inner.borrow().stats
+  }
+
+  /// Take the logged rejections, oldest first
+  pub fn take_rejections (&self) -> Vec <Rejection> {
+    self.inner.borrow_mut().rejections.drain (..).collect()
+  }
+
+  /// Check an address against the allow and deny lists
+  pub fn check_address (&self, address : &Address) -> Result <(), Reason> {
+    self.inner.borrow().check_address (*SocketAddrV4::from (address.clone()).ip())
+  }
+
+  /// Write the rules to a file
+  pub fn save <P : AsRef <std::path::Path>> (&self, path : P)
+    -> std::io::Result <()>
+  {
+    let inner = self.inner.borrow();
+    let mut lines = Vec::new();
+    lines.extend (inner.allow.iter().map (|cidr| format!("allow {cidr}\n")));
+    lines.extend (inner.deny.iter().map (|cidr| format!("deny {cidr}\n")));
+    if let Some (max) = inner.max_connections_per_ip {
+      lines.push (format!("max-connections-per-ip {max}\n"));
+    }
+    if let Some (rate) = inner.connect_rate {
+      lines.push (format!("connect-rate {} {}\n", rate.attempts,
+        rate.window.as_millis()));
+    }
+    std::fs::write (path, lines.concat())
+  }
+
+  /// Replace the rules with the rules read from a file.
+  ///
+  /// Statistics and logged rejections are kept.
+  pub fn load <P : AsRef <std::path::Path>> (&self, path : P)
+    -> std::io::Result <()>
+  {
+    let text = std::fs::read_to_string (path)?;
+    let mut allow = Vec::new();
+    let mut deny  = Vec::new();
+    let mut max_connections_per_ip = None;
+    let mut connect_rate = None;
+    for (number, line) in text.lines().enumerate() {
+      let line = line.split ('#').next().unwrap_or_default().trim();
+      if line.is_empty() {
+        continue
+      }
+      let invalid = || std::io::Error::new (std::io::ErrorKind::InvalidData,
+        format!("invalid access control rule on line {}: {line}", number + 1));
+      let mut words = line.split_whitespace();
+      let rule      = words.next().unwrap_or_default();
+      let values    = words.collect::<Vec <_>>();
+      match (rule, values.as_slice()) {
+        ("allow", [cidr]) => allow.push (cidr.parse().map_err (|_| invalid())?),
+        ("deny",  [cidr]) => deny.push  (cidr.parse().map_err (|_| invalid())?),
+        ("max-connections-per-ip", [max]) =>
+          max_connections_per_ip = Some (max.parse().map_err (|_| invalid())?),
+        ("connect-rate", [attempts, window]) => connect_rate = Some (ConnectRate {
+          attempts: attempts.parse().map_err (|_| invalid())?,
+          window:   Duration::from_millis (window.parse().map_err (|_| invalid())?)
+        }),
+        _ => return Err (invalid())
+      }
+    }
+    let mut inner = self.inner.borrow_mut();
+    inner.allow = allow;
+    inner.deny  = deny;
+    inner.max_connections_per_ip = max_connections_per_ip;
+    inner.connect_rate = connect_rate;
+    inner.attempts.clear();
+    Ok (())
+  }
+
+  /// Create the intercept enforcing the rules on a host
+  pub(crate) fn intercept (&self, host : *mut ll::ENetHost)
+    -> Box <dyn intercept::Intercept>
+  {
+    Box::new (AccessIntercept { control: self.clone(), host })
+  }
+}
+
+impl Inner {
+  fn check_address (&self, ip : Ipv4Addr) -> Result <(), Reason> {
+    if self.deny.iter().any (|cidr| cidr.contains (ip)) {
+      return Err (Reason::Denied)
+    }
+    if !self.allow.is_empty() && !self.allow.iter().any (|cidr| cidr.contains (ip)) {
+      return Err (Reason::NotAllowed)
+    }
+    Ok (())
+  }
+
+  /// Check a connection request, recording it as an attempt
+  fn check_connect (&mut self, host : &ll::ENetHost, ip : Ipv4Addr)
+    -> Result <(), Reason>
+  {
+    if let Some (max) = self.max_connections_per_ip &&
+      max <= connections (host, ip)
+    {
+      return Err (Reason::TooManyPeers)
+    }
+    if let Some (rate) = self.connect_rate {
+      let now = Instant::now();
+      if PRUNE_THRESHOLD < self.attempts.len() {
+        self.attempts.retain (|_, attempts| attempts.back()
+          .is_some_and (|last| now.duration_since (*last) < rate.window));
+      }
+      let attempts = self.attempts.entry (ip).or_default();
+      while attempts.front()
+        .is_some_and (|first| rate.window <= now.duration_since (*first))
+      {
+        attempts.pop_front();
+      }
+      if rate.attempts as usize <= attempts.len() {
+        return Err (Reason::RateLimited)
+      }
+      attempts.push_back (now);
+    }
+    Ok (())
+  }
+
+  fn reject (&mut self, address : Address, reason : Reason) {
+    match reason {
+      Reason::Denied       => self.stats.denied += 1,
+      Reason::NotAllowed   => self.stats.not_allowed += 1,
+      Reason::TooManyPeers => self.stats.too_many_peers += 1,
+      Reason::RateLimited  => self.stats.rate_limited += 1
+    }
+    if MAX_LOGGED_REJECTIONS <= self.rejections.len() {
+      self.rejections.pop_front();
+    }
+    self.rejections.push_back (
+      Rejection { time: SystemTime::now(), address, reason });
+  }
+}
+
+impl intercept::Intercept for AccessIntercept {
+  fn receive (&mut self, datagram : &mut intercept::Datagram)
+    -> intercept::Action
+  {
+    let address = datagram.address();
+    let ip      = *SocketAddrV4::from (address.clone()).ip();
+    let mut inner = self.control.inner.borrow_mut();
+    let mut result = inner.check_address (ip);
+    if result.is_ok() && is_connect (datagram.data()) {
+      result = inner.check_connect (unsafe { &*self.host }, ip);
+    }
+    match result {
+      Ok (()) => intercept::Action::Continue,
+      Err (reason) => {
+        inner.reject (address, reason);
+        intercept::Action::Consume
+      }
+    }
+  }
+}
+
+impl Cidr {
+  /// # Panics
+  ///
+  /// Panics if `prefix` is greater than 32.
+  pub const fn new (address : Ipv4Addr, prefix : u8) -> Self {
+    assert!(prefix <= 32, "CIDR prefix must be at most 32");
+    Cidr { address, prefix }
+  }
+
+  /// A range containing a single address
+  #[inline]
+  pub const fn single (address : Ipv4Addr) -> Self {
+    Cidr { address, prefix: 32 }
+  }
+
+  pub const fn contains (&self, address : Ipv4Addr) -> bool {
+    let mask = self.mask();
+    self.address.to_bits() & mask == address.to_bits() & mask
+  }
+
+  const fn mask (self) -> u32 {
+    match self.prefix {
+      0 => 0,
+      prefix => u32::MAX << (32 - prefix)
+    }
+  }
+}
+
+impl std::fmt::Display for Cidr {
+  fn fmt (&self, f : &mut std::fmt::Formatter) -> std::fmt::Result {
+    write!(f, "{}/{}", self.address, self.prefix)
+  }
+}
+
+impl std::str::FromStr for Cidr {
+  type Err = CidrParseError;
+  /// Parses `a.b.c.d/prefix`, or `a.b.c.d` as a single address
+  fn from_str (s : &str) -> Result <Self, Self::Err> {
+    let invalid = || CidrParseError (s.to_owned());
+    let (address, prefix) = s.split_once ('/').unwrap_or ((s, "32"));
+    let address = address.parse().map_err (|_| invalid())?;
+    let prefix  = prefix.parse::<u8>().map_err (|_| invalid())?;
+    if 32 < prefix {
+      return Err (invalid())
+    }
+    Ok (Cidr { address, prefix })
+  }
+}
+
+impl std::fmt::Display for CidrParseError {
+  fn fmt (&self, f : &mut std::fmt::Formatter) -> std::fmt::Result {
+    write!(f, "invalid CIDR range: {}", self.0)
+  }
+}
+impl std::error::Error for CidrParseError {}
+
+////////////////////////////////////////////////////////////////////////////////
+//  functions                                                                 //
+////////////////////////////////////////////////////////////////////////////////
+
+fn remove (list : &mut Vec <Cidr>, cidr : Cidr) -> bool {
+  let length = list.len();
+  list.retain (|other| *other != cidr);
+  list.len() != length
+}
+
+/// True if the datagram is from a peer without a peer ID, i.e. a connection
+/// request
+fn is_connect (data : &[u8]) -> bool {
+  const PEER_ID_MASK : u16 = 0x0FFF;
+  match data {
+    [high, low, ..] =>
+      u32::from (u16::from_be_bytes ([*high, *low]) & PEER_ID_MASK) == MAX_PEERS,
+    _ => false
+  }
+}
+
+/// Number of peers of the host connected or connecting from an IP address
+fn connections (host : &ll::ENetHost, ip : Ipv4Addr) -> u32 {
+  let host_ip = u32::from_le_bytes (ip.octets());
+  (0..host.peerCount).map (|index| unsafe { &*host.peers.add (index) })
+    .filter (|peer| peer.address.host == host_ip &&
+      peer.state != ll::_ENetPeerState_ENET_PEER_STATE_DISCONNECTED &&
+      peer.state != ll::_ENetPeerState_ENET_PEER_STATE_ZOMBIE)
+    .count() as u32
+}
+
+////////////////////////////////////////////////////////////////////////////////
+//  tests                                                                     //
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg (test)]
+mod tests {
+  use super::*;
+  use crate::{testing, Event, Host};
+
+  fn cidr (s : &str) -> Cidr {
+    s.parse().unwrap()
+  }
+
+  /// A server with access control, and a function creating clients connecting
+  /// to it
+  fn server (control : &AccessControl) -> (Host, impl Fn() -> Host) {
+    let enet = testing::enet();
+    let mut server = enet.server_host_create (Address::localhost (0), 8, None,
+      None, None).unwrap();
+    server.set_access_control (Some (control.clone()));
+    let port = server.local_address().port();
+    let client = move || {
+      let mut client = enet.client_host_create (1, None, None).unwrap();
+      client.connect (&Address::localhost (port), 1, 0).unwrap();
+      client
+    };
+    (server, client)
+  }
+
+  fn connects (events : &[testing::Recorded], host : usize) -> usize {
+    events.iter().filter (|recorded|
+      recorded.host == host && matches!(recorded.event, Event::Connect {..})
+    ).count()
+  }
+
+  /// Service the hosts for a while, returning the events
+  fn settle (hosts : &mut [&mut Host]) -> Vec <testing::Recorded> {
+    match testing::pump_until (hosts, Duration::from_millis (300), |_| false) {
+      Err (testing::PumpError::Timeout (events)) => events,
+      result => panic!("unexpected {result:?}")
+    }
+  }
+
+  #[test]
+  fn cidr_parses_ranges_and_single_addresses() {
+    assert_eq!(cidr ("10.0.0.0/8"), Cidr::new (Ipv4Addr::new (10, 0, 0, 0), 8));
+    assert_eq!(cidr ("0.0.0.0/0"), Cidr::new (Ipv4Addr::UNSPECIFIED, 0));
+    assert_eq!(cidr ("10.1.2.3"), Cidr::single (Ipv4Addr::new (10, 1, 2, 3)));
+    assert_eq!(cidr ("10.1.2.3/32"), cidr ("10.1.2.3"));
+  }
+
+  #[test]
+  fn cidr_rejects_invalid_input() {
+    for invalid in [
+      "", "/8", "10.0.0.0/", "10.0.0.0/33", "10.0.0.0/-1", "10.0.0/8",
+      "10.0.0.256/8", "10.0.0.0/8/8", "::1/128", " 10.0.0.0/8", "10.0.0.0/ 8"
+    ] {
+      let err = invalid.parse::<Cidr>().unwrap_err();
+      assert_eq!(err.0, invalid);
+    }
+  }
+
+  #[test]
+  fn cidr_display_parses_back() {
+    for s in ["10.0.0.0/8", "192.168.1.0/24", "10.1.2.3/32", "0.0.0.0/0"] {
+      assert_eq!(cidr (s).to_string(), s);
+      assert_eq!(cidr (&cidr (s).to_string()), cidr (s));
+    }
+  }
+
+  #[test]
+  fn cidr_contains_masks_host_bits() {
+    let range = cidr ("192.168.1.77/24");
+    assert!(range.contains (Ipv4Addr::new (192, 168, 1, 0)));
+    assert!(range.contains (Ipv4Addr::new (192, 168, 1, 255)));
+    assert!(!range.contains (Ipv4Addr::new (192, 168, 2, 1)));
+    assert!(cidr ("0.0.0.0/0").contains (Ipv4Addr::BROADCAST));
+    let single = cidr ("10.1.2.3");
+    assert!(single.contains (Ipv4Addr::new (10, 1, 2, 3)));
+    assert!(!single.contains (Ipv4Addr::new (10, 1, 2, 4)));
+  }
+
+  #[test]
+  fn deny_list_overrides_allow_list() {
+    let control = AccessControl::new();
+    let check = |a, b, c, d| control.check_address (
+      &SocketAddrV4::new (Ipv4Addr::new (a, b, c, d), 1234).into());
+    assert_eq!(check (1, 2, 3, 4), Ok (()));
+    control.allow (cidr ("10.0.0.0/8"));
+    control.deny (cidr ("10.1.0.0/16"));
+    assert_eq!(check (10, 2, 0, 1), Ok (()));
+    assert_eq!(check (10, 1, 0, 1), Err (Reason::Denied));
+    assert_eq!(check (11, 0, 0, 1), Err (Reason::NotAllowed));
+  }
+
+  #[test]
+  fn rules_are_saved_and_loaded() {
+    let path = std::env::temp_dir().join (
+      format!("enet-access-control-{}.txt", std::process::id()));
+    let control = AccessControl::new();
+    control.allow (cidr ("10.0.0.0/8"));
+    control.deny (cidr ("10.1.2.3"));
+    control.set_max_connections_per_ip (Some (4));
+    control.set_connect_rate (
+      Some (ConnectRate { attempts: 10, window: Duration::from_secs (60) }));
+    control.save (&path).unwrap();
+    let loaded = AccessControl::new();
+    loaded.load (&path).unwrap();
+    assert_eq!(loaded.allow_list(), control.allow_list());
+    assert_eq!(loaded.deny_list(), control.deny_list());
+    assert_eq!(loaded.max_connections_per_ip(), Some (4));
+    assert_eq!(loaded.connect_rate(), control.connect_rate());
+    std::fs::write (&path, "allow 10.0.0.0/33\n").unwrap();
+    let err = loaded.load (&path).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    // the rules are unchanged by a failed load
+    assert_eq!(loaded.allow_list(), control.allow_list());
+    std::fs::remove_file (&path).unwrap();
+  }
+
+  #[test]
+  fn connections_past_the_maximum_per_ip_get_no_peer() {
+    let control = AccessControl::new();
+    control.set_max_connections_per_ip (Some (2));
+    let (mut server, client) = server (&control);
+    let (mut first, mut second) = (client(), client());
+    let events = testing::pump_until (
+      &mut [&mut server, &mut first, &mut second], testing::TIMEOUT,
+      |events| connects (events, 0) == 2 && connects (events, 1) == 1 &&
+        connects (events, 2) == 1
+    ).unwrap();
+    assert_eq!(connects (&events, 0), 2);
+    let mut extra = client();
+    let extra_port = extra.local_address().port();
+    let events = settle (&mut [&mut server, &mut first, &mut second, &mut extra]);
+    assert_eq!(connects (&events, 0), 0);
+    assert_eq!(connects (&events, 3), 0);
+    let peers = server.peers()
+      .filter (|peer| peer.state() != crate::peer::State::Disconnected)
+      .map (|peer| peer.address().port())
+      .collect::<Vec <_>>();
+    assert_eq!(peers.len(), 2);
+    assert!(!peers.contains (&extra_port));
+    assert!(0 < control.stats().too_many_peers);
+    let rejections = control.take_rejections();
+    assert!(rejections.iter().all (|rejection|
+      rejection.reason == Reason::TooManyPeers &&
+      rejection.address.clone().port() == extra_port));
+  }
+
+  #[test]
+  fn connection_attempts_past_the_rate_are_rejected_and_logged() {
+    let control = AccessControl::new();
+    control.set_connect_rate (
+      Some (ConnectRate { attempts: 1, window: Duration::from_secs (60) }));
+    let (mut server, client) = server (&control);
+    let mut first = client();
+    testing::pump_until (&mut [&mut server, &mut first], testing::TIMEOUT,
+      |events| connects (events, 0) == 1 && connects (events, 1) == 1
+    ).unwrap();
+    assert_eq!(control.stats(), Stats::default());
+    let mut second = client();
+    let second_port = second.local_address().port();
+    let events = settle (&mut [&mut server, &mut first, &mut second]);
+    assert_eq!(connects (&events, 0), 0);
+    assert_eq!(connects (&events, 2), 0);
+    let stats = control.stats();
+    assert!(0 < stats.rate_limited);
+    let rejections = control.take_rejections();
+    assert_eq!(rejections.len() as u64, stats.rate_limited);
+    assert!(rejections.iter().all (|rejection|
+      rejection.reason == Reason::RateLimited &&
+      rejection.address.clone().port() == second_port));
+    assert!(control.take_rejections().is_empty());
+  }
+}
@@ -0,0 +1,165 @@
+//! Inspection of raw datagrams received by a host.
+//!
+//! An `Intercept` added with `host.add_intercept()` is called with every UDP
+//! datagram the host receives, before ENet processes it. Intercepts are called
+//! in the order they were added, and an intercept may consume a datagram so
+//! that it is neither passed to later intercepts nor processed by ENet.
+//!
+//! Intercepts are called from within `service()`; they cannot access the host
+//! itself.
+
+use std;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::{Rc, Weak};
+
+use ll;
+use crate::{host, Address};
+
+thread_local! {
+  /// Hosts with intercepts or a capture on this thread, by `ENetHost` pointer
+  static HOSTS : RefCell <HashMap <usize, Weak <host::HostDrop>>> =
+    RefCell::default();
+}
+
+////////////////////////////////////////////////////////////////////////////////
+//  traits                                                                    //
+////////////////////////////////////////////////////////////////////////////////
+
+pub trait Intercept {
+  /// Called with each datagram received by the host
+  fn receive (&mut self, datagram : &mut Datagram) -> Action;
+}
+
+////////////////////////////////////////////////////////////////////////////////
+//  structs                                                                   //
+////////////////////////////////////////////////////////////////////////////////
+
+/// A datagram received by a host
+#[derive(Debug)]
+pub struct Datagram <'a> {
+  address : &'a mut ll::ENetAddress,
+  data    : &'a [u8]
+}
+
+/// Identifies an intercept added to a host
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct InterceptId (u64);
+
+/// Intercepts added to a host
+#[derive(Default)]
+pub(crate) struct Intercepts {
+  next_id : u64,
+  list    : Vec <(InterceptId, Box <dyn Intercept>)>
+}
+
+////////////////////////////////////////////////////////////////////////////////
+//  enums                                                                     //
+////////////////////////////////////////////////////////////////////////////////
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Action {
+  /// Pass the datagram on to the next intercept, or to ENet
+  Continue,
+  /// Drop the datagram
+  Consume
+}
+
+////////////////////////////////////////////////////////////////////////////////
+//  impls                                                                     //
+////////////////////////////////////////////////////////////////////////////////
+
+impl Datagram <'_> {
+  /// Address the datagram was received from
+  #[inline]
+  pub const fn address (&self) -> Address {
+    Address::from_ll (*self.address)
+  }
+
+  /// Change the address the datagram is treated as received from
+  #[inline]
+  pub const fn set_address (&mut self, address : &Address) {
+    *self.address = unsafe { *address.raw() };
+  }
+
+  #[inline]
+  pub const fn data (&self) -> &[u8] {
+    self.data
+  }
+}
+
+impl Intercepts {
+  pub(crate) fn add (&mut self, intercept : Box <dyn Intercept>) -> InterceptId {
+    let id = InterceptId (self.next_id);
+    self.next_id += 1;
+    self.list.push ((id, intercept));
+    id
+  }
+
+  pub(crate) fn remove (&mut self, id : InterceptId)
+    -> Option <Box <dyn Intercept>>
+  {
+    let index = self.list.iter().position (|(other, _)| *other == id)?;
+    Some (self.list.remove (index).1)
+  }
+
+  fn receive (&mut self, datagram : &mut Datagram) -> Action {
+    for (_, intercept) in &mut self.list {
+      if intercept.receive (datagram) == Action::Consume {
+        return Action::Consume
+      }
+    }
+    Action::Continue
+  }
+}
+
+impl std::fmt::Debug for Intercepts {
+  fn fmt (&self, f : &mut std::fmt::Formatter) -> std::fmt::Result {
+    f.debug_struct ("Intercepts")
+      .field ("ids", &self.list.iter().map (|(id, _)| id).collect::<Vec <_>>())
+      .finish_non_exhaustive()
+  }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+//  functions                                                                 //
+////////////////////////////////////////////////////////////////////////////////
+
+/// Install the intercept callback on a host
+pub(crate) fn register (hostdrop : &Rc <host::HostDrop>) {
+  unsafe {
+    let raw = hostdrop.raw();
+    HOSTS.with_borrow_mut (|hosts|
+      hosts.insert (raw as usize, Rc::downgrade (hostdrop)));
+    (*raw).intercept = Some (intercept);
+  }
+}
+
+/// Remove a host that is being destroyed
+pub(crate) fn unregister (raw : *mut ll::ENetHost) {
+  // the registry may already be destroyed if the host is dropped during thread
+  // exit
+  let _ = HOSTS.try_with (|hosts| hosts.borrow_mut().remove (&(raw as usize)));
+}
+
+unsafe extern "C" fn intercept (
+  host : *mut ll::ENetHost, _event : *mut ll::ENetEvent
+) -> std::os::raw::c_int {
+  let hostdrop = HOSTS.with_borrow (|hosts| hosts.get (&(host as usize))
+    .and_then (Weak::upgrade));
+  let Some (hostdrop) = hostdrop else {
+    return 0
+  };
+  unsafe {
+    let data = std::slice::from_raw_parts (
+      (*host).receivedData, (*host).receivedDataLength);
+    let mut datagram = Datagram { address: &mut (*host).receivedAddress, data };
+    if let Some (capture) = hostdrop.capture.borrow_mut().as_mut() {
+      capture.incoming (host, &datagram);
+    }
+    match hostdrop.intercepts.borrow_mut().receive (&mut datagram) {
+      Action::Continue => 0,
+      Action::Consume  => 1
+    }
+  }
+}
@@ -2,20 +2,37 @@
 
 extern crate enet_sys as ll;
 
-use std::sync::atomic;
+use std::sync::{Condvar, Mutex, PoisonError, Weak};
 
 pub mod address;
+pub mod auth;
+pub mod capture;
+pub mod clock;
+#[cfg(feature = "encryption")]
+pub mod crypto;
+pub mod discovery;
 pub mod event;
 pub mod host;
+pub mod intercept;
+pub mod memory;
+pub mod netgraph;
 pub mod packet;
 pub mod peer;
+pub mod quality;
+pub mod query;
+pub mod reconnect;
+pub mod simulator;
+pub mod socket;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
 pub mod version;
 
 pub use self::address::Address;
 pub use self::event::Event;
 pub use self::host::Host;
-pub use self::packet::Packet;
+pub use self::packet::{Packet, SharedPacket};
 pub use self::peer::Peer;
+pub use self::socket::Socket;
 pub use self::version::Version;
 
 /// (4096)
@@ -25,13 +42,18 @@ pub const MAX_PEERS         : u32 = ll::ENET_PROTOCOL_MAXIMUM_PEER_ID as u32;
 #[expect(clippy::unnecessary_cast)]  // on windows ll flags are i32
 pub const MAX_CHANNEL_COUNT : u32 = ll::ENET_PROTOCOL_MAXIMUM_CHANNEL_COUNT as u32;
 
-static ENET_CONTEXT_ALIVE : atomic::AtomicBool = atomic::AtomicBool::new (false);
+/// The live ENet context, if any
+static CONTEXT : Mutex <Context> =
+  Mutex::new (Context { enetdrop: Weak::new(), alive: false });
+/// Notified when the ENet context is deinitialized
+static CONTEXT_DROPPED : Condvar = Condvar::new();
 
 /// The initialized ENet context.
 ///
 /// This can be sent accross threads and used to create thread-local `Host`s.
 ///
-/// The context will be kept alive as long as any `Host`s still exist.
+/// The context will be kept alive as long as any `Host`s still exist. Once all
+/// handles are dropped, ENet is deinitialized and can be initialized again.
 #[derive(Clone)]
 pub struct Enet {
   enetdrop : std::sync::Arc <EnetDrop>
@@ -40,7 +62,15 @@ pub struct Enet {
 #[derive(Clone, Debug, PartialEq)]
 struct EnetDrop;
 
+struct Context {
+  enetdrop : Weak <EnetDrop>,
+  /// ENet is initialized; remains set until the last handle has finished
+  /// deinitializing it
+  alive    : bool
+}
+
 /// ENet context errors
+#[expect(clippy::error_impl_error)] // public name kept for compatibility
 #[derive(Clone, Debug)]
 pub enum Error {
   Initialize   (String),
@@ -50,10 +80,22 @@ pub enum Error {
 
 /// Initialize the ENet context.
 ///
-/// An error is returned if ENet is already initialized.
+/// An error is returned if ENet is already initialized, or was initialized with
+/// `initialize_with()` earlier in the process; see `Enet::shared()` to use the
+/// context from several places.
 #[inline]
 pub fn initialize() -> Result <Enet, Error> {
-  Enet::new()
+  Enet::new (None)
+}
+
+/// Initialize the ENet context with allocation callbacks; see the `memory`
+/// module.
+///
+/// An error is returned if ENet is already initialized, or was initialized
+/// without callbacks earlier in the process.
+#[inline]
+pub fn initialize_with (callbacks : memory::Callbacks) -> Result <Enet, Error> {
+  Enet::new (Some (callbacks))
 }
 
 /// Safe to call regardless of ENet initialization
@@ -73,17 +115,40 @@ impl Enet {
   /// Bandwidth parameters determine the "window size" of a connection which
   /// limits the number of reliable packets that may be in transit at any given
   /// time.
+  ///
+  /// The host is bound to an ephemeral port, available from
+  /// `host.local_address()`.
   pub fn client_host_create (&self,
     peer_count         : u32,
     incoming_bandwidth : Option <u32>,
     outgoing_bandwidth : Option <u32>
   ) -> Result <Host, Error> {
     Host::new (
+      Some (Address::any (0)),
+      peer_count,
       None,
+      incoming_bandwidth,
+      outgoing_bandwidth,
+      &[],
+      self.enetdrop.clone()
+    ).map_err (Error::ClientCreate)
+  }
+
+  /// Same as `client_host_create()`, setting `socket_options` on the host
+  /// socket before it is returned
+  pub fn client_host_create_with_options (&self,
+    peer_count         : u32,
+    incoming_bandwidth : Option <u32>,
+    outgoing_bandwidth : Option <u32>,
+    socket_options     : &[socket::SocketOption]
+  ) -> Result <Host, Error> {
+    Host::new (
+      Some (Address::any (0)),
       peer_count,
       None,
       incoming_bandwidth,
       outgoing_bandwidth,
+      socket_options,
       self.enetdrop.clone()
     ).map_err (Error::ClientCreate)
   }
@@ -110,23 +175,147 @@ impl Enet {
       channel_limit,
       incoming_bandwidth,
       outgoing_bandwidth,
+      &[],
       self.enetdrop.clone()
     ).map_err (Error::ServerCreate)
   }
 
-  fn new() -> Result <Self, Error> {
-    unsafe {
-      let was_alive =
-        ENET_CONTEXT_ALIVE.swap (true, atomic::Ordering::Relaxed);
-      if was_alive {
+  /// Same as `server_host_create()`, setting `socket_options` on the host
+  /// socket before it is returned.
+  ///
+  /// The host socket is already bound when the options are set, so
+  /// `ReuseAddress` has no effect here.
+  pub fn server_host_create_with_options (&self,
+    address            : Address,
+    peer_count         : u32,
+    channel_limit      : Option <u32>,
+    incoming_bandwidth : Option <u32>,
+    outgoing_bandwidth : Option <u32>,
+    socket_options     : &[socket::SocketOption]
+  ) -> Result <Host, Error> {
+    Host::new (
+      Some (address),
+      peer_count,
+      channel_limit,
+      incoming_bandwidth,
+      outgoing_bandwidth,
+      socket_options,
+      self.enetdrop.clone()
+    ).map_err (Error::ServerCreate)
+  }
+
+  /// Create a socket with the ENet socket layer, for traffic that does not go
+  /// through a `Host`.
+  ///
+  /// The socket will keep the ENet context alive.
+  pub fn socket_create (&self, socket_type : socket::Type)
+    -> std::io::Result <Socket>
+  {
+    Socket::new (socket_type, self.enetdrop.clone())
+  }
+
+  /// ENet time in milliseconds, used by hosts for timeouts and round trip
+  /// times; wraps around after about 49 days
+  #[inline]
+  pub fn time (&self) -> u32 {
+    unsafe { ll::enet_time_get() }
+  }
+
+  /// Set the ENet time.
+  ///
+  /// This shifts the time of every host of the context; packets in flight
+  /// when it is changed may be timed out or retransmitted early.
+  ///
+  /// # Safety
+  ///
+  /// ENet keeps the time base in a global without synchronization: no other
+  /// thread may service a host or read the ENet time during the call.
+  #[inline]
+  pub unsafe fn set_time (&self, time : u32) {
+    unsafe { ll::enet_time_set (time) }
+  }
+
+  /// Return the live ENet context, or initialize it if there is none.
+  ///
+  /// Unlike `initialize()` this can be called any number of times, e.g. by
+  /// independent libraries each creating their own hosts. The context is
+  /// deinitialized when the last handle is dropped and initialized again by the
+  /// next call, with the allocator chosen by the first initialization of the
+  /// process (see the `memory` module).
+  #[inline]
+  pub fn shared() -> Result <Self, Error> {
+    Enet::create (None, true)
+  }
+
+  #[inline]
+  fn new (callbacks : Option <memory::Callbacks>) -> Result <Self, Error> {
+    Enet::create (callbacks, false)
+  }
+
+  fn create (callbacks : Option <memory::Callbacks>, shared : bool)
+    -> Result <Self, Error>
+  {
+    let mut context = CONTEXT.lock().unwrap_or_else (PoisonError::into_inner);
+    loop {
+      if let Some (enetdrop) = context.enetdrop.upgrade() {
+        if shared {
+          return Ok (Enet { enetdrop })
+        }
         return Err (Error::Initialize (
           "`Enet` cannot be initialized more than once".to_owned()))
       }
-      if ll::enet_initialize() < 0 {
-        return Err (Error::Initialize(
-          "`enet_initialize` returned an error".to_owned()))
+      if !context.alive {
+        break
+      }
+      // the last handle was dropped and is deinitializing ENet
+      context = CONTEXT_DROPPED.wait (context)
+        .unwrap_or_else (PoisonError::into_inner);
+    }
+    let callbacks_allocator = memory::choose_allocator (callbacks.is_some());
+    if !shared && callbacks.is_some() != callbacks_allocator {
+      return Err (Error::Initialize (
+        "`Enet` was initialized with a different allocator earlier in the process"
+          .to_owned()))
+    }
+    let result = unsafe {
+      if callbacks_allocator {
+        if let Some (callbacks) = callbacks {
+          callbacks.install();
+        }
+        let version = Version::create (ll::ENET_VERSION_MAJOR,
+          ll::ENET_VERSION_MINOR, ll::ENET_VERSION_PATCH);
+        ll::enet_initialize_with_callbacks (version.to_ll(),
+          &memory::raw_callbacks())
+      } else {
+        ll::enet_initialize()
       }
-      Ok (Enet { enetdrop: std::sync::Arc::new (EnetDrop) })
+    };
+    if result < 0 {
+      return Err (Error::Initialize(
+        "`enet_initialize` returned an error".to_owned()))
+    }
+    let enetdrop = std::sync::Arc::new (EnetDrop);
+    context.enetdrop = std::sync::Arc::downgrade (&enetdrop);
+    context.alive    = true;
+    drop (context);
+    Ok (Enet { enetdrop })
+  }
+}
+
+impl std::fmt::Display for Error {
+  fn fmt (&self, f : &mut std::fmt::Formatter) -> std::fmt::Result {
+    match self {
+      Error::Initialize   (message) => write!(f, "{message}"),
+      Error::ServerCreate (_) => write!(f, "failed to create server host"),
+      Error::ClientCreate (_) => write!(f, "failed to create client host")
+    }
+  }
+}
+impl std::error::Error for Error {
+  fn source (&self) -> Option <&(dyn std::error::Error + 'static)> {
+    match self {
+      Error::Initialize (_) => None,
+      Error::ServerCreate (error) | Error::ClientCreate (error) => Some (error)
     }
   }
 }
@@ -134,11 +323,14 @@ impl Enet {
 impl Drop for EnetDrop {
   #[inline]
   fn drop (&mut self) {
-    let was_alive = ENET_CONTEXT_ALIVE.swap (false, atomic::Ordering::Relaxed);
-    debug_assert!(was_alive);
-    if was_alive {
+    let mut context = CONTEXT.lock().unwrap_or_else (PoisonError::into_inner);
+    debug_assert!(context.alive);
+    if context.alive {
       unsafe { ll::enet_deinitialize() }
+      context.alive = false;
     }
+    drop (context);
+    CONTEXT_DROPPED.notify_all();
   }
 }
 
@@ -146,10 +338,41 @@ impl Drop for EnetDrop {
 //  tests                                                                     //
 ////////////////////////////////////////////////////////////////////////////////
 
-/*
 #[cfg (test)]
 mod tests {
+  use std::error::Error as _;
+  use super::*;
+
   #[test]
-  fn it_works() {}
+  fn create_error_is_the_source() {
+    let error = testing::enet().client_host_create (MAX_PEERS + 1, None, None)
+      .unwrap_err();
+    assert_eq!(error.to_string(), "failed to create client host");
+    let source = error.source().unwrap();
+    assert_eq!(source.to_string(), format!(
+      "peer count {} exceeds the maximum of {MAX_PEERS}", MAX_PEERS + 1));
+    assert!(source.source().is_none());
+  }
+
+  #[test]
+  fn os_error_is_the_source_of_the_source() {
+    let error = Error::ServerCreate (host::CreateError::ReturnedNull (
+      std::io::Error::from (std::io::ErrorKind::AddrInUse).into()));
+    let clone = error.clone();
+    for error in [error, clone] {
+      assert_eq!(error.to_string(), "failed to create server host");
+      let source = error.source().unwrap();
+      assert_eq!(source.to_string(), "host creation failed");
+      let io_error = source.source().unwrap()
+        .downcast_ref::<std::io::Error>().unwrap();
+      assert_eq!(io_error.kind(), std::io::ErrorKind::AddrInUse);
+    }
+  }
+
+  #[test]
+  fn initialize_error_has_no_source() {
+    let error = Error::Initialize ("message".to_owned());
+    assert_eq!(error.to_string(), "message");
+    assert!(error.source().is_none());
+  }
 }
-*/
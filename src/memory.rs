@@ -0,0 +1,325 @@
+//! ENet memory allocation through Rust.
+//!
+//! A context created with `enet::initialize_with (Callbacks::new())` allocates
+//! all ENet memory (hosts, peers, packets) through the Rust global allocator
+//! and keeps count of the live and peak bytes, available from `stats()`.
+//!
+//! When an allocation fails ENet calls its `no_memory` callback, which aborts
+//! by default. With `Callbacks` the failure is counted and passed to the
+//! `on_no_memory` hook, or logged as a warning with the `log` crate if no hook
+//! is set, and the failing ENet call returns an error.
+//!
+//! Memory allocated by ENet must be freed by the same allocator, and packets can
+//! outlive the context that created them, so the allocator is chosen once per
+//! process: by the first initialization of ENet. Initializing ENet again later
+//! with `initialize()` after `initialize_with()`, or the reverse, returns an
+//! error. The hooks of `Callbacks` can be changed by each initialization, and
+//! `Enet::shared()` uses whichever allocator was chosen.
+//!
+//! With `Callbacks::recycle (n)` freed ENet memory is kept by the thread that
+//! freed it, up to `n` blocks of each size, and reused by the next allocations
+//! of the same size. ENet allocates a few fixed-size structures for each packet
+//! sent or received (the packet header, queued commands and acknowledgements),
+//! so together with a `PacketPool` a host that has reached its working set
+//! sends and receives without allocating.
+//!
+//! For testing, `fail_allocation (n)` makes the `n`th following allocation
+//! fail:
+//!
+//! ```no_run
+//! # use enet::memory::{self, Callbacks};
+//! let enet = enet::initialize_with (Callbacks::new()).unwrap();
+//! let mut host = enet.client_host_create (1, None, None).unwrap();
+//! memory::fail_allocation (1);
+//! assert!(host.connect (&enet::Address::localhost (12345), 1, 0).is_err());
+//! ```
+
+use std;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::sync::OnceLock;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+use ll;
+
+/// Bytes before each allocation holding its size; keeps the maximum alignment
+/// of `malloc`
+const HEADER : usize = 16;
+
+static LIVE_BYTES  : AtomicUsize = AtomicUsize::new (0);
+static PEAK_BYTES  : AtomicUsize = AtomicUsize::new (0);
+static ALLOCATIONS : AtomicU64   = AtomicU64::new (0);
+static FREES       : AtomicU64   = AtomicU64::new (0);
+static FAILURES    : AtomicU64   = AtomicU64::new (0);
+static RECYCLED    : AtomicU64   = AtomicU64::new (0);
+static CACHED_BYTES : AtomicUsize = AtomicUsize::new (0);
+/// Freed blocks kept by each thread for each size, 0 to free them
+static RECYCLE     : AtomicUsize = AtomicUsize::new (0);
+/// Number of allocations until one fails, 0 if none should fail
+static FAIL_IN     : AtomicU64   = AtomicU64::new (0);
+static ON_NO_MEMORY : std::sync::Mutex <Option <fn()>> =
+  std::sync::Mutex::new (None);
+/// Allocator chosen by the first initialization of ENet in the process: true
+/// for `Callbacks`, false for the ENet default
+static CALLBACKS_ALLOCATOR : OnceLock <bool> = OnceLock::new();
+
+thread_local! {
+  static CACHE : RefCell <Cache> = RefCell::new (Cache::default());
+}
+
+////////////////////////////////////////////////////////////////////////////////
+//  structs                                                                   //
+////////////////////////////////////////////////////////////////////////////////
+
+/// Allocation callbacks for `enet::initialize_with()`
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Callbacks {
+  on_no_memory    : Option <fn()>,
+  fail_allocation : Option <u64>,
+  recycle         : usize
+}
+
+/// Memory allocated by ENet through `Callbacks`
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct Stats {
+  /// Bytes currently allocated
+  pub live_bytes   : usize,
+  /// Largest number of bytes allocated at once
+  pub peak_bytes   : usize,
+  /// Allocations from the global allocator
+  pub allocations  : u64,
+  /// Frees to the global allocator
+  pub frees        : u64,
+  /// Allocations that failed, including injected failures
+  pub failures     : u64,
+  /// Allocations that reused memory kept by `Callbacks::recycle()`
+  pub recycled     : u64,
+  /// Bytes freed by ENet and kept for reuse
+  pub cached_bytes : usize
+}
+
+/// Freed blocks of each thread by size, for `Callbacks::recycle()`
+#[derive(Debug, Default)]
+struct Cache {
+  blocks : HashMap <usize, Vec <std::ptr::NonNull <u8>>>
+}
+
+////////////////////////////////////////////////////////////////////////////////
+//  impls                                                                     //
+////////////////////////////////////////////////////////////////////////////////
+
+impl Callbacks {
+  /// Allocate through the global allocator, without a hook for failures
+  #[inline]
+  pub const fn new() -> Self {
+    Callbacks { on_no_memory: None, fail_allocation: None, recycle: 0 }
+  }
+
+  /// Call `hook` when an allocation fails, instead of logging a warning
+  #[inline]
+  pub const fn on_no_memory (mut self, hook : fn()) -> Self {
+    self.on_no_memory = Some (hook);
+    self
+  }
+
+  /// Make the `n`th allocation after initialization fail; see
+  /// `fail_allocation()`
+  #[inline]
+  pub const fn fail_allocation (mut self, n : u64) -> Self {
+    self.fail_allocation = Some (n);
+    self
+  }
+
+  /// Keep up to `max_per_size` freed blocks of each size in each thread for
+  /// reuse; 0 (the default) frees them
+  #[inline]
+  pub const fn recycle (mut self, max_per_size : usize) -> Self {
+    self.recycle = max_per_size;
+    self
+  }
+
+  /// Install the hooks
+  pub(crate) fn install (self) {
+    RECYCLE.store (self.recycle, Ordering::Relaxed);
+    *ON_NO_MEMORY.lock().unwrap_or_else (std::sync::PoisonError::into_inner) =
+      self.on_no_memory;
+    FAIL_IN.store (self.fail_allocation.unwrap_or (0), Ordering::Relaxed);
+  }
+}
+
+impl Cache {
+  /// Take a freed block of `size` bytes
+  fn take (&mut self, size : usize) -> Option <std::ptr::NonNull <u8>> {
+    let block = self.blocks.get_mut (&size)?.pop()?;
+    CACHED_BYTES.fetch_sub (size, Ordering::Relaxed);
+    Some (block)
+  }
+
+  /// Keep a freed block of `size` bytes, or give it back if there are already
+  /// `max` blocks of that size
+  fn keep (&mut self, size : usize, block : std::ptr::NonNull <u8>, max : usize)
+    -> Result <(), std::ptr::NonNull <u8>>
+  {
+    let blocks = self.blocks.entry (size).or_default();
+    if max <= blocks.len() {
+      return Err (block)
+    }
+    blocks.push (block);
+    CACHED_BYTES.fetch_add (size, Ordering::Relaxed);
+    Ok (())
+  }
+
+  /// Free all blocks
+  fn clear (&mut self) {
+    for (size, blocks) in self.blocks.drain() {
+      for block in blocks {
+        CACHED_BYTES.fetch_sub (size, Ordering::Relaxed);
+        unsafe { deallocate (block.as_ptr(), size) }
+      }
+    }
+  }
+}
+
+impl Drop for Cache {
+  fn drop (&mut self) {
+    self.clear()
+  }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+//  functions                                                                 //
+////////////////////////////////////////////////////////////////////////////////
+
+/// Memory allocated by ENet. All zero unless ENet was initialized with
+/// `Callbacks`.
+pub fn stats() -> Stats {
+  Stats {
+    live_bytes:   LIVE_BYTES.load (Ordering::Relaxed),
+    peak_bytes:   PEAK_BYTES.load (Ordering::Relaxed),
+    allocations:  ALLOCATIONS.load (Ordering::Relaxed),
+    frees:        FREES.load (Ordering::Relaxed),
+    failures:     FAILURES.load (Ordering::Relaxed),
+    recycled:     RECYCLED.load (Ordering::Relaxed),
+    cached_bytes: CACHED_BYTES.load (Ordering::Relaxed)
+  }
+}
+
+/// Free the memory kept for reuse by the current thread
+pub fn shrink() {
+  // the cache of an exiting thread is already freed
+  let _ = CACHE.try_with (|cache| cache.borrow_mut().clear());
+}
+
+/// Reset the peak to the bytes currently allocated
+pub fn reset_peak() {
+  PEAK_BYTES.store (LIVE_BYTES.load (Ordering::Relaxed), Ordering::Relaxed);
+}
+
+/// Make the `n`th following ENet allocation fail (`n = 1` fails the next one);
+/// `n = 0` cancels a pending failure.
+///
+/// Only has an effect if ENet was initialized with `Callbacks`.
+pub fn fail_allocation (n : u64) {
+  FAIL_IN.store (n, Ordering::Relaxed);
+}
+
+/// Choose the allocator of the process if it has not been chosen yet, and
+/// return true if it is the allocator of `Callbacks`
+pub(crate) fn choose_allocator (callbacks : bool) -> bool {
+  *CALLBACKS_ALLOCATOR.get_or_init (|| callbacks)
+}
+
+/// True if ENet allocates through the callbacks of `Callbacks`
+pub(crate) fn callbacks_allocator() -> bool {
+  CALLBACKS_ALLOCATOR.get() == Some (&true)
+}
+
+/// The raw callbacks for ENet
+pub(crate) const fn raw_callbacks() -> ll::ENetCallbacks {
+  ll::ENetCallbacks {
+    malloc:    Some (malloc),
+    free:      Some (free),
+    no_memory: Some (no_memory)
+  }
+}
+
+/// Returns true if this allocation should fail
+fn inject_failure() -> bool {
+  FAIL_IN.fetch_update (Ordering::Relaxed, Ordering::Relaxed,
+    |n| (n != 0).then (|| n - 1)
+  ) == Ok (1)
+}
+
+const fn layout (size : usize) -> Option <std::alloc::Layout> {
+  let Some (size) = size.checked_add (HEADER) else {
+    return None
+  };
+  match std::alloc::Layout::from_size_align (size, HEADER) {
+    Ok (layout) => Some (layout),
+    Err (_)     => None
+  }
+}
+
+unsafe extern "C" fn malloc (size : usize) -> *mut std::os::raw::c_void {
+  let layout = match layout (size) {
+    Some (layout) if !inject_failure() => layout,
+    _ => {
+      FAILURES.fetch_add (1, Ordering::Relaxed);
+      return std::ptr::null_mut()
+    }
+  };
+  let recycled = (0 < RECYCLE.load (Ordering::Relaxed)).then (||
+    CACHE.try_with (|cache| cache.borrow_mut().take (size)).ok().flatten()
+  ).flatten();
+  let memory = if let Some (block) = recycled {
+    RECYCLED.fetch_add (1, Ordering::Relaxed);
+    block.as_ptr()
+  } else {
+    let memory = unsafe { std::alloc::alloc (layout) };
+    if memory.is_null() {
+      FAILURES.fetch_add (1, Ordering::Relaxed);
+      return std::ptr::null_mut()
+    }
+    unsafe { memory.cast::<usize>().write (size) }
+    ALLOCATIONS.fetch_add (1, Ordering::Relaxed);
+    memory
+  };
+  let live = LIVE_BYTES.fetch_add (size, Ordering::Relaxed) + size;
+  PEAK_BYTES.fetch_max (live, Ordering::Relaxed);
+  unsafe { memory.add (HEADER).cast() }
+}
+
+unsafe extern "C" fn free (memory : *mut std::os::raw::c_void) {
+  let Some (memory) = std::ptr::NonNull::new (memory.cast::<u8>()) else {
+    return
+  };
+  let (memory, size) = unsafe {
+    let memory = memory.sub (HEADER);
+    (memory, memory.cast::<usize>().read())
+  };
+  LIVE_BYTES.fetch_sub (size, Ordering::Relaxed);
+  let max = RECYCLE.load (Ordering::Relaxed);
+  let kept = 0 < max && CACHE.try_with (
+    |cache| cache.borrow_mut().keep (size, memory, max).is_ok()
+  ) == Ok (true);
+  if !kept {
+    unsafe { deallocate (memory.as_ptr(), size) }
+  }
+}
+
+/// Return a block of `size` bytes to the global allocator
+unsafe fn deallocate (memory : *mut u8, size : usize) {
+  FREES.fetch_add (1, Ordering::Relaxed);
+  // the layout was valid when allocated
+  unsafe { std::alloc::dealloc (memory, layout (size).unwrap()) }
+}
+
+/// Called by ENet after a failed allocation; the ENet call that allocated
+/// then fails instead of aborting
+extern "C" fn no_memory() {
+  let hook = ON_NO_MEMORY.lock().map_or (None, |hook| *hook);
+  match hook {
+    Some (hook) => hook(),
+    None => log::warn!("ENet allocation failed")
+  }
+}
@@ -0,0 +1,383 @@
+//! Time series of peer connection quality.
+//!
+//! A `NetGraph` set on a host with `host.set_net_graph()` samples the round
+//! trip time, jitter, packet loss, throughput and queue depth of each connected
+//! peer when the host is serviced, at most once per sample interval. The
+//! samples of each peer are kept in a ring buffer of fixed capacity.
+//!
+//! `summary()` gives percentiles of the round trip time and packet loss over a
+//! `Window` of the most recent samples, which show spikes that averages hide.
+//! `samples()` and `export()` return the samples as plain data, e.g. for an
+//! in-game overlay or an offline plot; with the `serde` feature they can be
+//! serialized.
+//!
+//! ```no_run
+//! # use enet::netgraph::{NetGraph, Window};
+//! # let enet = enet::Enet::shared().unwrap();
+//! # let mut host = enet.client_host_create (1, None, None).unwrap();
+//! # let peer = host.connect (&enet::Address::localhost (12345), 1, 0).unwrap();
+//! let graph = NetGraph::default();
+//! host.set_net_graph (Some (graph.clone()));
+//! loop {
+//!   while let Some (_event) = host.service (16).unwrap() {}
+//!   if let Some (summary) = graph.summary (&peer, Window::Samples (100)) {
+//!     println!("rtt p99: {} ms", summary.round_trip_time.p99);
+//!   }
+//! }
+//! ```
+//!
+//! `NetGraph` is a handle: clones share the same samples.
+
+use std;
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use crate::{peer, Host, Peer};
+
+/// Samples kept for each peer by `NetGraph::default()` (one minute)
+pub const DEFAULT_CAPACITY : usize    = 600;
+/// Time between samples of `NetGraph::default()`
+pub const DEFAULT_INTERVAL : Duration = Duration::from_millis (100);
+
+////////////////////////////////////////////////////////////////////////////////
+//  structs                                                                   //
+////////////////////////////////////////////////////////////////////////////////
+
+/// Shared handle to the samples of the peers of a host
+#[derive(Clone, Debug)]
+pub struct NetGraph {
+  inner : Rc <RefCell <Inner>>
+}
+
+/// Connection quality of a peer at one point in time
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct Sample {
+  /// Time since the graph was created
+  pub time            : Duration,
+  /// Time since the previous sample of the peer; zero for the first sample
+  pub elapsed         : Duration,
+  /// Mean round trip time in milliseconds
+  pub round_trip_time : u32,
+  /// Round trip time variance in milliseconds
+  pub jitter          : u32,
+  /// Packet loss scaled by `peer::PACKET_LOSS_SCALE`
+  pub packet_loss     : u32,
+  /// Application payload bytes queued since the previous sample
+  pub bytes_sent      : u64,
+  /// Application payload bytes received since the previous sample
+  pub bytes_received  : u64,
+  /// Bytes queued or waiting for acknowledgement
+  pub queued_bytes    : usize
+}
+
+/// The samples of a peer, from `NetGraph::export()`
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct PeerSamples {
+  pub incoming_peer_id : u16,
+  pub connect_id       : u32,
+  /// Oldest first
+  pub samples          : Vec <Sample>
+}
+
+/// 50th, 95th and 99th percentiles of a value
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct Percentiles {
+  pub p50 : u32,
+  pub p95 : u32,
+  pub p99 : u32
+}
+
+/// Statistics of a peer over a window of samples
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Summary {
+  /// Samples in the window
+  pub samples             : usize,
+  /// Round trip time in milliseconds
+  pub round_trip_time     : Percentiles,
+  /// Packet loss scaled by `peer::PACKET_LOSS_SCALE`
+  pub packet_loss         : Percentiles,
+  pub max_jitter          : u32,
+  /// Payload bytes queued per second
+  pub sent_per_second     : f64,
+  /// Payload bytes received per second
+  pub received_per_second : f64,
+  pub max_queued_bytes    : usize
+}
+
+#[derive(Debug)]
+struct Inner {
+  capacity : usize,
+  interval : Duration,
+  start    : Instant,
+  last     : Option <Instant>,
+  /// Indexed by `incomingPeerID`
+  peers    : HashMap <u16, History>
+}
+
+/// Samples of the current connection of a peer slot
+#[derive(Debug)]
+struct History {
+  connect_id     : u32,
+  /// Application payload bytes sent and received by the connection at the last
+  /// sample
+  bytes_sent     : u64,
+  bytes_received : u64,
+  samples        : VecDeque <Sample>
+}
+
+////////////////////////////////////////////////////////////////////////////////
+//  enums                                                                     //
+////////////////////////////////////////////////////////////////////////////////
+
+/// The most recent samples to summarize
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Window {
+  All,
+  /// The last number of samples
+  Samples  (usize),
+  /// Samples taken within a duration of the last sample
+  Duration (Duration)
+}
+
+////////////////////////////////////////////////////////////////////////////////
+//  impls                                                                     //
+////////////////////////////////////////////////////////////////////////////////
+
+impl NetGraph {
+  /// Keep up to `capacity` samples per peer, taken at most once every
+  /// `interval`
+  pub fn new (capacity : usize, interval : Duration) -> Self {
+    NetGraph {
+      inner: Rc::new (RefCell::new (Inner {
+        capacity: capacity.max (1),
+        interval,
+        start:    Instant::now(),
+        last:     None,
+        peers:    HashMap::new()
+      }))
+    }
+  }
+
+  pub fn capacity (&self) -> usize {
+    self.inner.borrow().capacity
+  }
+
+  pub fn interval (&self) -> Duration {
+    self.inner.borrow().interval
+  }
+
+  /// Samples of the current connection of a peer, oldest first
+  pub fn samples (&self, peer : &Peer) -> Vec <Sample> {
+    let inner = self.inner.borrow();
+    inner.history (peer).map_or_else (Vec::new,
+      |history| history.samples.iter().copied().collect())
+  }
+
+  /// Summarize the samples of a peer within a window, or `None` if there are
+  /// no samples
+  pub fn summary (&self, peer : &Peer, window : Window) -> Option <Summary> {
+    let samples = self.samples (peer);
+    Summary::new (window.select (&samples))
+  }
+
+  /// Samples of all peers, including disconnected peers whose slot has not been
+  /// used by a new connection
+  pub fn export (&self) -> Vec <PeerSamples> {
+    let inner = self.inner.borrow();
+    let mut peers = inner.peers.iter().map (|(index, history)| PeerSamples {
+      incoming_peer_id: *index,
+      connect_id:       history.connect_id,
+      samples:          history.samples.iter().copied().collect()
+    }).collect::<Vec <_>>();
+    peers.sort_unstable_by_key (|peer| peer.incoming_peer_id);
+    peers
+  }
+
+  /// Remove all samples
+  pub fn clear (&self) {
+    self.inner.borrow_mut().peers.clear();
+  }
+
+  /// Sample the connected peers of the host if the interval has elapsed since
+  /// the last sample
+  pub(crate) fn sample (&self, host : &Host) {
+    let now = Instant::now();
+    let mut inner = self.inner.borrow_mut();
+    if inner.last.is_some_and (|last| now < last + inner.interval) {
+      return
+    }
+    inner.last = Some (now);
+    let time = now - inner.start;
+    let capacity = inner.capacity;
+    for peer in host.peers() {
+      if peer.state() != peer::State::Connected {
+        continue
+      }
+      let (sent, received) = peer.bytes_transferred();
+      let connect_id = peer.connect_id();
+      let history = inner.peers.entry (peer.incoming_peer_id())
+        .or_insert_with (|| History::new (connect_id, sent, received));
+      if history.connect_id != connect_id {
+        // the peer slot is used by a new connection
+        *history = History::new (connect_id, sent, received);
+      }
+      let elapsed = history.samples.back()
+        .map_or (Duration::ZERO, |sample| time - sample.time);
+      if history.samples.len() == capacity {
+        history.samples.pop_front();
+      }
+      history.samples.push_back (Sample {
+        time,
+        elapsed,
+        round_trip_time: peer.round_trip_time(),
+        jitter:          peer.round_trip_time_variance(),
+        packet_loss:     peer.packet_loss(),
+        bytes_sent:      sent.saturating_sub (history.bytes_sent),
+        bytes_received:  received.saturating_sub (history.bytes_received),
+        queued_bytes:    peer.queued_bytes()
+      });
+      history.bytes_sent     = sent;
+      history.bytes_received = received;
+    }
+  }
+}
+
+impl Default for NetGraph {
+  /// `DEFAULT_CAPACITY` samples taken every `DEFAULT_INTERVAL`
+  fn default() -> Self {
+    NetGraph::new (DEFAULT_CAPACITY, DEFAULT_INTERVAL)
+  }
+}
+
+impl Summary {
+  fn new (samples : &[Sample]) -> Option <Self> {
+    if samples.is_empty() {
+      return None
+    }
+    let elapsed = samples.iter().map (|sample| sample.elapsed)
+      .sum::<Duration>().as_secs_f64();
+    let per_second = |bytes : u64| if elapsed > 0.0 {
+      bytes as f64 / elapsed
+    } else {
+      0.0
+    };
+    Some (Summary {
+      samples:             samples.len(),
+      round_trip_time:     Percentiles::new (
+        samples.iter().map (|sample| sample.round_trip_time).collect()),
+      packet_loss:         Percentiles::new (
+        samples.iter().map (|sample| sample.packet_loss).collect()),
+      max_jitter:          samples.iter().map (|sample| sample.jitter)
+        .max().unwrap_or_default(),
+      sent_per_second:     per_second (
+        samples.iter().map (|sample| sample.bytes_sent).sum()),
+      received_per_second: per_second (
+        samples.iter().map (|sample| sample.bytes_received).sum()),
+      max_queued_bytes:    samples.iter().map (|sample| sample.queued_bytes)
+        .max().unwrap_or_default()
+    })
+  }
+}
+
+impl Percentiles {
+  /// Nearest rank percentiles of a non-empty set of values
+  fn new (mut values : Vec <u32>) -> Self {
+    values.sort_unstable();
+    let rank = |percent : usize|
+      values[(values.len() * percent).div_ceil (100).max (1) - 1];
+    Percentiles { p50: rank (50), p95: rank (95), p99: rank (99) }
+  }
+}
+
+impl Window {
+  /// The samples within the window, oldest first
+  fn select (self, samples : &[Sample]) -> &[Sample] {
+    let skip = match self {
+      Window::All => 0,
+      Window::Samples (count) => samples.len().saturating_sub (count),
+      Window::Duration (duration) => {
+        let Some (last) = samples.last() else {
+          return samples
+        };
+        samples.partition_point (|sample| sample.time + duration < last.time)
+      }
+    };
+    &samples[skip..]
+  }
+}
+
+impl Inner {
+  fn history (&self, peer : &Peer) -> Option <&History> {
+    self.peers.get (&peer.incoming_peer_id())
+      .filter (|history| history.connect_id == peer.connect_id())
+  }
+}
+
+impl History {
+  /// Bytes transferred before the first sample are not counted
+  const fn new (connect_id : u32, bytes_sent : u64, bytes_received : u64)
+    -> Self
+  {
+    History { connect_id, bytes_sent, bytes_received, samples: VecDeque::new() }
+  }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+//  tests                                                                     //
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg (test)]
+mod tests {
+  use super::*;
+
+  /// One sample per second, with the round trip time set to the second
+  fn samples (count : u32) -> Vec <Sample> {
+    (0..count).map (|second| Sample {
+      time:            Duration::from_secs (second.into()),
+      elapsed:         Duration::from_secs (1),
+      round_trip_time: second,
+      bytes_sent:      100,
+      .. Sample::default()
+    }).collect()
+  }
+
+  #[test]
+  fn percentiles_use_nearest_rank() {
+    assert_eq!(Percentiles::new ((1..=100).rev().collect()),
+      Percentiles { p50: 50, p95: 95, p99: 99 });
+    assert_eq!(Percentiles::new (vec![5, 1, 3]),
+      Percentiles { p50: 3, p95: 5, p99: 5 });
+    assert_eq!(Percentiles::new (vec![7]),
+      Percentiles { p50: 7, p95: 7, p99: 7 });
+  }
+
+  #[test]
+  fn window_selects_most_recent_samples() {
+    let samples = samples (10);
+    let rtts = |window : Window| window.select (&samples).iter()
+      .map (|sample| sample.round_trip_time).collect::<Vec <_>>();
+    assert_eq!(rtts (Window::All).len(), 10);
+    assert_eq!(rtts (Window::Samples (3)), [7, 8, 9]);
+    assert_eq!(rtts (Window::Samples (20)).len(), 10);
+    assert!(rtts (Window::Samples (0)).is_empty());
+    assert_eq!(rtts (Window::Duration (Duration::from_secs (2))), [7, 8, 9]);
+    assert_eq!(rtts (Window::Duration (Duration::ZERO)), [9]);
+    assert!(Window::Duration (Duration::from_secs (1)).select (&[]).is_empty());
+  }
+
+  #[test]
+  fn summary_of_window() {
+    let samples = samples (10);
+    let summary = Summary::new (Window::Samples (4).select (&samples)).unwrap();
+    assert_eq!(summary.samples, 4);
+    assert_eq!(summary.round_trip_time.p50, 7);
+    assert!((summary.sent_per_second - 100.0).abs() < f64::EPSILON);
+    assert!(Summary::new (&[]).is_none());
+  }
+}
@@ -1,6 +1,13 @@
 use {std, ll};
+use std::rc::Rc;
 use bitflags::bitflags;
 
+use crate::{event, memory, peer};
+
+pub mod pool;
+
+pub use self::pool::PacketPool;
+
 ////////////////////////////////////////////////////////////////////////////////
 //  structs                                                                   //
 ////////////////////////////////////////////////////////////////////////////////
@@ -18,16 +25,71 @@ pub enum Packet <'a> {
   }
 }
 
-/// Received packet
+/// An outgoing packet created once and queued to any number of peers with
+/// `peer.send_shared()` or `host.send_shared()`.
+///
+/// ENet reference counts the packet, so the data is not copied for each peer.
 #[derive(Debug)]
-pub struct PacketRecv {
+pub struct SharedPacket {
   raw : *mut ll::ENetPacket
 }
 
+/// An outgoing packet written in place, created with `host.packet_builder()`.
+///
+/// The data is written directly into an ENet packet, which grows as needed up
+/// to the maximum packet size of the host. `finish()` returns the packet as a
+/// `SharedPacket` without copying it.
+#[derive(Debug)]
+pub struct PacketBuilder {
+  raw   : *mut ll::ENetPacket,
+  /// Bytes written; the packet data length is the capacity until finished
+  len   : usize,
+  limit : usize
+}
+
+/// Received packet.
+///
+/// Dereferences to the packet data and implements `io::Read` for reading it in
+/// order. A received packet is owned by the application and can be sent to
+/// other threads.
+#[derive(Debug)]
+pub struct PacketRecv {
+  storage  : Storage,
+  /// Read position for `io::Read`
+  position : usize
+}
+
+/// Data of a received packet
+#[derive(Debug)]
+enum Storage {
+  /// Packet allocated by the system allocator, without a free callback
+  Enet (*mut ll::ENetPacket),
+  /// Copy of a packet that cannot be freed on another thread
+  Owned {
+    data  : Vec <u8>,
+    flags : u32
+  }
+}
+
+/// Identifies a packet sent with `peer.send_tracked()` in the `Delivered` or
+/// `Dropped` event reporting its outcome
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct SendTicket (u64);
+
+/// Attached to the `userData` of a packet sent with `peer.send_tracked()`
+struct Tracking {
+  ticket     : SendTicket,
+  peer       : *mut ll::ENetPeer,
+  /// Connection of the peer when the packet was sent
+  connect_id : u32,
+  pending    : Rc <event::Queue>
+}
+
 bitflags! {
-  /// Flags for outgoing packets.
+  /// Packet flags.
   ///
-  /// `Flags::empty()` indicates unreliable, sequenced delivery.
+  /// `Flags::empty()` indicates unreliable, sequenced delivery. Flags read from
+  /// a packet keep any bits set by ENet that are not named here.
   #[derive(Clone, Copy, Debug)]
   pub struct Flags : u32 {
     /// Reliable, sequenced delivery
@@ -43,10 +105,10 @@ bitflags! {
     /// Packet will not allocate data and user must supply it instead
     #[allow(clippy::unnecessary_cast)] // on windows ll flags are i32
     const NO_ALLOCATE = ll::_ENetPacketFlag_ENET_PACKET_FLAG_NO_ALLOCATE as u32;
-    // TODO: choose to expose the packet sent flag?
-    // Whether the packet has been sent from all queues it has been entered into.
-    //#[allow(clippy::unnecessary_cast)] // on windows ll flags are i32
-    //const SENT        = ll::_ENetPacketFlag_ENET_PACKET_FLAG_SENT as u32;
+    /// Whether the packet has been sent from all queues it has been entered
+    /// into; set by ENet
+    #[allow(clippy::unnecessary_cast)] // on windows ll flags are i32
+    const SENT        = ll::_ENetPacketFlag_ENET_PACKET_FLAG_SENT as u32;
   }
 }
 
@@ -54,38 +116,543 @@ bitflags! {
 //  impls                                                                     //
 ////////////////////////////////////////////////////////////////////////////////
 
+impl <'a> Packet <'a> {
+  /// Payload and flags (without `NO_ALLOCATE`)
+  #[inline]
+  pub const fn parts (self) -> (&'a [u8], Flags) {
+    match self {
+      Packet::Allocate   { bytes, flags } |
+      Packet::NoAllocate { bytes, flags } => (bytes, flags)
+    }
+  }
+}
+
+impl SharedPacket {
+  /// Create the ENet packet; `Packet::NoAllocate` data is not copied
+  pub fn new (packet : Packet) -> Result <Self, peer::SendErrorKind> {
+    let (bytes, flags) = match packet {
+      Packet::Allocate   { bytes, flags } => (bytes, flags),
+      Packet::NoAllocate { bytes, flags } => (bytes, flags | Flags::NO_ALLOCATE)
+    };
+    if bytes.is_empty() {
+      return Err (peer::SendErrorKind::PacketCreateZeroLength)
+    }
+    unsafe {
+      let raw = ll::enet_packet_create (
+        bytes.as_ptr() as *const std::os::raw::c_void, bytes.len(), flags.bits());
+      if raw.is_null() {
+        return Err (peer::SendErrorKind::PacketCreateMallocFailure)
+      }
+      Ok (SharedPacket::from_raw (raw))
+    }
+  }
+
+  /// Take ownership of a packet that has not been queued
+  const unsafe fn from_raw (raw : *mut ll::ENetPacket) -> Self {
+    // the handle holds a reference so that ENet does not destroy the packet
+    // once it has been sent to every peer
+    unsafe { (*raw).referenceCount += 1 }
+    SharedPacket { raw }
+  }
+
+  /// # Safety
+  ///
+  /// Unsafe: returns raw pointer.
+  #[inline]
+  pub const unsafe fn raw (&self) -> *mut ll::ENetPacket {
+    self.raw
+  }
+
+  /// Flags set on the packet except `NO_ALLOCATE`, including bits not named by
+  /// `Flags`
+  #[inline]
+  pub fn flags (&self) -> Flags {
+    unsafe { Flags::from_bits_retain ((*self.raw).flags) - Flags::NO_ALLOCATE }
+  }
+
+  #[inline]
+  pub fn data (&self) -> &[u8] {
+    unsafe {
+      std::slice::from_raw_parts ((*self.raw).data, (*self.raw).dataLength)
+    }
+  }
+
+  /// Number of peer queues still holding the packet
+  #[inline]
+  pub fn queued (&self) -> usize {
+    unsafe { (*self.raw).referenceCount - 1 }
+  }
+}
+
+impl Drop for SharedPacket {
+  fn drop (&mut self) {
+    unsafe {
+      (*self.raw).referenceCount -= 1;
+      if (*self.raw).referenceCount == 0 {
+        ll::enet_packet_destroy (self.raw)
+      }
+    }
+  }
+}
+
+impl PacketBuilder {
+  /// Smallest capacity allocated when the builder first grows
+  const MIN_CAPACITY : usize = 64;
+
+  pub(crate) fn new (flags : Flags, capacity : usize, limit : usize)
+    -> Result <Self, peer::SendErrorKind>
+  {
+    let capacity = capacity.min (limit);
+    let flags    = flags - Flags::NO_ALLOCATE;
+    let raw = unsafe {
+      ll::enet_packet_create (std::ptr::null(), capacity, flags.bits())
+    };
+    if raw.is_null() {
+      return Err (peer::SendErrorKind::PacketCreateMallocFailure)
+    }
+    Ok (PacketBuilder { raw, len: 0, limit })
+  }
+
+  /// Bytes written
+  #[inline]
+  pub const fn len (&self) -> usize {
+    self.len
+  }
+
+  #[inline]
+  pub const fn is_empty (&self) -> bool {
+    self.len == 0
+  }
+
+  /// Largest packet that can be written, the maximum packet size of the host
+  #[inline]
+  pub const fn limit (&self) -> usize {
+    self.limit
+  }
+
+  /// Flags set on the packet, including bits not named by `Flags`
+  #[inline]
+  pub fn flags (&self) -> Flags {
+    unsafe { Flags::from_bits_retain ((*self.raw).flags) }
+  }
+
+  /// Data written so far
+  #[inline]
+  pub fn data (&self) -> &[u8] {
+    if self.len == 0 {
+      return &[]
+    }
+    unsafe { std::slice::from_raw_parts ((*self.raw).data, self.len) }
+  }
+
+  /// Discard the data written, keeping the allocation
+  #[inline]
+  pub const fn clear (&mut self) {
+    self.len = 0;
+  }
+
+  /// Return the written packet, ready to be sent
+  pub fn finish (mut self) -> SharedPacket {
+    let raw = std::mem::replace (&mut self.raw, std::ptr::null_mut());
+    unsafe {
+      // shrinking only changes the data length
+      ll::enet_packet_resize (raw, self.len);
+      SharedPacket::from_raw (raw)
+    }
+  }
+
+  /// Grow the packet to hold `additional` more bytes
+  fn reserve (&mut self, additional : usize) -> std::io::Result <()> {
+    let required = self.len + additional;
+    if self.limit < required {
+      return Err (std::io::Error::new (std::io::ErrorKind::InvalidInput,
+        "packet exceeds the maximum packet size of the host"))
+    }
+    unsafe {
+      let capacity = (*self.raw).dataLength;
+      if required <= capacity {
+        return Ok (())
+      }
+      let capacity = required.max (capacity * 2).max (Self::MIN_CAPACITY)
+        .min (self.limit);
+      if ll::enet_packet_resize (self.raw, capacity) < 0 {
+        return Err (std::io::ErrorKind::OutOfMemory.into())
+      }
+    }
+    Ok (())
+  }
+}
+
+impl std::io::Write for PacketBuilder {
+  /// Writes all of `buf`, or fails if it would exceed the maximum packet size
+  fn write (&mut self, buf : &[u8]) -> std::io::Result <usize> {
+    if buf.is_empty() {
+      return Ok (0)
+    }
+    self.reserve (buf.len())?;
+    unsafe {
+      std::ptr::copy_nonoverlapping (
+        buf.as_ptr(), (*self.raw).data.add (self.len), buf.len());
+    }
+    self.len += buf.len();
+    Ok (buf.len())
+  }
+
+  #[inline]
+  fn flush (&mut self) -> std::io::Result <()> {
+    Ok (())
+  }
+}
+
+impl Drop for PacketBuilder {
+  fn drop (&mut self) {
+    if !self.raw.is_null() {
+      unsafe { ll::enet_packet_destroy (self.raw) }
+    }
+  }
+}
+
+impl SendTicket {
+  #[inline]
+  pub(crate) const fn new (id : u64) -> Self {
+    SendTicket (id)
+  }
+
+  /// Tickets of a host are numbered in the order the packets were sent
+  #[inline]
+  pub const fn id (self) -> u64 {
+    self.0
+  }
+}
+
 impl PacketRecv {
+  /// Take ownership of a packet that is not referenced by the host or any
+  /// peer.
+  ///
+  /// A packet with a free callback, or allocated through `memory::Callbacks`,
+  /// is copied and destroyed right away, so that it is never freed on another
+  /// thread.
+  ///
   /// # Safety
   ///
   /// Unsafe: raw pointer could be invalid.
-  #[inline]
-  pub const unsafe fn from_raw (raw : *mut ll::ENetPacket) -> Self {
-    PacketRecv { raw }
+  pub unsafe fn from_raw (raw : *mut ll::ENetPacket) -> Self {
+    let storage = unsafe {
+      if (*raw).freeCallback.is_none() && !memory::callbacks_allocator() {
+        Storage::Enet (raw)
+      } else {
+        let data = raw_data (raw).to_vec();
+        let flags = (*raw).flags;
+        ll::enet_packet_destroy (raw);
+        Storage::Owned { data, flags }
+      }
+    };
+    PacketRecv { storage, position: 0 }
   }
 
+  /// All flags set on the packet, including bits not named by `Flags`
   #[inline]
   pub fn flags (&self) -> Flags {
-    unsafe { Flags::from_bits ((*self.raw).flags).unwrap() }
+    match self.storage {
+      Storage::Enet (raw) => unsafe { Flags::from_bits_retain ((*raw).flags) },
+      Storage::Owned { flags, .. } => Flags::from_bits_retain (flags)
+    }
   }
 
   #[inline]
   pub fn data_length (&self) -> usize {
-    unsafe { (*self.raw).dataLength }
+    self.data().len()
   }
 
   #[inline]
   pub fn data (&self) -> &[u8] {
-    unsafe {
-      let len = (*self.raw).dataLength;
-      std::slice::from_raw_parts ((*self.raw).data, len)
+    match &self.storage {
+      Storage::Enet (raw) => unsafe { raw_data (*raw) },
+      Storage::Owned { data, .. } => data
     }
   }
 
-  // TODO: set_packet_free_callback
+  /// Copy the data into a `Vec`.
+  ///
+  /// The data is allocated by ENet, so it cannot be taken over by a `Vec`
+  /// without a copy; the ENet packet is freed right away.
+  #[inline]
+  pub fn into_vec (mut self) -> Vec <u8> {
+    match &mut self.storage {
+      Storage::Enet (raw) => unsafe { raw_data (*raw) }.to_vec(),
+      Storage::Owned { data, .. } => std::mem::take (data)
+    }
+  }
+
+  #[cfg(feature = "encryption")]
+  #[inline]
+  pub(crate) fn data_mut (&mut self) -> &mut [u8] {
+    match &mut self.storage {
+      Storage::Enet (raw) => unsafe {
+        let len = (**raw).dataLength;
+        if len == 0 {
+          return &mut []
+        }
+        std::slice::from_raw_parts_mut ((**raw).data, len)
+      }
+      Storage::Owned { data, .. } => data
+    }
+  }
+
+  /// Keep only the given range of the packet data
+  #[cfg(feature = "encryption")]
+  pub(crate) fn retain (&mut self, range : std::ops::Range <usize>) {
+    let length = range.len();
+    self.data_mut().copy_within (range, 0);
+    match &mut self.storage {
+      Storage::Enet (raw) => unsafe { (**raw).dataLength = length },
+      Storage::Owned { data, .. } => data.truncate (length)
+    }
+  }
 }
 impl Drop for PacketRecv {
   #[inline]
   fn drop (&mut self) {
-    unsafe { ll::enet_packet_destroy (self.raw) }
+    if let Storage::Enet (raw) = self.storage {
+      unsafe { ll::enet_packet_destroy (raw) }
+    }
+  }
+}
+
+// a packet kept by `Storage::Enet` is referenced by no host or peer, has no
+// free callback and was allocated by the system allocator: destroying it only
+// calls the C library `free`, which is thread safe (the allocator of ENet is
+// chosen once per process and never changes afterwards)
+unsafe impl Send for PacketRecv {}
+
+impl std::ops::Deref for PacketRecv {
+  type Target = [u8];
+  #[inline]
+  fn deref (&self) -> &[u8] {
+    self.data()
+  }
+}
+
+impl AsRef <[u8]> for PacketRecv {
+  #[inline]
+  fn as_ref (&self) -> &[u8] {
+    self.data()
+  }
+}
+
+impl std::io::Read for PacketRecv {
+  fn read (&mut self, buf : &mut [u8]) -> std::io::Result <usize> {
+    let rest   = self.data().get (self.position..).unwrap_or_default();
+    let length = rest.len().min (buf.len());
+    buf[..length].copy_from_slice (&rest[..length]);
+    self.position += length;
+    Ok (length)
+  }
+}
+
+impl From <PacketRecv> for Vec <u8> {
+  #[inline]
+  fn from (packet : PacketRecv) -> Self {
+    packet.into_vec()
+  }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+//  functions                                                                 //
+////////////////////////////////////////////////////////////////////////////////
+
+/// Data of an ENet packet
+unsafe fn raw_data <'a> (raw : *mut ll::ENetPacket) -> &'a [u8] {
+  unsafe {
+    let len = (*raw).dataLength;
+    if len == 0 {
+      return &[]
+    }
+    std::slice::from_raw_parts ((*raw).data, len)
+  }
+}
+
+/// Report the release of a packet that has not yet been queued as a
+/// `Delivered` or `Dropped` event for the peer
+pub(crate) unsafe fn track (raw : *mut ll::ENetPacket,
+  ticket  : SendTicket,
+  peer    : *mut ll::ENetPeer,
+  pending : Rc <event::Queue>
+) {
+  let connect_id = unsafe { (*peer).connectID };
+  let tracking = Box::new (Tracking { ticket, peer, connect_id, pending });
+  unsafe {
+    (*raw).userData     = Box::into_raw (tracking).cast();
+    (*raw).freeCallback = Some (tracked_free);
+  }
+}
+
+/// Detach the tracking of a packet that could not be queued
+pub(crate) unsafe fn untrack (raw : *mut ll::ENetPacket) {
+  unsafe {
+    (*raw).freeCallback = None;
+    drop (Box::from_raw ((*raw).userData.cast::<Tracking>()));
+    (*raw).userData = std::ptr::null_mut();
+  }
+}
+
+/// Free callback of tracked packets.
+///
+/// ENet sets the `SENT` flag of a reliable packet only when the last
+/// acknowledgement for it is received; a packet released without it was
+/// discarded with the queues of the peer.
+unsafe extern "C" fn tracked_free (raw : *mut ll::ENetPacket) {
+  let tracking = unsafe {
+    let tracking = Box::from_raw ((*raw).userData.cast::<Tracking>());
+    (*raw).userData = std::ptr::null_mut();
+    tracking
+  };
+  let Tracking { ticket, peer, connect_id, pending } = *tracking;
+  let delivered = unsafe { (*raw).flags } & Flags::SENT.bits() != 0;
+  pending.push (if delivered {
+    event::Pending::Delivered { peer, connect_id, ticket }
+  } else {
+    event::Pending::Dropped { peer, connect_id, ticket }
+  });
+}
+
+////////////////////////////////////////////////////////////////////////////////
+//  tests                                                                     //
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg (test)]
+mod tests {
+  use std::sync::atomic::{AtomicUsize, Ordering};
+  use super::*;
+
+  static FREED : AtomicUsize = AtomicUsize::new (0);
+
+  unsafe extern "C" fn count_free (_ : *mut ll::ENetPacket) {
+    FREED.fetch_add (1, Ordering::Relaxed);
+  }
+
+  #[test]
+  fn packet_with_free_callback_is_copied() {
+    let packet = unsafe {
+      let raw = ll::enet_packet_create (b"data".as_ptr().cast(), 4,
+        Flags::RELIABLE.bits());
+      assert!(!raw.is_null());
+      (*raw).freeCallback = Some (count_free);
+      PacketRecv::from_raw (raw)
+    };
+    // the callback runs on this thread, before the packet can be sent away
+    assert_eq!(FREED.load (Ordering::Relaxed), 1);
+    assert!(matches!(packet.storage, Storage::Owned { .. }));
+    let packet = std::thread::spawn (move || packet).join().unwrap();
+    assert_eq!(packet.data(), b"data");
+    assert_eq!(packet.flags().bits(), Flags::RELIABLE.bits());
+    assert_eq!(packet.into_vec(), b"data");
+    assert_eq!(FREED.load (Ordering::Relaxed), 1);
+  }
+
+  /// Release a tracked packet, as ENet does, and return the queued events
+  fn release_tracked (flags : Flags) -> Vec <event::Pending> {
+    let pending = Rc::new (event::Queue::default());
+    let mut peer : ll::ENetPeer = unsafe { std::mem::zeroed() };
+    let peer = &raw mut peer;
+    unsafe {
+      (*peer).connectID = 7;
+      let raw = ll::enet_packet_create (b"data".as_ptr().cast(), 4,
+        Flags::RELIABLE.bits());
+      assert!(!raw.is_null());
+      track (raw, SendTicket::new (3), peer, pending.clone());
+      // the peer is reset before its queues are released
+      (*peer).connectID = 0;
+      (*raw).flags |= flags.bits();
+      ll::enet_packet_destroy (raw);
+    }
+    pending.take().into()
+  }
+
+  #[test]
+  fn tracked_packet_is_reported_once() {
+    let [event::Pending::Delivered { connect_id: 7, ticket, .. }] =
+      release_tracked (Flags::SENT)[..]
+    else {
+      panic!("expected one Delivered event")
+    };
+    assert_eq!(ticket.id(), 3);
+    let [event::Pending::Dropped { connect_id: 7, ticket, .. }] =
+      release_tracked (Flags::empty())[..]
+    else {
+      panic!("expected one Dropped event")
+    };
+    assert_eq!(ticket.id(), 3);
+  }
+
+  #[test]
+  fn untracked_packet_is_not_reported() {
+    let pending = Rc::new (event::Queue::default());
+    let mut peer : ll::ENetPeer = unsafe { std::mem::zeroed() };
+    unsafe {
+      let raw = ll::enet_packet_create (b"data".as_ptr().cast(), 4, 0);
+      track (raw, SendTicket::new (0), &raw mut peer, pending.clone());
+      untrack (raw);
+      ll::enet_packet_destroy (raw);
+    }
+    assert!(pending.take().is_empty());
+  }
+
+  #[test]
+  fn builder_grows_up_to_the_limit() {
+    use std::io::Write;
+    let mut builder = PacketBuilder::new (Flags::RELIABLE, 4, 100).unwrap();
+    assert!(builder.is_empty());
+    assert_eq!(builder.data(), b"");
+    builder.write_all (b"abc").unwrap();
+    assert_eq!(builder.data(), b"abc");
+    // past the initial capacity
+    builder.write_all (&[7; 60]).unwrap();
+    assert_eq!(builder.len(), 63);
+    assert_eq!(&builder.data()[..3], b"abc");
+    assert!(builder.data()[3..].iter().all (|byte| *byte == 7));
+    builder.write_all (&[8; 37]).unwrap();
+    assert_eq!(builder.len(), 100);
+    let err = builder.write (&[9]).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+    // a failed write leaves the data unchanged
+    assert_eq!(builder.len(), 100);
+    assert_eq!(builder.write (&[]).unwrap(), 0);
+    builder.clear();
+    assert!(builder.is_empty());
+    builder.write_all (b"again").unwrap();
+    let packet = builder.finish();
+    assert_eq!(packet.data(), b"again");
+    assert_eq!(packet.flags().bits(), Flags::RELIABLE.bits());
+    assert_eq!(packet.queued(), 0);
+  }
+
+  #[test]
+  fn built_packet_is_sent() {
+    use std::io::Write;
+    use crate::testing;
+    let mut pair = testing::connected_pair();
+    let mut builder = pair.client.packet_builder (Flags::RELIABLE, 0).unwrap();
+    write!(builder, "built {}", 42).unwrap();
+    let packet = builder.finish();
+    pair.client_peer.send_shared (1, &packet).unwrap();
+    assert_eq!(packet.queued(), 1);
+    let events = testing::pump_until (&mut [&mut pair.server, &mut pair.client],
+      testing::TIMEOUT, |events| !events.is_empty()).unwrap();
+    testing::assert_events (&events,
+      &[(0, testing::Expect::Receive { channel_id: 1, data: b"built 42" })]);
+  }
+
+  #[test]
+  fn shared_packet_flags_keep_unnamed_bits() {
+    const UNNAMED : u32 = 1 << 12;
+    let packet = SharedPacket::new (Packet::NoAllocate {
+      bytes: b"static", flags: Flags::RELIABLE
+    }).unwrap();
+    unsafe { (*packet.raw()).flags |= UNNAMED }
+    assert_eq!(packet.flags().bits(), Flags::RELIABLE.bits() | UNNAMED);
+    let builder = PacketBuilder::new (Flags::empty(), 4, 4).unwrap();
+    unsafe { (*builder.raw).flags |= UNNAMED }
+    assert_eq!(builder.flags().bits(), UNNAMED);
   }
 }
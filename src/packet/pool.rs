@@ -0,0 +1,297 @@
+//! Recycling of outgoing packets.
+//!
+//! A `PacketPool` set on a host with `host.set_packet_pool()` provides the
+//! packets created by `peer.send()` and `host.broadcast()` for
+//! `Packet::Allocate` data. Packets are grouped in size classes; a packet is
+//! taken from the smallest class that fits the data, and data larger than the
+//! largest class is allocated by ENet as usual.
+//!
+//! A pooled packet owns a data buffer of the pool, and its `freeCallback`
+//! returns the buffer to the pool when ENet releases the packet, that is once
+//! the host has sent it (and it has been acknowledged if reliable) and no one
+//! else holds a reference. ENet frees the packet header itself after the
+//! callback, so once the pool has grown to the working set, sending only
+//! allocates the packet header and the ENet commands queuing it; with
+//! `memory::Callbacks::recycle()` these are reused as well and sending does not
+//! allocate.
+//!
+//! `pool.write()` creates a pooled packet written in place instead of copied,
+//! to be queued with `peer.send_shared()` or `host.send_shared()`.
+//!
+//! `PacketPool` is a handle: clones share the same packets and statistics.
+
+use std;
+use std::cell::RefCell;
+use std::rc::{Rc, Weak};
+
+use ll;
+use crate::packet::{Flags, SharedPacket};
+
+/// Size classes of `PacketPool::default()`, in bytes
+pub const DEFAULT_SIZE_CLASSES : [usize; 4] = [64, 256, 1024, 4096];
+/// Packets kept in each size class by `PacketPool::default()`
+pub const DEFAULT_MAX_PER_CLASS : usize = 1024;
+
+////////////////////////////////////////////////////////////////////////////////
+//  structs                                                                   //
+////////////////////////////////////////////////////////////////////////////////
+
+/// Shared handle to a pool of outgoing packets
+#[derive(Clone, Debug)]
+pub struct PacketPool {
+  inner : Rc <RefCell <Inner>>
+}
+
+/// Pool statistics
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct Stats {
+  /// Packets created by the pool
+  pub allocated  : u64,
+  /// Packets taken from the pool instead of being created
+  pub reused     : u64,
+  /// Packets too large for any size class, allocated by ENet
+  pub oversize   : u64,
+  /// Packets allocated by ENet because their class was full
+  pub overflow   : u64,
+  /// Packets not yet released by ENet
+  pub in_use     : usize,
+  /// Packets ready to be reused
+  pub available  : usize
+}
+
+#[derive(Debug)]
+struct Inner {
+  classes       : Vec <SizeClass>,
+  max_per_class : usize,
+  stats         : Stats
+}
+
+#[derive(Debug)]
+struct SizeClass {
+  size      : usize,
+  #[expect(clippy::vec_box)] // the box is lent to packets as their `userData`
+  available : Vec <Box <Buffer>>,
+  /// Buffers owned by packets that ENet has not released yet
+  lent      : usize
+}
+
+/// Data buffer of a pooled packet, given to the packet in its `userData`
+#[derive(Debug)]
+struct Buffer {
+  pool  : Weak <RefCell <Inner>>,
+  class : usize,
+  data  : Box <[u8]>
+}
+
+////////////////////////////////////////////////////////////////////////////////
+//  impls                                                                     //
+////////////////////////////////////////////////////////////////////////////////
+
+impl PacketPool {
+  /// Create a pool with the given size classes, keeping up to `max_per_class`
+  /// packets in each
+  pub fn new (size_classes : &[usize], max_per_class : usize) -> Self {
+    let mut sizes = size_classes.to_vec();
+    sizes.sort_unstable();
+    sizes.dedup();
+    let classes = sizes.into_iter().filter (|size| 0 < *size)
+      .map (|size| SizeClass { size, available: Vec::new(), lent: 0 })
+      .collect();
+    PacketPool {
+      inner: Rc::new (RefCell::new (Inner {
+        classes, max_per_class, stats: Stats::default()
+      }))
+    }
+  }
+
+  pub fn stats (&self) -> Stats {
+    let inner = self.inner.borrow();
+    let (in_use, available) = inner.classes.iter().fold ((0, 0),
+      |(in_use, available), class|
+        (in_use + class.lent, available + class.available.len()));
+    Stats { in_use, available, .. inner.stats }
+  }
+
+  /// Free the buffers that are ready to be reused
+  pub fn shrink (&self) {
+    for class in &mut self.inner.borrow_mut().classes {
+      class.available = Vec::new();
+    }
+  }
+
+  /// A pooled packet of `len` bytes written in place by `write`, or `None` if
+  /// the data is too large for the pool or its class is full
+  pub fn write <F : FnOnce (&mut [u8])> (&self,
+    len : usize, flags : Flags, write : F
+  ) -> Option <SharedPacket> {
+    let raw = self.take (len, flags)?;
+    unsafe {
+      if 0 < len {
+        write (std::slice::from_raw_parts_mut ((*raw).data, len));
+      }
+      Some (SharedPacket::from_raw (raw))
+    }
+  }
+
+  /// A packet holding `bytes`, or `None` if the data is too large for the pool,
+  /// its class is full or a packet cannot be created.
+  ///
+  /// The buffer of the packet returns to the pool when the packet is destroyed.
+  pub(crate) fn acquire (&self, bytes : &[u8], flags : Flags)
+    -> Option <*mut ll::ENetPacket>
+  {
+    let raw = self.take (bytes.len(), flags)?;
+    unsafe {
+      std::ptr::copy_nonoverlapping (bytes.as_ptr(), (*raw).data, bytes.len());
+    }
+    Some (raw)
+  }
+
+  /// A packet of `len` bytes with the data left as the buffer had it
+  fn take (&self, len : usize, flags : Flags) -> Option <*mut ll::ENetPacket> {
+    let mut inner = self.inner.borrow_mut();
+    let max_per_class = inner.max_per_class;
+    let Some (index) = inner.classes.iter()
+      .position (|class| len <= class.size)
+    else {
+      inner.stats.oversize += 1;
+      return None
+    };
+    let class = &mut inner.classes[index];
+    let (mut buffer, reused) = if let Some (buffer) = class.available.pop() {
+      (buffer, true)
+    } else if class.lent < max_per_class {
+      let buffer = Box::new (Buffer {
+        pool:  Rc::downgrade (&self.inner),
+        class: index,
+        data:  vec![0; class.size].into_boxed_slice()
+      });
+      (buffer, false)
+    } else {
+      // the packet is allocated by ENet
+      inner.stats.overflow += 1;
+      return None
+    };
+    let raw = unsafe {
+      ll::enet_packet_create (buffer.data.as_mut_ptr().cast(), len,
+        (flags | Flags::NO_ALLOCATE).bits())
+    };
+    if raw.is_null() {
+      class.available.push (buffer);
+      return None
+    }
+    unsafe {
+      (*raw).userData     = Box::into_raw (buffer).cast();
+      (*raw).freeCallback = Some (recycle);
+    }
+    class.lent += 1;
+    if reused {
+      inner.stats.reused += 1;
+    } else {
+      inner.stats.allocated += 1;
+    }
+    Some (raw)
+  }
+}
+
+impl Default for PacketPool {
+  /// `DEFAULT_SIZE_CLASSES` with `DEFAULT_MAX_PER_CLASS` packets in each
+  fn default() -> Self {
+    PacketPool::new (&DEFAULT_SIZE_CLASSES, DEFAULT_MAX_PER_CLASS)
+  }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+//  functions                                                                 //
+////////////////////////////////////////////////////////////////////////////////
+
+/// Free callback of pooled packets: return the buffer to its pool, or free it
+/// if the pool was dropped
+unsafe extern "C" fn recycle (raw : *mut ll::ENetPacket) {
+  let buffer = unsafe {
+    let buffer = Box::from_raw ((*raw).userData.cast::<Buffer>());
+    (*raw).userData = std::ptr::null_mut();
+    buffer
+  };
+  let Some (inner) = buffer.pool.upgrade() else {
+    return
+  };
+  // ENet destroys packets while servicing the host or sending, never while the
+  // pool is borrowed
+  let mut inner = inner.borrow_mut();
+  let class = &mut inner.classes[buffer.class];
+  class.lent -= 1;
+  class.available.push (buffer);
+}
+
+////////////////////////////////////////////////////////////////////////////////
+//  tests                                                                     //
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg (test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn packet_is_reused_after_enet_releases_it() {
+    let pool = PacketPool::new (&[16], 4);
+    let first = pool.acquire (b"first", Flags::RELIABLE).unwrap();
+    let data = unsafe { (*first).data };
+    assert_eq!(pool.stats().in_use, 1);
+    unsafe { ll::enet_packet_destroy (first) }
+    assert_eq!(pool.stats().in_use, 0);
+    assert_eq!(pool.stats().available, 1);
+    let second = pool.acquire (b"second", Flags::RELIABLE).unwrap();
+    unsafe {
+      assert_eq!((*second).data, data);
+      assert_eq!(std::slice::from_raw_parts ((*second).data, (*second).dataLength),
+        b"second");
+      ll::enet_packet_destroy (second);
+    }
+    let stats = pool.stats();
+    assert_eq!((stats.allocated, stats.reused), (1, 1));
+  }
+
+  #[test]
+  fn referenced_packet_is_not_reused() {
+    let pool = PacketPool::new (&[16], 4);
+    let queued = pool.acquire (b"queued", Flags::empty()).unwrap();
+    // a reference held by ENet while the packet is queued
+    unsafe { (*queued).referenceCount = 1 }
+    let other = pool.acquire (b"other", Flags::empty()).unwrap();
+    unsafe {
+      assert_ne!((*other).data, (*queued).data);
+      assert_eq!(std::slice::from_raw_parts ((*queued).data, 6), b"queued");
+      ll::enet_packet_destroy (other);
+      (*queued).referenceCount = 0;
+      ll::enet_packet_destroy (queued);
+    }
+    assert_eq!(pool.stats().available, 2);
+  }
+
+  #[test]
+  fn full_class_and_oversize_are_left_to_enet() {
+    let pool = PacketPool::new (&[16], 1);
+    let kept = pool.acquire (b"kept", Flags::empty()).unwrap();
+    assert!(pool.acquire (b"full", Flags::empty()).is_none());
+    assert!(pool.acquire (&[0; 17], Flags::empty()).is_none());
+    let stats = pool.stats();
+    assert_eq!((stats.overflow, stats.oversize), (1, 1));
+    // a buffer released after the pool is dropped is freed
+    drop (pool);
+    unsafe { ll::enet_packet_destroy (kept) }
+  }
+
+  #[test]
+  fn write_fills_a_pooled_packet_in_place() {
+    let pool = PacketPool::new (&[16], 4);
+    let packet = pool.write (5, Flags::RELIABLE,
+      |data| data.copy_from_slice (b"write")).unwrap();
+    assert_eq!(packet.data(), b"write");
+    assert_eq!(packet.flags().bits(), Flags::RELIABLE.bits());
+    assert_eq!(pool.stats().in_use, 1);
+    drop (packet);
+    assert_eq!(pool.stats().available, 1);
+    assert!(pool.write (17, Flags::empty(), |_| unreachable!()).is_none());
+  }
+}
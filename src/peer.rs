@@ -3,11 +3,25 @@ use enum_primitive_derive::Primitive;
 use num_traits;
 
 use ll;
-use crate::{host, Address, Packet};
+use crate::{host, packet, Address, Packet, SharedPacket};
 
 /// (65536)
 #[expect(clippy::unnecessary_cast)]  // on windows ll flags are i32
 pub const PACKET_LOSS_SCALE : u32 = ll::ENET_PEER_PACKET_LOSS_SCALE as u32;
+/// Bytes a peer may have queued before `try_send()` refuses packets, unless
+/// changed with `host.set_send_budget()` (1 MiB)
+pub const DEFAULT_SEND_BUDGET : usize = 1 << 20;
+
+/// The queues of a peer differ from ENet 1.3.18 on, so the `enet-1-3-18`
+/// feature must match the version of the bindings
+const _ : () = {
+  let version = (ll::ENET_VERSION_MAJOR << 16) | (ll::ENET_VERSION_MINOR << 8) |
+    ll::ENET_VERSION_PATCH;
+  let enet_1_3_18 = (1 << 16) | (3 << 8) | 18;
+  assert!(cfg!(feature = "enet-1-3-18") == (enet_1_3_18 <= version),
+    "the `enet-1-3-18` feature must be enabled if and only if the enet-sys \
+    bindings are of ENet 1.3.18 or later");
+};
 
 ////////////////////////////////////////////////////////////////////////////////
 //  structs                                                                   //
@@ -43,10 +57,46 @@ pub struct Peer {
   hostdrop : std::rc::Rc <host::HostDrop>
 }
 
+/// A packet that could not be sent, with the payload given back to the caller
+#[derive(Clone, Debug)]
+pub struct SendError {
+  kind    : SendErrorKind,
+  payload : Vec <u8>,
+  flags   : packet::Flags
+}
+
+/// State of a peer channel, from `peer.channel_stats()`
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct ChannelStats {
+  pub channel_id : u8,
+  /// Sequence number of the last reliable packet queued
+  pub outgoing_reliable_sequence   : u16,
+  /// Sequence number of the last unreliable packet queued
+  pub outgoing_unreliable_sequence : u16,
+  /// Sequence number of the last reliable packet received in order
+  pub incoming_reliable_sequence   : u16,
+  /// Sequence number of the last unreliable packet received in order
+  pub incoming_unreliable_sequence : u16,
+  /// Commands queued and not yet sent
+  pub queued_outgoing : usize,
+  /// Reliable commands sent and waiting for acknowledgement
+  pub sent_reliable   : usize,
+  /// Received commands waiting to be returned as events
+  pub dispatched      : usize,
+  /// Application payload bytes queued on the channel, before encryption;
+  /// session layer messages are not counted
+  pub bytes_sent      : u64,
+  /// Application payload bytes returned in `Receive` events on the channel,
+  /// after decryption
+  pub bytes_received  : u64
+}
+
 ////////////////////////////////////////////////////////////////////////////////
 //  enums                                                                     //
 ////////////////////////////////////////////////////////////////////////////////
 
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 #[derive(Copy, Clone, Debug, Eq, PartialEq, Primitive)]
 pub enum State {
   Disconnected         = ll::_ENetPeerState_ENET_PEER_STATE_DISCONNECTED       as isize,
@@ -64,18 +114,25 @@ pub enum State {
   Zombie               = ll::_ENetPeerState_ENET_PEER_STATE_ZOMBIE             as isize
 }
 
-#[derive(Debug)]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
 pub enum ConnectError {
   NoPeersAvailable,
   /// failure due to internal malloc failure of channel allocation
   Failure
 }
 
-#[derive(Debug)]
-pub enum SendError {
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum SendErrorKind {
   PeerNotConnected (State),
+  /// the host has a handshake set and the peer has not answered it
+  PeerNotAuthenticated,
+  /// the host has encryption set and the key exchange with the peer is not
+  /// complete
+  KeyExchangePending,
   PeerNoChannelID (u8),
   PacketCreateZeroLength,
+  /// `try_send()` would exceed the send budget of the host
+  WouldBlock,
   /// packet creation failed due to internal malloc call failing
   PacketCreateMallocFailure,
   /// packet size exceeds raw value of `peer.host->maximumPacketSize`
@@ -116,6 +173,12 @@ impl Peer {
     unsafe { (*self.raw).incomingPeerID }
   }
 
+  /// Random identifier of the current connection using this peer
+  #[inline]
+  pub fn connect_id (&self) -> u32 {
+    unsafe { (*self.raw).connectID }
+  }
+
   #[inline]
   pub fn state (&self) -> State {
     use num_traits::FromPrimitive;
@@ -123,6 +186,67 @@ impl Peer {
     unsafe { State::from_u32 ((*self.raw).state as u32).unwrap() }
   }
 
+  /// Bytes queued to be sent to the peer, plus reliable data sent but not yet
+  /// acknowledged
+  pub fn queued_bytes (&self) -> usize {
+    unsafe {
+      (*self.raw).reliableDataInTransit as usize +
+        outgoing_bytes (self.raw, false)
+    }
+  }
+
+  /// Sequence numbers, queued commands and bytes of a channel, or `None` if
+  /// the peer has no such channel.
+  ///
+  /// Bytes are counted over the current connection of the peer.
+  pub fn channel_stats (&self, channel_id : u8) -> Option <ChannelStats> {
+    unsafe {
+      if (*self.raw).channelCount <= usize::from (channel_id) {
+        return None
+      }
+      let channel = (*self.raw).channels.add (usize::from (channel_id));
+      let outgoing = |list : *mut ll::ENetList| list_nodes (list)
+        .filter (|node| {
+          // the list node is the first field of the command
+          let command = node.cast::<ll::ENetOutgoingCommand>();
+          (*command).command.header.channelID == channel_id
+        }).count();
+      let (bytes_sent, bytes_received) =
+        self.hostdrop.state.borrow().channel_bytes (self.raw, channel_id);
+      Some (ChannelStats {
+        channel_id,
+        outgoing_reliable_sequence:   (*channel).outgoingReliableSequenceNumber,
+        outgoing_unreliable_sequence: (*channel).outgoingUnreliableSequenceNumber,
+        incoming_reliable_sequence:   (*channel).incomingReliableSequenceNumber,
+        incoming_unreliable_sequence: (*channel).incomingUnreliableSequenceNumber,
+        queued_outgoing: outgoing_lists (self.raw).map (outgoing).sum(),
+        sent_reliable:   outgoing (&raw mut (*self.raw).sentReliableCommands),
+        dispatched: list_nodes (&raw mut (*self.raw).dispatchedCommands)
+          .filter (|node| {
+            let command = node.cast::<ll::ENetIncomingCommand>();
+            (*command).command.header.channelID == channel_id
+          }).count(),
+        bytes_sent,
+        bytes_received
+      })
+    }
+  }
+
+  /// Estimate of the current time of the clock of the remote host; see the
+  /// `clock` module.
+  ///
+  /// Returns `None` if the host has no `ClockSync` or the peer has not
+  /// answered a probe yet.
+  pub fn remote_time_now (&self) -> Option <std::time::Duration> {
+    let clock_sync = self.hostdrop.state.borrow().clock_sync.clone()?;
+    clock_sync.remote_time (self)
+  }
+
+  /// Payload bytes sent and received over the current connection
+  pub(crate) fn bytes_transferred (&self) -> (u64, u64) {
+    self.hostdrop.state.borrow().peer_bytes (self.raw)
+  }
+
   #[inline]
   pub fn address (&self) -> Address {
     unsafe { Address::from_ll ((*self.raw).address) }
@@ -236,52 +360,168 @@ impl Peer {
     }
   }
 
+  /// Queue a packet to be sent on a channel.
+  ///
+  /// On failure the payload is returned in the error.
   pub fn send (&mut self, channel_id : u8, packet : Packet) -> Result <(), SendError> {
-    use num_traits::FromPrimitive;
+    self.send_packet (channel_id, packet, None)
+      .map_err (|kind| SendError::new (kind, packet))
+  }
+
+  /// Queue a packet like `send()`, returning a ticket that is reported in an
+  /// `Event::Delivered` once the peer has acknowledged the packet, or in an
+  /// `Event::Dropped` if the peer disconnects or is reset first.
+  ///
+  /// The packet is always sent reliably, and is not taken from the packet pool
+  /// of the host.
+  pub fn send_tracked (&mut self, channel_id : u8, packet : Packet)
+    -> Result <packet::SendTicket, SendError>
+  {
+    let ticket = self.hostdrop.state.borrow_mut().next_ticket();
+    self.send_packet (channel_id, packet, Some (ticket))
+      .map (|()| ticket)
+      .map_err (|kind| SendError::new (kind, packet))
+  }
+
+  /// Queue a packet like `send()` unless the bytes queued for the peer would
+  /// exceed the send budget of the host, in which case `WouldBlock` is
+  /// returned.
+  ///
+  /// After a `WouldBlock`, an `Event::Drained` is generated for the peer once
+  /// all of its reliable data has been sent and acknowledged.
+  pub fn try_send (&mut self, channel_id : u8, packet : Packet)
+    -> Result <(), SendError>
+  {
+    let budget = self.hostdrop.state.borrow().send_budget();
+    if budget < self.queued_bytes() + packet.parts().0.len() {
+      self.hostdrop.state.borrow_mut().backlogged (self.raw);
+      return Err (SendError::new (SendErrorKind::WouldBlock, packet))
+    }
+    self.send (channel_id, packet)
+  }
+
+  /// Queue a shared packet on a channel without copying its data.
+  ///
+  /// If the session with the peer is encrypted, the packet is sealed into a
+  /// packet of its own.
+  pub fn send_shared (&mut self, channel_id : u8, packet : &SharedPacket)
+    -> Result <(), SendErrorKind>
+  {
+    self.check_send (channel_id)?;
+    #[cfg(feature = "encryption")]
+    if self.hostdrop.state.borrow().encrypted (self.raw) {
+      return self.send_packet (channel_id,
+        Packet::Allocate { bytes: packet.data(), flags: packet.flags() }, None)
+    }
     unsafe {
-      if (*self.raw).state != ll::_ENetPeerState_ENET_PEER_STATE_CONNECTED {
-        #[expect(clippy::unnecessary_cast)]  // on windows ll flags are i32
-        return Err (SendError::PeerNotConnected(
-          State::from_u32 ((*self.raw).state as u32).unwrap()
-        ))
+      let raw = packet.raw();
+      if (*raw).dataLength == 0 {
+        return Err (SendErrorKind::PacketCreateZeroLength)
       }
-      if (*self.raw).channelCount as u8 <= channel_id {
-        return Err (SendError::PeerNoChannelID (channel_id))
+      if (*self.hostdrop.raw()).maximumPacketSize < (*raw).dataLength {
+        return Err (SendErrorKind::PacketExceedsMaximumSize ((*raw).dataLength))
       }
-      let raw = match packet {
-        Packet::Allocate { bytes, flags } => {
-          if (*self.hostdrop.raw()).maximumPacketSize < bytes.len() {
-            return Err (SendError::PacketExceedsMaximumSize (bytes.len()))
-          }
-          if bytes.is_empty() {
-            return Err (SendError::PacketCreateZeroLength)
-          }
-          ll::enet_packet_create (
-            bytes.as_ptr() as *const std::os::raw::c_void, bytes.len(), flags.bits())
-        }
-        Packet::NoAllocate { bytes, flags } => {
-          if (*(*self.raw).host).maximumPacketSize < bytes.len() {
-            return Err (SendError::PacketExceedsMaximumSize(bytes.len()))
-          }
-          if bytes.is_empty() {
-            return Err (SendError::PacketCreateZeroLength)
-          }
-          #[expect(clippy::unnecessary_cast)]  // on windows ll flags are i32
-          ll::enet_packet_create (
-            bytes.as_ptr() as *const std::os::raw::c_void,
-            bytes.len(),
-            flags.bits() | ll::_ENetPacketFlag_ENET_PACKET_FLAG_NO_ALLOCATE as u32)
-        }
+      if ll::enet_peer_send (self.raw, channel_id, raw) < 0 {
+        return Err (SendErrorKind::Failure)
+      }
+      self.hostdrop.state.borrow_mut()
+        .count_sent (self.raw, channel_id, (*raw).dataLength);
+    }
+    Ok (())
+  }
+
+  /// Send a packet written with a `PacketBuilder` without copying it.
+  ///
+  /// On failure the payload is copied into the returned error.
+  pub fn send_builder (&mut self,
+    channel_id : u8, builder : packet::PacketBuilder
+  ) -> Result <(), SendError>
+  {
+    let packet = builder.finish();
+    self.send_shared (channel_id, &packet).map_err (|kind| SendError::new (kind,
+      Packet::Allocate { bytes: packet.data(), flags: packet.flags() }))
+  }
+
+  /// Check that the peer can be sent a packet on a channel
+  fn check_send (&self, channel_id : u8) -> Result <(), SendErrorKind> {
+    let state = self.state();
+    if state != State::Connected {
+      return Err (SendErrorKind::PeerNotConnected (state))
+    }
+    self.hostdrop.state.borrow().check_send (self.raw)?;
+    if unsafe { (*self.raw).channelCount } <= usize::from (channel_id) {
+      return Err (SendErrorKind::PeerNoChannelID (channel_id))
+    }
+    Ok (())
+  }
+
+  /// Queue a session layer message, e.g. for clock synchronization, through
+  /// the checks and encryption of `send()`; it is not counted as payload
+  pub(crate) fn send_session (&mut self,
+    channel_id : u8, bytes : &[u8], flags : packet::Flags
+  ) -> Result <(), SendErrorKind> {
+    self.queue_packet (channel_id, Packet::Allocate { bytes, flags }, None)
+  }
+
+  fn send_packet (&mut self,
+    channel_id : u8, packet : Packet, ticket : Option <packet::SendTicket>
+  ) -> Result <(), SendErrorKind>
+  {
+    self.queue_packet (channel_id, packet, ticket)?;
+    self.hostdrop.state.borrow_mut()
+      .count_sent (self.raw, channel_id, packet.parts().0.len());
+    Ok (())
+  }
+
+  fn queue_packet (&mut self,
+    channel_id : u8, packet : Packet, ticket : Option <packet::SendTicket>
+  ) -> Result <(), SendErrorKind>
+  {
+    self.check_send (channel_id)?;
+    unsafe {
+      let (bytes, flags) = match packet {
+        Packet::Allocate   { bytes, flags } => (bytes, flags),
+        Packet::NoAllocate { bytes, flags } =>
+          (bytes, flags | packet::Flags::NO_ALLOCATE)
+      };
+      if bytes.is_empty() {
+        return Err (SendErrorKind::PacketCreateZeroLength)
+      }
+      #[cfg(feature = "encryption")]
+      let sealed = self.hostdrop.state.borrow_mut()
+        .seal (self.raw, channel_id, bytes);
+      #[cfg(feature = "encryption")]
+      let (bytes, flags) = match sealed.as_ref() {
+        Some (sealed) => (sealed.as_slice(), flags - packet::Flags::NO_ALLOCATE),
+        None => (bytes, flags)
+      };
+      if (*self.hostdrop.raw()).maximumPacketSize < bytes.len() {
+        return Err (SendErrorKind::PacketExceedsMaximumSize (bytes.len()))
+      }
+      let raw = if ticket.is_some() {
+        // a tracked packet must be released by ENet
+        let flags = (flags | packet::Flags::RELIABLE) - packet::Flags::SENT;
+        ll::enet_packet_create (bytes.as_ptr() as *const std::os::raw::c_void,
+          bytes.len(), flags.bits())
+      } else {
+        self.hostdrop.state.borrow().create_packet (bytes, flags)
       };
       if raw.is_null() {
-        return Err (SendError::PacketCreateMallocFailure)
+        return Err (SendErrorKind::PacketCreateMallocFailure)
+      }
+      if let Some (ticket) = ticket {
+        packet::track (raw, ticket, self.raw, self.hostdrop.pending.clone());
       }
       if ll::enet_peer_send (self.raw(), channel_id, raw) < 0 {
-        return Err (SendError::Failure)
+        if ticket.is_some() {
+          packet::untrack (raw);
+        }
+        ll::enet_packet_destroy (raw);
+        return Err (SendErrorKind::Failure)
       }
       Ok(())
     }
-  } // end send
+  } // end queue_packet
 
   // TODO: expose data parameter in the following ?
 
@@ -299,9 +539,9 @@ impl Peer {
   /// Force immediate disconnection
   #[inline]
   pub fn disconnect_now (&self) {
-    unsafe {
+    self.hostdrop.send_resetting (unsafe { self.raw() }, || unsafe {
       ll::enet_peer_disconnect_now (self.raw(), 0)
-    }
+    })
   }
 
   /// Request disconnection after all queued outgoing packets are sent
@@ -323,3 +563,265 @@ impl Peer {
   }
 
 } // end impl Peer
+
+impl SendError {
+  pub(crate) fn new (kind : SendErrorKind, packet : Packet) -> Self {
+    let (bytes, flags) = packet.parts();
+    SendError { kind, payload: bytes.to_vec(), flags }
+  }
+
+  #[inline]
+  pub const fn kind (&self) -> SendErrorKind {
+    self.kind
+  }
+
+  /// The payload of the packet that was not sent
+  #[inline]
+  pub fn payload (&self) -> &[u8] {
+    &self.payload
+  }
+
+  /// The flags of the packet that was not sent
+  #[inline]
+  pub const fn flags (&self) -> packet::Flags {
+    self.flags
+  }
+
+  /// The packet that was not sent, e.g. to retry sending it
+  #[inline]
+  pub fn packet (&self) -> Packet <'_> {
+    Packet::Allocate { bytes: &self.payload, flags: self.flags }
+  }
+
+  #[inline]
+  pub fn into_payload (self) -> Vec <u8> {
+    self.payload
+  }
+}
+
+impl std::fmt::Display for SendError {
+  fn fmt (&self, f : &mut std::fmt::Formatter) -> std::fmt::Result {
+    write!(f, "failed to send {} byte packet: {}", self.payload.len(),
+      self.kind)
+  }
+}
+impl std::error::Error for SendError {}
+
+impl SendErrorKind {
+  /// True if sending may succeed later without the application changing
+  /// anything, e.g. once the key exchange completes
+  pub const fn is_transient (self) -> bool {
+    matches!(self,
+      SendErrorKind::PeerNotAuthenticated |
+      SendErrorKind::KeyExchangePending |
+      SendErrorKind::WouldBlock |
+      SendErrorKind::PacketCreateMallocFailure)
+  }
+}
+
+impl std::fmt::Display for SendErrorKind {
+  fn fmt (&self, f : &mut std::fmt::Formatter) -> std::fmt::Result {
+    match self {
+      SendErrorKind::PeerNotConnected (state) =>
+        write!(f, "peer is not connected ({state:?})"),
+      SendErrorKind::PeerNotAuthenticated =>
+        write!(f, "peer has not completed the handshake"),
+      SendErrorKind::KeyExchangePending =>
+        write!(f, "key exchange with the peer is not complete"),
+      SendErrorKind::PeerNoChannelID (channel_id) =>
+        write!(f, "peer has no channel {channel_id}"),
+      SendErrorKind::PacketCreateZeroLength =>
+        write!(f, "packet is empty"),
+      SendErrorKind::WouldBlock =>
+        write!(f, "peer send budget exceeded"),
+      SendErrorKind::PacketCreateMallocFailure =>
+        write!(f, "packet allocation failed"),
+      SendErrorKind::PacketExceedsMaximumSize (size) =>
+        write!(f, "packet size {size} exceeds the host maximum packet size"),
+      SendErrorKind::Failure =>
+        write!(f, "packet could not be queued")
+    }
+  }
+}
+
+impl std::fmt::Display for ConnectError {
+  fn fmt (&self, f : &mut std::fmt::Formatter) -> std::fmt::Result {
+    match self {
+      ConnectError::NoPeersAvailable => write!(f, "no peers available"),
+      ConnectError::Failure => write!(f, "connection could not be started")
+    }
+  }
+}
+impl std::error::Error for ConnectError {}
+
+////////////////////////////////////////////////////////////////////////////////
+//  functions                                                                 //
+////////////////////////////////////////////////////////////////////////////////
+
+/// True if the peer has no reliable data queued or waiting for acknowledgement
+pub(crate) unsafe fn reliable_drained (peer : *mut ll::ENetPeer) -> bool {
+  unsafe {
+    (*peer).reliableDataInTransit == 0 && outgoing_bytes (peer, true) == 0
+  }
+}
+
+/// Nodes of an ENet list
+unsafe fn list_nodes (list : *mut ll::ENetList)
+  -> impl Iterator <Item = *mut ll::ENetListNode>
+{
+  let sentinel = unsafe { &raw mut (*list).sentinel };
+  std::iter::successors (Some (unsafe { (*sentinel).next }),
+    |node| Some (unsafe { (**node).next })
+  ).take_while (move |node| *node != sentinel)
+}
+
+/// Lists of the commands queued to be sent to a peer
+unsafe fn outgoing_lists (peer : *mut ll::ENetPeer)
+  -> impl Iterator <Item = *mut ll::ENetList>
+{
+  unsafe {
+    [
+      &raw mut (*peer).outgoingCommands,
+      // ENet 1.3.18 moved reliable commands to a list of their own
+      #[cfg(feature = "enet-1-3-18")]
+      &raw mut (*peer).outgoingSendReliableCommands
+    ].into_iter()
+  }
+}
+
+/// Bytes of the packets in the outgoing queues of a peer
+unsafe fn outgoing_bytes (peer : *mut ll::ENetPeer, reliable_only : bool)
+  -> usize
+{
+  #[expect(clippy::unnecessary_cast)] // on windows ll flags are i32
+  const RELIABLE : u32 = ll::_ENetPacketFlag_ENET_PACKET_FLAG_RELIABLE as u32;
+  unsafe {
+    outgoing_lists (peer).flat_map (|list| list_nodes (list)).map (|node| {
+      // the list node is the first field of the command
+      let command = node.cast::<ll::ENetOutgoingCommand>();
+      let packet  = (*command).packet;
+      if !packet.is_null() &&
+        (!reliable_only || (*packet).flags & RELIABLE != 0)
+      {
+        usize::from ((*command).fragmentLength)
+      } else {
+        0
+      }
+    }).sum()
+  }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+//  tests                                                                     //
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg (test)]
+mod tests {
+  use super::*;
+  use crate::{testing, Event};
+
+  #[test]
+  fn send_error_display_and_transience() {
+    let error = SendError::new (SendErrorKind::PeerNoChannelID (7),
+      Packet::Allocate { bytes: b"data", flags: packet::Flags::RELIABLE });
+    assert_eq!(error.to_string(),
+      "failed to send 4 byte packet: peer has no channel 7");
+    assert!(std::error::Error::source (&error).is_none());
+    let clone = error.clone();
+    assert_eq!(clone.kind(), error.kind());
+    assert_eq!(clone.payload(), b"data");
+    assert_eq!(clone.flags().bits(), packet::Flags::RELIABLE.bits());
+    let transient = [
+      SendErrorKind::PeerNotAuthenticated,
+      SendErrorKind::KeyExchangePending,
+      SendErrorKind::WouldBlock,
+      SendErrorKind::PacketCreateMallocFailure
+    ];
+    for kind in transient {
+      assert!(kind.is_transient(), "{kind:?}");
+    }
+    let permanent = [
+      SendErrorKind::PeerNotConnected (State::Disconnected),
+      SendErrorKind::PeerNoChannelID (7),
+      SendErrorKind::PacketCreateZeroLength,
+      SendErrorKind::PacketExceedsMaximumSize (1),
+      SendErrorKind::Failure
+    ];
+    for kind in permanent {
+      assert!(!kind.is_transient(), "{kind:?}");
+    }
+  }
+
+  #[test]
+  fn try_send_past_the_budget_is_drained_once() {
+    let mut pair = testing::connected_pair();
+    pair.client.set_send_budget (1000);
+    let packet = Packet::Allocate {
+      bytes: &[7; 400], flags: packet::Flags::RELIABLE
+    };
+    pair.client_peer.try_send (0, packet).unwrap();
+    pair.client_peer.try_send (0, packet).unwrap();
+    assert_eq!(pair.client_peer.try_send (0, packet).unwrap_err().kind(),
+      SendErrorKind::WouldBlock);
+    // refusing again does not generate another event
+    assert_eq!(pair.client_peer.try_send (0, packet).unwrap_err().kind(),
+      SendErrorKind::WouldBlock);
+    let drained = |events : &[testing::Recorded]| events.iter()
+      .filter (|recorded| matches!(recorded.event, Event::Drained {..}))
+      .count();
+    let mut events = testing::pump_until (&mut [&mut pair.server, &mut pair.client],
+      testing::TIMEOUT, |events| 0 < drained (events)).unwrap();
+    assert_eq!(pair.client_peer.queued_bytes(), 0);
+    let testing::PumpError::Timeout (more) = testing::pump_until (
+      &mut [&mut pair.server, &mut pair.client],
+      std::time::Duration::from_millis (200), |events| 0 < drained (events)
+    ).unwrap_err() else {
+      panic!("servicing failed")
+    };
+    events.extend (more);
+    assert_eq!(drained (&events), 1);
+    let received = events.iter().filter (|recorded|
+      matches!(&recorded.event, Event::Receive { packet, .. }
+        if packet.data() == [7; 400])
+    ).count();
+    assert_eq!(received, 2);
+    // the budget is available again
+    pair.client_peer.try_send (0, packet).unwrap();
+  }
+
+  #[test]
+  fn channel_stats_count_queued_and_received_packets() {
+    let mut pair = testing::connected_pair();
+    let send = |peer : &mut Peer, channel_id, bytes : &[u8], flags|
+      peer.send (channel_id, Packet::Allocate { bytes, flags }).unwrap();
+    send (&mut pair.client_peer, 0, b"zero", packet::Flags::RELIABLE);
+    send (&mut pair.client_peer, 1, b"one", packet::Flags::RELIABLE);
+    send (&mut pair.client_peer, 1, b"two", packet::Flags::RELIABLE);
+    for _ in 0..3 {
+      send (&mut pair.client_peer, 1, b"four", packet::Flags::empty());
+    }
+    let stats = pair.client_peer.channel_stats (1).unwrap();
+    assert_eq!(stats.channel_id, 1);
+    assert_eq!(stats.outgoing_reliable_sequence, 2);
+    assert_eq!(stats.outgoing_unreliable_sequence, 3);
+    assert_eq!(stats.queued_outgoing, 5);
+    assert_eq!(stats.sent_reliable, 0);
+    assert_eq!(stats.bytes_sent, 18);
+    let stats = pair.client_peer.channel_stats (0).unwrap();
+    assert_eq!(stats.outgoing_reliable_sequence, 1);
+    assert_eq!(stats.outgoing_unreliable_sequence, 0);
+    assert_eq!(stats.queued_outgoing, 1);
+    assert_eq!(stats.bytes_sent, 4);
+    assert_eq!(pair.client_peer.channel_stats (2), None);
+    testing::pump_until (&mut [&mut pair.server, &mut pair.client],
+      testing::TIMEOUT, |events| events.len() == 6).unwrap();
+    let stats = pair.server_peer.channel_stats (1).unwrap();
+    assert_eq!(stats.incoming_reliable_sequence, 2);
+    assert_eq!(stats.incoming_unreliable_sequence, 3);
+    assert_eq!(stats.dispatched, 0);
+    assert_eq!(stats.bytes_received, 18);
+    assert_eq!(pair.server_peer.channel_stats (0).unwrap().bytes_received, 4);
+    let stats = pair.client_peer.channel_stats (1).unwrap();
+    assert_eq!(stats.queued_outgoing, 0);
+  }
+}
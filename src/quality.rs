@@ -0,0 +1,278 @@
+//! Connection quality levels with threshold events.
+//!
+//! A `QualityMonitor` set on a host with `host.set_quality_monitor()` checks
+//! the round trip time, jitter (round trip time variance) and packet loss of
+//! each connected peer when the host is serviced, and generates an
+//! `Event::QualityChanged` when the `Level` of a peer changes.
+//!
+//! A peer enters a worse level as soon as one of its values reaches the limit
+//! of that level. It only leaves the level once each value has fallen the
+//! `hysteresis` percentage below the limit, so that a value hovering around a
+//! limit does not flap between levels.
+//!
+//! A peer is `TimingOut` when reliable data sent to it has not been
+//! acknowledged for `timeout_warning` percent of its minimum timeout (see
+//! `peer.timeout()`), after which ENet may disconnect it.
+//!
+//! `QualityMonitor` is a handle: clones share the same thresholds and levels.
+
+use std;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use ll;
+use crate::{peer, Host, Peer};
+
+////////////////////////////////////////////////////////////////////////////////
+//  structs                                                                   //
+////////////////////////////////////////////////////////////////////////////////
+
+/// Shared handle to the thresholds and peer levels of a quality monitor
+#[derive(Clone, Debug, Default)]
+pub struct QualityMonitor {
+  inner : Rc <RefCell <Inner>>
+}
+
+/// Values at which a peer enters the `Degraded` and `Poor` levels
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Limits {
+  pub degraded : u32,
+  pub poor     : u32
+}
+
+/// Thresholds of a `QualityMonitor`
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Thresholds {
+  /// Round trip time in milliseconds (150, 300)
+  pub round_trip_time : Limits,
+  /// Round trip time variance in milliseconds (50, 100)
+  pub jitter          : Limits,
+  /// Packet loss scaled by `peer::PACKET_LOSS_SCALE` (5%, 15%)
+  pub packet_loss     : Limits,
+  /// Percentage below a limit that a value must fall to leave the level (20)
+  pub hysteresis      : u32,
+  /// Percentage of the minimum timeout of a peer that reliable data may stay
+  /// unacknowledged before the peer is `TimingOut` (50)
+  pub timeout_warning : u32,
+  /// Time after a peer is first seen during which only `TimingOut` is reported,
+  /// while ENet has not yet measured the round trip time (2 seconds)
+  pub warmup          : Duration
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+  thresholds : Thresholds,
+  /// Indexed by `incomingPeerID`
+  peers      : HashMap <u16, PeerQuality>
+}
+
+#[derive(Debug)]
+struct PeerQuality {
+  connect_id : u32,
+  first_seen : Instant,
+  level      : Level
+}
+
+////////////////////////////////////////////////////////////////////////////////
+//  enums                                                                     //
+////////////////////////////////////////////////////////////////////////////////
+
+/// Connection quality of a peer, from best to worst
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub enum Level {
+  #[default]
+  Good,
+  Degraded,
+  Poor,
+  /// Reliable data has not been acknowledged for a large part of the peer
+  /// timeout
+  TimingOut
+}
+
+////////////////////////////////////////////////////////////////////////////////
+//  impls                                                                     //
+////////////////////////////////////////////////////////////////////////////////
+
+impl QualityMonitor {
+  pub fn new (thresholds : Thresholds) -> Self {
+    QualityMonitor {
+      inner: Rc::new (RefCell::new (Inner {
+        thresholds, peers: HashMap::new()
+      }))
+    }
+  }
+
+  pub fn thresholds (&self) -> Thresholds {
+    self.inner.borrow().thresholds
+  }
+
+  /// Change the thresholds; levels are updated when the host is next serviced
+  pub fn set_thresholds (&self, thresholds : Thresholds) {
+    self.inner.borrow_mut().thresholds = thresholds;
+  }
+
+  /// The last level reported for a peer; `Good` for a peer that has not been
+  /// checked yet
+  pub fn level (&self, peer : &Peer) -> Level {
+    self.inner.borrow().peers.get (&peer.incoming_peer_id())
+      .filter (|quality| quality.connect_id == peer.connect_id())
+      .map_or (Level::Good, |quality| quality.level)
+  }
+
+  /// Update the levels of the connected peers of the host, returning the peers
+  /// whose level changed
+  pub(crate) fn check (&self, host : &Host) -> Vec <(Peer, Level)> {
+    let now = Instant::now();
+    let mut inner = self.inner.borrow_mut();
+    let thresholds = inner.thresholds;
+    let mut changed = Vec::new();
+    for peer in host.peers() {
+      let index = peer.incoming_peer_id();
+      if peer.state() != peer::State::Connected {
+        inner.peers.remove (&index);
+        continue
+      }
+      let connect_id = peer.connect_id();
+      let quality = inner.peers.entry (index)
+        .or_insert_with (|| PeerQuality::new (connect_id, now));
+      if quality.connect_id != connect_id {
+        // the peer slot is used by a new connection
+        *quality = PeerQuality::new (connect_id, now);
+      }
+      let warm = thresholds.warmup <= now - quality.first_seen;
+      let level = thresholds.level (&peer, quality.level, warm);
+      if level != quality.level {
+        quality.level = level;
+        changed.push ((peer, level));
+      }
+    }
+    changed
+  }
+}
+
+impl Limits {
+  /// The level of a value, with limits lowered by `margin` percent
+  const fn level (self, value : u32, margin : u32) -> Level {
+    const fn lower (limit : u32, margin : u32) -> u32 {
+      limit / 100 * (100 - margin) + limit % 100 * (100 - margin) / 100
+    }
+    if lower (self.poor, margin) <= value {
+      Level::Poor
+    } else if lower (self.degraded, margin) <= value {
+      Level::Degraded
+    } else {
+      Level::Good
+    }
+  }
+
+  /// The level of a value for a peer at the `current` level: worse levels are
+  /// entered at the limits, better levels at the limits lowered by the
+  /// hysteresis
+  fn next (self, current : Level, value : u32, hysteresis : u32) -> Level {
+    let level = self.level (value, 0);
+    if current <= level {
+      level
+    } else {
+      current.min (self.level (value, hysteresis.min (100)))
+    }
+  }
+}
+
+impl Thresholds {
+  fn level (&self, peer : &Peer, current : Level, warm : bool) -> Level {
+    if self.timing_out (peer) {
+      return Level::TimingOut
+    }
+    if !warm {
+      return Level::Good
+    }
+    // a peer leaving `TimingOut` is treated as `Poor`, so that it only
+    // recovers once its values are below the lowered limits
+    let current = current.min (Level::Poor);
+    [ (self.round_trip_time, peer.round_trip_time()),
+      (self.jitter,          peer.round_trip_time_variance()),
+      (self.packet_loss,     peer.packet_loss())
+    ].into_iter()
+      .map (|(limits, value)| limits.next (current, value, self.hysteresis))
+      .max().unwrap_or_default()
+  }
+
+  /// True if the oldest unacknowledged reliable data sent to the peer has been
+  /// waiting for `timeout_warning` percent of its minimum timeout
+  fn timing_out (&self, peer : &Peer) -> bool {
+    unsafe {
+      let raw = peer.raw();
+      if (*raw).earliestTimeout == 0 {
+        return false
+      }
+      let waiting = ll::enet_time_get().wrapping_sub ((*raw).earliestTimeout);
+      u64::from ((*raw).timeoutMinimum) * u64::from (self.timeout_warning) <=
+        u64::from (waiting) * 100
+    }
+  }
+}
+
+impl Default for Thresholds {
+  fn default() -> Self {
+    Thresholds {
+      round_trip_time: Limits { degraded: 150, poor: 300 },
+      jitter:          Limits { degraded: 50,  poor: 100 },
+      packet_loss:     Limits {
+        degraded: peer::PACKET_LOSS_SCALE / 20,
+        poor:     peer::PACKET_LOSS_SCALE * 3 / 20
+      },
+      hysteresis:      20,
+      timeout_warning: 50,
+      warmup:          Duration::from_secs (2)
+    }
+  }
+}
+
+impl PeerQuality {
+  const fn new (connect_id : u32, first_seen : Instant) -> Self {
+    PeerQuality { connect_id, first_seen, level: Level::Good }
+  }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+//  tests                                                                     //
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg (test)]
+mod tests {
+  use super::*;
+
+  const LIMITS : Limits = Limits { degraded: 100, poor: 200 };
+
+  #[test]
+  fn worse_levels_are_entered_at_the_limits() {
+    assert_eq!(LIMITS.next (Level::Good, 99, 20),  Level::Good);
+    assert_eq!(LIMITS.next (Level::Good, 100, 20), Level::Degraded);
+    assert_eq!(LIMITS.next (Level::Good, 200, 20), Level::Poor);
+    assert_eq!(LIMITS.next (Level::Degraded, 199, 20), Level::Degraded);
+    assert_eq!(LIMITS.next (Level::Degraded, 200, 20), Level::Poor);
+  }
+
+  #[test]
+  fn better_levels_are_entered_below_the_lowered_limits() {
+    // the limits lowered by 20 percent are 80 and 160
+    assert_eq!(LIMITS.next (Level::Degraded, 80, 20), Level::Degraded);
+    assert_eq!(LIMITS.next (Level::Degraded, 79, 20), Level::Good);
+    assert_eq!(LIMITS.next (Level::Poor, 160, 20), Level::Poor);
+    assert_eq!(LIMITS.next (Level::Poor, 159, 20), Level::Degraded);
+    assert_eq!(LIMITS.next (Level::Poor, 79, 20),  Level::Good);
+    // without hysteresis the limits apply both ways
+    assert_eq!(LIMITS.next (Level::Poor, 99, 0), Level::Good);
+  }
+
+  #[test]
+  fn hysteresis_is_capped() {
+    // a hysteresis of 100 percent or more lowers the limits to zero
+    assert_eq!(LIMITS.next (Level::Degraded, 0, 100), Level::Degraded);
+    assert_eq!(LIMITS.next (Level::Poor, 0, 250), Level::Poor);
+    assert_eq!(LIMITS.next (Level::Good, 99, 250), Level::Good);
+  }
+}
@@ -0,0 +1,542 @@
+//! Server info queries without a connection.
+//!
+//! A `QueryResponder` set on a server host with `host.set_query_responder()`
+//! answers query datagrams received on the host port with the current
+//! `ServerInfo`. Queries are recognized by the host intercept and never reach
+//! ENet. Answers are rate limited per IP address and in total, so that the
+//! responder cannot be used to flood a spoofed address.
+//!
+//! A `ServerBrowser` sends queries to many servers at once from its own socket
+//! and reports the info and round trip time of each server that answers.
+//!
+//! Query datagrams start with the bytes `FF FF 'Q' '?'` and answers with
+//! `FF FF 'Q' '!'`. A query is padded to `QUERY_LENGTH`, the length of the
+//! longest answer, and shorter queries are dropped, so that an answer is never
+//! larger than the query that caused it. An ENet datagram could only start
+//! with the same bytes if it were a compressed connection request with a
+//! matching sent time.
+
+use std;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::net::{Ipv4Addr, SocketAddrV4, UdpSocket};
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use ll;
+use crate::{intercept, Address};
+
+const QUERY_MAGIC    : [u8; 4] = [0xFF, 0xFF, b'Q', b'?'];
+const RESPONSE_MAGIC : [u8; 4] = [0xFF, 0xFF, b'Q', b'!'];
+/// Longest name or map sent in an answer; longer strings are truncated
+pub const MAX_STRING_LENGTH : usize = 255;
+/// Length of a query datagram: the length of the longest answer
+pub const QUERY_LENGTH : usize = 18 + 2 * MAX_STRING_LENGTH;
+
+////////////////////////////////////////////////////////////////////////////////
+//  structs                                                                   //
+////////////////////////////////////////////////////////////////////////////////
+
+/// Information sent in answer to a query
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct ServerInfo {
+  pub name        : String,
+  pub map         : String,
+  pub players     : u32,
+  pub max_players : u32
+}
+
+/// Maximum number of answers in each window of time
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct QueryRate {
+  /// Answers to a single IP address
+  pub per_address : u32,
+  /// Answers to all addresses
+  pub total       : u32,
+  pub window      : Duration
+}
+
+/// Shared handle to the info and statistics of a query responder
+#[derive(Clone, Debug, Default)]
+pub struct QueryResponder {
+  inner : Rc <RefCell <ResponderInner>>
+}
+
+/// An answer received by a `ServerBrowser`
+#[derive(Clone, Debug)]
+pub struct ServerResponse {
+  pub address : Address,
+  pub info    : ServerInfo,
+  /// Time from sending the query to receiving the answer
+  pub latency : Duration
+}
+
+/// Queries servers for their info
+#[derive(Debug)]
+pub struct ServerBrowser {
+  socket     : UdpSocket,
+  /// Token and send time of the query sent to each server
+  pending    : HashMap <SocketAddrV4, (u32, Instant)>,
+  next_token : u32,
+  buffer     : Vec <u8>
+}
+
+#[derive(Debug, Default)]
+struct ResponderInner {
+  info         : ServerInfo,
+  rate         : QueryRate,
+  window_start : Option <Instant>,
+  /// Answers sent in the current window, by IP address
+  counts       : HashMap <Ipv4Addr, u32>,
+  total        : u32,
+  answered     : u64,
+  rate_limited : u64
+}
+
+/// Intercept answering queries received by a host
+struct ResponderIntercept {
+  responder : QueryResponder,
+  host      : *mut ll::ENetHost
+}
+
+////////////////////////////////////////////////////////////////////////////////
+//  impls                                                                     //
+////////////////////////////////////////////////////////////////////////////////
+
+impl ServerInfo {
+  fn encode (&self, token : u32) -> Vec <u8> {
+    let name = truncate (&self.name);
+    let map  = truncate (&self.map);
+    let mut bytes = Vec::with_capacity (18 + name.len() + map.len());
+    bytes.extend_from_slice (&RESPONSE_MAGIC);
+    bytes.extend_from_slice (&token.to_be_bytes());
+    bytes.extend_from_slice (&self.players.to_be_bytes());
+    bytes.extend_from_slice (&self.max_players.to_be_bytes());
+    bytes.push (name.len() as u8);
+    bytes.extend_from_slice (name);
+    bytes.push (map.len() as u8);
+    bytes.extend_from_slice (map);
+    bytes
+  }
+
+  /// Decode an answer, returning its token and the info
+  fn decode (bytes : &[u8]) -> Option <(u32, Self)> {
+    let rest = bytes.strip_prefix (&RESPONSE_MAGIC)?;
+    let (token,       rest) = read_u32 (rest)?;
+    let (players,     rest) = read_u32 (rest)?;
+    let (max_players, rest) = read_u32 (rest)?;
+    let (name,        rest) = read_string (rest)?;
+    let (map,         _)    = read_string (rest)?;
+    Some ((token, ServerInfo { name, map, players, max_players }))
+  }
+}
+
+impl Default for QueryRate {
+  /// 4 answers per address and 256 in total per second
+  fn default() -> Self {
+    QueryRate { per_address: 4, total: 256, window: Duration::from_secs (1) }
+  }
+}
+
+impl QueryResponder {
+  pub fn new (info : ServerInfo) -> Self {
+    let responder = QueryResponder::default();
+    responder.set_info (info);
+    responder
+  }
+
+  pub fn info (&self) -> ServerInfo {
+    self.inner.borrow().info.clone()
+  }
+
+  /// Change the info sent in answers, e.g. when a player joins
+  pub fn set_info (&self, info : ServerInfo) {
+    self.inner.borrow_mut().info = info;
+  }
+
+  pub fn rate (&self) -> QueryRate {
+    self.inner.borrow().rate
+  }
+
+  pub fn set_rate (&self, rate : QueryRate) {
+    self.inner.borrow_mut().rate = rate;
+  }
+
+  /// Number of queries answered
+  pub fn answered (&self) -> u64 {
+    self.inner.borrow().answered
+  }
+
+  /// Number of queries dropped by the rate limit
+  pub fn rate_limited (&self) -> u64 {
+    self.inner.borrow().rate_limited
+  }
+
+  /// Create the intercept answering queries on a host
+  pub(crate) fn intercept (&self, host : *mut ll::ENetHost)
+    -> Box <dyn intercept::Intercept>
+  {
+    Box::new (ResponderIntercept { responder: self.clone(), host })
+  }
+}
+
+impl ResponderInner {
+  /// Count an answer to `ip`, returning false if it exceeds the rate
+  fn allow (&mut self, ip : Ipv4Addr) -> bool {
+    let now = Instant::now();
+    if self.window_start
+      .is_none_or (|start| self.rate.window <= now.duration_since (start))
+    {
+      self.window_start = Some (now);
+      self.counts.clear();
+      self.total = 0;
+    }
+    let count = self.counts.entry (ip).or_default();
+    if self.rate.total <= self.total || self.rate.per_address <= *count {
+      self.rate_limited += 1;
+      return false
+    }
+    *count     += 1;
+    self.total += 1;
+    true
+  }
+}
+
+impl intercept::Intercept for ResponderIntercept {
+  fn receive (&mut self, datagram : &mut intercept::Datagram)
+    -> intercept::Action
+  {
+    if !datagram.data().starts_with (&QUERY_MAGIC) {
+      return intercept::Action::Continue
+    }
+    let Some (token) = decode_query (datagram.data()) else {
+      return intercept::Action::Consume
+    };
+    let address  = datagram.address();
+    let response = {
+      let mut inner = self.responder.inner.borrow_mut();
+      if !inner.allow (*SocketAddrV4::from (address.clone()).ip()) {
+        return intercept::Action::Consume
+      }
+      inner.answered += 1;
+      inner.info.encode (token)
+    };
+    unsafe {
+      let buffer = ll::ENetBuffer {
+        data:       response.as_ptr() as *mut std::os::raw::c_void,
+        dataLength: response.len()
+      };
+      // a failed answer is the same as a lost one
+      let _ = ll::enet_socket_send ((*self.host).socket, address.raw(), &buffer, 1);
+    }
+    intercept::Action::Consume
+  }
+}
+
+impl ServerBrowser {
+  /// Create a browser with a socket bound to an ephemeral port
+  pub fn new() -> std::io::Result <Self> {
+    let socket = UdpSocket::bind ("0.0.0.0:0")?;
+    socket.set_nonblocking (true)?;
+    let next_token = std::time::SystemTime::now()
+      .duration_since (std::time::UNIX_EPOCH).unwrap_or_default().subsec_nanos();
+    Ok (ServerBrowser {
+      socket, pending: HashMap::new(), next_token, buffer: vec![0; 2048]
+    })
+  }
+
+  /// Send a query to a server; a query already pending for the server is
+  /// replaced
+  pub fn send_query (&mut self, address : &Address) -> std::io::Result <()> {
+    let address = SocketAddrV4::from (address.clone());
+    let token   = self.next_token;
+    self.next_token = self.next_token.wrapping_add (0x9E37_79B9);
+    self.socket.send_to (&encode_query (token), address)?;
+    self.pending.insert (address, (token, Instant::now()));
+    Ok (())
+  }
+
+  /// Number of queries that have not been answered
+  #[inline]
+  pub fn pending (&self) -> usize {
+    self.pending.len()
+  }
+
+  /// Forget queries that have not been answered
+  #[inline]
+  pub fn clear_pending (&mut self) {
+    self.pending.clear();
+  }
+
+  /// Return the next answer received, without blocking.
+  ///
+  /// Datagrams that are not an answer to a pending query are ignored.
+  pub fn receive (&mut self) -> std::io::Result <Option <ServerResponse>> {
+    loop {
+      let (length, from) = match self.socket.recv_from (&mut self.buffer) {
+        Ok ((length, std::net::SocketAddr::V4 (from))) => (length, from),
+        Ok ((_, std::net::SocketAddr::V6 (_))) => continue,
+        Err (err) if err.kind() == std::io::ErrorKind::WouldBlock =>
+          return Ok (None),
+        // an earlier query could not be delivered
+        Err (err) if matches!(err.kind(), std::io::ErrorKind::ConnectionRefused |
+          std::io::ErrorKind::ConnectionReset) => continue,
+        Err (err) => return Err (err)
+      };
+      let Some ((token, info)) = ServerInfo::decode (&self.buffer[..length]) else {
+        continue
+      };
+      match self.pending.get (&from) {
+        Some ((expected, sent)) if *expected == token => {
+          let latency = sent.elapsed();
+          self.pending.remove (&from);
+          return Ok (Some (ServerResponse { address: from.into(), info, latency }))
+        }
+        _ => {}
+      }
+    }
+  }
+
+  /// Query all `addresses` at once and wait up to `timeout` for their answers.
+  ///
+  /// Servers that did not answer in time are not included.
+  pub fn query (&mut self, addresses : &[Address], timeout : Duration)
+    -> std::io::Result <Vec <ServerResponse>>
+  {
+    self.clear_pending();
+    for address in addresses {
+      self.send_query (address)?;
+    }
+    let deadline      = Instant::now() + timeout;
+    let mut responses = Vec::new();
+    while 0 < self.pending() && Instant::now() < deadline {
+      match self.receive()? {
+        Some (response) => responses.push (response),
+        None => std::thread::sleep (Duration::from_millis (1))
+      }
+    }
+    self.clear_pending();
+    Ok (responses)
+  }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+//  functions                                                                 //
+////////////////////////////////////////////////////////////////////////////////
+
+/// A query padded to `QUERY_LENGTH`
+fn encode_query (token : u32) -> Vec <u8> {
+  let mut query = QUERY_MAGIC.to_vec();
+  query.extend_from_slice (&token.to_be_bytes());
+  query.resize (QUERY_LENGTH, 0);
+  query
+}
+
+/// Decode a query, returning its token; queries shorter than `QUERY_LENGTH`
+/// are refused
+fn decode_query (bytes : &[u8]) -> Option <u32> {
+  if bytes.len() < QUERY_LENGTH {
+    return None
+  }
+  let (token, _) = read_u32 (bytes.strip_prefix (&QUERY_MAGIC)?)?;
+  Some (token)
+}
+
+/// Truncate to at most `MAX_STRING_LENGTH` bytes on a character boundary
+fn truncate (string : &str) -> &[u8] {
+  let mut end = string.len().min (MAX_STRING_LENGTH);
+  while !string.is_char_boundary (end) {
+    end -= 1;
+  }
+  &string.as_bytes()[..end]
+}
+
+fn read_u32 (bytes : &[u8]) -> Option <(u32, &[u8])> {
+  let (word, rest) = bytes.split_first_chunk::<4>()?;
+  Some ((u32::from_be_bytes (*word), rest))
+}
+
+fn read_string (bytes : &[u8]) -> Option <(String, &[u8])> {
+  let (length, rest) = bytes.split_first()?;
+  let (string, rest) = rest.split_at_checked (usize::from (*length))?;
+  Some ((String::from_utf8_lossy (string).into_owned(), rest))
+}
+
+////////////////////////////////////////////////////////////////////////////////
+//  tests                                                                     //
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg (test)]
+mod tests {
+  use super::*;
+  use crate::{testing, Host};
+
+  fn info() -> ServerInfo {
+    ServerInfo {
+      name:        "server".to_owned(),
+      map:         "dust".to_owned(),
+      players:     3,
+      max_players: 16
+    }
+  }
+
+  /// A server answering queries with `responder`, and its localhost address
+  fn server (responder : &QueryResponder) -> (Host, Address) {
+    let mut host = testing::enet().server_host_create (Address::localhost (0),
+      1, None, None, None).unwrap();
+    host.set_query_responder (Some (responder.clone()));
+    let address = Address::localhost (host.local_address().port());
+    (host, address)
+  }
+
+  /// Service the host until `done` returns true or the timeout expires
+  fn service_until <F : FnMut() -> bool> (host : &mut Host, mut done : F) {
+    let start = Instant::now();
+    while !done() && start.elapsed() < testing::TIMEOUT {
+      host.service (1).unwrap();
+    }
+  }
+
+  /// Send a query from a socket bound to `ip` and return the answer, if any
+  fn query_from (host : &mut Host, server : &Address, ip : Ipv4Addr)
+    -> Option <ServerInfo>
+  {
+    let socket = UdpSocket::bind ((ip, 0)).unwrap();
+    socket.set_nonblocking (true).unwrap();
+    socket.send_to (&encode_query (9), SocketAddrV4::from (server.clone()))
+      .unwrap();
+    let mut buffer = [0; 1024];
+    let mut answer = None;
+    let start = Instant::now();
+    // an answer is sent while servicing, so one round is enough to see that
+    // none is coming
+    while answer.is_none() && start.elapsed() < Duration::from_millis (100) {
+      host.service (1).unwrap();
+      if let Ok ((length, _)) = socket.recv_from (&mut buffer) {
+        answer = ServerInfo::decode (&buffer[..length]);
+      }
+    }
+    answer.map (|(token, info)| {
+      assert_eq!(token, 9);
+      info
+    })
+  }
+
+  #[test]
+  fn info_decodes_as_encoded() {
+    let bytes = info().encode (0xDEAD_BEEF);
+    assert_eq!(ServerInfo::decode (&bytes), Some ((0xDEAD_BEEF, info())));
+    let empty = ServerInfo::default().encode (0);
+    assert_eq!(ServerInfo::decode (&empty), Some ((0, ServerInfo::default())));
+  }
+
+  #[test]
+  fn info_decode_rejects_truncated_answers() {
+    let bytes = info().encode (1);
+    for length in 0..bytes.len() {
+      assert_eq!(ServerInfo::decode (&bytes[..length]), None);
+    }
+    let mut query = bytes;
+    query[3] = b'?';
+    assert_eq!(ServerInfo::decode (&query), None);
+  }
+
+  #[test]
+  fn long_strings_are_truncated_on_a_character_boundary() {
+    let info = ServerInfo {
+      name: "é".repeat (200),
+      map:  "m".repeat (300),
+      .. info()
+    };
+    let bytes = info.encode (7);
+    assert_eq!(bytes.len(), QUERY_LENGTH - 1);
+    let (_, decoded) = ServerInfo::decode (&bytes).unwrap();
+    assert_eq!(decoded.name, "é".repeat (127));
+    assert_eq!(decoded.map, "m".repeat (MAX_STRING_LENGTH));
+  }
+
+  #[test]
+  fn answers_are_not_longer_than_queries() {
+    let info = ServerInfo {
+      name: "n".repeat (1000),
+      map:  "m".repeat (1000),
+      .. info()
+    };
+    assert!(info.encode (0).len() <= encode_query (0).len());
+  }
+
+  #[test]
+  fn queries_must_be_padded() {
+    let query = encode_query (42);
+    assert_eq!(query.len(), QUERY_LENGTH);
+    assert_eq!(decode_query (&query), Some (42));
+    assert_eq!(decode_query (&query[..8]), None);
+    assert_eq!(decode_query (&query[..QUERY_LENGTH - 1]), None);
+    let mut answer = query;
+    answer[3] = b'!';
+    assert_eq!(decode_query (&answer), None);
+  }
+
+  #[test]
+  fn browser_receives_the_info_of_a_host() {
+    let responder = QueryResponder::new (info());
+    let (mut host, address) = server (&responder);
+    let mut browser = ServerBrowser::new().unwrap();
+    browser.send_query (&address).unwrap();
+    assert_eq!(browser.pending(), 1);
+    let mut response = None;
+    service_until (&mut host, || {
+      response = browser.receive().unwrap();
+      response.is_some()
+    });
+    let response = response.unwrap();
+    assert_eq!(response.info, info());
+    assert_eq!(response.address.port(), address.clone().port());
+    assert!(response.latency < testing::TIMEOUT);
+    assert_eq!(browser.pending(), 0);
+    assert_eq!(responder.answered(), 1);
+    // the info can change between queries
+    responder.set_info (ServerInfo { players: 4, .. info() });
+    browser.send_query (&address).unwrap();
+    let mut response = None;
+    service_until (&mut host, || {
+      response = browser.receive().unwrap();
+      response.is_some()
+    });
+    assert_eq!(response.unwrap().info.players, 4);
+    // queries never reach ENet
+    assert_eq!(host.connected_peers(), 0);
+  }
+
+  #[test]
+  fn answers_past_the_rate_per_address_are_dropped() {
+    let responder = QueryResponder::new (info());
+    responder.set_rate (QueryRate {
+      per_address: 2, total: 100, window: Duration::from_secs (60)
+    });
+    let (mut host, address) = server (&responder);
+    let answers = std::iter::repeat_with (
+      || query_from (&mut host, &address, Ipv4Addr::LOCALHOST)
+    ).take (4).collect::<Vec <_>>();
+    assert_eq!(answers, [Some (info()), Some (info()), None, None]);
+    assert_eq!((responder.answered(), responder.rate_limited()), (2, 2));
+    // another address still gets answers
+    assert_eq!(query_from (&mut host, &address, Ipv4Addr::new (127, 0, 0, 2)),
+      Some (info()));
+  }
+
+  #[test]
+  fn answers_past_the_total_rate_are_dropped() {
+    let responder = QueryResponder::new (info());
+    responder.set_rate (QueryRate {
+      per_address: 2, total: 3, window: Duration::from_secs (60)
+    });
+    let (mut host, address) = server (&responder);
+    let answered = [1, 1, 2, 3, 4].into_iter()
+      .map (|ip| query_from (&mut host, &address, Ipv4Addr::new (127, 0, 0, ip))
+        .is_some())
+      .collect::<Vec <_>>();
+    assert_eq!(answered, [true, true, true, false, false]);
+    assert_eq!((responder.answered(), responder.rate_limited()), (3, 2));
+  }
+}
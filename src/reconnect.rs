@@ -0,0 +1,687 @@
+//! Client connection that reconnects automatically.
+//!
+//! A `ReconnectingClient` owns a client `Host` and connects it to a server.
+//! When the connection is lost, or an attempt to connect fails, it tries again
+//! after an exponential `Backoff` with random jitter, up to a maximum number of
+//! attempts. A `Target` given by host name is resolved again on each attempt,
+//! so a server that moved to another address is found; the lookup blocks
+//! `service()` until it completes.
+//!
+//! `service()` returns the host events along with `Reconnecting`,
+//! `Reconnected` and `GaveUp` events:
+//!
+//! ```no_run
+//! # use enet::reconnect::{ClientEvent, ReconnectingClient, Target};
+//! # let enet = enet::Enet::shared().unwrap();
+//! let host = enet.client_host_create (1, None, None).unwrap();
+//! let target = Target::Hostname {
+//!   hostname: "example.com".to_owned(), port: 12345
+//! };
+//! let mut client = ReconnectingClient::new (host, target, 2, 0)
+//!   .queue_while_disconnected (64 * 1024);
+//! loop {
+//!   match client.service (16).unwrap() {
+//!     Some (ClientEvent::Reconnecting { attempt, delay }) =>
+//!       println!("reconnecting ({attempt}) in {delay:?}"),
+//!     Some (ClientEvent::GaveUp { .. }) => break,
+//!     Some (_) | None => {}
+//!   }
+//! }
+//! ```
+//!
+//! With `queue_while_disconnected()`, packets sent while the connection is down
+//! are copied into a bounded queue and sent in order once it is back. A queued
+//! packet that cannot be sent for a transient reason stays queued, with the
+//! packets after it, until a later `service()`; other failures are returned as
+//! `SendFailed` events.
+
+use std;
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use crate::{host, packet, peer, simulator, Address, Event, Host, Packet, Peer};
+
+////////////////////////////////////////////////////////////////////////////////
+//  structs                                                                   //
+////////////////////////////////////////////////////////////////////////////////
+
+/// A client host that reconnects to its server when the connection is lost
+#[derive(Debug)]
+pub struct ReconnectingClient {
+  host          : Host,
+  target        : Target,
+  channel_count : u8,
+  data          : u32,
+  backoff       : Backoff,
+  state         : State,
+  /// The host has been connected once; later connections are reported as
+  /// `Reconnected`
+  connected_once : bool,
+  /// Packets sent while disconnected, if queueing is enabled
+  queue         : Option <Queue>,
+  events        : VecDeque <ClientEvent>,
+  rng           : simulator::Rng
+}
+
+/// Delays between connection attempts.
+///
+/// Attempt `n` waits `initial * multiplier^(n - 1)`, at most `max`, shortened
+/// by a random fraction of up to `jitter` so that clients disconnected at the
+/// same time do not reconnect at the same time.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Backoff {
+  /// Delay before the first attempt after the connection is lost (500 ms)
+  pub initial      : Duration,
+  /// Longest delay between attempts (30 seconds)
+  pub max          : Duration,
+  /// Factor applied to the delay after each failed attempt (2)
+  pub multiplier   : u32,
+  /// Largest fraction, between 0 and 1, that the delay is randomly shortened
+  /// by (0.5)
+  pub jitter       : f64,
+  /// Attempts before giving up, or `None` to try forever (10)
+  pub max_attempts : Option <u32>
+}
+
+#[derive(Debug)]
+struct Queue {
+  max_bytes : usize,
+  bytes     : usize,
+  packets   : VecDeque <(u8, Vec <u8>, packet::Flags)>
+}
+
+////////////////////////////////////////////////////////////////////////////////
+//  enums                                                                     //
+////////////////////////////////////////////////////////////////////////////////
+
+/// Server to connect to
+#[derive(Clone, Debug)]
+pub enum Target {
+  Address  (Address),
+  /// Resolved with `Address::with_hostname()` on each attempt. The lookup is
+  /// blocking: `service()` does not return until it completes or fails.
+  Hostname {
+    hostname : String,
+    port     : u16
+  }
+}
+
+/// Event returned by `client.service()`
+#[derive(Debug)]
+pub enum ClientEvent {
+  /// An event of the host. The `Connect` (or `Authenticated`) event of the
+  /// first connection is returned as is, later ones as `Reconnected`.
+  Host (Event),
+  /// The connection was lost or an attempt failed; attempt number `attempt`
+  /// starts after `delay`
+  Reconnecting {
+    attempt : u32,
+    delay   : Duration
+  },
+  /// The connection is back after `attempts` attempts; queued packets have
+  /// been sent
+  Reconnected {
+    peer     : Peer,
+    attempts : u32
+  },
+  /// The maximum number of attempts failed; queued packets were discarded.
+  /// `reconnect()` starts over.
+  GaveUp {
+    attempts : u32
+  },
+  /// A packet queued while disconnected could not be sent and was removed from
+  /// the queue; the error holds its payload
+  SendFailed (peer::SendError)
+}
+
+#[derive(Debug)]
+enum State {
+  /// Waiting for attempt number `attempt`
+  Waiting {
+    attempt : u32,
+    due     : Instant
+  },
+  Connecting {
+    attempt : u32,
+    peer    : Peer
+  },
+  Connected {
+    peer    : Peer
+  },
+  /// Disconnected by the application, or gave up
+  Stopped
+}
+
+////////////////////////////////////////////////////////////////////////////////
+//  impls                                                                     //
+////////////////////////////////////////////////////////////////////////////////
+
+impl ReconnectingClient {
+  /// Connect the host to the target with the given number of channels and
+  /// connect data; the first attempt is made by the first `service()`
+  pub fn new (host : Host, target : Target, channel_count : u8, data : u32)
+    -> Self
+  {
+    let seed = std::time::SystemTime::now()
+      .duration_since (std::time::UNIX_EPOCH)
+      .map_or (0, |time| time.as_nanos() as u64) ^
+      u64::from (host.local_address().port());
+    ReconnectingClient {
+      host,
+      target,
+      channel_count,
+      data,
+      backoff:        Backoff::default(),
+      state:          State::Waiting { attempt: 1, due: Instant::now() },
+      connected_once: false,
+      queue:          None,
+      events:         VecDeque::new(),
+      rng:            simulator::Rng::new (seed)
+    }
+  }
+
+  /// Use the given backoff instead of `Backoff::default()`
+  #[inline]
+  #[must_use]
+  pub const fn backoff (mut self, backoff : Backoff) -> Self {
+    self.backoff = backoff;
+    self
+  }
+
+  /// Queue packets sent while disconnected, up to `max_bytes` of payload
+  #[must_use]
+  pub fn queue_while_disconnected (mut self, max_bytes : usize) -> Self {
+    self.queue = Some (Queue { max_bytes, bytes: 0, packets: VecDeque::new() });
+    self
+  }
+
+  #[inline]
+  pub const fn host (&self) -> &Host {
+    &self.host
+  }
+
+  #[inline]
+  pub const fn host_mut (&mut self) -> &mut Host {
+    &mut self.host
+  }
+
+  /// The connected peer, if the connection is up
+  pub const fn peer (&self) -> Option <&Peer> {
+    match &self.state {
+      State::Connected { peer } => Some (peer),
+      _ => None
+    }
+  }
+
+  #[inline]
+  pub const fn is_connected (&self) -> bool {
+    matches!(self.state, State::Connected { .. })
+  }
+
+  /// Bytes of payload queued while disconnected
+  pub fn queued_bytes (&self) -> usize {
+    self.queue.as_ref().map_or (0, |queue| queue.bytes)
+  }
+
+  /// Send a packet to the server.
+  ///
+  /// While disconnected the packet is queued if queueing is enabled, failing
+  /// with `WouldBlock` when the queue is full; otherwise sending fails with
+  /// `PeerNotConnected`. While connected, the packet is queued behind packets
+  /// that are still queued, so that packets are sent in order.
+  pub fn send (&mut self, channel_id : u8, packet : Packet)
+    -> Result <(), peer::SendError>
+  {
+    if let State::Connected { peer } = &mut self.state &&
+      self.queue.as_ref().is_none_or (|queue| queue.packets.is_empty())
+    {
+      return peer.send (channel_id, packet)
+    }
+    let stopped = matches!(self.state, State::Stopped);
+    let Some (queue) = self.queue.as_mut().filter (|_| !stopped) else {
+      return Err (peer::SendError::new (
+        peer::SendErrorKind::PeerNotConnected (peer::State::Disconnected),
+        packet))
+    };
+    let (bytes, flags) = packet.parts();
+    if queue.max_bytes < queue.bytes + bytes.len() {
+      return Err (
+        peer::SendError::new (peer::SendErrorKind::WouldBlock, packet))
+    }
+    queue.bytes += bytes.len();
+    queue.packets.push_back ((channel_id, bytes.to_vec(), flags));
+    Ok (())
+  }
+
+  /// Disconnect from the server without reconnecting; the `Disconnect` event
+  /// is returned by `service()`
+  pub fn disconnect (&mut self) {
+    match std::mem::replace (&mut self.state, State::Stopped) {
+      State::Connected { peer } | State::Connecting { peer, .. } =>
+        peer.disconnect(),
+      State::Waiting { .. } | State::Stopped => {}
+    }
+    self.clear_queue();
+  }
+
+  /// Start connecting again after giving up or disconnecting
+  pub fn reconnect (&mut self) {
+    if matches!(self.state, State::Stopped) {
+      self.state = State::Waiting { attempt: 1, due: Instant::now() };
+    }
+  }
+
+  /// Service the host, making connection attempts when they are due.
+  ///
+  /// `timeout` is the number of milliseconds to wait for events; the wait ends
+  /// early when an attempt is due.
+  pub fn service (&mut self, timeout : u32)
+    -> Result <Option <ClientEvent>, host::Error>
+  {
+    self.flush_queue();
+    self.attempt_if_due();
+    if let Some (event) = self.events.pop_front() {
+      return Ok (Some (event))
+    }
+    let timeout = match self.state {
+      State::Waiting { due, .. } => {
+        let remaining = due.saturating_duration_since (Instant::now());
+        timeout.min (remaining.as_millis().try_into().unwrap_or (u32::MAX))
+      }
+      _ => timeout
+    };
+    if let Some (event) = self.host.service (timeout)? {
+      self.host_event (event);
+    }
+    self.attempt_if_due();
+    Ok (self.events.pop_front())
+  }
+
+  fn host_event (&mut self, event : Event) {
+    match (&self.state, event) {
+      ( State::Connecting { peer: connecting, attempt },
+        event @ (Event::Connect { .. } | Event::Authenticated { .. })
+      ) if event_peer (&event) == connecting => {
+        let attempts = *attempt;
+        let peer     = connecting.clone();
+        self.state   = State::Connected { peer: peer.clone() };
+        self.flush_queue();
+        if self.connected_once {
+          self.events.push_back (ClientEvent::Reconnected { peer, attempts });
+        } else {
+          self.connected_once = true;
+          self.events.push_back (ClientEvent::Host (event));
+        }
+      }
+      ( State::Connecting { peer: connecting, attempt },
+        Event::Disconnect { peer, .. }
+      ) if peer == *connecting => {
+        let attempt = *attempt;
+        self.schedule (attempt + 1);
+      }
+      (State::Connected { peer: connected }, event @ Event::Disconnect { .. })
+        if event_peer (&event) == connected =>
+      {
+        self.events.push_back (ClientEvent::Host (event));
+        self.schedule (1);
+      }
+      (_, event) => self.events.push_back (ClientEvent::Host (event))
+    }
+  }
+
+  /// Make the connection attempt if it is due; a `Target::Hostname` is
+  /// resolved with a blocking lookup
+  fn attempt_if_due (&mut self) {
+    let State::Waiting { attempt, due } = self.state else {
+      return
+    };
+    if Instant::now() < due {
+      return
+    }
+    let address = match &self.target {
+      Target::Address (address) => Ok (address.clone()),
+      Target::Hostname { hostname, port } =>
+        Address::with_hostname (hostname, *port)
+    };
+    let peer = address.ok().and_then (|address|
+      self.host.connect (&address, self.channel_count, self.data).ok());
+    match peer {
+      Some (peer) => self.state = State::Connecting { attempt, peer },
+      None => self.schedule (attempt + 1)
+    }
+  }
+
+  /// Wait for attempt number `attempt`, or give up
+  fn schedule (&mut self, attempt : u32) {
+    if self.backoff.max_attempts.is_some_and (|max| max < attempt) {
+      self.state = State::Stopped;
+      self.clear_queue();
+      self.events.push_back (ClientEvent::GaveUp { attempts: attempt - 1 });
+      return
+    }
+    let delay  = self.backoff.delay (attempt, self.rng.next_f64());
+    self.state = State::Waiting { attempt, due: Instant::now() + delay };
+    self.events.push_back (ClientEvent::Reconnecting { attempt, delay });
+  }
+
+  /// Send the packets queued while disconnected, in order. Sending stops at a
+  /// transient failure, keeping the packet queued for the next call; a packet
+  /// that fails otherwise is removed and reported as `SendFailed`.
+  fn flush_queue (&mut self) {
+    let State::Connected { peer } = &mut self.state else {
+      return
+    };
+    let Some (queue) = self.queue.as_mut() else {
+      return
+    };
+    while let Some ((channel_id, bytes, flags)) = queue.packets.front() {
+      let length = bytes.len();
+      let result =
+        peer.send (*channel_id, Packet::Allocate { bytes, flags: *flags });
+      if let Err (error) = result {
+        if error.kind().is_transient() {
+          return
+        }
+        self.events.push_back (ClientEvent::SendFailed (error));
+      }
+      queue.bytes -= length;
+      queue.packets.pop_front();
+    }
+  }
+
+  fn clear_queue (&mut self) {
+    if let Some (queue) = self.queue.as_mut() {
+      queue.bytes = 0;
+      queue.packets.clear();
+    }
+  }
+}
+
+impl Backoff {
+  /// Delay before attempt number `attempt` (from 1), with `random` in
+  /// `[0.0, 1.0)`
+  fn delay (&self, attempt : u32, random : f64) -> Duration {
+    let factor = self.multiplier.saturating_pow (attempt.saturating_sub (1));
+    let delay  = self.initial.saturating_mul (factor).min (self.max);
+    let jitter = if self.jitter.is_nan() {
+      0.0
+    } else {
+      self.jitter.clamp (0.0, 1.0)
+    };
+    delay.mul_f64 (jitter.mul_add (-random, 1.0))
+  }
+}
+
+impl Default for Backoff {
+  fn default() -> Self {
+    Backoff {
+      initial:      Duration::from_millis (500),
+      max:          Duration::from_secs (30),
+      multiplier:   2,
+      jitter:       0.5,
+      max_attempts: Some (10)
+    }
+  }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+//  functions                                                                 //
+////////////////////////////////////////////////////////////////////////////////
+
+/// The peer of a connection event
+const fn event_peer (event : &Event) -> &Peer {
+  match event {
+    Event::Connect { peer, .. } | Event::Disconnect { peer, .. } |
+    Event::Receive { peer, .. } | Event::Authenticated { peer, .. } |
+    Event::Drained { peer } | Event::Delivered { peer, .. } |
+    Event::Dropped { peer, .. } | Event::QualityChanged { peer, .. } => peer
+  }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+//  tests                                                                     //
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg (test)]
+mod tests {
+  use super::*;
+  use crate::{auth, testing};
+
+  const fn millis (millis : u64) -> Duration {
+    Duration::from_millis (millis)
+  }
+
+  /// Short delays without jitter
+  const fn backoff (max_attempts : u32) -> Backoff {
+    Backoff {
+      initial:      millis (20),
+      max:          millis (20),
+      multiplier:   1,
+      jitter:       0.0,
+      max_attempts: Some (max_attempts)
+    }
+  }
+
+  fn server_at (port : u16) -> Host {
+    testing::enet().server_host_create (Address::localhost (port), 2, Some (2),
+      None, None).unwrap()
+  }
+
+  fn client_of (port : u16, max_attempts : u32) -> ReconnectingClient {
+    let host = testing::enet().client_host_create (1, None, None).unwrap();
+    ReconnectingClient::new (host, Target::Address (Address::localhost (port)),
+      2, 0).backoff (backoff (max_attempts)).queue_while_disconnected (1024)
+  }
+
+  /// Service the server, if any, and the client until the client returns an
+  /// event satisfying `done`; returns the events of the client and the server
+  fn run <F> (
+    mut server : Option <&mut Host>, client : &mut ReconnectingClient, done : F
+  ) -> (Vec <ClientEvent>, Vec <Event>) where
+    F : Fn (&ClientEvent) -> bool
+  {
+    let start = Instant::now();
+    let (mut client_events, mut server_events) = (Vec::new(), Vec::new());
+    while !client_events.last().is_some_and (&done) {
+      assert!(start.elapsed() < testing::TIMEOUT,
+        "{client_events:?} {server_events:?}");
+      if let Some (server) = server.as_mut() {
+        server_events.extend (server.service (0).unwrap());
+      }
+      client_events.extend (client.service (1).unwrap());
+    }
+    (client_events, server_events)
+  }
+
+  /// Connect the client for the first time; returns the client as seen by the
+  /// server
+  fn connect (server : &mut Host, client : &mut ReconnectingClient) -> Peer {
+    let (_, mut events) = run (Some (server), client,
+      |event| matches!(event, ClientEvent::Host (Event::Connect { .. })));
+    let start = Instant::now();
+    loop {
+      if let Some (Event::Connect { peer, .. }) = events.pop() {
+        return peer
+      }
+      assert!(start.elapsed() < testing::TIMEOUT);
+      events.extend (server.service (1).unwrap());
+      assert!(client.service (0).unwrap().is_none());
+    }
+  }
+
+  fn send (client : &mut ReconnectingClient, channel_id : u8, bytes : &[u8]) {
+    client.send (channel_id,
+      Packet::Allocate { bytes, flags: packet::Flags::RELIABLE }).unwrap();
+  }
+
+  /// Never completes the handshake
+  struct Silent;
+
+  impl auth::Authenticator for Silent {
+    fn challenge (&mut self, _peer : &Peer) -> Vec <u8> {
+      vec![0; 8]
+    }
+    fn respond (&mut self,
+      _peer : &Peer, _binding : &auth::Binding, _challenge : &[u8]
+    ) -> Vec <u8> {
+      Vec::new()
+    }
+    fn verify (&mut self,
+      _peer : &Peer, _binding : &auth::Binding, _challenge : &[u8],
+      _response : &[u8]
+    ) -> bool {
+      false
+    }
+  }
+
+  #[test]
+  fn backoff_grows_up_to_max() {
+    let backoff = Backoff::default();
+    assert_eq!(backoff.delay (1, 0.0), millis (500));
+    assert_eq!(backoff.delay (2, 0.0), millis (1000));
+    assert_eq!(backoff.delay (4, 0.0), millis (4000));
+    assert_eq!(backoff.delay (7, 0.0), Duration::from_secs (30));
+    assert_eq!(backoff.delay (u32::MAX, 0.0), Duration::from_secs (30));
+  }
+
+  #[test]
+  fn backoff_jitter_shortens_delay() {
+    let backoff = Backoff::default();
+    assert_eq!(backoff.delay (2, 0.5), millis (750));
+    assert!(millis (500) < backoff.delay (2, 0.999));
+    let full = Backoff { jitter: 4.0, .. backoff };
+    assert_eq!(full.delay (2, 0.5), millis (500));
+    let none = Backoff { jitter: -1.0, .. backoff };
+    assert_eq!(none.delay (2, 0.5), millis (1000));
+  }
+
+  #[test]
+  fn backoff_ignores_nan_jitter() {
+    let backoff = Backoff { jitter: f64::NAN, .. Backoff::default() };
+    assert_eq!(backoff.delay (1, 0.5), millis (500));
+  }
+
+  #[test]
+  fn client_reconnects_when_the_server_comes_back() {
+    let mut server = server_at (0);
+    let port       = server.local_address().port();
+    let mut client = client_of (port, 3);
+    connect (&mut server, &mut client).disconnect_now();
+    drop (server);
+    let (events, _) = run (None, &mut client,
+      |event| matches!(event, ClientEvent::Reconnecting { .. }));
+    assert!(matches!(events[..], [
+      ClientEvent::Host (Event::Disconnect { .. }),
+      ClientEvent::Reconnecting { attempt: 1, .. }
+    ]), "{events:?}");
+    // the attempt starts while the server is down
+    let start = Instant::now();
+    while start.elapsed() < millis (100) {
+      assert!(client.service (1).unwrap().is_none());
+    }
+    assert!(matches!(client.state, State::Connecting { attempt: 1, .. }));
+    let mut server  = server_at (port);
+    let (events, _) = run (Some (&mut server), &mut client,
+      |event| matches!(event, ClientEvent::Reconnected { .. }));
+    assert!(
+      matches!(events[..], [ClientEvent::Reconnected { attempts: 1, .. }]),
+      "{events:?}");
+    assert!(client.is_connected());
+  }
+
+  #[test]
+  fn client_gives_up_after_max_attempts() {
+    let port       = server_at (0).local_address().port();
+    let mut client = client_of (port, 2);
+    send (&mut client, 0, b"discarded");
+    let start      = Instant::now();
+    let mut events = Vec::new();
+    while !matches!(events.last(), Some (ClientEvent::GaveUp { .. })) {
+      assert!(start.elapsed() < testing::TIMEOUT, "{events:?}");
+      events.extend (client.service (1).unwrap());
+      // time out connection requests at the first retransmission
+      if let State::Connecting { peer, .. } = &mut client.state {
+        peer.timeout (1, 50, 50);
+      }
+    }
+    assert!(matches!(events[..], [
+      ClientEvent::Reconnecting { attempt: 2, .. },
+      ClientEvent::GaveUp { attempts: 2 }
+    ]), "{events:?}");
+    assert!(matches!(client.state, State::Stopped));
+    assert_eq!(client.queued_bytes(), 0);
+    let error = client.send (0,
+      Packet::Allocate { bytes: b"late", flags: packet::Flags::RELIABLE }
+    ).unwrap_err();
+    assert_eq!(error.kind(),
+      peer::SendErrorKind::PeerNotConnected (peer::State::Disconnected));
+  }
+
+  #[test]
+  fn queued_packets_are_sent_in_order_after_reconnecting() {
+    let mut server = server_at (0);
+    let mut client = client_of (server.local_address().port(), 3);
+    connect (&mut server, &mut client).disconnect_now();
+    run (Some (&mut server), &mut client,
+      |event| matches!(event, ClientEvent::Reconnecting { .. }));
+    send (&mut client, 0, b"first");
+    // there is no channel 7: removed from the queue
+    send (&mut client, 7, b"invalid");
+    send (&mut client, 1, b"second");
+    send (&mut client, 0, b"third");
+    assert_eq!(client.queued_bytes(), 23);
+    let (events, mut received) = run (Some (&mut server), &mut client,
+      |event| matches!(event, ClientEvent::Reconnected { .. }));
+    let [
+      ClientEvent::SendFailed (error),
+      ClientEvent::Reconnected { attempts: 1, .. }
+    ] = &events[..] else {
+      panic!("{events:?}")
+    };
+    assert_eq!(error.kind(), peer::SendErrorKind::PeerNoChannelID (7));
+    assert_eq!(error.payload(), b"invalid");
+    assert_eq!(client.queued_bytes(), 0);
+    let payloads = |events : &[Event]| events.iter()
+      .filter_map (|event| match event {
+        Event::Receive { packet, .. } => Some (packet.data().to_vec()),
+        _ => None
+      }).collect::<Vec <_>>();
+    let start = Instant::now();
+    while payloads (&received).len() < 3 {
+      assert!(start.elapsed() < testing::TIMEOUT, "{received:?}");
+      received.extend (server.service (1).unwrap());
+      client.service (0).unwrap();
+    }
+    assert_eq!(payloads (&received), [&b"first"[..], b"second", b"third"]);
+  }
+
+  #[test]
+  fn flush_stops_at_a_transient_failure() {
+    let mut server = server_at (0);
+    let mut client = client_of (server.local_address().port(), 3);
+    // the server has no handshake, so the client peer connects but is never
+    // authenticated
+    client.host_mut().set_handshake (
+      Some (auth::Handshake::new (Box::new (Silent), 1, 5000)));
+    let start = Instant::now();
+    let peer  = loop {
+      assert!(start.elapsed() < testing::TIMEOUT);
+      server.service (0).unwrap();
+      assert!(client.service (1).unwrap().is_none());
+      if let State::Connecting { peer, .. } = &client.state &&
+        peer.state() == peer::State::Connected
+      {
+        break peer.clone()
+      }
+    };
+    send (&mut client, 0, b"first");
+    send (&mut client, 0, b"second");
+    client.state = State::Connected { peer };
+    client.flush_queue();
+    assert_eq!(client.queued_bytes(), 11);
+    assert!(client.events.is_empty());
+    // queued behind the packets still queued
+    send (&mut client, 0, b"third");
+    assert_eq!(client.queued_bytes(), 16);
+  }
+}
@@ -0,0 +1,655 @@
+//! Network impairment simulator for testing over loopback.
+//!
+//! A `Simulator` is a UDP proxy that sits in front of a server host. Clients
+//! connect to `simulator.address()` instead of the server, and each datagram
+//! passing through the proxy is subject to a `Profile` of packet loss, latency,
+//! jitter, duplication, reordering and bandwidth limits.
+//!
+//! Profiles are set separately for each `Direction`, and may be overridden for
+//! the address of a single client. Random decisions are made with a generator
+//! seeded from the seed given to `Simulator::new()`, so a run with the same
+//! seed and the same traffic makes the same decisions.
+//!
+//! The proxy is driven either by calling `pump()` from the test loop, or in a
+//! background thread with `spawn()`:
+//!
+//! ```no_run
+//! # use std::time::Duration;
+//! # use enet::{simulator, Address};
+//! # let enet = enet::initialize().unwrap();
+//! let server    = enet.server_host_create (Address::localhost (12345), 8, None,
+//!   None, None).unwrap();
+//! let simulator = simulator::Simulator::new (Address::localhost (12345), 1)
+//!   .unwrap();
+//! simulator.set_profile (simulator::Direction::Upstream, simulator::Profile {
+//!   loss:    0.05,
+//!   latency: Duration::from_millis (50),
+//!   jitter:  Duration::from_millis (10),
+//!   .. simulator::Profile::default()
+//! });
+//! let simulator = simulator.spawn().unwrap();
+//! let mut client = enet.client_host_create (1, None, None).unwrap();
+//! let peer = client.connect (&simulator.address(), 2, 0).unwrap();
+//! ```
+
+use std;
+use std::collections::{BinaryHeap, HashMap};
+use std::net::{SocketAddrV4, UdpSocket};
+use std::sync::{atomic, Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::Address;
+
+/// Largest datagram forwarded by the proxy
+const MAX_DATAGRAM : usize = 65536;
+/// Longest time the background thread sleeps between pumps
+const POLL_INTERVAL : Duration = Duration::from_millis (1);
+
+////////////////////////////////////////////////////////////////////////////////
+//  structs                                                                   //
+////////////////////////////////////////////////////////////////////////////////
+
+/// Impairments applied to datagrams travelling in one direction.
+///
+/// The default profile is a perfect network.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Profile {
+  /// Probability (0.0 to 1.0) that a datagram is dropped
+  pub loss      : f64,
+  /// Delay added to every datagram
+  pub latency   : Duration,
+  /// Maximum random delay added on top of `latency`
+  pub jitter    : Duration,
+  /// Probability (0.0 to 1.0) that a datagram is delivered twice
+  pub duplicate : f64,
+  /// Probability (0.0 to 1.0) that a datagram skips the latency and jitter,
+  /// overtaking datagrams sent before it
+  pub reorder   : f64,
+  /// Bytes per second; datagrams exceeding the rate are queued
+  pub bandwidth : Option <u32>
+}
+
+/// UDP proxy applying impairment profiles between clients and a server
+#[derive(Debug)]
+pub struct Simulator {
+  socket   : UdpSocket,
+  server   : SocketAddrV4,
+  seed     : u64,
+  profiles : Arc <Mutex <Profiles>>,
+  links    : HashMap <SocketAddrV4, Link>,
+  queue    : BinaryHeap <Scheduled>,
+  sequence : u64,
+  buffer   : Vec <u8>
+}
+
+/// A `Simulator` running in a background thread.
+///
+/// The thread stops if forwarding a datagram fails; the error is returned by
+/// `stop()`, or logged as a warning when this is dropped.
+#[derive(Debug)]
+pub struct SimulatorThread {
+  address  : Address,
+  profiles : Arc <Mutex <Profiles>>,
+  running  : Arc <atomic::AtomicBool>,
+  thread   : Option <std::thread::JoinHandle <std::io::Result <()>>>
+}
+
+/// Default and per-client profiles
+#[derive(Debug, Default)]
+struct Profiles {
+  default : [Profile; 2],
+  clients : HashMap <SocketAddrV4, [Profile; 2]>
+}
+
+/// Proxy state for a single client: the socket used to reach the server on
+/// its behalf, and the random generator and bandwidth schedule of each
+/// direction
+#[derive(Debug)]
+struct Link {
+  socket : UdpSocket,
+  rng    : [Rng; 2],
+  free   : [Instant; 2]
+}
+
+/// A datagram waiting to be delivered
+#[derive(Debug)]
+struct Scheduled {
+  due       : Instant,
+  sequence  : u64,
+  client    : SocketAddrV4,
+  direction : Direction,
+  bytes     : Vec <u8>
+}
+
+/// SplitMix64 generator
+#[derive(Clone, Debug)]
+pub(crate) struct Rng {
+  state : u64
+}
+
+////////////////////////////////////////////////////////////////////////////////
+//  enums                                                                     //
+////////////////////////////////////////////////////////////////////////////////
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Direction {
+  /// From clients to the server
+  Upstream,
+  /// From the server to clients
+  Downstream
+}
+
+////////////////////////////////////////////////////////////////////////////////
+//  impls                                                                     //
+////////////////////////////////////////////////////////////////////////////////
+
+impl Simulator {
+  /// Create a proxy for the server at `server`, listening on an ephemeral
+  /// localhost port
+  pub fn new (server : Address, seed : u64) -> std::io::Result <Self> {
+    let socket = UdpSocket::bind ("127.0.0.1:0")?;
+    socket.set_nonblocking (true)?;
+    Ok (Simulator {
+      socket,
+      server:   server.into(),
+      seed,
+      profiles: Arc::default(),
+      links:    HashMap::new(),
+      queue:    BinaryHeap::new(),
+      sequence: 0,
+      buffer:   vec![0; MAX_DATAGRAM]
+    })
+  }
+
+  /// Address for clients to connect to
+  pub fn address (&self) -> Address {
+    local_address (&self.socket)
+  }
+
+  /// Set the profile of a direction for all clients without their own profile
+  pub fn set_profile (&self, direction : Direction, profile : Profile) {
+    self.profiles.lock().unwrap().set (None, direction, profile);
+  }
+
+  /// Set the profile of a direction for the client at `client`.
+  ///
+  /// The other direction of the client keeps the current default profile.
+  pub fn set_client_profile (&self,
+    client : Address, direction : Direction, profile : Profile
+  ) {
+    self.profiles.lock().unwrap().set (Some (client), direction, profile);
+  }
+
+  /// Forward received datagrams and deliver datagrams that are due.
+  ///
+  /// Returns the time at which the next queued datagram is due, if any.
+  pub fn pump (&mut self) -> std::io::Result <Option <Instant>> {
+    while let Some ((length, client)) = recv (&self.socket, &mut self.buffer)? {
+      self.schedule (client, Direction::Upstream, length)?;
+    }
+    let clients = self.links.keys().copied().collect::<Vec <_>>();
+    for client in clients {
+      while let Some (length) = {
+        let link = &self.links[&client];
+        recv (&link.socket, &mut self.buffer)?.map (|(length, _)| length)
+      } {
+        self.schedule (client, Direction::Downstream, length)?;
+      }
+    }
+    let now = Instant::now();
+    while self.queue.peek().is_some_and (|next| next.due <= now) {
+      let datagram = self.queue.pop().unwrap();
+      let result = match datagram.direction {
+        Direction::Upstream =>
+          self.links[&datagram.client].socket.send (&datagram.bytes),
+        Direction::Downstream =>
+          self.socket.send_to (&datagram.bytes, datagram.client)
+      };
+      match result {
+        Ok (_) => {}
+        // the destination is gone; the datagram is lost
+        Err (err) if is_unreachable (&err) => {}
+        Err (err) => return Err (err)
+      }
+    }
+    Ok (self.queue.peek().map (|next| next.due))
+  }
+
+  /// Run the proxy in a background thread until the returned handle is stopped
+  /// or dropped
+  pub fn spawn (mut self) -> std::io::Result <SimulatorThread> {
+    let address  = self.address();
+    let profiles = self.profiles.clone();
+    let running  = Arc::new (atomic::AtomicBool::new (true));
+    let thread   = {
+      let running = running.clone();
+      std::thread::Builder::new().name ("enet-simulator".to_owned())
+        .spawn (move || {
+          while running.load (atomic::Ordering::Relaxed) {
+            let next  = self.pump()?;
+            let sleep = next.map_or (POLL_INTERVAL,
+              |next| next.saturating_duration_since (Instant::now())
+                .min (POLL_INTERVAL));
+            std::thread::sleep (sleep);
+          }
+          Ok (())
+        })?
+    };
+    Ok (SimulatorThread { address, profiles, running, thread: Some (thread) })
+  }
+
+  /// Apply the profile of a direction to the datagram in the buffer
+  fn schedule (&mut self,
+    client : SocketAddrV4, direction : Direction, length : usize
+  ) -> std::io::Result <()> {
+    let profile = self.profiles.lock().unwrap().get (client, direction);
+    let link = match self.links.entry (client) {
+      std::collections::hash_map::Entry::Occupied (entry) => entry.into_mut(),
+      std::collections::hash_map::Entry::Vacant (entry) =>
+        entry.insert (Link::new (self.server, self.seed, client)?)
+    };
+    let index = direction as usize;
+    let rng   = &mut link.rng[index];
+    if rng.chance (profile.loss) {
+      return Ok (())
+    }
+    let copies = if rng.chance (profile.duplicate) { 2 } else { 1 };
+    let now    = Instant::now();
+    for _ in 0..copies {
+      let mut due = if rng.chance (profile.reorder) {
+        now
+      } else {
+        now + profile.latency + profile.jitter.mul_f64 (rng.next_f64())
+      };
+      if let Some (bandwidth) = profile.bandwidth {
+        let start = link.free[index].max (now);
+        link.free[index] = start +
+          Duration::from_secs_f64 (length as f64 / f64::from (bandwidth.max (1)));
+        due = due.max (link.free[index]);
+      }
+      self.queue.push (Scheduled {
+        due,
+        sequence: self.sequence,
+        client,
+        direction,
+        bytes: self.buffer[..length].to_vec()
+      });
+      self.sequence += 1;
+    }
+    Ok (())
+  }
+}
+
+impl SimulatorThread {
+  /// Address for clients to connect to
+  #[inline]
+  pub fn address (&self) -> Address {
+    self.address.clone()
+  }
+
+  /// Set the profile of a direction for all clients without their own profile
+  pub fn set_profile (&self, direction : Direction, profile : Profile) {
+    self.profiles.lock().unwrap().set (None, direction, profile);
+  }
+
+  /// Set the profile of a direction for the client at `client`
+  pub fn set_client_profile (&self,
+    client : Address, direction : Direction, profile : Profile
+  ) {
+    self.profiles.lock().unwrap().set (Some (client), direction, profile);
+  }
+
+  /// Stop the thread, returning the error that stopped it earlier, if any
+  pub fn stop (mut self) -> std::io::Result <()> {
+    self.join()
+  }
+
+  fn join (&mut self) -> std::io::Result <()> {
+    self.running.store (false, atomic::Ordering::Relaxed);
+    let Some (thread) = self.thread.take() else {
+      return Ok (())
+    };
+    thread.join().unwrap_or_else (
+      |_| Err (std::io::Error::other ("simulator thread panicked")))
+  }
+}
+
+impl Drop for SimulatorThread {
+  fn drop (&mut self) {
+    if let Err (err) = self.join() {
+      log::warn!("simulator thread stopped: {err}");
+    }
+  }
+}
+
+impl Profiles {
+  fn set (&mut self,
+    client : Option <Address>, direction : Direction, profile : Profile
+  ) {
+    let default = self.default;
+    let entry   = match client {
+      Some (client) => self.clients.entry (client.into()).or_insert (default),
+      None => &mut self.default
+    };
+    entry[direction as usize] = profile;
+  }
+
+  fn get (&self, client : SocketAddrV4, direction : Direction) -> Profile {
+    self.clients.get (&client).unwrap_or (&self.default)[direction as usize]
+  }
+}
+
+impl Link {
+  fn new (server : SocketAddrV4, seed : u64, client : SocketAddrV4)
+    -> std::io::Result <Self>
+  {
+    let socket = UdpSocket::bind ("0.0.0.0:0")?;
+    socket.connect (server)?;
+    socket.set_nonblocking (true)?;
+    // each client and direction has its own generator so that decisions do
+    // not depend on how traffic of different clients is interleaved
+    let client = (u64::from (client.ip().to_bits()) << 16) |
+      u64::from (client.port());
+    let now    = Instant::now();
+    Ok (Link {
+      socket,
+      rng:  [Rng::new (seed ^ (client << 1)), Rng::new (seed ^ ((client << 1) | 1))],
+      free: [now, now]
+    })
+  }
+}
+
+impl PartialEq for Scheduled {
+  fn eq (&self, other : &Self) -> bool {
+    self.cmp (other) == std::cmp::Ordering::Equal
+  }
+}
+impl Eq for Scheduled {}
+impl PartialOrd for Scheduled {
+  fn partial_cmp (&self, other : &Self) -> Option <std::cmp::Ordering> {
+    Some (self.cmp (other))
+  }
+}
+impl Ord for Scheduled {
+  /// Reversed so that the earliest datagram is at the top of the heap
+  fn cmp (&self, other : &Self) -> std::cmp::Ordering {
+    (other.due, other.sequence).cmp (&(self.due, self.sequence))
+  }
+}
+
+impl Rng {
+  pub(crate) const fn new (seed : u64) -> Self {
+    Rng { state: seed }
+  }
+
+  pub(crate) const fn next_u64 (&mut self) -> u64 {
+    self.state = self.state.wrapping_add (0x9E37_79B9_7F4A_7C15);
+    let mut z = self.state;
+    z = (z ^ (z >> 30)).wrapping_mul (0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul (0x94D0_49BB_1331_11EB);
+    z ^ (z >> 31)
+  }
+
+  /// Uniform in `[0.0, 1.0)`
+  pub(crate) fn next_f64 (&mut self) -> f64 {
+    (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+  }
+
+  pub(crate) fn chance (&mut self, probability : f64) -> bool {
+    probability > 0.0 && self.next_f64() < probability
+  }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+//  functions                                                                 //
+////////////////////////////////////////////////////////////////////////////////
+
+/// Receive a datagram without blocking, ignoring datagrams that are not IPv4
+fn recv (socket : &UdpSocket, buffer : &mut [u8])
+  -> std::io::Result <Option <(usize, SocketAddrV4)>>
+{
+  loop {
+    match socket.recv_from (buffer) {
+      Ok ((length, std::net::SocketAddr::V4 (from))) =>
+        return Ok (Some ((length, from))),
+      Ok ((_, std::net::SocketAddr::V6 (_))) => {}
+      Err (err) if err.kind() == std::io::ErrorKind::WouldBlock =>
+        return Ok (None),
+      // an earlier datagram could not be delivered
+      Err (err) if is_unreachable (&err) => {}
+      Err (err) => return Err (err)
+    }
+  }
+}
+
+fn is_unreachable (err : &std::io::Error) -> bool {
+  matches!(err.kind(),
+    std::io::ErrorKind::ConnectionRefused | std::io::ErrorKind::ConnectionReset)
+}
+
+fn local_address (socket : &UdpSocket) -> Address {
+  match socket.local_addr() {
+    Ok (std::net::SocketAddr::V4 (address)) => address.into(),
+    _ => unreachable!("simulator sockets are bound to IPv4 addresses")
+  }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+//  tests                                                                     //
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg (test)]
+mod tests {
+  use super::*;
+
+  /// Time allowed for datagrams to arrive over loopback
+  const TIMEOUT : Duration = Duration::from_secs (2);
+
+  /// A non-blocking socket bound to an ephemeral localhost port
+  fn socket() -> UdpSocket {
+    let socket = UdpSocket::bind ("127.0.0.1:0").unwrap();
+    socket.set_nonblocking (true).unwrap();
+    socket
+  }
+
+  fn address (socket : &UdpSocket) -> SocketAddrV4 {
+    local_address (socket).into()
+  }
+
+  /// Pump the simulator until `count` datagrams are received by `socket` or
+  /// `timeout` has elapsed
+  fn receive (simulator : &mut Simulator, socket : &UdpSocket, count : usize,
+    timeout : Duration
+  ) -> Vec <(Vec <u8>, SocketAddrV4)> {
+    let start = Instant::now();
+    let mut buffer   = vec![0; MAX_DATAGRAM];
+    let mut received = Vec::new();
+    while received.len() < count && start.elapsed() < timeout {
+      simulator.pump().unwrap();
+      while let Some ((length, from)) = recv (socket, &mut buffer).unwrap() {
+        received.push ((buffer[..length].to_vec(), from));
+      }
+      std::thread::sleep (Duration::from_millis (1));
+    }
+    received
+  }
+
+  fn payloads (received : &[(Vec <u8>, SocketAddrV4)]) -> Vec <&[u8]> {
+    received.iter().map (|(bytes, _)| bytes.as_slice()).collect()
+  }
+
+  #[test]
+  fn forwards_both_directions() {
+    let server    = socket();
+    let client    = socket();
+    let mut simulator = Simulator::new (address (&server).into(), 1).unwrap();
+    let proxy = SocketAddrV4::from (simulator.address());
+    client.send_to (b"up", proxy).unwrap();
+    let received = receive (&mut simulator, &server, 1, TIMEOUT);
+    assert_eq!(payloads (&received), [b"up"]);
+    // the server answers the address the proxy used for the client
+    server.send_to (b"down", received[0].1).unwrap();
+    let received = receive (&mut simulator, &client, 1, TIMEOUT);
+    assert_eq!(received, [(b"down".to_vec(), proxy)]);
+  }
+
+  #[test]
+  fn loss_drops_datagrams() {
+    let server    = socket();
+    let client    = socket();
+    let mut simulator = Simulator::new (address (&server).into(), 1).unwrap();
+    simulator.set_profile (Direction::Upstream,
+      Profile { loss: 1.0, .. Profile::default() });
+    let proxy = SocketAddrV4::from (simulator.address());
+    for _ in 0..10 {
+      client.send_to (b"lost", proxy).unwrap();
+    }
+    let received = receive (&mut simulator, &server, 1,
+      Duration::from_millis (100));
+    assert!(received.is_empty());
+  }
+
+  #[test]
+  fn same_seed_makes_same_decisions() {
+    // decisions depend on the seed and the client address
+    let run = |seed, client : &UdpSocket| {
+      let server    = socket();
+      let mut simulator = Simulator::new (address (&server).into(), seed)
+        .unwrap();
+      simulator.set_client_profile (address (client).into(),
+        Direction::Upstream, Profile { loss: 0.5, .. Profile::default() });
+      let proxy = SocketAddrV4::from (simulator.address());
+      for index in 0..32u8 {
+        client.send_to (&[index], proxy).unwrap();
+      }
+      let received = receive (&mut simulator, &server, 32,
+        Duration::from_millis (200));
+      received.into_iter().map (|(bytes, _)| bytes[0]).collect::<Vec <_>>()
+    };
+    let client    = socket();
+    let delivered = run (7, &client);
+    assert!(0 < delivered.len() && delivered.len() < 32, "{delivered:?}");
+    assert_eq!(delivered, run (7, &client));
+  }
+
+  #[test]
+  fn client_profile_overrides_default() {
+    let server    = socket();
+    let lossy     = socket();
+    let client    = socket();
+    let mut simulator = Simulator::new (address (&server).into(), 1).unwrap();
+    simulator.set_client_profile (address (&lossy).into(), Direction::Upstream,
+      Profile { loss: 1.0, .. Profile::default() });
+    let proxy = SocketAddrV4::from (simulator.address());
+    lossy.send_to (b"lossy", proxy).unwrap();
+    client.send_to (b"client", proxy).unwrap();
+    let received = receive (&mut simulator, &server, 2,
+      Duration::from_millis (100));
+    assert_eq!(payloads (&received), [b"client"]);
+  }
+
+  #[test]
+  fn latency_delays_and_reorder_overtakes() {
+    let server    = socket();
+    let client    = socket();
+    let mut simulator = Simulator::new (address (&server).into(), 1).unwrap();
+    let latency   = Duration::from_millis (100);
+    simulator.set_profile (Direction::Upstream,
+      Profile { latency, .. Profile::default() });
+    let proxy = SocketAddrV4::from (simulator.address());
+    let start = Instant::now();
+    client.send_to (b"first", proxy).unwrap();
+    assert!(receive (&mut simulator, &server, 1, Duration::from_millis (20))
+      .is_empty());
+    simulator.set_profile (Direction::Upstream,
+      Profile { latency, reorder: 1.0, .. Profile::default() });
+    client.send_to (b"second", proxy).unwrap();
+    let received = receive (&mut simulator, &server, 2, TIMEOUT);
+    assert_eq!(payloads (&received), [b"second".as_slice(), b"first"]);
+    assert!(latency <= start.elapsed());
+  }
+
+  #[test]
+  fn duplicate_delivers_twice() {
+    let server    = socket();
+    let client    = socket();
+    let mut simulator = Simulator::new (address (&server).into(), 1).unwrap();
+    simulator.set_profile (Direction::Upstream,
+      Profile { duplicate: 1.0, .. Profile::default() });
+    let proxy = SocketAddrV4::from (simulator.address());
+    client.send_to (b"twice", proxy).unwrap();
+    let received = receive (&mut simulator, &server, 3,
+      Duration::from_millis (100));
+    assert_eq!(payloads (&received), [b"twice", b"twice"]);
+  }
+
+  #[test]
+  fn bandwidth_spaces_datagrams() {
+    let server    = socket();
+    let client    = socket();
+    let mut simulator = Simulator::new (address (&server).into(), 1).unwrap();
+    // 1000 byte datagrams take 10 ms each
+    simulator.set_profile (Direction::Upstream,
+      Profile { bandwidth: Some (100_000), .. Profile::default() });
+    let proxy = SocketAddrV4::from (simulator.address());
+    let start = Instant::now();
+    for _ in 0..5 {
+      client.send_to (&[0; 1000], proxy).unwrap();
+    }
+    let received = receive (&mut simulator, &server, 5, TIMEOUT);
+    assert_eq!(received.len(), 5);
+    assert!(Duration::from_millis (50) <= start.elapsed());
+  }
+
+  #[test]
+  fn rng_is_uniform_and_seeded() {
+    let mut rng = Rng::new (3);
+    let values  = std::iter::repeat_with (|| rng.next_f64()).take (1000)
+      .collect::<Vec <_>>();
+    assert!(values.iter().all (|value| (0.0..1.0).contains (value)));
+    let mean = values.iter().sum::<f64>() / 1000.0;
+    assert!((0.45..0.55).contains (&mean), "{mean}");
+    assert_eq!(Rng::new (3).next_u64(), Rng::new (3).next_u64());
+    assert_ne!(Rng::new (3).next_u64(), Rng::new (4).next_u64());
+    assert!(!rng.chance (0.0));
+    assert!(rng.chance (1.0));
+  }
+
+  #[test]
+  fn hosts_connect_through_a_lossy_proxy() {
+    use crate::{testing, Event, Packet};
+    const PACKETS : u8 = 20;
+    let enet = testing::enet();
+    let mut server = enet.server_host_create (Address::localhost (0), 1, None,
+      None, None).unwrap();
+    let simulator = Simulator::new (server.local_address(), 7).unwrap();
+    let profile   = Profile {
+      loss:    0.1,
+      latency: Duration::from_millis (20),
+      .. Profile::default()
+    };
+    simulator.set_profile (Direction::Upstream, profile);
+    simulator.set_profile (Direction::Downstream, profile);
+    let simulator  = simulator.spawn().unwrap();
+    let mut client = enet.client_host_create (1, None, None).unwrap();
+    let mut peer   = client.connect (&simulator.address(), 1, 0).unwrap();
+    let timeout    = Duration::from_secs (20);
+    testing::pump_until (&mut [&mut server, &mut client], timeout,
+      |events| events.len() == 2).unwrap();
+    for index in 0..PACKETS {
+      peer.send (0, Packet::Allocate {
+        bytes: &[index], flags: crate::packet::Flags::RELIABLE
+      }).unwrap();
+    }
+    let events = testing::pump_until (&mut [&mut server, &mut client], timeout,
+      |events| events.len() == usize::from (PACKETS)).unwrap();
+    let received = events.iter().filter_map (|recorded| match &recorded.event {
+      Event::Receive { packet, .. } => Some (packet.data()[0]),
+      _ => None
+    }).collect::<Vec <_>>();
+    assert_eq!(received, (0..PACKETS).collect::<Vec <_>>());
+    // both directions are delayed
+    assert!(40 <= peer.round_trip_time(), "{}", peer.round_trip_time());
+    simulator.stop().unwrap();
+  }
+}
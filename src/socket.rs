@@ -0,0 +1,458 @@
+//! Portable sockets from the ENet socket layer.
+//!
+//! A `Socket` is useful for traffic next to a `Host` that should use the same
+//! platform layer, e.g. discovery broadcasts. Sends and receives gather and
+//! scatter a datagram over several buffers, the same as ENet does internally.
+//!
+//! ```no_run
+//! # use enet::{socket, Address};
+//! let enet   = enet::initialize().unwrap();
+//! let socket = enet.socket_create (socket::Type::Datagram).unwrap();
+//! socket.bind (&Address::any (0)).unwrap();
+//! let header = [0u8; 4];
+//! socket.send_to (&Address::localhost (12345), &[&header, b"payload"]).unwrap();
+//! ```
+
+use std;
+use bitflags::bitflags;
+
+use ll;
+use crate::{Address, EnetDrop};
+
+/// `ENET_SOCKET_NULL`: -1 on unix and `INVALID_SOCKET` on windows
+const SOCKET_NULL : ll::ENetSocket = !0;
+/// Largest UDP payload over IPv4
+const MAX_DATAGRAM_LENGTH : usize = 65_507;
+
+////////////////////////////////////////////////////////////////////////////////
+//  structs                                                                   //
+////////////////////////////////////////////////////////////////////////////////
+
+/// A socket created with the ENet socket layer, for auxiliary traffic outside
+/// of a `Host`.
+///
+/// Created with `enet.socket_create()`; the socket will keep Enet alive.
+#[derive(Debug)]
+pub struct Socket {
+  raw       : ll::ENetSocket,
+  /// Sockets need the ENet context to be initialized
+  _enetdrop : std::sync::Arc <EnetDrop>
+}
+
+bitflags! {
+  /// Socket conditions for `socket.wait()`
+  #[derive(Clone, Copy, Debug, Eq, PartialEq)]
+  pub struct Wait : u32 {
+    #[allow(clippy::unnecessary_cast)] // on windows ll flags are i32
+    const SEND      = ll::_ENetSocketWait_ENET_SOCKET_WAIT_SEND as u32;
+    #[allow(clippy::unnecessary_cast)] // on windows ll flags are i32
+    const RECEIVE   = ll::_ENetSocketWait_ENET_SOCKET_WAIT_RECEIVE as u32;
+    /// The wait was interrupted by a signal
+    #[allow(clippy::unnecessary_cast)] // on windows ll flags are i32
+    const INTERRUPT = ll::_ENetSocketWait_ENET_SOCKET_WAIT_INTERRUPT as u32;
+  }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+//  enums                                                                     //
+////////////////////////////////////////////////////////////////////////////////
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Type {
+  Datagram,
+  Stream
+}
+
+/// Options for `socket.set_option()`
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum SocketOption {
+  NonBlock       (bool),
+  Broadcast      (bool),
+  /// Bytes
+  ReceiveBuffer  (u32),
+  /// Bytes
+  SendBuffer     (u32),
+  ReuseAddress   (bool),
+  /// Milliseconds
+  ReceiveTimeout (u32),
+  /// Milliseconds
+  SendTimeout    (u32),
+  NoDelay        (bool),
+  Ttl            (u8)
+}
+
+/// Names of socket options, for reading back their values
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum OptionName {
+  NonBlock,
+  Broadcast,
+  ReceiveBuffer,
+  SendBuffer,
+  ReuseAddress,
+  ReceiveTimeout,
+  SendTimeout,
+  NoDelay,
+  Ttl
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Shutdown {
+  Read,
+  Write,
+  ReadWrite
+}
+
+////////////////////////////////////////////////////////////////////////////////
+//  impls                                                                     //
+////////////////////////////////////////////////////////////////////////////////
+
+impl Socket {
+  pub(crate) fn new (socket_type : Type, enetdrop : std::sync::Arc <EnetDrop>)
+    -> std::io::Result <Self>
+  {
+    let raw_type = match socket_type {
+      Type::Datagram => ll::_ENetSocketType_ENET_SOCKET_TYPE_DATAGRAM,
+      Type::Stream   => ll::_ENetSocketType_ENET_SOCKET_TYPE_STREAM
+    };
+    let raw = unsafe { ll::enet_socket_create (raw_type) };
+    if raw == SOCKET_NULL {
+      return Err (std::io::Error::last_os_error())
+    }
+    Ok (Socket { raw, _enetdrop: enetdrop })
+  }
+
+  /// # Safety
+  ///
+  /// Unsafe: returns the raw socket, which must not be destroyed.
+  #[inline]
+  pub const unsafe fn raw (&self) -> ll::ENetSocket {
+    self.raw
+  }
+
+  pub fn bind (&self, address : &Address) -> std::io::Result <()> {
+    check (unsafe { ll::enet_socket_bind (self.raw, address.raw()) })
+  }
+
+  /// Address the socket is bound to
+  pub fn local_address (&self) -> std::io::Result <Address> {
+    let mut address = Address::any (0);
+    check (unsafe { ll::enet_socket_get_address (self.raw, address.raw_mut()) })?;
+    Ok (address)
+  }
+
+  pub fn set_option (&self, option : SocketOption) -> std::io::Result <()> {
+    set_option (self.raw, option)
+  }
+
+  /// Read back the value of an option as applied by the operating system.
+  ///
+  /// Options are read from the socket directly since ENet can only read a few
+  /// of them. Reading `NonBlock` on windows returns `ErrorKind::Unsupported`,
+  /// and reading `NoDelay` from a datagram socket fails.
+  pub fn option (&self, name : OptionName) -> std::io::Result <SocketOption> {
+    get_option (self.raw, name)
+  }
+
+  /// Take the pending error of the socket (`SO_ERROR`), if any
+  pub fn take_error (&self) -> std::io::Result <Option <std::io::Error>> {
+    let mut value = 0;
+    check (unsafe {
+      ll::enet_socket_get_option (self.raw,
+        ll::_ENetSocketOption_ENET_SOCKOPT_ERROR, &mut value)
+    })?;
+    Ok ((value != 0).then (|| std::io::Error::from_raw_os_error (value)))
+  }
+
+  /// Send a datagram gathered from `buffers` to `address`.
+  ///
+  /// Returns the number of bytes sent, or `ErrorKind::WouldBlock` if the socket
+  /// is non-blocking and the datagram could not be sent.
+  pub fn send_to (&self, address : &Address, buffers : &[&[u8]])
+    -> std::io::Result <usize>
+  {
+    let buffers = buffers.iter().map (|buffer| ll::ENetBuffer {
+      data:       buffer.as_ptr() as *mut std::os::raw::c_void,
+      dataLength: buffer.len()
+    }).collect::<Vec <_>>();
+    let length = buffers.iter().map (|buffer| buffer.dataLength).sum::<usize>();
+    let sent = unsafe {
+      ll::enet_socket_send (self.raw, address.raw(), buffers.as_ptr(), buffers.len())
+    };
+    match sent {
+      sent if sent < 0 => Err (std::io::Error::last_os_error()),
+      0 if 0 < length  => Err (std::io::ErrorKind::WouldBlock.into()),
+      sent => Ok (sent as usize)
+    }
+  }
+
+  /// Receive a datagram, scattering it into `buffers`.
+  ///
+  /// Returns the sender and the number of bytes received, or `None` if the
+  /// socket is non-blocking and no datagram is waiting. A datagram that does
+  /// not fit in the buffers is discarded and returns `ErrorKind::InvalidData`.
+  pub fn recv_from (&self, buffers : &mut [&mut [u8]])
+    -> std::io::Result <Option <(Address, usize)>>
+  {
+    let mut buffers = buffers.iter_mut().map (|buffer| ll::ENetBuffer {
+      data:       buffer.as_mut_ptr() as *mut std::os::raw::c_void,
+      dataLength: buffer.len()
+    }).collect::<Vec <_>>();
+    let capacity = buffers.iter().map (|buffer| buffer.dataLength).sum::<usize>();
+    // ENet fails on a truncated datagram without setting an OS error: a spare
+    // buffer takes what does not fit so that no datagram is truncated
+    let mut spare = std::mem::MaybeUninit::<[u8; MAX_DATAGRAM_LENGTH]>::uninit();
+    if capacity < MAX_DATAGRAM_LENGTH {
+      buffers.push (ll::ENetBuffer {
+        data:       spare.as_mut_ptr() as *mut std::os::raw::c_void,
+        dataLength: MAX_DATAGRAM_LENGTH - capacity
+      });
+    }
+    let mut address = Address::any (0);
+    let received = unsafe {
+      ll::enet_socket_receive (self.raw, address.raw_mut(), buffers.as_mut_ptr(),
+        buffers.len())
+    };
+    match received {
+      received if received < 0 => Err (std::io::Error::last_os_error()),
+      0 => Ok (None),
+      received if capacity < received as usize => Err (std::io::Error::new (
+        std::io::ErrorKind::InvalidData, "datagram larger than the buffers")),
+      received => Ok (Some ((address, received as usize)))
+    }
+  }
+
+  /// Wait up to `timeout` milliseconds for any of the `conditions`, returning
+  /// the conditions that are met (empty on timeout)
+  pub fn wait (&self, conditions : Wait, timeout : u32) -> std::io::Result <Wait> {
+    let mut condition = conditions.bits();
+    check (unsafe { ll::enet_socket_wait (self.raw, &mut condition, timeout) })?;
+    Ok (Wait::from_bits_retain (condition))
+  }
+
+  pub fn shutdown (&self, how : Shutdown) -> std::io::Result <()> {
+    let how = match how {
+      Shutdown::Read      => ll::_ENetSocketShutdown_ENET_SOCKET_SHUTDOWN_READ,
+      Shutdown::Write     => ll::_ENetSocketShutdown_ENET_SOCKET_SHUTDOWN_WRITE,
+      Shutdown::ReadWrite => ll::_ENetSocketShutdown_ENET_SOCKET_SHUTDOWN_READ_WRITE
+    };
+    check (unsafe { ll::enet_socket_shutdown (self.raw, how) })
+  }
+}
+
+impl SocketOption {
+  pub const fn name (self) -> OptionName {
+    match self {
+      SocketOption::NonBlock       (_) => OptionName::NonBlock,
+      SocketOption::Broadcast      (_) => OptionName::Broadcast,
+      SocketOption::ReceiveBuffer  (_) => OptionName::ReceiveBuffer,
+      SocketOption::SendBuffer     (_) => OptionName::SendBuffer,
+      SocketOption::ReuseAddress   (_) => OptionName::ReuseAddress,
+      SocketOption::ReceiveTimeout (_) => OptionName::ReceiveTimeout,
+      SocketOption::SendTimeout    (_) => OptionName::SendTimeout,
+      SocketOption::NoDelay        (_) => OptionName::NoDelay,
+      SocketOption::Ttl            (_) => OptionName::Ttl
+    }
+  }
+
+  /// Raw option and value passed to ENet
+  fn to_ll (self) -> (ll::ENetSocketOption, i32) {
+    let value = match self {
+      SocketOption::NonBlock (value)     | SocketOption::Broadcast (value) |
+      SocketOption::ReuseAddress (value) | SocketOption::NoDelay (value)   =>
+        i32::from (value),
+      SocketOption::ReceiveBuffer (value) | SocketOption::SendBuffer (value) |
+      SocketOption::ReceiveTimeout (value) | SocketOption::SendTimeout (value) =>
+        i32::try_from (value).unwrap_or (i32::MAX),
+      SocketOption::Ttl (ttl) => i32::from (ttl)
+    };
+    (self.name().to_ll(), value)
+  }
+}
+
+impl OptionName {
+  const fn to_ll (self) -> ll::ENetSocketOption {
+    match self {
+      OptionName::NonBlock       => ll::_ENetSocketOption_ENET_SOCKOPT_NONBLOCK,
+      OptionName::Broadcast      => ll::_ENetSocketOption_ENET_SOCKOPT_BROADCAST,
+      OptionName::ReceiveBuffer  => ll::_ENetSocketOption_ENET_SOCKOPT_RCVBUF,
+      OptionName::SendBuffer     => ll::_ENetSocketOption_ENET_SOCKOPT_SNDBUF,
+      OptionName::ReuseAddress   => ll::_ENetSocketOption_ENET_SOCKOPT_REUSEADDR,
+      OptionName::ReceiveTimeout => ll::_ENetSocketOption_ENET_SOCKOPT_RCVTIMEO,
+      OptionName::SendTimeout    => ll::_ENetSocketOption_ENET_SOCKOPT_SNDTIMEO,
+      OptionName::NoDelay        => ll::_ENetSocketOption_ENET_SOCKOPT_NODELAY,
+      OptionName::Ttl            => ll::_ENetSocketOption_ENET_SOCKOPT_TTL
+    }
+  }
+}
+
+impl Drop for Socket {
+  #[inline]
+  fn drop (&mut self) {
+    unsafe { ll::enet_socket_destroy (self.raw) }
+  }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+//  functions                                                                 //
+////////////////////////////////////////////////////////////////////////////////
+
+fn check (result : std::os::raw::c_int) -> std::io::Result <()> {
+  if result < 0 {
+    return Err (std::io::Error::last_os_error())
+  }
+  Ok (())
+}
+
+/// Set an option on a raw socket, including the socket of a host
+pub(crate) fn set_option (raw : ll::ENetSocket, option : SocketOption)
+  -> std::io::Result <()>
+{
+  let (option, value) = option.to_ll();
+  check (unsafe { ll::enet_socket_set_option (raw, option, value) })
+}
+
+/// Read an option from a raw socket, including the socket of a host
+pub(crate) fn get_option (raw : ll::ENetSocket, name : OptionName)
+  -> std::io::Result <SocketOption>
+{
+  if raw == SOCKET_NULL {
+    return Err (std::io::Error::new (std::io::ErrorKind::InvalidInput,
+      "invalid socket"))
+  }
+  let millis = |timeout : Option <std::time::Duration>| timeout
+    .map_or (0, |timeout| u32::try_from (timeout.as_millis()).unwrap_or (u32::MAX));
+  let bytes = |size : usize| u32::try_from (size).unwrap_or (u32::MAX);
+  // the socket stays owned by ENet
+  #[cfg(unix)]
+  let borrowed = unsafe { std::os::fd::BorrowedFd::borrow_raw (raw) };
+  #[cfg(windows)]
+  let borrowed = unsafe {
+    std::os::windows::io::BorrowedSocket::borrow_raw (raw as _)
+  };
+  let socket = socket2::SockRef::from (&borrowed);
+  let option = match name {
+    #[cfg(unix)]
+    OptionName::NonBlock => SocketOption::NonBlock (socket.nonblocking()?),
+    #[cfg(not(unix))]
+    OptionName::NonBlock => return Err (std::io::Error::new (
+      std::io::ErrorKind::Unsupported,
+      "the NonBlock socket option cannot be read on this platform")),
+    OptionName::Broadcast      => SocketOption::Broadcast (socket.broadcast()?),
+    OptionName::ReceiveBuffer  =>
+      SocketOption::ReceiveBuffer (bytes (socket.recv_buffer_size()?)),
+    OptionName::SendBuffer     =>
+      SocketOption::SendBuffer (bytes (socket.send_buffer_size()?)),
+    OptionName::ReuseAddress   =>
+      SocketOption::ReuseAddress (socket.reuse_address()?),
+    OptionName::ReceiveTimeout =>
+      SocketOption::ReceiveTimeout (millis (socket.read_timeout()?)),
+    OptionName::SendTimeout    =>
+      SocketOption::SendTimeout (millis (socket.write_timeout()?)),
+    OptionName::NoDelay        => SocketOption::NoDelay (socket.tcp_nodelay()?),
+    OptionName::Ttl            =>
+      SocketOption::Ttl (u8::try_from (socket.ttl_v4()?).unwrap_or (u8::MAX))
+  };
+  Ok (option)
+}
+
+////////////////////////////////////////////////////////////////////////////////
+//  tests                                                                     //
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg (test)]
+mod tests {
+  use std::net::SocketAddrV4;
+  use super::*;
+
+  /// A datagram socket bound to an ephemeral localhost port, and its address
+  fn bound() -> (Socket, Address) {
+    let socket = crate::testing::enet().socket_create (Type::Datagram).unwrap();
+    socket.bind (&Address::localhost (0)).unwrap();
+    let port = socket.local_address().unwrap().port();
+    (socket, Address::localhost (port))
+  }
+
+  /// Wait until a datagram is waiting on `socket`
+  fn readable (socket : &Socket) {
+    assert_eq!(socket.wait (Wait::RECEIVE, 1000).unwrap(), Wait::RECEIVE);
+  }
+
+  #[test]
+  fn options_are_read_back() {
+    let enet   = crate::testing::enet();
+    let socket = enet.socket_create (Type::Datagram).unwrap();
+    for option in [
+      SocketOption::NonBlock (true), SocketOption::Broadcast (true),
+      SocketOption::ReuseAddress (true), SocketOption::ReceiveTimeout (1500),
+      SocketOption::SendTimeout (500), SocketOption::Ttl (17),
+      SocketOption::NonBlock (false), SocketOption::Broadcast (false)
+    ] {
+      socket.set_option (option).unwrap();
+      assert_eq!(socket.option (option.name()).unwrap(), option);
+    }
+    socket.set_option (SocketOption::SendBuffer (64 * 1024)).unwrap();
+    let SocketOption::SendBuffer (size) =
+      socket.option (OptionName::SendBuffer).unwrap()
+    else {
+      unreachable!()
+    };
+    // linux doubles the size for bookkeeping
+    assert!(64 * 1024 <= size, "{size}");
+    socket.option (OptionName::NoDelay).unwrap_err();
+  }
+
+  #[test]
+  fn invalid_socket_is_rejected() {
+    let err = get_option (SOCKET_NULL, OptionName::Ttl).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+  }
+
+  #[test]
+  fn datagram_is_gathered_and_scattered() {
+    let (sender,   sender_address)   = bound();
+    let (receiver, receiver_address) = bound();
+    let sent = sender.send_to (&receiver_address, &[b"head", b"-", b"tail"])
+      .unwrap();
+    assert_eq!(sent, 9);
+    readable (&receiver);
+    let (mut first, mut second) = ([0; 3], [0; 10]);
+    let (source, length) =
+      receiver.recv_from (&mut [&mut first, &mut second]).unwrap().unwrap();
+    assert_eq!(length, 9);
+    assert_eq!(&first, b"hea");
+    assert_eq!(&second[..6], b"d-tail");
+    assert_eq!(SocketAddrV4::from (source),
+      SocketAddrV4::from (sender_address));
+  }
+
+  #[test]
+  fn oversized_datagram_is_discarded() {
+    let (sender, _) = bound();
+    let (receiver, receiver_address) = bound();
+    receiver.set_option (SocketOption::NonBlock (true)).unwrap();
+    sender.send_to (&receiver_address, &[&[1; 100]]).unwrap();
+    sender.send_to (&receiver_address, &[&[2; 10]]).unwrap();
+    readable (&receiver);
+    let mut buffer = [0; 10];
+    let err = receiver.recv_from (&mut [&mut buffer]).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    // the next datagram is received whole
+    readable (&receiver);
+    let (_, length) = receiver.recv_from (&mut [&mut buffer]).unwrap().unwrap();
+    assert_eq!(length, 10);
+    assert_eq!(buffer, [2; 10]);
+    assert!(receiver.recv_from (&mut [&mut buffer]).unwrap().is_none());
+  }
+
+  #[test]
+  fn wait_times_out_until_readable() {
+    let (sender, _) = bound();
+    let (receiver, receiver_address) = bound();
+    let start = std::time::Instant::now();
+    assert_eq!(receiver.wait (Wait::RECEIVE, 50).unwrap(), Wait::empty());
+    assert!(std::time::Duration::from_millis (45) <= start.elapsed());
+    assert_eq!(sender.wait (Wait::SEND, 0).unwrap(), Wait::SEND);
+    sender.send_to (&receiver_address, &[b"ready"]).unwrap();
+    readable (&receiver);
+  }
+}
@@ -0,0 +1,331 @@
+//! Helpers for testing hosts within a single process.
+//!
+//! Available to the tests of this crate, and to other crates with the `testing`
+//! feature.
+//!
+//! Tests should create hosts from the context returned by `testing::enet()`,
+//! which is shared by all tests in the binary and by `Enet::shared()`. Calling
+//! `enet::initialize()` elsewhere in the same binary will fail once the shared
+//! context exists.
+//!
+//! ```no_run
+//! # use std::time::Duration;
+//! # use enet::{testing, Packet, packet};
+//! let mut pair = testing::connected_pair();
+//! pair.client_peer.send (0, Packet::Allocate {
+//!   bytes: b"ping", flags: packet::Flags::RELIABLE
+//! }).unwrap();
+//! let events = testing::pump_until (&mut [&mut pair.server, &mut pair.client],
+//!   testing::TIMEOUT, |events| !events.is_empty()).unwrap();
+//! testing::assert_events (&events, &[
+//!   (0, testing::Expect::Receive { channel_id: 0, data: b"ping" })
+//! ]);
+//! ```
+
+use std;
+use std::time::{Duration, Instant};
+
+use crate::{host, Address, Enet, Event, Host, Peer};
+
+/// Default time allowed for a test exchange to complete
+pub const TIMEOUT : Duration = Duration::from_secs (5);
+/// Peer count of the server host created by `connected_pair()`
+const SERVER_PEER_COUNT : u32 = 8;
+
+static SHARED : std::sync::OnceLock <Enet> = std::sync::OnceLock::new();
+
+////////////////////////////////////////////////////////////////////////////////
+//  structs                                                                   //
+////////////////////////////////////////////////////////////////////////////////
+
+/// A server and client host connected over loopback
+#[derive(Debug)]
+pub struct Pair {
+  pub server      : Host,
+  pub client      : Host,
+  /// The client, as seen by the server
+  pub server_peer : Peer,
+  /// The server, as seen by the client
+  pub client_peer : Peer
+}
+
+/// An event returned by `pump_until()`, with the index of the host that
+/// returned it
+#[derive(Debug)]
+pub struct Recorded {
+  pub host  : usize,
+  pub event : Event
+}
+
+////////////////////////////////////////////////////////////////////////////////
+//  enums                                                                     //
+////////////////////////////////////////////////////////////////////////////////
+
+/// Expected event for `assert_events()`
+#[derive(Clone, Copy, Debug)]
+pub enum Expect <'a> {
+  Connect,
+  Authenticated,
+  /// Disconnect with the given `data`, or any data if `None`
+  Disconnect (Option <u32>),
+  Receive {
+    channel_id : u8,
+    data       : &'a [u8]
+  }
+}
+
+#[derive(Debug)]
+pub enum PumpError {
+  /// The predicate was not satisfied in time; contains the events received
+  Timeout (Vec <Recorded>),
+  /// Servicing a host failed
+  Service (host::Error)
+}
+
+////////////////////////////////////////////////////////////////////////////////
+//  impls                                                                     //
+////////////////////////////////////////////////////////////////////////////////
+
+impl Expect <'_> {
+  pub fn matches (&self, event : &Event) -> bool {
+    match (*self, event) {
+      (Expect::Connect,       Event::Connect {..}) |
+      (Expect::Authenticated, Event::Authenticated {..}) => true,
+      (Expect::Disconnect (expected), Event::Disconnect { data, .. }) =>
+        expected.is_none_or (|expected| expected == *data),
+      (Expect::Receive { channel_id, data }, Event::Receive {
+        channel_id: received, packet, ..
+      }) => channel_id == *received && data == packet.data(),
+      _ => false
+    }
+  }
+}
+
+impl std::fmt::Display for PumpError {
+  fn fmt (&self, f : &mut std::fmt::Formatter) -> std::fmt::Result {
+    match self {
+      PumpError::Timeout (events) =>
+        write!(f, "timed out after {} events", events.len()),
+      PumpError::Service (_) => write!(f, "servicing a host failed")
+    }
+  }
+}
+impl std::error::Error for PumpError {
+  fn source (&self) -> Option <&(dyn std::error::Error + 'static)> {
+    match self {
+      PumpError::Timeout (_) => None,
+      PumpError::Service (error) => Some (error)
+    }
+  }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+//  functions                                                                 //
+////////////////////////////////////////////////////////////////////////////////
+
+/// The ENet context shared by all tests in the binary, initialized on first
+/// use.
+///
+/// # Panics
+///
+/// Panics if ENet cannot be initialized.
+pub fn enet() -> Enet {
+  SHARED.get_or_init (|| Enet::shared()
+    .expect ("failed to initialize the shared ENet context")).clone()
+}
+
+/// Create a server bound to an ephemeral localhost port and a client connected
+/// to it with 2 channels.
+///
+/// # Panics
+///
+/// Panics if a host cannot be created or the connection does not complete
+/// within `TIMEOUT`.
+#[inline]
+pub fn connected_pair() -> Pair {
+  connected_pair_with (2, |_| ())
+}
+
+/// Like `connected_pair()`, calling `setup` with the server and then the client
+/// host before connecting, e.g. to set a handshake.
+///
+/// The connection is complete when both hosts have returned a `Connect` or
+/// `Authenticated` event.
+///
+/// # Panics
+///
+/// Panics if a host cannot be created or the connection does not complete
+/// within `TIMEOUT`.
+pub fn connected_pair_with <F> (channel_count : u8, mut setup : F) -> Pair where
+  F : FnMut (&mut Host)
+{
+  let enet = enet();
+  let mut server = enet.server_host_create (Address::localhost (0),
+    SERVER_PEER_COUNT, Some (u32::from (channel_count)), None, None)
+    .expect ("failed to create server host");
+  let mut client = enet.client_host_create (1, None, None)
+    .expect ("failed to create client host");
+  setup (&mut server);
+  setup (&mut client);
+  let port = server.local_address().port();
+  let client_peer = client.connect (&Address::localhost (port), channel_count, 0)
+    .expect ("failed to connect client host");
+  let connected = |events : &[Recorded], host| events.iter().any (|recorded|
+    recorded.host == host && matches!(recorded.event,
+      Event::Connect {..} | Event::Authenticated {..}));
+  let events = pump_until (&mut [&mut server, &mut client], TIMEOUT,
+    |events| connected (events, 0) && connected (events, 1)
+  ).expect ("connection between test hosts failed");
+  let server_peer = events.into_iter().find_map (|recorded| match recorded {
+    Recorded {
+      host: 0, event: Event::Connect { peer, .. } | Event::Authenticated { peer, .. }
+    } => Some (peer),
+    _ => None
+  }).unwrap();
+  Pair { server, client, server_peer, client_peer }
+}
+
+/// Service every host in turn, recording the events returned, until
+/// `predicate` is satisfied by the events recorded so far.
+///
+/// The predicate is checked after each event; returns `PumpError::Timeout` if
+/// it is not satisfied within `timeout`.
+pub fn pump_until <P> (
+  hosts : &mut [&mut Host], timeout : Duration, mut predicate : P
+) -> Result <Vec <Recorded>, PumpError> where
+  P : FnMut (&[Recorded]) -> bool
+{
+  let start      = Instant::now();
+  let mut events = Vec::new();
+  if predicate (&events) {
+    return Ok (events)
+  }
+  loop {
+    let mut idle = true;
+    for (index, host) in hosts.iter_mut().enumerate() {
+      while let Some (event) = host.service (0).map_err (PumpError::Service)? {
+        idle = false;
+        events.push (Recorded { host: index, event });
+        if predicate (&events) {
+          return Ok (events)
+        }
+      }
+    }
+    if timeout <= start.elapsed() {
+      return Err (PumpError::Timeout (events))
+    }
+    if idle {
+      std::thread::sleep (Duration::from_millis (1));
+    }
+  }
+}
+
+/// Assert that the recorded events match the expected `(host, event)` sequence.
+///
+/// # Panics
+///
+/// Panics with both sequences if they differ.
+#[track_caller]
+pub fn assert_events (events : &[Recorded], expected : &[(usize, Expect)]) {
+  let matched = events.len() == expected.len() &&
+    events.iter().zip (expected).all (|(recorded, (host, expect))|
+      recorded.host == *host && expect.matches (&recorded.event));
+  assert!(matched, "event sequence mismatch:\n  expected: {expected:?}\n  \
+    recorded: {:?}", events.iter().map (|recorded| (recorded.host, &recorded.event))
+      .collect::<Vec <_>>());
+}
+
+////////////////////////////////////////////////////////////////////////////////
+//  tests                                                                     //
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg (test)]
+mod tests {
+  use super::*;
+  use crate::{packet, peer, Packet};
+
+  #[test]
+  fn connected_pair_peers_see_each_other() {
+    let pair = connected_pair();
+    assert_eq!(pair.server_peer.state(), peer::State::Connected);
+    assert_eq!(pair.client_peer.state(), peer::State::Connected);
+    assert_eq!(pair.client_peer.address().port(),
+      pair.server.local_address().port());
+    assert_eq!(pair.server_peer.address().port(),
+      pair.client.local_address().port());
+    assert_eq!(pair.server.connected_peers(), 1);
+    assert_eq!(pair.client.connected_peers(), 1);
+  }
+
+  #[test]
+  fn connected_pair_with_sets_up_server_then_client() {
+    let mut setup = Vec::new();
+    let pair = connected_pair_with (3, |host| {
+      setup.push (host.peer_count());
+      host.set_send_budget (1000 * setup.len());
+    });
+    assert_eq!(setup, [SERVER_PEER_COUNT as usize, 1]);
+    assert_eq!((pair.server.send_budget(), pair.client.send_budget()),
+      (1000, 2000));
+    assert!(pair.client_peer.channel_stats (2).is_some());
+    assert!(pair.client_peer.channel_stats (3).is_none());
+  }
+
+  #[test]
+  fn expect_matches_events() {
+    let mut pair = connected_pair();
+    pair.client_peer.send (1, Packet::Allocate {
+      bytes: b"data", flags: packet::Flags::RELIABLE
+    }).unwrap();
+    let events = pump_until (&mut [&mut pair.server, &mut pair.client], TIMEOUT,
+      |events| !events.is_empty()).unwrap();
+    let received = &events[0].event;
+    let receive = |channel_id, data| Expect::Receive { channel_id, data };
+    assert!(receive (1, b"data").matches (received));
+    assert!(!receive (0, b"data").matches (received));
+    assert!(!receive (1, b"other").matches (received));
+    assert!(!Expect::Connect.matches (received));
+    pair.client_peer.disconnect();
+    let events = pump_until (&mut [&mut pair.server, &mut pair.client], TIMEOUT,
+      |events| events.len() == 2).unwrap();
+    let disconnect = &events[0].event;
+    assert!(Expect::Disconnect (None).matches (disconnect));
+    assert!(Expect::Disconnect (Some (0)).matches (disconnect));
+    assert!(!Expect::Disconnect (Some (1)).matches (disconnect));
+    assert!(!Expect::Authenticated.matches (disconnect));
+    assert_events (&events, &[
+      (0, Expect::Disconnect (Some (0))), (1, Expect::Disconnect (None))
+    ]);
+  }
+
+  #[test]
+  #[should_panic (expected = "event sequence mismatch")]
+  fn assert_events_panics_on_mismatch() {
+    let mut pair = connected_pair();
+    pair.client_peer.send (0, Packet::Allocate {
+      bytes: b"data", flags: packet::Flags::RELIABLE
+    }).unwrap();
+    let events = pump_until (&mut [&mut pair.server, &mut pair.client], TIMEOUT,
+      |events| !events.is_empty()).unwrap();
+    assert_events (&events,
+      &[(1, Expect::Receive { channel_id: 0, data: b"data" })]);
+  }
+
+  #[test]
+  fn pump_until_times_out_with_the_events_received() {
+    let mut pair = connected_pair();
+    pair.client_peer.send (0, Packet::Allocate {
+      bytes: b"data", flags: packet::Flags::RELIABLE
+    }).unwrap();
+    let start = Instant::now();
+    let Err (PumpError::Timeout (events)) = pump_until (
+      &mut [&mut pair.server, &mut pair.client], Duration::from_millis (100),
+      |_| false)
+    else {
+      panic!("pump did not time out")
+    };
+    assert!(Duration::from_millis (100) <= start.elapsed());
+    assert_events (&events,
+      &[(0, Expect::Receive { channel_id: 0, data: b"data" })]);
+  }
+}
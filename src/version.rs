@@ -15,6 +15,10 @@ impl Version {
     Version { version: (major << 16) | (minor << 8) | patch }
   }
   #[inline]
+  pub const fn to_ll (self) -> ll::ENetVersion {
+    self.version
+  }
+  #[inline]
   pub const fn get_major (self) -> u32 {
     (self.version >> 16) & 0xff
   }
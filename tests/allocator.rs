@@ -0,0 +1,18 @@
+//! The ENet allocator is chosen once per process, so these tests run in their
+//! own test binary.
+
+use enet::memory::Callbacks;
+
+#[test]
+fn allocator_cannot_change_after_first_initialization() {
+  let enet = enet::initialize_with (Callbacks::new()).unwrap();
+  drop (enet);
+  let err = enet::initialize().err().unwrap();
+  assert!(err.to_string().contains ("different allocator"), "{err}");
+  // the same allocator, with different hooks
+  let enet = enet::initialize_with (Callbacks::new().fail_allocation (0)).unwrap();
+  drop (enet);
+  // a shared context uses the allocator already chosen
+  let enet = enet::Enet::shared().unwrap();
+  drop (enet);
+}
@@ -0,0 +1,53 @@
+//! Allocation failures injected through `memory::Callbacks`. The ENet allocator
+//! is chosen once per process, so these tests run in their own test binary.
+
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, OnceLock};
+
+use enet::memory::{self, Callbacks};
+use enet::{peer, Address, Enet, SharedPacket, Packet};
+
+static ENET : OnceLock <Enet> = OnceLock::new();
+/// Injected failures are global: tests injecting them run one at a time
+static FAILING : Mutex <()> = Mutex::new (());
+static NO_MEMORY_CALLS : AtomicU64 = AtomicU64::new (0);
+
+fn enet() -> Enet {
+  ENET.get_or_init (|| enet::initialize_with (Callbacks::new()
+    .on_no_memory (|| { NO_MEMORY_CALLS.fetch_add (1, Ordering::Relaxed); }))
+    .unwrap()
+  ).clone()
+}
+
+#[test]
+fn packet_create_reports_failed_allocation() {
+  let _enet   = enet();
+  let _lock   = FAILING.lock().unwrap();
+  let failures = memory::stats().failures;
+  let calls    = NO_MEMORY_CALLS.load (Ordering::Relaxed);
+  memory::fail_allocation (1);
+  let result = SharedPacket::new (Packet::Allocate {
+    bytes: b"payload", flags: enet::packet::Flags::RELIABLE
+  });
+  assert_eq!(result.err(), Some (peer::SendErrorKind::PacketCreateMallocFailure));
+  assert_eq!(memory::stats().failures, failures + 1);
+  assert_eq!(NO_MEMORY_CALLS.load (Ordering::Relaxed), calls + 1);
+  // the failure is only injected once
+  let packet = SharedPacket::new (Packet::Allocate {
+    bytes: b"payload", flags: enet::packet::Flags::RELIABLE
+  }).unwrap();
+  assert_eq!(packet.data(), b"payload");
+}
+
+#[test]
+fn connect_reports_failed_allocation() {
+  let enet     = enet();
+  let mut host = enet.client_host_create (1, None, None).unwrap();
+  let address  = Address::from (SocketAddrV4::new (Ipv4Addr::LOCALHOST, 9));
+  let _lock    = FAILING.lock().unwrap();
+  // the channels of the peer are allocated when connecting
+  memory::fail_allocation (1);
+  assert_eq!(host.connect (&address, 1, 0).err(), Some (peer::ConnectError::Failure));
+  host.connect (&address, 1, 0).unwrap();
+}
@@ -0,0 +1,40 @@
+//! The default handling of a failed allocation with `memory::Callbacks`, which
+//! logs a warning. The ENet allocator and the logger are set once per process,
+//! so this test runs in its own test binary.
+
+use std::sync::Mutex;
+
+use enet::memory::{self, Callbacks};
+use enet::{peer, Packet, SharedPacket};
+
+/// Records the messages logged
+struct Logger {
+  messages : Mutex <Vec <(log::Level, String)>>
+}
+
+static LOGGER : Logger = Logger { messages: Mutex::new (Vec::new()) };
+
+impl log::Log for Logger {
+  fn enabled (&self, _metadata : &log::Metadata) -> bool {
+    true
+  }
+  fn log (&self, record : &log::Record) {
+    self.messages.lock().unwrap()
+      .push ((record.level(), record.args().to_string()));
+  }
+  fn flush (&self) {}
+}
+
+#[test]
+fn failed_allocation_is_logged_without_a_hook() {
+  log::set_logger (&LOGGER).unwrap();
+  log::set_max_level (log::LevelFilter::Warn);
+  let _enet = enet::initialize_with (Callbacks::new()).unwrap();
+  memory::fail_allocation (1);
+  let result = SharedPacket::new (Packet::Allocate {
+    bytes: b"payload", flags: enet::packet::Flags::RELIABLE
+  });
+  assert_eq!(result.err(), Some (peer::SendErrorKind::PacketCreateMallocFailure));
+  assert_eq!(*LOGGER.messages.lock().unwrap(),
+    [(log::Level::Warn, "ENet allocation failed".to_owned())]);
+}
@@ -0,0 +1,61 @@
+//! Sending from a `PacketPool` with ENet memory recycled through
+//! `memory::Callbacks`. The ENet allocator is chosen once per process and its
+//! statistics are global, so this test runs alone in its own test binary.
+
+use std::time::Duration;
+
+use enet::memory::{self, Callbacks};
+use enet::packet::{Flags, PacketPool};
+use enet::{testing, Event, Packet};
+
+const PAYLOAD : usize = 100;
+
+/// Send a packet each way with `peer.send()`, broadcast one with
+/// `host.broadcast()` and one written in place with `pool.write()`, and wait
+/// until they are received
+fn exchange (pair : &mut testing::Pair, pool : &PacketPool, round : u8) {
+  let bytes = [round; PAYLOAD];
+  pair.client_peer.send (0, Packet::Allocate { bytes: &bytes, flags: Flags::RELIABLE })
+    .unwrap();
+  pair.server_peer.send (0, Packet::Allocate { bytes: &bytes, flags: Flags::empty() })
+    .unwrap();
+  pair.server.broadcast (1,
+    Packet::Allocate { bytes: &bytes, flags: Flags::RELIABLE });
+  let written = pool.write (PAYLOAD, Flags::RELIABLE, |data| data.fill (round))
+    .unwrap();
+  pair.client_peer.send_shared (1, &written).unwrap();
+  drop (written);
+  let received = |events : &[testing::Recorded]| events.iter()
+    .filter (|recorded| matches!(&recorded.event,
+      Event::Receive { packet, .. } if packet.data() == bytes))
+    .count();
+  testing::pump_until (&mut [&mut pair.server, &mut pair.client],
+    testing::TIMEOUT, |events| received (events) == 4
+  ).unwrap();
+  // let the acknowledgements arrive
+  testing::pump_until (&mut [&mut pair.server, &mut pair.client],
+    Duration::from_millis (20), |_| false
+  ).unwrap_err();
+}
+
+#[test]
+fn pooled_sends_do_not_allocate_after_warmup() {
+  let _enet = enet::initialize_with (Callbacks::new().recycle (64)).unwrap();
+  let pool = PacketPool::new (&[256], 16);
+  let mut pair = testing::connected_pair_with (2,
+    |host| host.set_packet_pool (Some (pool.clone())));
+  for round in 0..10 {
+    exchange (&mut pair, &pool, round);
+  }
+  let before      = memory::stats();
+  let pool_before = pool.stats();
+  for round in 10..30 {
+    exchange (&mut pair, &pool, round);
+  }
+  let after = memory::stats();
+  assert_eq!(after.allocations, before.allocations, "{before:?} {after:?}");
+  assert!(before.recycled < after.recycled);
+  let pool_after = pool.stats();
+  assert_eq!(pool_after.allocated, pool_before.allocated);
+  assert_eq!(pool_after.reused, pool_before.reused + 20 * 4);
+}
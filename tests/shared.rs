@@ -0,0 +1,61 @@
+//! `Enet::shared()` initializing and deinitializing the process-wide context,
+//! so these tests run in their own test binary.
+
+use std::sync::{Barrier, Mutex};
+
+use enet::{Address, Enet};
+
+/// The context is global: tests run one at a time
+static CONTEXT : Mutex <()> = Mutex::new (());
+
+/// `initialize()` fails if and only if a context is live
+fn is_live() -> bool {
+  enet::initialize().is_err()
+}
+
+#[test]
+fn concurrent_first_calls_share_one_context() {
+  const THREADS : usize = 8;
+  let _lock   = CONTEXT.lock().unwrap();
+  let barrier = Barrier::new (THREADS);
+  let mut handles = std::thread::scope (|scope| {
+    let threads = std::iter::repeat_with (|| scope.spawn (|| {
+      barrier.wait();
+      Enet::shared().unwrap()
+    })).take (THREADS).collect::<Vec <_>>();
+    threads.into_iter().map (|thread| thread.join().unwrap())
+      .collect::<Vec <_>>()
+  });
+  let last = handles.pop().unwrap();
+  let mut host = last.client_host_create (1, None, None).unwrap();
+  // any handle keeps the context alive for the others
+  drop (handles);
+  assert!(is_live());
+  host.service (0).unwrap();
+  drop (last);
+  assert!(is_live());
+  drop (host);
+  assert!(!is_live());
+}
+
+#[test]
+fn shared_context_is_initialized_again_after_the_last_handle_is_dropped() {
+  let _lock = CONTEXT.lock().unwrap();
+  for _ in 0..3 {
+    let enet = Enet::shared().unwrap();
+    let mut server = enet.server_host_create (Address::localhost (0), 1, None,
+      None, None).unwrap();
+    let other = Enet::shared().unwrap();
+    drop (enet);
+    server.service (0).unwrap();
+    drop (server);
+    assert!(is_live());
+    drop (other);
+    assert!(!is_live());
+  }
+  // an exclusive context after the shared ones
+  let enet = enet::initialize().unwrap();
+  Enet::shared().unwrap();
+  drop (enet);
+  assert!(!is_live());
+}